@@ -0,0 +1,163 @@
+use crate::config::AgentConfig;
+use crate::error::{Error, Result};
+use crate::scrobble::{Confidence, Scrobble, ScrobbleBackend};
+use crate::websocket::{self, Stream, WebSocket};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// How long the hub may take to connect or answer
+const HUB_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// What an agent sends the hub over its WebSocket, one JSON message each.
+/// The hub answers every message with `{"ok": true}`, or with `"ok": false`
+/// and an `error`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Forwarded {
+    #[serde(rename = "type")]
+    pub kind: ForwardKind,
+    pub listen: ForwardedListen,
+}
+
+/// What the hub is to do with a forwarded listen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardKind {
+    NowPlaying,
+    Scrobble,
+    Love,
+}
+
+/// A [`Scrobble`] as it travels from an agent to the hub
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ForwardedListen {
+    pub device: String,
+    pub room: Option<String>,
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+    pub started_at: i64,
+    pub duration_secs: Option<u64>,
+    #[serde(default)]
+    pub featured: Vec<String>,
+    pub artist_confidence: u8,
+    pub title_confidence: u8,
+    pub chosen_by_user: bool,
+    pub track_uri: Option<String>,
+}
+
+impl From<&Scrobble> for ForwardedListen {
+    fn from(scrobble: &Scrobble) -> Self {
+        Self {
+            device: scrobble.device.clone(),
+            room: scrobble.room.clone(),
+            artist: scrobble.artist.clone(),
+            title: scrobble.title.clone(),
+            album: scrobble.album.clone(),
+            started_at: scrobble.started_at,
+            duration_secs: scrobble.duration.map(|duration| duration.as_secs()),
+            featured: scrobble.featured.clone(),
+            artist_confidence: scrobble.confidence.artist,
+            title_confidence: scrobble.confidence.title,
+            chosen_by_user: scrobble.chosen_by_user,
+            track_uri: scrobble.track_uri.clone(),
+        }
+    }
+}
+
+impl From<ForwardedListen> for Scrobble {
+    fn from(listen: ForwardedListen) -> Self {
+        Self {
+            device: listen.device,
+            artist: listen.artist,
+            title: listen.title,
+            album: listen.album,
+            started_at: listen.started_at,
+            duration: listen.duration_secs.map(Duration::from_secs),
+            featured: listen.featured,
+            confidence: Confidence { artist: listen.artist_confidence, title: listen.title_confidence },
+            chosen_by_user: listen.chosen_by_user,
+            track_uri: listen.track_uri,
+            room: listen.room,
+        }
+    }
+}
+
+/// The only scrobble backend of an agent: forwards its speakers' listens to
+/// the hub, which records, filters, routes and submits them. The connection
+/// is opened on the first listen and again after it breaks; a listen the hub
+/// couldn't be told about fails like any other scrobble.
+pub struct HubLink {
+    url: String,
+    token: String,
+    ca_cert: Option<PathBuf>,
+    connection: Mutex<Option<WebSocket<Box<dyn Stream>>>>,
+}
+
+impl HubLink {
+    pub fn new(config: &AgentConfig) -> Self {
+        Self {
+            url: config.hub.clone(),
+            token: config.token.clone(),
+            ca_cert: config.ca_cert.clone(),
+            connection: Mutex::default(),
+        }
+    }
+
+    async fn forward(&self, kind: ForwardKind, scrobble: &Scrobble) -> Result<()> {
+        let message = Forwarded { kind, listen: scrobble.into() };
+        let text = serde_json::to_string(&message).map_err(|e| Error::Scrobble(e.to_string()))?;
+        let mut connection = self.connection.lock().await;
+        let reply = match tokio::time::timeout(HUB_TIMEOUT, self.exchange(&mut connection, &text)).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(e)) => {
+                *connection = None;
+                return Err(e);
+            }
+            Err(_) => {
+                *connection = None;
+                return Err(Error::Scrobble(format!("hub at {} didn't answer", self.url)));
+            }
+        };
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap_or_default();
+        match reply["ok"].as_bool() {
+            Some(true) => Ok(()),
+            _ => Err(Error::Scrobble(format!("hub: {}", reply["error"].as_str().unwrap_or("invalid reply")))),
+        }
+    }
+
+    /// Sends `text` over the connection, opening it first if needed, and
+    /// reads the hub's reply
+    async fn exchange(&self, connection: &mut Option<WebSocket<Box<dyn Stream>>>, text: &str) -> Result<String> {
+        let socket = match connection {
+            Some(socket) => socket,
+            None => connection.insert(websocket::connect(&self.url, &self.token, self.ca_cert.as_deref()).await?),
+        };
+        socket.send(text).await?;
+        socket
+            .recv()
+            .await?
+            .ok_or_else(|| Error::Scrobble(format!("hub at {} closed the connection", self.url)))
+    }
+}
+
+#[async_trait]
+impl ScrobbleBackend for HubLink {
+    fn name(&self) -> &str {
+        "hub"
+    }
+
+    async fn now_playing(&self, scrobble: &Scrobble) -> Result<()> {
+        self.forward(ForwardKind::NowPlaying, scrobble).await
+    }
+
+    async fn scrobble(&self, scrobble: &Scrobble) -> Result<()> {
+        self.forward(ForwardKind::Scrobble, scrobble).await
+    }
+
+    async fn love(&self, scrobble: &Scrobble) -> Result<()> {
+        self.forward(ForwardKind::Love, scrobble).await
+    }
+}
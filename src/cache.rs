@@ -0,0 +1,113 @@
+use crate::config::CacheConfig;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Values kept in memory for a while, dropping the least recently used once
+/// full. Spares lookups that keep asking the same, e.g. while an album is on
+/// repeat.
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<K, Entry<V>>,
+    /// Keys by when they were last used, oldest first
+    order: BTreeMap<u64, K>,
+    /// Counts uses, to order them
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+    stored_at: Instant,
+    used: u64,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> LruCache<K, V> {
+    /// Keeps up to `capacity` values for `ttl` each; a capacity of 0 keeps
+    /// none
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self { capacity, ttl, entries: HashMap::new(), order: BTreeMap::new(), clock: 0 }
+    }
+
+    pub fn from_config(config: &CacheConfig) -> Self {
+        Self::new(config.size, Duration::from_secs(config.ttl_secs))
+    }
+
+    /// The value stored for `key`, unless it expired
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.get(key)?;
+        if entry.stored_at.elapsed() >= self.ttl {
+            self.remove(key);
+            return None;
+        }
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.used);
+        entry.used = self.clock;
+        self.order.insert(self.clock, key.clone());
+        Some(entry.value.clone())
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.entries.insert(key, Entry { value, stored_at: Instant::now(), used: self.clock });
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_recently_used_is_dropped() {
+        let mut cache = LruCache::new(2, Duration::from_secs(60));
+        cache.insert("Get Lucky", 1);
+        cache.insert("Instant Crush", 2);
+        assert_eq!(cache.get(&"Get Lucky"), Some(1));
+        cache.insert("Doin' It Right", 3);
+
+        assert_eq!(cache.get(&"Instant Crush"), None);
+        assert_eq!(cache.get(&"Get Lucky"), Some(1));
+        assert_eq!(cache.get(&"Doin' It Right"), Some(3));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_expired_values_are_not_served() {
+        let mut cache = LruCache::new(2, Duration::ZERO);
+        cache.insert("Get Lucky", 1);
+        assert_eq!(cache.get(&"Get Lucky"), None);
+        assert!(cache.is_empty());
+
+        let mut disabled = LruCache::new(0, Duration::from_secs(60));
+        disabled.insert("Get Lucky", 1);
+        assert!(disabled.is_empty());
+    }
+}
@@ -0,0 +1,379 @@
+use clap::builder::ValueHint;
+use clap::{Arg, Command};
+use std::fmt::Write;
+
+/// Value name of arguments that take a room, which the scripts complete from
+/// the rooms in the device cache
+pub const ROOM_VALUE_NAME: &str = "ROOM";
+
+/// Hidden subcommand the scripts run to list the cached rooms
+pub const ROOMS_COMMAND: &str = "complete-rooms";
+
+/// Shells `completions` writes scripts for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// What an option or positional argument completes to
+#[derive(Debug, Clone, PartialEq)]
+enum Values {
+    /// Takes no value
+    Flag,
+    /// Takes a value nothing is known about
+    Any,
+    Path,
+    Room,
+    Choices(Vec<String>),
+}
+
+#[derive(Debug)]
+struct Opt {
+    long: Option<String>,
+    short: Option<char>,
+    help: String,
+    values: Values,
+}
+
+/// A command or subcommand; `key` names it in the scripts, e.g.
+/// `sonos_scrobbler__queue__quarantine`
+#[derive(Debug)]
+struct Node {
+    key: String,
+    /// Name, description and key of each subcommand
+    subcommands: Vec<(String, String, String)>,
+    options: Vec<Opt>,
+    positionals: Vec<Values>,
+}
+
+/// Writes the completion script for `shell` from the CLI definition
+pub fn generate(shell: Shell, mut command: Command) -> String {
+    command.build();
+    let bin = command.get_name().to_string();
+    let mut nodes = Vec::new();
+    collect(&command, bin.replace('-', "_"), &mut nodes);
+    match shell {
+        Shell::Bash => bash(&bin, &nodes),
+        Shell::Zsh => zsh(&bin, &nodes),
+        Shell::Fish => fish(&bin, &nodes),
+    }
+}
+
+fn collect(command: &Command, key: String, nodes: &mut Vec<Node>) {
+    let subcommands: Vec<_> = command
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set() && sub.get_name() != "help")
+        .collect();
+    let mut node = Node { key: key.clone(), subcommands: Vec::new(), options: Vec::new(), positionals: Vec::new() };
+    for arg in command.get_arguments().filter(|arg| !arg.is_hide_set()) {
+        if arg.is_positional() {
+            node.positionals.push(values(arg));
+        } else {
+            node.options.push(Opt {
+                long: arg.get_long().map(str::to_string),
+                short: arg.get_short(),
+                help: first_line(arg.get_help().map(|help| help.to_string())),
+                values: values(arg),
+            });
+        }
+    }
+    for sub in &subcommands {
+        let child = format!("{}__{}", key, sub.get_name().replace('-', "_"));
+        let about = first_line(sub.get_about().map(|about| about.to_string()));
+        node.subcommands.push((sub.get_name().to_string(), about, child));
+    }
+    nodes.push(node);
+    for sub in subcommands {
+        collect(sub, format!("{}__{}", key, sub.get_name().replace('-', "_")), nodes);
+    }
+}
+
+fn values(arg: &Arg) -> Values {
+    if !arg.get_action().takes_values() {
+        return Values::Flag;
+    }
+    if arg.get_value_names().is_some_and(|names| names.iter().any(|name| name == ROOM_VALUE_NAME)) {
+        return Values::Room;
+    }
+    let choices: Vec<_> = arg
+        .get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_string())
+        .collect();
+    if !choices.is_empty() {
+        return Values::Choices(choices);
+    }
+    match arg.get_value_hint() {
+        ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath | ValueHint::ExecutablePath => Values::Path,
+        _ => Values::Any,
+    }
+}
+
+fn first_line(text: Option<String>) -> String {
+    text.unwrap_or_default().lines().next().unwrap_or_default().trim_end_matches('.').to_string()
+}
+
+/// Quotes for bash and zsh
+fn quoted(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+fn fish_quoted(text: &str) -> String {
+    format!("'{}'", text.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+/// The ways an option is written, e.g. `--output` and `-o`
+fn spellings(opt: &Opt) -> Vec<String> {
+    opt.long.iter().map(|long| format!("--{}", long)).chain(opt.short.map(|short| format!("-{}", short))).collect()
+}
+
+fn bash(bin: &str, nodes: &[Node]) -> String {
+    let root = &nodes[0].key;
+    let mut script = String::new();
+    let _ = writeln!(script, "__{}_rooms() {{", root);
+    let _ = writeln!(script, "    local IFS=$'\\n' room");
+    let rooms = format!("$({} {} 2>/dev/null)", bin, ROOMS_COMMAND);
+    let _ = writeln!(script, "    for room in $(compgen -W \"{}\" -- \"${{cur}}\"); do", rooms);
+    let _ = writeln!(script, "        COMPREPLY+=(\"$(printf '%q' \"${{room}}\")\")");
+    let _ = writeln!(script, "    done\n}}\n");
+
+    let _ = writeln!(script, "_{}() {{", root);
+    let _ = writeln!(script, "    local cur prev cmd i opts extra");
+    let _ = writeln!(script, "    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"");
+    let _ = writeln!(script, "    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"");
+    let _ = writeln!(script, "    cmd=\"{}\"", root);
+    let _ = writeln!(script, "    COMPREPLY=()");
+    let _ = writeln!(script, "    for ((i = 1; i < COMP_CWORD; i++)); do");
+    let _ = writeln!(script, "        case \"${{cmd}},${{COMP_WORDS[i]}}\" in");
+    for node in nodes {
+        for (name, _, child) in &node.subcommands {
+            let _ = writeln!(script, "            {},{}) cmd=\"{}\" ;;", node.key, name, child);
+        }
+    }
+    let _ = writeln!(script, "        esac\n    done\n");
+
+    let _ = writeln!(script, "    case \"${{cmd}},${{prev}}\" in");
+    for node in nodes {
+        for opt in &node.options {
+            let action = match &opt.values {
+                Values::Flag => continue,
+                Values::Any => "return".to_string(),
+                Values::Path => "COMPREPLY=($(compgen -f -- \"${cur}\")); return".to_string(),
+                Values::Room => format!("__{}_rooms; return", root),
+                Values::Choices(choices) => {
+                    format!("COMPREPLY=($(compgen -W \"{}\" -- \"${{cur}}\")); return", choices.join(" "))
+                }
+            };
+            let patterns: Vec<_> = spellings(opt).iter().map(|spelling| format!("{},{}", node.key, spelling)).collect();
+            let _ = writeln!(script, "        {}) {} ;;", patterns.join("|"), action);
+        }
+    }
+    let _ = writeln!(script, "    esac\n");
+
+    let _ = writeln!(script, "    case \"${{cmd}}\" in");
+    for node in nodes {
+        let mut words: Vec<_> = node.subcommands.iter().map(|(name, _, _)| name.clone()).collect();
+        words.extend(node.options.iter().flat_map(spellings));
+        let mut extra = "";
+        for positional in &node.positionals {
+            match positional {
+                Values::Choices(choices) => words.extend(choices.iter().cloned()),
+                Values::Path => extra = "files",
+                Values::Room => extra = "rooms",
+                Values::Flag | Values::Any => {}
+            }
+        }
+        let _ = writeln!(script, "        {})", node.key);
+        let _ = writeln!(script, "            opts=\"{}\"", words.join(" "));
+        if !extra.is_empty() {
+            let _ = writeln!(script, "            extra=\"{}\"", extra);
+        }
+        let _ = writeln!(script, "            ;;");
+    }
+    let _ = writeln!(script, "    esac");
+    let _ = writeln!(script, "    COMPREPLY=($(compgen -W \"${{opts}}\" -- \"${{cur}}\"))");
+    let _ = writeln!(script, "    case \"${{extra}}\" in");
+    let _ = writeln!(script, "        files) COMPREPLY+=($(compgen -f -- \"${{cur}}\")) ;;");
+    let _ = writeln!(script, "        rooms) __{}_rooms ;;", root);
+    let _ = writeln!(script, "    esac\n}}\n");
+    let _ = writeln!(script, "complete -F _{} {}", root, bin);
+    script
+}
+
+fn zsh(bin: &str, nodes: &[Node]) -> String {
+    let root = &nodes[0].key;
+    let mut script = String::new();
+    let _ = writeln!(script, "#compdef {}\n", bin);
+    let _ = writeln!(script, "__{}_rooms() {{", root);
+    let _ = writeln!(script, "    local -a rooms");
+    let _ = writeln!(script, "    rooms=(${{(f)\"$({} {} 2>/dev/null)\"}})", bin, ROOMS_COMMAND);
+    let _ = writeln!(script, "    compadd -a rooms\n}}\n");
+
+    let _ = writeln!(script, "_{}() {{", root);
+    let _ = writeln!(script, "    local cmd={} prev=${{words[CURRENT-1]}} i", root);
+    let _ = writeln!(script, "    local -a opts");
+    let _ = writeln!(script, "    for ((i = 2; i < CURRENT; i++)); do");
+    let _ = writeln!(script, "        case \"${{cmd}},${{words[i]}}\" in");
+    for node in nodes {
+        for (name, _, child) in &node.subcommands {
+            let _ = writeln!(script, "            {},{}) cmd={} ;;", node.key, name, child);
+        }
+    }
+    let _ = writeln!(script, "        esac\n    done\n");
+
+    let _ = writeln!(script, "    case \"${{cmd}},${{prev}}\" in");
+    for node in nodes {
+        for opt in &node.options {
+            let action = match &opt.values {
+                Values::Flag => continue,
+                Values::Any => "return 1".to_string(),
+                Values::Path => "_files; return".to_string(),
+                Values::Room => format!("__{}_rooms; return", root),
+                Values::Choices(choices) => format!("compadd -- {}; return", choices.join(" ")),
+            };
+            let patterns: Vec<_> = spellings(opt).iter().map(|spelling| format!("{},{}", node.key, spelling)).collect();
+            let _ = writeln!(script, "        {}) {} ;;", patterns.join("|"), action);
+        }
+    }
+    let _ = writeln!(script, "    esac\n");
+
+    let _ = writeln!(script, "    case \"${{cmd}}\" in");
+    for node in nodes {
+        let mut entries: Vec<_> =
+            node.subcommands.iter().map(|(name, about, _)| quoted(&format!("{}:{}", name, about))).collect();
+        for opt in &node.options {
+            entries.extend(spellings(opt).iter().map(|spelling| quoted(&format!("{}:{}", spelling, opt.help))));
+        }
+        let _ = writeln!(script, "        {})", node.key);
+        let _ = writeln!(script, "            opts=({})", entries.join(" "));
+        let _ = writeln!(script, "            _describe command opts");
+        for positional in &node.positionals {
+            match positional {
+                Values::Choices(choices) => {
+                    let _ = writeln!(script, "            compadd -- {}", choices.join(" "));
+                }
+                Values::Path => {
+                    let _ = writeln!(script, "            _files");
+                }
+                Values::Room => {
+                    let _ = writeln!(script, "            __{}_rooms", root);
+                }
+                Values::Flag | Values::Any => {}
+            }
+        }
+        let _ = writeln!(script, "            ;;");
+    }
+    let _ = writeln!(script, "    esac\n}}\n");
+    let _ = writeln!(script, "if [ \"${{funcstack[1]}}\" = \"_{}\" ]; then", root);
+    let _ = writeln!(script, "    _{} \"$@\"\nelse\n    compdef _{} {}\nfi", root, root, bin);
+    script
+}
+
+fn fish(bin: &str, nodes: &[Node]) -> String {
+    let root = &nodes[0].key;
+    let mut script = String::new();
+    let _ = writeln!(script, "function __{}_cmd", root);
+    let _ = writeln!(script, "    set -l cmd {}", root);
+    let _ = writeln!(script, "    for word in (commandline -opc)[2..-1]");
+    let _ = writeln!(script, "        switch \"$cmd,$word\"");
+    for node in nodes {
+        for (name, _, child) in &node.subcommands {
+            let _ = writeln!(script, "            case '{},{}'\n                set cmd {}", node.key, name, child);
+        }
+    }
+    let _ = writeln!(script, "        end\n    end\n    echo $cmd\nend\n");
+    let _ = writeln!(script, "function __{}_using\n    test (__{}_cmd) = $argv[1]\nend\n", root, root);
+    let _ = writeln!(script, "function __{}_rooms\n    {} {} 2>/dev/null\nend\n", root, bin, ROOMS_COMMAND);
+
+    let _ = writeln!(script, "complete -c {} -f", bin);
+    for node in nodes {
+        let condition = format!("-n '__{}_using {}'", root, node.key);
+        for (name, about, _) in &node.subcommands {
+            let _ = writeln!(script, "complete -c {} {} -a {} -d {}", bin, condition, name, fish_quoted(about));
+        }
+        for opt in &node.options {
+            let mut line = format!("complete -c {} {}", bin, condition);
+            if let Some(long) = &opt.long {
+                let _ = write!(line, " -l {}", long);
+            }
+            if let Some(short) = opt.short {
+                let _ = write!(line, " -s {}", short);
+            }
+            match &opt.values {
+                Values::Flag => {}
+                Values::Any => line.push_str(" -r"),
+                Values::Path => line.push_str(" -r -F"),
+                Values::Room => {
+                    let _ = write!(line, " -r -a '(__{}_rooms)'", root);
+                }
+                Values::Choices(choices) => {
+                    let _ = write!(line, " -r -a {}", fish_quoted(&choices.join(" ")));
+                }
+            }
+            let _ = writeln!(script, "{} -d {}", line, fish_quoted(&opt.help));
+        }
+        for positional in &node.positionals {
+            match positional {
+                Values::Choices(choices) => {
+                    let choices = fish_quoted(&choices.join(" "));
+                    let _ = writeln!(script, "complete -c {} {} -a {}", bin, condition, choices);
+                }
+                Values::Path => {
+                    let _ = writeln!(script, "complete -c {} {} -F", bin, condition);
+                }
+                Values::Room => {
+                    let _ = writeln!(script, "complete -c {} {} -a '(__{}_rooms)'", bin, condition, root);
+                }
+                Values::Flag | Values::Any => {}
+            }
+        }
+    }
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ArgAction;
+
+    fn command() -> Command {
+        Command::new("sonos-scrobbler")
+            .arg(Arg::new("config").long("config").value_parser(clap::value_parser!(std::path::PathBuf)).global(true))
+            .subcommand(
+                Command::new("now-playing")
+                    .about("Show what each speaker is playing right now")
+                    .arg(Arg::new("room").long("room").value_name(ROOM_VALUE_NAME).action(ArgAction::Append)),
+            )
+            .subcommand(
+                Command::new("replay").arg(Arg::new("file").value_parser(clap::value_parser!(std::path::PathBuf))),
+            )
+            .subcommand(Command::new(ROOMS_COMMAND).hide(true))
+    }
+
+    #[test]
+    fn test_bash_completes_subcommands_and_rooms() {
+        let script = generate(Shell::Bash, command());
+        assert!(script.contains("sonos_scrobbler,now-playing) cmd=\"sonos_scrobbler__now_playing\" ;;"));
+        // Global options are offered after subcommands too
+        assert!(script.contains("sonos_scrobbler__replay,--config) COMPREPLY=($(compgen -f"));
+        assert!(script.contains("sonos_scrobbler__now_playing,--room) __sonos_scrobbler_rooms; return ;;"));
+        assert!(script.contains("$(sonos-scrobbler complete-rooms 2>/dev/null)"));
+        // The hidden subcommand is not offered
+        assert!(script.contains("opts=\"now-playing replay --config --help -h\""));
+        assert!(script.ends_with("complete -F _sonos_scrobbler sonos-scrobbler\n"));
+    }
+
+    #[test]
+    fn test_fish_describes_subcommands() {
+        let script = generate(Shell::Fish, command());
+        assert!(script.contains(
+            "complete -c sonos-scrobbler -n '__sonos_scrobbler_using sonos_scrobbler' -a now-playing \
+             -d 'Show what each speaker is playing right now'"
+        ));
+        assert!(script.contains("-n '__sonos_scrobbler_using sonos_scrobbler__replay' -F"));
+        assert!(script.contains("-l room -r -a '(__sonos_scrobbler_rooms)'"));
+    }
+}
@@ -0,0 +1,989 @@
+use crate::error::{Error, Result};
+use crate::retry::RetryPolicy;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const CONFIG_DIR: &str = "sonos-scrobbler";
+const CONFIG_FILE: &str = "config.toml";
+/// Where the system keeps its time zone database
+const ZONEINFO_DIRS: [&str; 3] = ["/usr/share/zoneinfo", "/usr/lib/zoneinfo", "/usr/share/lib/zoneinfo"];
+
+/// Settings read from `config.toml`. Every field has a default, so a missing
+/// file or section behaves like an empty one.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Minutes between status summaries in the log; 0 disables them
+    pub status_interval: u64,
+    /// When a listen is submitted to the scrobble backends
+    pub scrobble_on: ScrobbleOn,
+    /// How much of a listen has to be played before it is scrobbled
+    pub threshold: ThresholdConfig,
+    /// Seconds a listen is held back before it is submitted, during which a
+    /// "don't scrobble" command can cancel it; 0 submits right away
+    pub scrobble_delay_secs: u64,
+    /// Least seconds between now-playing updates for the same track on a
+    /// speaker; a new track is always announced right away
+    pub now_playing_interval_secs: u64,
+    /// Days the events of every stage of the listen pipeline are kept, so
+    /// later stages can be derived again after a fix; 0 logs none
+    pub event_log_days: u32,
+    /// Hours a Last.fm session key that proved valid is trusted before
+    /// startup checks it again; 0 checks at every startup. A rejected key is
+    /// checked at the next startup regardless.
+    pub session_check_hours: u64,
+    /// `host:port` connected to when every scrobble backend fails, to tell
+    /// whether the internet is down. While it is, listens are recorded
+    /// locally and submitted once it is back. Empty disables the check.
+    pub connectivity_check: String,
+    pub discovery: DiscoveryConfig,
+    /// Speakers polled at the same time; the others wait their turn. 0 means
+    /// no limit.
+    pub max_concurrent_polls: usize,
+    /// Rooms to monitor, e.g. as chosen with `run --pick`; every room when
+    /// empty
+    pub rooms: Vec<String>,
+    /// Rooms only subscribed to events while they play; all others are
+    /// subscribed for as long as the daemon runs
+    pub on_demand_rooms: Vec<String>,
+    /// Rooms whose speakers go to sleep, besides the Roam and Move, which
+    /// are recognized by model. Their silence is not reported as a failure.
+    pub portable_rooms: Vec<String>,
+    /// SMTP settings for the monthly listening digest; no digest when unset
+    pub email: Option<EmailConfig>,
+    /// Telegram bot that announces scrobbles and takes commands
+    pub telegram: Option<TelegramConfig>,
+    /// HTTP server for remote control; not started when unset
+    pub http: Option<HttpConfig>,
+    /// Alerts when a room that scrobbles every day plays for hours without
+    /// scrobbling; off when unset
+    pub scrobble_guard: Option<ScrobbleGuardConfig>,
+    /// Announces scrobble counts, track plays and listening streaks passing
+    /// round numbers; off when unset
+    pub milestones: Option<MilestonesConfig>,
+    /// Stops scrobbling rooms that keep playing at night with nobody
+    /// touching them; off when unset
+    pub sleep: Option<SleepConfig>,
+    /// Tracks and artists never to scrobble, synced from Last.fm tags; off
+    /// when unset
+    pub blocklist: Option<BlocklistConfig>,
+    /// Copy of the log in a rotating file; stderr only when unset
+    pub log_file: Option<LogFileConfig>,
+    /// IANA time zone, e.g. "Europe/Berlin", for days, months, alarms and
+    /// budgets; the system's when unset. Timestamps are stored in UTC.
+    pub timezone: Option<String>,
+    /// Separators between artists in multi-artist strings like "A, B & C";
+    /// only the first artist is scrobbled, the others are kept for stats.
+    /// Empty disables splitting.
+    pub artist_separators: Vec<String>,
+    /// Listens whose artist or title is trusted less than this, from 0 to
+    /// 100, are quarantined instead of scrobbled
+    pub min_confidence: u8,
+    /// Daily listening time per room, e.g. `"Kids Room" = { minutes = 120 }`
+    pub listen_budgets: BTreeMap<String, ListenBudgetConfig>,
+    /// Subsonic-compatible server to report plays to; none when unset
+    pub subsonic: Option<SubsonicConfig>,
+    /// Plex server on which tracks played from its library are marked as
+    /// played; none when unset
+    pub plex: Option<PlexConfig>,
+    /// Funkwhale instance to submit listens to; none when unset
+    pub funkwhale: Option<FunkwhaleConfig>,
+    /// Sends scrobbles to a party account instead of the personal ones
+    pub party: PartyConfig,
+    /// Named sets of settings to switch between, e.g. `home`, `away` and
+    /// `party`, each replacing the settings above that it gives
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    /// Profile active until another one is switched to; the switch is
+    /// remembered across restarts
+    pub profile: Option<String>,
+    /// Whether plays from a source count as chosen by the listener, e.g.
+    /// `radio = true`. Radio, line-in, TV and alarms default to not chosen.
+    pub chosen_by_user: BTreeMap<String, bool>,
+    /// Scrobble backends per room, e.g. `Office = ["lastfm"]`. Rooms not
+    /// listed use every backend; an empty list only logs locally.
+    pub routes: BTreeMap<String, Vec<String>>,
+    /// Whether ListenBrainz and Last.fm are told which room a listen was
+    /// played in; the room is recorded locally either way
+    pub share_room: bool,
+    /// Hub to forward every listen to, which records, routes and submits
+    /// them; scrobbles directly when unset
+    pub agent: Option<AgentConfig>,
+    /// Further Sonos systems monitored by the same process, e.g. an office
+    /// reached over a VPN, by name. The settings above are the main one's.
+    pub households: BTreeMap<String, HouseholdConfig>,
+    /// D-Bus service for desktop keybindings and other daemons, on Linux;
+    /// off when unset
+    pub dbus: Option<DbusConfig>,
+    /// How failed speaker calls, subscription renewals, scrobbles and
+    /// database writes are retried
+    pub retry: RetryConfig,
+    /// How the daemon's submissions to the scrobble backends are queued
+    pub submission: SubmissionConfig,
+    /// How many enrichment lookups and device descriptions are kept in
+    /// memory, and for how long
+    pub cache: CacheConfig,
+    /// Where the NAS music library is mounted, to read the tags of the files
+    /// speakers play from it
+    pub library: LibraryConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            status_interval: 15,
+            scrobble_on: ScrobbleOn::default(),
+            threshold: ThresholdConfig::default(),
+            scrobble_delay_secs: 0,
+            now_playing_interval_secs: 60,
+            event_log_days: 30,
+            session_check_hours: 24,
+            connectivity_check: "ws.audioscrobbler.com:443".to_string(),
+            discovery: DiscoveryConfig::default(),
+            max_concurrent_polls: 8,
+            rooms: Vec::new(),
+            on_demand_rooms: Vec::new(),
+            portable_rooms: Vec::new(),
+            email: None,
+            telegram: None,
+            http: None,
+            scrobble_guard: None,
+            milestones: None,
+            sleep: None,
+            blocklist: None,
+            log_file: None,
+            timezone: None,
+            artist_separators: Vec::new(),
+            min_confidence: 0,
+            listen_budgets: BTreeMap::new(),
+            subsonic: None,
+            plex: None,
+            funkwhale: None,
+            party: PartyConfig::default(),
+            profiles: BTreeMap::new(),
+            profile: None,
+            chosen_by_user: BTreeMap::new(),
+            routes: BTreeMap::new(),
+            share_room: false,
+            households: BTreeMap::new(),
+            agent: None,
+            dbus: None,
+            retry: RetryConfig::default(),
+            submission: SubmissionConfig::default(),
+            cache: CacheConfig::default(),
+            library: LibraryConfig::default(),
+        }
+    }
+}
+
+/// Every scrobble backend has its own queue of submissions, now-playing
+/// updates first, then fresh scrobbles, then those recorded while offline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubmissionConfig {
+    /// Calls to the same backend at a time
+    pub concurrency: usize,
+    /// Failed calls in a row after which a backend is left alone for a while;
+    /// 0 keeps calling it regardless
+    pub breaker_failures: u32,
+    /// Seconds a backend is left alone after that; then one call tries
+    /// whether it recovered
+    pub breaker_cooldown_secs: u64,
+}
+
+impl Default for SubmissionConfig {
+    fn default() -> Self {
+        Self { concurrency: 2, breaker_failures: 5, breaker_cooldown_secs: 60 }
+    }
+}
+
+/// In-memory caches of lookups that keep asking the same: album lookups on
+/// Discogs and speakers' device descriptions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Entries kept per cache before the least recently used is dropped; 0
+    /// caches nothing
+    pub size: usize,
+    /// Seconds an entry is served before it is looked up again
+    pub ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self { size: 1000, ttl_secs: 60 * 60 }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LibraryConfig {
+    /// Local mount points by share, e.g. `"//nas/Music" = "/mnt/music"`
+    pub mounts: BTreeMap<String, PathBuf>,
+}
+
+/// Retry policies by what is retried. A table given for one replaces its
+/// defaults; keys missing from it are no retries, 500 ms linear backoff and
+/// at most 5 s between attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// SOAP calls to speakers
+    pub device: RetryPolicy,
+    /// Event subscription renewals
+    pub subscription: RetryPolicy,
+    /// Submissions to each scrobble backend
+    pub scrobble: RetryPolicy,
+    /// Database writes while the database is busy
+    pub database: RetryPolicy,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            device: RetryPolicy::DEVICE,
+            subscription: RetryPolicy::SUBSCRIPTION,
+            scrobble: RetryPolicy::SCROBBLE,
+            database: RetryPolicy::DATABASE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrobbleOn {
+    /// As soon as the threshold has been played
+    #[default]
+    Threshold,
+    /// Once the track has ended, provided it reached the threshold. Listens
+    /// cut short by skipping or stopping are never submitted.
+    TrackEnd,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThresholdConfig {
+    /// Share of the track that has to be played, in percent
+    pub percent: u8,
+    /// Seconds after which a listen counts however long the track is; also
+    /// what songs in streams without a length need
+    pub max_secs: u64,
+    /// Seconds to play per source instead of a share of the track, by
+    /// source kind: "queue", "playlist", "radio", "line-in", "tv" or "track"
+    pub sources: BTreeMap<String, u64>,
+}
+
+impl ThresholdConfig {
+    /// Play time after which a listen of a track `duration` long, played
+    /// from a `source` kind, counts as a scrobble
+    pub fn play_time(&self, source: &str, duration: Option<Duration>) -> Duration {
+        let max = Duration::from_secs(self.max_secs);
+        if let Some(&secs) = self.sources.get(source) {
+            return Duration::from_secs(secs);
+        }
+        match duration {
+            Some(duration) => (duration * u32::from(self.percent.min(100)) / 100).min(max),
+            None => max,
+        }
+    }
+}
+
+impl Default for ThresholdConfig {
+    fn default() -> Self {
+        Self {
+            percent: 50,
+            max_secs: 4 * 60,
+            sources: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Speakers to use instead of discovering them
+    pub devices: Vec<Ipv4Addr>,
+    /// CIDR range to scan for speakers on networks that block multicast
+    pub subnet: Option<String>,
+    /// Maximum number of addresses probed at once during a subnet scan
+    pub scan_concurrency: usize,
+    /// How long discovery results are reused before rescanning
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            devices: Vec::new(),
+            subnet: None,
+            scan_concurrency: 64,
+            cache_ttl_secs: 30 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub listen: SocketAddr,
+    /// Shared secret for the `/trigger/*` endpoints, passed as `?secret=` or
+    /// the `X-Trigger-Secret` header; triggers are disabled without one
+    pub trigger_secret: Option<String>,
+    /// Bearer token accepted by the HTTP API, sent as
+    /// `Authorization: Bearer <token>`
+    pub api_token: Option<String>,
+    /// Username and password accepted by the HTTP API, for browsers
+    pub basic_auth: Option<BasicAuth>,
+    /// Seconds each event subscription is requested for; speakers may grant
+    /// less, and subscriptions are renewed halfway through what was granted
+    pub subscription_timeout_secs: u64,
+    /// Prefix length of the subnet speakers share with this host, e.g. 16
+    /// on a /16 network. Speakers outside it are still subscribed to, with
+    /// a warning, unless this host is on a container network.
+    pub speaker_prefix_len: u32,
+    /// Secret path segment of the URL speakers send their events to, made of
+    /// letters, digits, `-` and `_`; a random one kept in the database when
+    /// unset
+    pub event_token: Option<String>,
+    /// Serve HTTPS instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+    /// Accounts of other players that scrobble through this service with
+    /// the Last.fm API at `/2.0/`; the proxy is off without any
+    pub audioscrobbler: Vec<AudioscrobblerClient>,
+    /// Token agents present to forward their listens over a WebSocket at
+    /// `/agent`; agents are refused when unset
+    pub agent_token: Option<String>,
+    /// Also serve the control API over gRPC (plaintext HTTP/2) here, see
+    /// `proto/control.proto`; calls authenticate with `api_token`
+    pub grpc_listen: Option<SocketAddr>,
+}
+
+impl HttpConfig {
+    /// Port speakers send their events to. Sonos only sends them over plain
+    /// HTTP, so with TLS they go to a separate listener.
+    pub fn event_port(&self) -> u16 {
+        match &self.tls {
+            Some(tls) => tls.event_listen.port(),
+            None => self.listen.port(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AudioscrobblerClient {
+    /// Also the device name its scrobbles are recorded under
+    pub username: String,
+    pub password: String,
+    /// Room its scrobbles are routed as; the username when unset
+    pub room: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain; a self-signed certificate is generated at
+    /// startup when neither it nor the key is set
+    pub cert: Option<PathBuf>,
+    /// PEM private key for `cert`
+    pub key: Option<PathBuf>,
+    /// Plain-HTTP listener that only accepts speaker events
+    pub event_listen: SocketAddr,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: None,
+            key: None,
+            event_listen: SocketAddr::from(([0, 0, 0, 0], 8081)),
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            trigger_secret: None,
+            api_token: None,
+            basic_auth: None,
+            subscription_timeout_secs: crate::sonos::DEFAULT_SUBSCRIPTION_TIMEOUT.as_secs(),
+            speaker_prefix_len: crate::sonos::DEFAULT_SPEAKER_PREFIX_LEN,
+            event_token: None,
+            tls: None,
+            audioscrobbler: Vec::new(),
+            agent_token: None,
+            grpc_listen: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenBudgetConfig {
+    /// Minutes of playback per day
+    pub minutes: u64,
+    /// What happens once the room has played that long
+    #[serde(default)]
+    pub over: OverBudget,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverBudget {
+    /// Send a notification and keep scrobbling
+    #[default]
+    Notify,
+    /// Send a notification and stop scrobbling the room for the rest of the day
+    StopScrobbling,
+}
+
+/// A Sonos system besides the main one, with its own speakers, rooms and
+/// routes. Everywhere else, e.g. in `portable_rooms`, profile routes and the
+/// stats, its rooms are named `<household>/<room>`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HouseholdConfig {
+    /// Where to find its speakers: configured addresses or a subnet scan,
+    /// since multicast discovery only reaches the main household
+    pub discovery: DiscoveryConfig,
+    /// Rooms to monitor, by their plain names; every room when empty
+    pub rooms: Vec<String>,
+    /// Scrobble backends per room, by their plain names, like the main
+    /// household's routes
+    pub routes: BTreeMap<String, Vec<String>>,
+}
+
+/// Name of `room` of the further household `household`, which keeps it apart
+/// from rooms of the same name in other households
+pub fn household_room(household: &str, room: &str) -> String {
+    format!("{}/{}", household, room)
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartyConfig {
+    /// Turn party mode on while every room plays as one group, or once three
+    /// rooms were turned up, down or skipped within half an hour, besides
+    /// switching it by hand
+    pub auto: bool,
+}
+
+/// Settings a profile replaces while it is active; those it doesn't give
+/// stay as configured
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub threshold: Option<ThresholdConfig>,
+    pub min_confidence: Option<u8>,
+    /// Replaces `routes` and those of every household as a whole, so rooms
+    /// it doesn't list use every backend
+    pub routes: Option<BTreeMap<String, Vec<String>>>,
+    /// Party mode on or off while active; automatic when unset
+    pub party: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScrobbleGuardConfig {
+    /// Hours a room may play without a scrobble before the alert
+    pub hours: u64,
+    /// Days of the week before with scrobbles from a room that make it one
+    /// that scrobbles every day
+    pub usual_days: u32,
+}
+
+impl Default for ScrobbleGuardConfig {
+    fn default() -> Self {
+        Self { hours: 6, usual_days: 5 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MilestonesConfig {
+    /// Every this many scrobbles in total is a milestone; 0 for none
+    pub scrobbles: i64,
+    /// Every this many plays of one track is a milestone; 0 for none
+    pub track_plays: i64,
+    /// Every this many days in a row with scrobbles is a milestone; 0 for
+    /// none
+    pub streak_days: i64,
+}
+
+impl Default for MilestonesConfig {
+    fn default() -> Self {
+        Self { scrobbles: 10_000, track_plays: 100, streak_days: 30 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SleepConfig {
+    /// Hour of the day, local time, from which the listener may be asleep
+    pub from_hour: u32,
+    /// Hour of the day the night is over
+    pub until_hour: u32,
+    /// Minutes of playback at night without any interaction after which
+    /// the listener is taken to be asleep
+    pub minutes: u64,
+}
+
+impl Default for SleepConfig {
+    fn default() -> Self {
+        Self { from_hour: 23, until_hour: 6, minutes: 60 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlocklistConfig {
+    /// Last.fm tags marking tracks and artists not to scrobble, e.g.
+    /// "noise"; tracks and whole artists can be tagged
+    pub lastfm_tags: Vec<String>,
+    /// Hours between syncs from Last.fm
+    pub refresh_hours: u64,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        Self { lastfm_tags: Vec::new(), refresh_hours: 24 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub rotate: LogRotation,
+    /// Size at which the file is rotated with `rotate = "size"`
+    #[serde(default = "default_log_max_size_mb")]
+    pub max_size_mb: u64,
+    /// Rotated files kept besides the current one
+    #[serde(default = "default_log_keep")]
+    pub keep: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    /// Start a new file every day
+    #[default]
+    Daily,
+    /// Start a new file once it reaches `max_size_mb`
+    Size,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub smtp_server: String,
+    /// 465 uses implicit TLS, any other port STARTTLS
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender address, e.g. `Sonos Scrobbler <scrobbler@example.com>`
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Runs as an agent of a hub instead of scrobbling itself
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    /// The hub's agent endpoint, e.g. `wss://hub.example.com:8080/agent`
+    pub hub: String,
+    /// The hub's `http.agent_token`
+    pub token: String,
+    /// PEM certificate to trust for a `wss://` hub instead of the public
+    /// authorities, e.g. the hub's own self-signed one
+    pub ca_cert: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbusConfig {
+    /// Bus the service is offered on
+    pub bus: DbusBus,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DbusBus {
+    /// The desktop session's bus, for keybindings
+    #[default]
+    Session,
+    /// The system bus, for other daemons; needs a bus policy allowing the
+    /// service's name
+    System,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TelegramConfig {
+    /// Token from @BotFather
+    pub bot_token: String,
+    /// Chat that receives announcements; commands from other chats are ignored
+    pub chat_id: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubsonicConfig {
+    /// Server address, e.g. `https://music.example.com`
+    pub url: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlexConfig {
+    /// Server address, e.g. `http://192.168.1.10:32400`
+    pub url: String,
+    /// The `X-Plex-Token` of the account
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FunkwhaleConfig {
+    /// Instance address, e.g. `https://funkwhale.example.com`
+    pub url: String,
+    /// Token of the instance's ListenBrainz integration
+    pub token: String,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_log_max_size_mb() -> u64 {
+    10
+}
+
+fn default_log_keep() -> usize {
+    7
+}
+
+impl Config {
+    /// Loads `path`, or the default location when `path` is `None`. Only an
+    /// explicitly given path has to exist.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        Self::parse(&contents).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Sets `rooms` in the config file at `path`, keeping its comments and
+    /// layout, and creates the file when there is none
+    pub fn save_rooms(path: &Path, rooms: &[String]) -> Result<()> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(Error::Config(format!("{}: {}", path.display(), e))),
+        };
+        let contents = with_rooms(&contents, rooms).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| Error::Config(format!("{}: {}", dir.display(), e)))?;
+        }
+        std::fs::write(path, contents).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let config: Self = toml::from_str(contents).map_err(|e| Error::Config(e.to_string()))?;
+        if let Some(zone) = &config.timezone {
+            if !zone_exists(zone) {
+                return Err(Error::Config(format!("unknown timezone {}", zone)));
+            }
+        }
+        if let Some(token) = config.http.as_ref().and_then(|http| http.event_token.as_ref()) {
+            if token.is_empty() || !token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                return Err(Error::Config("http.event_token may only contain letters, digits, - and _".to_string()));
+            }
+        }
+        if config.http.as_ref().is_some_and(|http| http.subscription_timeout_secs == 0) {
+            return Err(Error::Config("http.subscription_timeout_secs must be at least 1".to_string()));
+        }
+        if config.http.as_ref().is_some_and(|http| !(1..=32).contains(&http.speaker_prefix_len)) {
+            return Err(Error::Config("http.speaker_prefix_len must be from 1 to 32".to_string()));
+        }
+        if let Some(http) = config.http.as_ref().filter(|http| http.grpc_listen.is_some()) {
+            if http.api_token.as_ref().is_none_or(|token| token.is_empty()) {
+                return Err(Error::Config("http.grpc_listen needs http.api_token".to_string()));
+            }
+        }
+        if config.sleep.is_some_and(|sleep| sleep.from_hour > 23 || sleep.until_hour > 23) {
+            return Err(Error::Config("sleep.from_hour and sleep.until_hour must be from 0 to 23".to_string()));
+        }
+        if config.sleep.is_some_and(|sleep| sleep.from_hour == sleep.until_hour) {
+            return Err(Error::Config("sleep.from_hour and sleep.until_hour must differ".to_string()));
+        }
+        if let Some(profile) = config.profile.as_ref().filter(|profile| !config.profiles.contains_key(*profile)) {
+            return Err(Error::Config(format!("profile {} is not one of [profiles]", profile)));
+        }
+        if config.submission.concurrency == 0 {
+            return Err(Error::Config("submission.concurrency must be at least 1".to_string()));
+        }
+        for (name, household) in &config.households {
+            if household.discovery.devices.is_empty() && household.discovery.subnet.is_none() {
+                return Err(Error::Config(format!("household {} needs discovery.devices or discovery.subnet", name)));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Makes the configured time zone the local one, for this process and
+    /// SQLite's `localtime`. Call before anything reads the local time and
+    /// before any other thread starts, since it sets `TZ`.
+    pub fn apply_timezone(&self) {
+        if let Some(zone) = &self.timezone {
+            // The zone database handles the zone's DST changes
+            std::env::set_var("TZ", zone);
+        }
+    }
+}
+
+/// Whether the system's zone database has `zone`
+fn zone_exists(zone: &str) -> bool {
+    if zone == "UTC" {
+        return true;
+    }
+    let valid = !zone.is_empty() && zone.split('/').all(|part| !part.is_empty() && part != "." && part != "..");
+    let tzdir = std::env::var("TZDIR").ok();
+    valid && tzdir.iter().map(String::as_str).chain(ZONEINFO_DIRS).any(|dir| Path::new(dir).join(zone).is_file())
+}
+
+/// `contents` with `rooms` set to `rooms`
+fn with_rooms(contents: &str, rooms: &[String]) -> std::result::Result<String, toml_edit::TomlError> {
+    let mut document: toml_edit::DocumentMut = contents.parse()?;
+    document["rooms"] = toml_edit::value(rooms.iter().collect::<toml_edit::Array>());
+    Ok(document.to_string())
+}
+
+/// `$XDG_CONFIG_HOME/sonos-scrobbler/config.toml`, falling back to `~/.config`
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join(CONFIG_DIR).join(CONFIG_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+            status_interval = 5
+            scrobble_on = "track_end"
+            scrobble_delay_secs = 30
+            now_playing_interval_secs = 120
+            event_log_days = 7
+            session_check_hours = 168
+            connectivity_check = "1.1.1.1:53"
+            artist_separators = [", ", " & "]
+            min_confidence = 60
+            timezone = "UTC"
+            rooms = ["Kitchen", "Bathroom"]
+            portable_rooms = ["Bathroom"]
+            share_room = true
+            profile = "home"
+
+            [threshold]
+            percent = 40
+            sources = { radio = 30 }
+
+            [discovery]
+            subnet = "192.168.1.0/24"
+            scan_concurrency = 16
+
+            [blocklist]
+            lastfm_tags = ["noise", "sleep"]
+
+            [scrobble_guard]
+            hours = 4
+
+            [milestones]
+            track_plays = 50
+
+            [sleep]
+            minutes = 45
+
+            [retry.scrobble]
+            max_retries = 2
+            backoff = "exponential"
+
+            [submission]
+            concurrency = 1
+            breaker_cooldown_secs = 300
+
+            [cache]
+            size = 200
+
+            [library.mounts]
+            "//nas/Music" = "/mnt/music"
+
+            [email]
+            smtp_server = "smtp.example.com"
+            from = "scrobbler@example.com"
+            to = ["listener@example.com"]
+
+            [listen_budgets]
+            "Kids Room" = { minutes = 90, over = "stop_scrobbling" }
+
+            [chosen_by_user]
+            radio = true
+
+            [subsonic]
+            url = "https://music.example.com"
+            username = "me"
+            password = "secret"
+
+            [plex]
+            url = "http://192.168.1.10:32400"
+            token = "plex-token"
+
+            [funkwhale]
+            url = "https://funkwhale.example.com"
+            token = "funkwhale-token"
+
+            [routes]
+            Office = ["lastfm"]
+            "Kids Room" = []
+
+            [households.office]
+            rooms = ["Meeting Room"]
+            discovery = { devices = ["10.8.0.20"] }
+            routes = { "Meeting Room" = ["listenbrainz"] }
+
+            [profiles.home]
+
+            [profiles.party]
+            min_confidence = 80
+            routes = { Kitchen = [] }
+            party = true
+
+            [dbus]
+            bus = "system"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.status_interval, 5);
+        assert_eq!(config.scrobble_on, ScrobbleOn::TrackEnd);
+        assert_eq!(config.scrobble_delay_secs, 30);
+        assert_eq!(config.now_playing_interval_secs, 120);
+        assert_eq!(config.event_log_days, 7);
+        assert_eq!(config.session_check_hours, 168);
+        assert_eq!(config.connectivity_check, "1.1.1.1:53");
+        assert_eq!(config.threshold.max_secs, 240);
+        let track = Some(Duration::from_secs(200));
+        assert_eq!(config.threshold.play_time("queue", track), Duration::from_secs(80));
+        assert_eq!(config.threshold.play_time("radio", None), Duration::from_secs(30));
+        assert_eq!(config.discovery.subnet.as_deref(), Some("192.168.1.0/24"));
+        assert_eq!(config.discovery.scan_concurrency, 16);
+        assert_eq!(config.discovery.cache_ttl_secs, 30 * 60);
+        assert_eq!(config.max_concurrent_polls, 8);
+        assert!(config.discovery.devices.is_empty());
+        assert_eq!(config.email.unwrap().smtp_port, 587);
+        let blocklist = config.blocklist.unwrap();
+        assert_eq!(blocklist.lastfm_tags, vec!["noise".to_string(), "sleep".to_string()]);
+        assert_eq!(blocklist.refresh_hours, 24);
+        let guard = config.scrobble_guard.unwrap();
+        assert_eq!((guard.hours, guard.usual_days), (4, 5));
+        let milestones = MilestonesConfig { track_plays: 50, ..MilestonesConfig::default() };
+        assert_eq!(config.milestones, Some(milestones));
+        assert_eq!(config.sleep, Some(SleepConfig { minutes: 45, ..SleepConfig::default() }));
+        assert_eq!(config.min_confidence, 60);
+        assert_eq!(config.timezone.as_deref(), Some("UTC"));
+        assert_eq!(config.rooms, vec!["Kitchen".to_string(), "Bathroom".to_string()]);
+        assert_eq!(config.portable_rooms, vec!["Bathroom".to_string()]);
+        assert!(config.share_room);
+        let office = &config.households["office"];
+        assert_eq!(office.discovery.devices, vec![Ipv4Addr::new(10, 8, 0, 20)]);
+        assert_eq!(office.rooms, vec!["Meeting Room".to_string()]);
+        assert_eq!(office.routes["Meeting Room"], vec!["listenbrainz".to_string()]);
+        assert_eq!(config.dbus.unwrap().bus, DbusBus::System);
+        assert_eq!(config.profile.as_deref(), Some("home"));
+        assert_eq!(config.profiles["home"], ProfileConfig::default());
+        let party = &config.profiles["party"];
+        assert_eq!((party.min_confidence, party.party, party.threshold.as_ref()), (Some(80), Some(true), None));
+        assert!(party.routes.as_ref().unwrap()["Kitchen"].is_empty());
+        assert_eq!(config.retry.scrobble.max_retries, 2);
+        assert_eq!(config.retry.database, RetryPolicy::DATABASE);
+        let submission = SubmissionConfig { concurrency: 1, breaker_failures: 5, breaker_cooldown_secs: 300 };
+        assert_eq!(config.submission, submission);
+        assert_eq!(config.cache, CacheConfig { size: 200, ttl_secs: 3600 });
+        assert_eq!(config.library.mounts["//nas/Music"], PathBuf::from("/mnt/music"));
+        assert_eq!(config.artist_separators, vec![", ".to_string(), " & ".to_string()]);
+        assert_eq!(config.routes["Office"], vec!["lastfm".to_string()]);
+        assert!(config.routes["Kids Room"].is_empty());
+        assert!(config.chosen_by_user["radio"]);
+        assert_eq!(config.subsonic.unwrap().url, "https://music.example.com");
+        assert_eq!(config.plex.unwrap().token, "plex-token");
+        assert_eq!(config.funkwhale.unwrap().url, "https://funkwhale.example.com");
+        assert_eq!(
+            config.listen_budgets["Kids Room"],
+            ListenBudgetConfig { minutes: 90, over: OverBudget::StopScrobbling }
+        );
+    }
+
+    #[test]
+    fn test_parse_config_rejects_unknown_keys() {
+        let result = Config::parse("[discovery]\nsubnet_range = \"10.0.0.0/24\"\n");
+        assert!(matches!(result, Err(Error::Config(_))));
+        let result = Config::parse("timezone = \"../../etc/passwd\"\n");
+        assert!(matches!(result, Err(Error::Config(_))));
+        let result = Config::parse("[http]\nevent_token = \"a/b\"\n");
+        assert!(matches!(result, Err(Error::Config(_))));
+        let result = Config::parse("[http]\ngrpc_listen = \"127.0.0.1:50051\"\n");
+        assert!(matches!(result, Err(Error::Config(_))));
+        let result = Config::parse("[http]\nsubscription_timeout_secs = 0\n");
+        assert!(matches!(result, Err(Error::Config(_))));
+        let result = Config::parse("[households.office]\nrooms = [\"Office\"]\n");
+        assert!(matches!(result, Err(Error::Config(_))));
+        let result = Config::parse("[submission]\nconcurrency = 0\n");
+        assert!(matches!(result, Err(Error::Config(_))));
+        let result = Config::parse("[sleep]\nfrom_hour = 24\n");
+        assert!(matches!(result, Err(Error::Config(_))));
+        let result = Config::parse("[sleep]\nfrom_hour = 6\nuntil_hour = 6\n");
+        assert!(matches!(result, Err(Error::Config(_))));
+        let result = Config::parse("profile = \"away\"\n[profiles.home]\n");
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_saved_rooms_keep_the_rest_of_the_file() {
+        let contents = "# Poll less\nstatus_interval = 5\nrooms = [\"Garage\"]\n\n[discovery]\nscan_concurrency = 16\n";
+        let saved = with_rooms(contents, &["Kitchen".to_string(), "Living Room".to_string()]).unwrap();
+
+        assert!(saved.starts_with("# Poll less\nstatus_interval = 5\n"));
+        let config = Config::parse(&saved).unwrap();
+        assert_eq!(config.rooms, vec!["Kitchen".to_string(), "Living Room".to_string()]);
+        assert_eq!(config.discovery.scan_concurrency, 16);
+        assert_eq!(Config::parse(&with_rooms("", &[]).unwrap()).unwrap().rooms, Vec::<String>::new());
+    }
+}
@@ -0,0 +1,485 @@
+use crate::config::ThresholdConfig;
+use crate::error::{Error, Result};
+use crate::retry::RetryPolicy;
+use crate::scrobble::Scrobbler;
+use crate::sonos::{ListenSession, NowPlaying, SleepDetector, SoapClient, SonosDevice, TrackDatabase};
+use crate::status::{Gauges, Status};
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Queue items looked at by [`Controller::queue_preview`]
+const QUEUE_PREVIEW_LENGTH: u32 = 100;
+/// How often the database is checked for a profile switched to from the
+/// command line
+pub const PROFILE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An upcoming queue item and whether it would be scrobbled once played
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueuePreview {
+    /// Position in the queue, counting from 1
+    pub position: u32,
+    pub track: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub duration_secs: Option<u64>,
+    pub scrobble: bool,
+    /// Why the item wouldn't be scrobbled
+    pub reason: Option<String>,
+}
+
+/// Actions shared by the remote control integrations, addressing speakers by
+/// room name
+pub struct Controller {
+    speakers: Vec<(String, SoapClient)>,
+    scrobbler: Arc<Scrobbler>,
+    /// Keeps switched off backends off and the active profile across
+    /// restarts
+    db: Option<TrackDatabase>,
+    status: Option<Arc<Status>>,
+    sleep: Option<Arc<SleepDetector>>,
+    /// Play time a listen needs when no profile sets its own
+    threshold: ThresholdConfig,
+}
+
+impl Controller {
+    pub fn new(devices: &[SonosDevice], scrobbler: Arc<Scrobbler>) -> Result<Self> {
+        let speakers = devices
+            .iter()
+            .map(|device| Ok((device.room.clone(), SoapClient::new(&device.ip_addr.to_string())?)))
+            .collect::<Result<_>>()?;
+        Ok(Self { speakers, scrobbler, db: None, status: None, sleep: None, threshold: ThresholdConfig::default() })
+    }
+
+    /// Retries failed calls to the speakers per `retry`
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.speakers = self.speakers.into_iter().map(|(room, soap)| (room, soap.with_retry_policy(retry))).collect();
+        self
+    }
+
+    /// Remembers in `db` which scrobble backends are switched off and which
+    /// profile is active
+    pub fn with_database(mut self, db: TrackDatabase) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Shows switched off scrobble backends and the active profile in
+    /// `status`
+    pub fn with_status(mut self, status: Arc<Status>) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Lets listeners taken to be asleep by `sleep` say they aren't
+    pub fn with_sleep_detector(mut self, sleep: Arc<SleepDetector>) -> Self {
+        self.sleep = Some(sleep);
+        self
+    }
+
+    /// Previews the queue with the play time `threshold` as the listeners
+    /// use it
+    pub fn with_threshold(mut self, threshold: ThresholdConfig) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// One `room: track` line per speaker
+    pub async fn now_playing(&self) -> Vec<String> {
+        self.playing()
+            .await
+            .into_iter()
+            .map(|(room, playing)| match playing {
+                Ok(playing) => format!("{}: {}", room, playing),
+                Err(e) => format!("{}: unavailable ({})", room, e),
+            })
+            .collect()
+    }
+
+    /// What each speaker is playing, by room, or why it couldn't be asked
+    pub async fn playing(&self) -> Vec<(String, Result<NowPlaying>)> {
+        let mut playing = Vec::new();
+        for (room, soap) in &self.speakers {
+            let position = soap.get_position_info().await;
+            playing.push((room.clone(), position.map(|position| NowPlaying::from_position(&position))));
+        }
+        playing
+    }
+
+    /// Pauses `room`, or every speaker when no room is given. Returns the
+    /// rooms that were paused.
+    pub async fn pause(&self, room: Option<&str>) -> Result<Vec<String>> {
+        let mut paused = Vec::new();
+        for (name, soap) in self.select(room)? {
+            soap.pause().await?;
+            paused.push(name.clone());
+        }
+        Ok(paused)
+    }
+
+    /// Loves the track playing in `room`, or in the first room playing a
+    /// known track. Returns the loved track.
+    pub async fn love(&self, room: Option<&str>) -> Result<String> {
+        for (name, soap) in self.select(room)? {
+            let Ok(position) = soap.get_position_info().await else {
+                continue;
+            };
+            let session = ListenSession::from_position(&position);
+            if let Some(scrobble) = session.to_scrobble(name) {
+                self.scrobbler.love(&scrobble).await?;
+                return Ok(session.track_info);
+            }
+        }
+        Err(Error::Scrobble("nothing with a known artist and title is playing".to_string()))
+    }
+
+    /// Cancels the scrobble held back for the grace period in `room`, or the
+    /// most recent one from any room. Returns the track that won't be
+    /// scrobbled.
+    pub fn dont_scrobble(&self, room: Option<&str>) -> Result<String> {
+        if room.is_some() {
+            self.select(room)?;
+        }
+        let scrobble = self
+            .scrobbler
+            .cancel(room)
+            .ok_or_else(|| Error::Scrobble("no scrobble is waiting to be submitted".to_string()))?;
+        Ok(format!("{} - {}", scrobble.artist, scrobble.title))
+    }
+
+    /// The queue of `room` after the current track, each item with the
+    /// decision it would get under the current settings if played long enough
+    pub async fn queue_preview(&self, room: &str) -> Result<Vec<QueuePreview>> {
+        let (room, soap) = self.select(Some(room))?[0];
+        let next = soap.get_position_info().await?.track_number.unwrap_or(0);
+        let queue = soap.browse_queue(next, QUEUE_PREVIEW_LENGTH).await?;
+
+        let mut preview = Vec::new();
+        for item in &queue {
+            let session = ListenSession::from_position(item);
+            let reason = self.skip_reason(room, &session).await?;
+            preview.push(QueuePreview {
+                position: item.track_number.unwrap_or_default(),
+                track: session.track_info.clone(),
+                artist: session.artist.clone(),
+                title: session.title.clone(),
+                album: session.album.clone(),
+                duration_secs: session.duration.map(|duration| duration.as_secs()),
+                scrobble: reason.is_none(),
+                reason: reason.map(str::to_string),
+            });
+        }
+        Ok(preview)
+    }
+
+    /// Why `session`, played in full from the queue of `room`, wouldn't be
+    /// scrobbled, going through the same checks as the listeners and
+    /// [`Scrobbler::submit`]
+    async fn skip_reason(&self, room: &str, session: &ListenSession) -> Result<Option<&'static str>> {
+        if session.too_short() {
+            return Ok(Some("shorter than 30 seconds"));
+        }
+        let threshold = self.scrobbler.profiles().threshold().unwrap_or_else(|| self.threshold.clone());
+        let play_time = threshold.play_time("queue", session.duration);
+        if session.duration.is_some_and(|duration| duration < play_time) {
+            return Ok(Some("shorter than the threshold"));
+        }
+        let Some(scrobble) = session.to_scrobble(room) else {
+            return Ok(Some("unknown artist or title"));
+        };
+        if !self.scrobbler.confident(&scrobble) {
+            return Ok(Some("low confidence"));
+        }
+        if let Some(db) = &self.db {
+            let mut scrobble = self.scrobbler.prepare(&scrobble);
+            if let Some((artist, title)) = db.correction(&scrobble.artist, &scrobble.title).await? {
+                (scrobble.artist, scrobble.title) = (artist, title);
+            }
+            if db.blocklisted(&scrobble.artist, &scrobble.title).await? {
+                return Ok(Some("blocklisted"));
+            }
+        }
+        if self.scrobbler.routed_names(room).is_empty() {
+            return Ok(Some("no backends for this room"));
+        }
+        Ok(None)
+    }
+
+    /// Skips to the next track in `room`. Returns the room's name.
+    pub async fn skip(&self, room: &str) -> Result<String> {
+        let (room, soap) = self.select(Some(room))?[0];
+        soap.next().await?;
+        Ok(room.clone())
+    }
+
+    /// Removes the item at `position` of the queue of `room`, counting from 1
+    /// like [`QueuePreview::position`]. Returns the room's name.
+    pub async fn remove_from_queue(&self, room: &str, position: u32) -> Result<String> {
+        if position == 0 {
+            return Err(Error::Config("queue positions count from 1".to_string()));
+        }
+        let (room, soap) = self.select(Some(room))?[0];
+        soap.remove_from_queue(position).await?;
+        Ok(room.clone())
+    }
+
+    /// Empties the queue of `room`. Returns the room's name.
+    pub async fn clear_queue(&self, room: &str) -> Result<String> {
+        let (room, soap) = self.select(Some(room))?[0];
+        soap.clear_queue().await?;
+        Ok(room.clone())
+    }
+
+    /// The sessions in progress and the depths of the scrobbler's and the
+    /// database's queues
+    pub async fn gauges(&self) -> Gauges {
+        let mut gauges = self.status.as_ref().map(|status| status.gauges()).unwrap_or_default();
+        gauges.submission_queues = self.scrobbler.queued();
+        gauges.submissions_pending = self.scrobbler.pending_submissions();
+        if let Some(db) = &self.db {
+            gauges.pending_writes = db.pending_writes().await;
+        }
+        gauges
+    }
+
+    /// Confirms the listener in `room`, or in every room, is still awake,
+    /// so its listens are scrobbled again
+    pub fn awake(&self, room: Option<&str>) -> Result<String> {
+        if room.is_some() {
+            self.select(room)?;
+        }
+        let woken = self.sleep.as_ref().map(|sleep| sleep.awake(room)).unwrap_or_default();
+        match woken.is_empty() {
+            true => Ok("Nobody was taken to be asleep".to_string()),
+            false => Ok(format!("Scrobbling {} again", woken.join(", "))),
+        }
+    }
+
+    /// Pauses or resumes scrobbling from every room
+    pub fn pause_scrobbling(&self, paused: bool) {
+        self.scrobbler.set_paused(paused);
+    }
+
+    pub fn scrobbling_paused(&self) -> bool {
+        self.scrobbler.paused()
+    }
+
+    /// Switches party mode "on", "off" or back to "auto", or only reports it
+    /// without `mode`
+    pub fn party(&self, mode: Option<&str>) -> Result<String> {
+        let party = self.scrobbler.party();
+        match mode.map(str::to_lowercase).as_deref() {
+            None => {}
+            Some("on") => self.scrobbler.set_party(Some(true)),
+            Some("off") => self.scrobbler.set_party(Some(false)),
+            Some("auto") => self.scrobbler.set_party(None),
+            Some(other) => return Err(Error::Config(format!("unknown party mode {}, use on, off or auto", other))),
+        }
+        Ok(party.describe())
+    }
+
+    /// Switches to the profile called `name`, or to none with "none", or
+    /// only reports the active one without `name`
+    pub async fn profile(&self, name: Option<&str>) -> Result<String> {
+        let profiles = self.scrobbler.profiles();
+        if let Some(name) = name {
+            let name = match name.eq_ignore_ascii_case("none") {
+                true => None,
+                false => Some(name),
+            };
+            let active = self.scrobbler.set_profile(name)?;
+            if let Some(db) = &self.db {
+                db.set_active_profile(active.as_deref()).await?;
+            }
+            if let Some(status) = &self.status {
+                status.set_profile(active);
+            }
+        }
+        Ok(profiles.describe())
+    }
+
+    /// Switches to the profile last switched to in the database, e.g. by the
+    /// `profile` command while the daemon runs
+    pub async fn follow_profile(&self) -> Result<()> {
+        let Some(db) = &self.db else {
+            return Ok(());
+        };
+        let Some(name) = db.active_profile().await? else {
+            return Ok(());
+        };
+        if name != self.scrobbler.profiles().active() {
+            let active = self.scrobbler.set_profile(name.as_deref())?;
+            info!("{}", self.scrobbler.profiles().describe());
+            if let Some(status) = &self.status {
+                status.set_profile(active);
+            }
+        }
+        Ok(())
+    }
+
+    /// Switches the scrobble backend called `name` "on" or "off", e.g. while
+    /// the service is down for maintenance. Without a mode, or without a
+    /// name, describes whether the backends are on.
+    pub async fn backend(&self, name: Option<&str>, mode: Option<&str>) -> Result<String> {
+        let enabled = match mode.map(str::to_lowercase).as_deref() {
+            None => None,
+            Some("on") => Some(true),
+            Some("off") => Some(false),
+            Some(other) => return Err(Error::Config(format!("unknown backend mode {}, use on or off", other))),
+        };
+        if let (Some(name), Some(enabled)) = (name, enabled) {
+            self.scrobbler.set_backend_enabled(name, enabled)?;
+            if let Some(db) = &self.db {
+                db.set_backend_disabled(name, !enabled).await?;
+            }
+            if let Some(status) = &self.status {
+                status.set_disabled_backends(self.scrobbler.disabled_backends());
+            }
+        }
+
+        let disabled = self.scrobbler.disabled_backends();
+        let states: Vec<_> = self
+            .scrobbler
+            .backend_names()
+            .into_iter()
+            .filter(|backend| name.is_none_or(|name| name == *backend))
+            .map(|backend| match disabled.iter().any(|name| name == backend) {
+                true => format!("{} is off", backend),
+                false => format!("{} is on", backend),
+            })
+            .collect();
+        match states.is_empty() {
+            true => Err(Error::Config(format!("no scrobble backend {}", name.unwrap_or_default()))),
+            false => Ok(states.join(", ")),
+        }
+    }
+
+    fn select(&self, room: Option<&str>) -> Result<Vec<&(String, SoapClient)>> {
+        let selected: Vec<_> = self
+            .speakers
+            .iter()
+            .filter(|(name, _)| room.is_none_or(|room| name.eq_ignore_ascii_case(room)))
+            .collect();
+        if selected.is_empty() {
+            return Err(Error::Discovery(format!("no speaker in room {}", room.unwrap_or_default())));
+        }
+        Ok(selected)
+    }
+}
+
+/// Follows profile switches made outside the daemon every `interval` until
+/// the task is dropped
+pub async fn follow_profile_periodically(controller: Arc<Controller>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = controller.follow_profile().await {
+            warn!("Profile: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProfileConfig;
+    use crate::scrobble::{MetadataSource, MockScrobbleBackend};
+    use crate::sonos::NowPlaying;
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn test_unknown_room() {
+        let devices = vec![SonosDevice {
+            ip_addr: "127.0.0.1".parse().unwrap(),
+            room: "Kitchen".to_string(),
+            model: "Sonos One".to_string(),
+            id: "RINCON_1".to_string(),
+            friendly_name: "127.0.0.1 - Sonos One - RINCON_1".to_string(),
+        }];
+        let controller = Controller::new(&devices, Arc::new(Scrobbler::default())).unwrap();
+
+        let result = controller.pause(Some("Garage")).await;
+        assert!(matches!(result, Err(Error::Discovery(_))));
+    }
+
+    #[test]
+    fn test_switch_party_mode() {
+        let controller = Controller::new(&[], Arc::new(Scrobbler::default())).unwrap();
+        assert_eq!(controller.party(None).unwrap(), "Party mode is off (automatic)");
+        assert_eq!(controller.party(Some("On")).unwrap(), "Party mode is on");
+        assert!(matches!(controller.party(Some("loud")), Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_switch_profile() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let party = ProfileConfig { party: Some(true), ..ProfileConfig::default() };
+        let scrobbler = Scrobbler::default().with_profiles(&BTreeMap::from([("party".to_string(), party)]));
+        let controller = Controller::new(&[], Arc::new(scrobbler)).unwrap().with_database(db.clone());
+
+        assert_eq!(controller.profile(None).await.unwrap(), "No profile is active");
+        assert_eq!(controller.profile(Some("Party")).await.unwrap(), "Profile party is active");
+        assert_eq!(controller.party(None).unwrap(), "Party mode is on");
+        assert_eq!(db.active_profile().await.unwrap(), Some(Some("party".to_string())));
+        assert!(matches!(controller.profile(Some("away")).await, Err(Error::Config(_))));
+
+        assert_eq!(controller.profile(Some("none")).await.unwrap(), "No profile is active");
+        assert_eq!(controller.party(None).unwrap(), "Party mode is off (automatic)");
+        assert_eq!(db.active_profile().await.unwrap(), Some(None));
+
+        // Switched from the command line
+        db.set_active_profile(Some("party")).await.unwrap();
+        controller.follow_profile().await.unwrap();
+        assert_eq!(controller.profile(None).await.unwrap(), "Profile party is active");
+    }
+
+    #[tokio::test]
+    async fn test_queue_preview_applies_submit_checks() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let mut backend = MockScrobbleBackend::new();
+        backend.expect_name().return_const("listenbrainz".to_string());
+        let scrobbler = Arc::new(Scrobbler::new(vec![Box::new(backend)]));
+        let threshold = ThresholdConfig { sources: BTreeMap::from([("queue".to_string(), 200)]), ..Default::default() };
+        let controller =
+            Controller::new(&[], scrobbler).unwrap().with_database(db.clone()).with_threshold(threshold);
+        let session = |artist: &str, title: &str, secs: u64| {
+            ListenSession::new(NowPlaying {
+                uri: "x-sonos-spotify:track".to_string(),
+                artist: Some(artist.to_string()),
+                title: Some(title.to_string()),
+                album: None,
+                duration: Some(Duration::from_secs(secs)),
+                position: None,
+                source: MetadataSource::Didl,
+                stream: false,
+            })
+        };
+
+        db.block_track("Enya", "Caribbean Blue", "manual").await.unwrap();
+        db.record_correction("enya", "caribbean blue", "Enya", "Caribbean Blue").await.unwrap();
+        let reason = |artist, title, secs| {
+            let controller = &controller;
+            async move { controller.skip_reason("Kitchen", &session(artist, title, secs)).await.unwrap() }
+        };
+        assert_eq!(reason("Daft Punk", "Get Lucky", 248).await, None);
+        assert_eq!(reason("Enya", "Caribbean Blue", 240).await, Some("blocklisted"));
+        assert_eq!(reason("enya", "caribbean blue", 240).await, Some("blocklisted"));
+        assert_eq!(reason("Daft Punk", "Crescendolls", 180).await, Some("shorter than the threshold"));
+    }
+
+    #[tokio::test]
+    async fn test_switch_backend_off() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let mut backend = MockScrobbleBackend::new();
+        backend.expect_name().return_const("listenbrainz".to_string());
+        let scrobbler = Scrobbler::new(vec![Box::new(backend)]);
+        let controller = Controller::new(&[], Arc::new(scrobbler)).unwrap().with_database(db.clone());
+
+        assert_eq!(controller.backend(None, None).await.unwrap(), "listenbrainz is on");
+        assert_eq!(controller.backend(Some("listenbrainz"), Some("OFF")).await.unwrap(), "listenbrainz is off");
+        assert_eq!(db.disabled_backends().await.unwrap(), vec!["listenbrainz".to_string()]);
+        assert!(matches!(controller.backend(Some("lastfm"), Some("on")).await, Err(Error::Config(_))));
+    }
+}
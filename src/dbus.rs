@@ -0,0 +1,556 @@
+use crate::config::{DbusBus, DbusConfig};
+use crate::control::Controller;
+use crate::error::{Error, Result};
+use log::{debug, info};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// Well-known bus name of the service, also its interface's name
+pub const NAME: &str = "io.github.harperreed.SonosScrobbler";
+/// Object the service's methods are called on
+pub const PATH: &str = "/io/github/harperreed/SonosScrobbler";
+
+const BUS: &str = "org.freedesktop.DBus";
+const BUS_PATH: &str = "/org/freedesktop/DBus";
+/// Largest message accepted; calls to the service have no arguments
+const MAX_MESSAGE: usize = 1 << 20;
+/// Longest line read while authenticating
+const MAX_AUTH_LINE: usize = 512;
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// `RequestName` flag: fail rather than wait for the name to be released
+const DO_NOT_QUEUE: u32 = 0x4;
+const PRIMARY_OWNER: u32 = 1;
+const ALREADY_OWNER: u32 = 4;
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="io.github.harperreed.SonosScrobbler">
+    <method name="PauseScrobbling"/>
+    <method name="ResumeScrobbling"/>
+    <method name="ScrobblingPaused"><arg type="b" direction="out"/></method>
+    <method name="NowPlaying"><arg type="as" direction="out"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg type="s" direction="out"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+</node>
+"#;
+
+/// Lets desktop keybindings and other daemons control the scrobbler over
+/// D-Bus, as [`NAME`] at [`PATH`]:
+///
+/// - `PauseScrobbling()` and `ResumeScrobbling()`, for every room
+/// - `ScrobblingPaused() -> b`
+/// - `NowPlaying() -> as`, one `room: track` line per speaker
+///
+/// Only the part of the D-Bus protocol this needs is spoken: `unix:path=`
+/// addresses, EXTERNAL authentication and methods without arguments.
+pub struct DbusService {
+    bus: DbusBus,
+    controller: Arc<Controller>,
+}
+
+impl DbusService {
+    pub fn new(config: &DbusConfig, controller: Arc<Controller>) -> Self {
+        Self { bus: config.bus, controller }
+    }
+
+    /// Connects to the bus, claims [`NAME`] and answers calls until the
+    /// connection breaks
+    pub async fn serve(&self) -> Result<()> {
+        let path = socket_path(self.bus)?;
+        let mut stream = UnixStream::connect(&path)
+            .await
+            .map_err(|e| Error::Dbus(format!("cannot connect to {}: {}", path.display(), e)))?;
+        authenticate(&mut stream).await?;
+        let mut connection = Connection { stream, serial: 0 };
+
+        connection.call_bus("Hello", "", Vec::new()).await?;
+        let mut request = Writer::default();
+        request.string(NAME);
+        request.u32(DO_NOT_QUEUE);
+        let reply = connection.call_bus("RequestName", "su", request.0).await?;
+        match reply.body_reader().u32()? {
+            PRIMARY_OWNER | ALREADY_OWNER => info!("Offering {} on the D-Bus {:?} bus", NAME, self.bus),
+            _ => return Err(Error::Dbus(format!("{} is already taken on the bus", NAME))),
+        }
+
+        loop {
+            let message = connection.read().await?;
+            if message.kind != METHOD_CALL {
+                continue;
+            }
+            let reply = self.handle(&message).await;
+            if message.flags & NO_REPLY_EXPECTED == 0 {
+                connection.send(reply).await?;
+            }
+        }
+    }
+
+    /// The reply to a method call
+    async fn handle(&self, call: &Message) -> Message {
+        let member = call.member.as_deref().unwrap_or_default();
+        debug!("D-Bus call {} from {}", member, call.sender.as_deref().unwrap_or("unknown"));
+        if call.path.as_deref() != Some(PATH) && member != "Ping" {
+            return call.error("org.freedesktop.DBus.Error.UnknownObject", "no such object");
+        }
+        if !call.signature.is_empty() {
+            return call.error("org.freedesktop.DBus.Error.InvalidArgs", "methods take no arguments");
+        }
+
+        let mut body = Writer::default();
+        let interface = call.interface.as_deref();
+        let signature = match (interface, member) {
+            (Some(NAME) | None, "PauseScrobbling") => {
+                self.controller.pause_scrobbling(true);
+                info!("Scrobbling paused over D-Bus");
+                ""
+            }
+            (Some(NAME) | None, "ResumeScrobbling") => {
+                self.controller.pause_scrobbling(false);
+                info!("Scrobbling resumed over D-Bus");
+                ""
+            }
+            (Some(NAME) | None, "ScrobblingPaused") => {
+                body.u32(self.controller.scrobbling_paused().into());
+                "b"
+            }
+            (Some(NAME) | None, "NowPlaying") => {
+                let array = body.begin_array(4);
+                for line in self.controller.now_playing().await {
+                    body.string(&line);
+                }
+                body.end_array(array);
+                "as"
+            }
+            (Some("org.freedesktop.DBus.Introspectable") | None, "Introspect") => {
+                body.string(INTROSPECTION);
+                "s"
+            }
+            (Some("org.freedesktop.DBus.Peer") | None, "Ping") => "",
+            _ => return call.error("org.freedesktop.DBus.Error.UnknownMethod", &format!("no method {}", member)),
+        };
+        call.reply(signature, body.0)
+    }
+}
+
+/// Path of the socket of `bus`, from the environment like other D-Bus
+/// clients find it
+fn socket_path(bus: DbusBus) -> Result<PathBuf> {
+    let (variable, fallback) = match bus {
+        DbusBus::Session => (
+            "DBUS_SESSION_BUS_ADDRESS",
+            std::env::var("XDG_RUNTIME_DIR").ok().map(|dir| format!("unix:path={}/bus", dir)),
+        ),
+        DbusBus::System => ("DBUS_SYSTEM_BUS_ADDRESS", Some("unix:path=/var/run/dbus/system_bus_socket".to_string())),
+    };
+    let address = std::env::var(variable)
+        .ok()
+        .or(fallback)
+        .ok_or_else(|| Error::Dbus(format!("{} is not set", variable)))?;
+    address_path(&address)
+}
+
+/// The socket of the first `unix:path=` address in a D-Bus address list
+fn address_path(addresses: &str) -> Result<PathBuf> {
+    addresses
+        .split(';')
+        .find_map(|address| {
+            let options = address.strip_prefix("unix:")?;
+            options.split(',').find_map(|option| option.strip_prefix("path="))
+        })
+        .map(|path| PathBuf::from(percent_encoding::percent_decode_str(path).decode_utf8_lossy().as_ref()))
+        .ok_or_else(|| Error::Dbus(format!("no unix:path= address in {}", addresses)))
+}
+
+/// Authenticates as the user owning the process, which the bus tells from
+/// the socket
+async fn authenticate(stream: &mut UnixStream) -> Result<()> {
+    stream.write_all(b"\0AUTH EXTERNAL\r\n").await.map_err(io_error)?;
+    let mut line = read_line(stream).await?;
+    if line.starts_with("DATA") {
+        stream.write_all(b"DATA\r\n").await.map_err(io_error)?;
+        line = read_line(stream).await?;
+    }
+    if !line.starts_with("OK ") {
+        return Err(Error::Dbus(format!("the bus refused to authenticate: {}", line.trim())));
+    }
+    stream.write_all(b"BEGIN\r\n").await.map_err(io_error)
+}
+
+async fn read_line(stream: &mut UnixStream) -> Result<String> {
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= MAX_AUTH_LINE {
+            return Err(Error::Dbus("authentication reply too long".to_string()));
+        }
+        line.push(stream.read_u8().await.map_err(io_error)?);
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::Dbus(e.to_string())
+}
+
+/// An authenticated connection to the bus
+struct Connection {
+    stream: UnixStream,
+    /// Serial of the last message sent
+    serial: u32,
+}
+
+impl Connection {
+    async fn send(&mut self, mut message: Message) -> Result<u32> {
+        self.serial += 1;
+        message.serial = self.serial;
+        self.stream.write_all(&message.encode()).await.map_err(io_error)?;
+        Ok(self.serial)
+    }
+
+    async fn read(&mut self) -> Result<Message> {
+        read_message(&mut self.stream).await
+    }
+
+    /// Calls `member` of the bus itself and waits for the reply. Anything
+    /// else arriving meanwhile, like the `NameAcquired` signal, is dropped.
+    async fn call_bus(&mut self, member: &str, signature: &str, body: Vec<u8>) -> Result<Message> {
+        let call = Message {
+            kind: METHOD_CALL,
+            path: Some(BUS_PATH.to_string()),
+            interface: Some(BUS.to_string()),
+            member: Some(member.to_string()),
+            destination: Some(BUS.to_string()),
+            signature: signature.to_string(),
+            body,
+            ..Message::default()
+        };
+        let serial = self.send(call).await?;
+        loop {
+            let message = self.read().await?;
+            match message.kind {
+                _ if message.reply_serial != Some(serial) => continue,
+                METHOD_RETURN => return Ok(message),
+                _ => {
+                    let error = message.error_name.unwrap_or_default();
+                    return Err(Error::Dbus(format!("{} failed: {}", member, error)));
+                }
+            }
+        }
+    }
+}
+
+/// A message with the header fields the service uses
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Message {
+    kind: u8,
+    flags: u8,
+    serial: u32,
+    /// Whether the sender marshalled it big-endian; sent messages are
+    /// always little-endian
+    big_endian: bool,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    reply_serial: Option<u32>,
+    destination: Option<String>,
+    sender: Option<String>,
+    /// Of the body; empty without one
+    signature: String,
+    body: Vec<u8>,
+}
+
+impl Message {
+    fn reply(&self, signature: &str, body: Vec<u8>) -> Message {
+        Message {
+            kind: METHOD_RETURN,
+            reply_serial: Some(self.serial),
+            destination: self.sender.clone(),
+            signature: signature.to_string(),
+            body,
+            ..Message::default()
+        }
+    }
+
+    fn error(&self, name: &str, text: &str) -> Message {
+        let mut body = Writer::default();
+        body.string(text);
+        Message { kind: ERROR, error_name: Some(name.to_string()), ..self.reply("s", body.0) }
+    }
+
+    fn body_reader(&self) -> Reader<'_> {
+        Reader { data: &self.body, pos: 0, big_endian: self.big_endian }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Writer::default();
+        out.0.extend([b'l', self.kind, self.flags, 1]);
+        out.u32(self.body.len() as u32);
+        out.u32(self.serial);
+
+        let strings = [
+            (FIELD_PATH, "o", &self.path),
+            (FIELD_INTERFACE, "s", &self.interface),
+            (FIELD_MEMBER, "s", &self.member),
+            (FIELD_ERROR_NAME, "s", &self.error_name),
+            (FIELD_DESTINATION, "s", &self.destination),
+            (FIELD_SENDER, "s", &self.sender),
+        ];
+        let fields = out.begin_array(8);
+        for (code, signature, value) in strings {
+            if let Some(value) = value {
+                out.align(8);
+                out.0.push(code);
+                out.signature(signature);
+                out.string(value);
+            }
+        }
+        if let Some(serial) = self.reply_serial {
+            out.align(8);
+            out.0.push(FIELD_REPLY_SERIAL);
+            out.signature("u");
+            out.u32(serial);
+        }
+        if !self.signature.is_empty() {
+            out.align(8);
+            out.0.push(FIELD_SIGNATURE);
+            out.signature("g");
+            out.signature(&self.signature);
+        }
+        out.end_array(fields);
+
+        out.align(8);
+        out.0.extend(&self.body);
+        out.0
+    }
+}
+
+async fn read_message(stream: &mut (impl AsyncRead + Unpin)) -> Result<Message> {
+    let mut head = vec![0; 16];
+    stream.read_exact(&mut head).await.map_err(io_error)?;
+    let big_endian = match head[0] {
+        b'l' => false,
+        b'B' => true,
+        other => return Err(Error::Dbus(format!("invalid endianness marker {}", other))),
+    };
+    let mut fixed = Reader { data: &head, pos: 4, big_endian };
+    let body_len = fixed.u32()? as usize;
+    fixed.u32()?;
+    let fields_len = fixed.u32()? as usize;
+    if body_len > MAX_MESSAGE || fields_len > MAX_MESSAGE {
+        return Err(Error::Dbus("message too large".to_string()));
+    }
+
+    head.resize((16 + fields_len).next_multiple_of(8), 0);
+    stream.read_exact(&mut head[16..]).await.map_err(io_error)?;
+    let mut body = vec![0; body_len];
+    stream.read_exact(&mut body).await.map_err(io_error)?;
+
+    let mut header = Reader { data: &head[..16 + fields_len], pos: 1, big_endian };
+    let mut message = Message {
+        kind: header.u8()?,
+        flags: header.u8()?,
+        big_endian,
+        body,
+        ..Message::default()
+    };
+    header.pos = 8;
+    message.serial = header.u32()?;
+    header.pos = 16;
+    while header.pos < header.data.len() {
+        header.align(8)?;
+        let code = header.u8()?;
+        let value = match header.signature()?.as_str() {
+            "s" | "o" => header.string()?,
+            "g" => header.signature()?,
+            "u" => header.u32()?.to_string(),
+            other => return Err(Error::Dbus(format!("unexpected header field type {}", other))),
+        };
+        match code {
+            FIELD_PATH => message.path = Some(value),
+            FIELD_INTERFACE => message.interface = Some(value),
+            FIELD_MEMBER => message.member = Some(value),
+            FIELD_ERROR_NAME => message.error_name = Some(value),
+            FIELD_REPLY_SERIAL => message.reply_serial = value.parse().ok(),
+            FIELD_DESTINATION => message.destination = Some(value),
+            FIELD_SENDER => message.sender = Some(value),
+            FIELD_SIGNATURE => message.signature = value,
+            _ => {}
+        }
+    }
+    Ok(message)
+}
+
+/// Marshals values little-endian, aligned from the start of the buffer
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn align(&mut self, alignment: usize) {
+        self.0.resize(self.0.len().next_multiple_of(alignment), 0);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend(value.as_bytes());
+        self.0.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.0.push(value.len() as u8);
+        self.0.extend(value.as_bytes());
+        self.0.push(0);
+    }
+
+    /// Starts an array of elements aligned to `alignment`. Returns where its
+    /// length goes and where its elements start, for [`Self::end_array`].
+    fn begin_array(&mut self, alignment: usize) -> (usize, usize) {
+        self.u32(0);
+        let length_at = self.0.len() - 4;
+        self.align(alignment);
+        (length_at, self.0.len())
+    }
+
+    fn end_array(&mut self, (length_at, start): (usize, usize)) {
+        let length = (self.0.len() - start) as u32;
+        self.0[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+}
+
+/// Unmarshals values in either byte order
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let taken = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| Error::Dbus("truncated message".to_string()))?;
+        self.pos += len;
+        Ok(taken)
+    }
+
+    fn align(&mut self, alignment: usize) -> Result<()> {
+        let padding = self.pos.next_multiple_of(alignment) - self.pos;
+        self.take(padding).map(drop)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.align(4)?;
+        let bytes = self.take(4)?.try_into().unwrap_or_default();
+        Ok(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        self.text(len)
+    }
+
+    fn signature(&mut self) -> Result<String> {
+        let len = self.u8()? as usize;
+        self.text(len)
+    }
+
+    /// `len` bytes of UTF-8 and the terminating NUL
+    fn text(&mut self, len: usize) -> Result<String> {
+        let text = String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| Error::Dbus("string is not UTF-8".to_string()))?;
+        self.take(1)?;
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrobble::Scrobbler;
+
+    #[tokio::test]
+    async fn test_message_round_trip() {
+        let mut body = Writer::default();
+        body.string(NAME);
+        body.u32(DO_NOT_QUEUE);
+        let message = Message {
+            kind: METHOD_CALL,
+            serial: 7,
+            path: Some(BUS_PATH.to_string()),
+            interface: Some(BUS.to_string()),
+            member: Some("RequestName".to_string()),
+            destination: Some(BUS.to_string()),
+            reply_serial: Some(3),
+            signature: "su".to_string(),
+            body: body.0,
+            ..Message::default()
+        };
+
+        let decoded = read_message(&mut message.encode().as_slice()).await.unwrap();
+        assert_eq!(decoded, message);
+        let mut body = decoded.body_reader();
+        assert_eq!((body.string().unwrap().as_str(), body.u32().unwrap()), (NAME, DO_NOT_QUEUE));
+
+        let addresses = "unix:abstract=/tmp/dbus-x;unix:path=/run/user/1000/b%75s,guid=1234";
+        assert_eq!(address_path(addresses).unwrap(), PathBuf::from("/run/user/1000/bus"));
+        assert!(address_path("tcp:host=localhost,port=1234").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume_scrobbling() {
+        let controller = Arc::new(Controller::new(&[], Arc::new(Scrobbler::default())).unwrap());
+        let service = DbusService::new(&DbusConfig::default(), controller.clone());
+        let call = |member: &str| Message {
+            kind: METHOD_CALL,
+            serial: 12,
+            path: Some(PATH.to_string()),
+            interface: Some(NAME.to_string()),
+            member: Some(member.to_string()),
+            sender: Some(":1.42".to_string()),
+            ..Message::default()
+        };
+
+        let reply = service.handle(&call("PauseScrobbling")).await;
+        assert_eq!((reply.kind, reply.reply_serial), (METHOD_RETURN, Some(12)));
+        assert_eq!(reply.destination.as_deref(), Some(":1.42"));
+        assert!(controller.scrobbling_paused());
+        let reply = service.handle(&call("ScrobblingPaused")).await;
+        assert_eq!((reply.signature.as_str(), reply.body_reader().u32().unwrap()), ("b", 1));
+
+        service.handle(&call("ResumeScrobbling")).await;
+        assert!(!controller.scrobbling_paused());
+        let reply = service.handle(&call("Scrobble")).await;
+        assert_eq!(reply.error_name.as_deref(), Some("org.freedesktop.DBus.Error.UnknownMethod"));
+    }
+}
@@ -0,0 +1,121 @@
+use crate::error::Result;
+use crate::scrobble::{Scrobble, CROSS_DEVICE_WINDOW};
+use crate::sonos::TrackDatabase;
+use chrono::{Local, TimeZone};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// A scrobble recorded twice, and the earlier row it is merged into
+#[derive(Debug, Clone, PartialEq)]
+pub struct Duplicate {
+    pub kept_id: i64,
+    pub kept: Scrobble,
+    pub id: i64,
+    pub scrobble: Scrobble,
+}
+
+impl fmt::Display for Duplicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = |scrobble: &Scrobble| {
+            Local
+                .timestamp_opt(scrobble.started_at, 0)
+                .single()
+                .map_or_else(|| scrobble.started_at.to_string(), |time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        };
+        write!(
+            f,
+            "{} - {} on {} at {}, same as on {} at {}",
+            self.scrobble.artist,
+            self.scrobble.title,
+            self.scrobble.device,
+            time(&self.scrobble),
+            self.kept.device,
+            time(&self.kept)
+        )
+    }
+}
+
+/// Finds scrobbles of the same track started within the cross-device window
+/// of an earlier one on another device, like [`Scrobble::same_play_elsewhere`].
+/// Grouped speakers recorded these before duplicates were caught on the way
+/// in, and imports overlap with what this service scrobbled itself. On the
+/// same device only a scrobble started at the very same time is a duplicate,
+/// as a short track on repeat is scrobbled once per loop.
+pub async fn find_duplicates(db: &TrackDatabase) -> Result<Vec<Duplicate>> {
+    let window = CROSS_DEVICE_WINDOW.as_secs() as i64;
+    // Scrobbles kept so far that later ones could still duplicate
+    let mut recent: VecDeque<(i64, Scrobble, String)> = VecDeque::new();
+    let mut duplicates = Vec::new();
+    for (id, scrobble) in db.scrobbles_by_start().await? {
+        while recent.front().is_some_and(|(_, kept, _)| scrobble.started_at - kept.started_at > window) {
+            recent.pop_front();
+        }
+        let fingerprint = scrobble.fingerprint();
+        let duplicated = |(_, kept, kept_fingerprint): &&(i64, Scrobble, String)| {
+            *kept_fingerprint == fingerprint
+                && (kept.device != scrobble.device || kept.started_at == scrobble.started_at)
+        };
+        match recent.iter().find(duplicated) {
+            Some((kept_id, kept, _)) => {
+                duplicates.push(Duplicate { kept_id: *kept_id, kept: kept.clone(), id, scrobble })
+            }
+            None => recent.push_back((id, scrobble, fingerprint)),
+        }
+    }
+    Ok(duplicates)
+}
+
+/// Merges each duplicate into the scrobble it duplicates, which takes over
+/// its album, featured artists and genre tags. Statistics are computed from
+/// the remaining scrobbles, so they count each play once afterwards.
+pub async fn merge_duplicates(db: &TrackDatabase, duplicates: &[Duplicate]) -> Result<()> {
+    for duplicate in duplicates {
+        db.merge_scrobble(duplicate.kept_id, duplicate.id).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::HistorySort;
+
+    #[tokio::test]
+    async fn test_duplicates_merged() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobble = Scrobble::new("Kitchen", "Daft Punk", "Get Lucky", 1_700_000_000);
+        // Heard on a grouped speaker, recorded before those were caught
+        let respelled = Scrobble {
+            device: "Living Room".to_string(),
+            title: "Get lucky!".to_string(),
+            album: Some("Random Access Memories".to_string()),
+            started_at: scrobble.started_at + 5,
+            featured: vec!["Pharrell Williams".to_string()],
+            ..scrobble.clone()
+        };
+        let replayed = Scrobble { started_at: scrobble.started_at + 600, ..scrobble.clone() };
+        // A short track on repeat-one, looping within the window
+        let looped = Scrobble { started_at: replayed.started_at + 40, ..scrobble.clone() };
+        for scrobble in [&scrobble, &replayed, &looped] {
+            assert!(db.record_scrobble(scrobble).await.unwrap());
+        }
+        assert!(!db.record_scrobble(&respelled).await.unwrap());
+        db.record_duplicate_scrobble(&respelled).await.unwrap();
+        db.record_album_art("Living Room", respelled.started_at, "http://10.0.0.2:1400/getaa?u=1").await.unwrap();
+
+        let duplicates = find_duplicates(&db).await.unwrap();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].scrobble.title, "Get lucky!");
+        assert_eq!(duplicates[0].kept.started_at, scrobble.started_at);
+
+        merge_duplicates(&db, &duplicates).await.unwrap();
+        assert!(find_duplicates(&db).await.unwrap().is_empty());
+        let artists = db.top_artists("Kitchen", 0, i64::MAX, 5).await.unwrap();
+        assert_eq!(artists, vec![("Daft Punk".to_string(), 3), ("Pharrell Williams".to_string(), 1)]);
+        let history = db.history(&Default::default(), HistorySort::Oldest, None, 5).await.unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].album.as_deref(), Some("Random Access Memories"));
+        let url = db.album_art_url(duplicates[0].kept_id).await.unwrap();
+        assert_eq!(url.as_deref(), Some("http://10.0.0.2:1400/getaa?u=1"));
+    }
+}
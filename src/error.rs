@@ -0,0 +1,59 @@
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Failure categories surfaced by the library, so callers can decide per
+/// category whether an operation is worth retrying.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    #[error("discovery failed: {0}")]
+    Discovery(String),
+    #[error("subscription failed: {0}")]
+    Subscription(String),
+    /// The speaker no longer knows the subscription, typically after a
+    /// restart; only subscribing anew helps
+    #[error("subscription expired: {0}")]
+    SubscriptionExpired(String),
+    #[error("SOAP call failed: {0}")]
+    Soap(String),
+    #[error("scrobble failed: {0}")]
+    Scrobble(String),
+    #[error("notification failed: {0}")]
+    Notify(String),
+    #[error("update failed: {0}")]
+    Update(String),
+    #[error("import failed: {0}")]
+    Import(String),
+    #[error("D-Bus error: {0}")]
+    Dbus(String),
+    #[error("database error: {0}")]
+    Db(#[from] sqlx::Error),
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Soap(e.to_string())
+    }
+}
+
+impl From<quick_xml::Error> for Error {
+    fn from(e: quick_xml::Error) -> Self {
+        Error::Soap(format!("invalid response XML: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_categories() {
+        let err: Error = sqlx::Error::RowNotFound.into();
+        assert!(matches!(err, Error::Db(_)));
+
+        let err = Error::Discovery("no devices responded".to_string());
+        assert_eq!(err.to_string(), "discovery failed: no devices responded");
+    }
+}
@@ -0,0 +1,274 @@
+use crate::error::{Error, Result};
+use crate::scrobble::Scrobble;
+use crate::sonos::TrackDatabase;
+use chrono::NaiveDateTime;
+use serde_json::Value;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+/// Device imported ListenBrainz listens are recorded under
+pub const LISTENBRAINZ_DEVICE: &str = "ListenBrainz import";
+/// Device imported Last.fm scrobbles are recorded under
+pub const LASTFM_DEVICE: &str = "Last.fm import";
+
+/// Signatures of the zip records read here
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+
+/// What an import added to the database
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub imported: usize,
+    /// Already recorded, e.g. scrobbled by this service before
+    pub duplicates: usize,
+    /// Entries without an artist, title or time
+    pub skipped: usize,
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Imported {} listens ({} already recorded, {} unreadable)",
+            self.imported, self.duplicates, self.skipped
+        )
+    }
+}
+
+impl ImportSummary {
+    async fn record(&mut self, db: &TrackDatabase, scrobble: Option<Scrobble>) -> Result<()> {
+        match scrobble {
+            Some(scrobble) if db.record_scrobble(&scrobble).await? => self.imported += 1,
+            Some(_) => self.duplicates += 1,
+            None => self.skipped += 1,
+        }
+        Ok(())
+    }
+}
+
+/// Loads the listens of a ListenBrainz export, the zip file or one of the
+/// JSON Lines files in it, into the database. Listens recorded before, also
+/// on another device, are left out.
+pub async fn import_listenbrainz(db: &TrackDatabase, path: &Path) -> Result<ImportSummary> {
+    let bytes = std::fs::read(path).map_err(|e| Error::Import(format!("{}: {}", path.display(), e)))?;
+    let files = match bytes.starts_with(&LOCAL_HEADER.to_le_bytes()) {
+        true => unzip(&bytes)?
+            .into_iter()
+            .filter(|(name, _)| name.ends_with(".jsonl"))
+            .map(|(_, data)| data)
+            .collect(),
+        false => vec![bytes],
+    };
+
+    let mut summary = ImportSummary::default();
+    for file in files {
+        for line in String::from_utf8_lossy(&file).lines().filter(|line| !line.trim().is_empty()) {
+            let listen = serde_json::from_str(line).ok().and_then(|listen| listenbrainz_listen(&listen));
+            summary.record(db, listen).await?;
+        }
+    }
+    db.flush_pending().await?;
+    Ok(summary)
+}
+
+/// Loads a CSV export of Last.fm scrobbles into the database. Reads files
+/// with a header naming `artist`, `track`, `album` and `uts` or `date`
+/// columns, and headerless `artist,album,track,date` files as written by
+/// the common export tools.
+pub async fn import_lastfm(db: &TrackDatabase, path: &Path) -> Result<ImportSummary> {
+    let text = std::fs::read_to_string(path).map_err(|e| Error::Import(format!("{}: {}", path.display(), e)))?;
+    let mut rows = text.lines().filter(|line| !line.trim().is_empty()).map(csv_fields).peekable();
+
+    let header = rows.peek().filter(|row| row.iter().any(|field| field.eq_ignore_ascii_case("artist")));
+    let columns: Vec<String> = match header {
+        Some(header) => header.iter().map(|field| field.to_lowercase()).collect(),
+        None => ["artist", "album", "track", "date"].map(str::to_string).to_vec(),
+    };
+    if header.is_some() {
+        rows.next();
+    }
+
+    let mut summary = ImportSummary::default();
+    for row in rows {
+        let field = |name: &str| {
+            let index = columns.iter().position(|column| column == name)?;
+            row.get(index).map(|value| value.trim()).filter(|value| !value.is_empty())
+        };
+        let started_at = field("uts")
+            .and_then(|uts| uts.parse().ok())
+            .or_else(|| field("date").and_then(parse_lastfm_date));
+        let scrobble = match (field("artist"), field("track"), started_at) {
+            (Some(artist), Some(title), Some(started_at)) => {
+                Some(imported(LASTFM_DEVICE, artist, title, field("album"), started_at, None))
+            }
+            _ => None,
+        };
+        summary.record(db, scrobble).await?;
+    }
+    db.flush_pending().await?;
+    Ok(summary)
+}
+
+fn listenbrainz_listen(listen: &Value) -> Option<Scrobble> {
+    let metadata = &listen["track_metadata"];
+    let duration = metadata["additional_info"]["duration_ms"].as_u64().map(Duration::from_millis);
+    Some(imported(
+        LISTENBRAINZ_DEVICE,
+        metadata["artist_name"].as_str()?,
+        metadata["track_name"].as_str()?,
+        metadata["release_name"].as_str(),
+        listen["listened_at"].as_i64()?,
+        duration,
+    ))
+}
+
+fn imported(
+    device: &str,
+    artist: &str,
+    title: &str,
+    album: Option<&str>,
+    started_at: i64,
+    duration: Option<Duration>,
+) -> Scrobble {
+    Scrobble {
+        album: album.map(str::to_string),
+        duration,
+        ..Scrobble::new(device, artist, title, started_at)
+    }
+}
+
+/// Dates like "31 Jan 2021 20:15", in UTC
+fn parse_lastfm_date(date: &str) -> Option<i64> {
+    NaiveDateTime::parse_from_str(date, "%d %b %Y %H:%M").ok().map(|date| date.and_utc().timestamp())
+}
+
+/// Splits a CSV line, honoring quoted fields with doubled quotes inside
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(String::new()),
+            (c, _) => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// The files in a zip archive, by name. Only what ListenBrainz exports use
+/// is supported: stored and deflated entries, without Zip64.
+fn unzip(bytes: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let invalid = || Error::Import("invalid or unsupported zip file".to_string());
+    let u16_at = |at: usize| bytes.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize);
+    let u32_at = |at: usize| bytes.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+
+    // The end record sits at the end, followed only by a comment
+    let end = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .find(|&at| u32_at(at) == Some(END_OF_CENTRAL_DIRECTORY))
+        .ok_or_else(invalid)?;
+    let entries = u16_at(end + 10).ok_or_else(invalid)?;
+    let mut at = u32_at(end + 16).ok_or_else(invalid)? as usize;
+
+    let mut files = Vec::new();
+    for _ in 0..entries {
+        if u32_at(at) != Some(CENTRAL_HEADER) {
+            return Err(invalid());
+        }
+        let method = u16_at(at + 10).ok_or_else(invalid)?;
+        let size = u32_at(at + 20).ok_or_else(invalid)? as usize;
+        let name_len = u16_at(at + 28).ok_or_else(invalid)?;
+        let extra_len = u16_at(at + 30).ok_or_else(invalid)?;
+        let comment_len = u16_at(at + 32).ok_or_else(invalid)?;
+        let offset = u32_at(at + 42).ok_or_else(invalid)? as usize;
+        let name = bytes.get(at + 46..at + 46 + name_len).ok_or_else(invalid)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        at += 46 + name_len + extra_len + comment_len;
+
+        if u32_at(offset) != Some(LOCAL_HEADER) {
+            return Err(invalid());
+        }
+        let start = offset + 30 + u16_at(offset + 26).ok_or_else(invalid)? + u16_at(offset + 28).ok_or_else(invalid)?;
+        let data = bytes.get(start..start + size).ok_or_else(invalid)?;
+        let data = match method {
+            0 => data.to_vec(),
+            8 => miniz_oxide::inflate::decompress_to_vec(data)
+                .map_err(|e| Error::Import(format!("{}: {:?}", name, e)))?,
+            _ => return Err(Error::Import(format!("{}: unsupported compression", name))),
+        };
+        files.push((name, data));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_import_lastfm_csv() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let path = std::env::temp_dir().join(format!("lastfm-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "Massive Attack,Mezzanine,Teardrop,31 Jan 2021 20:15\n\
+             \"Crosby, Stills & Nash\",,\"Suite: Judy Blue Eyes\",31 Jan 2021 20:20\n\
+             Massive Attack,Mezzanine,Teardrop,31 Jan 2021 20:15\n\
+             Unknown,,,\n",
+        )
+        .unwrap();
+        let summary = import_lastfm(&db, &path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(summary, ImportSummary { imported: 2, duplicates: 1, skipped: 1 });
+        let artists = db.top_artists(LASTFM_DEVICE, 0, i64::MAX, 5).await.unwrap();
+        assert_eq!(artists[0], ("Crosby, Stills & Nash".to_string(), 1));
+    }
+
+    #[tokio::test]
+    async fn test_import_listenbrainz_zip() {
+        let listen = r#"{"listened_at":1700000000,"track_metadata":{"artist_name":"Bonobo","track_name":"Kerala"}}"#;
+        // A zip with one stored entry, built by hand
+        let name = b"listens/2023/11.jsonl";
+        let data = listen.as_bytes();
+        let mut zip = Vec::new();
+        zip.extend(LOCAL_HEADER.to_le_bytes());
+        zip.extend([20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        zip.extend((data.len() as u32).to_le_bytes());
+        zip.extend((data.len() as u32).to_le_bytes());
+        zip.extend((name.len() as u16).to_le_bytes());
+        zip.extend([0, 0]);
+        zip.extend(name);
+        zip.extend(data);
+        let directory = zip.len() as u32;
+        zip.extend(CENTRAL_HEADER.to_le_bytes());
+        zip.extend([20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        zip.extend((data.len() as u32).to_le_bytes());
+        zip.extend((data.len() as u32).to_le_bytes());
+        zip.extend((name.len() as u16).to_le_bytes());
+        zip.extend([0; 12]);
+        zip.extend(0u32.to_le_bytes());
+        zip.extend(name);
+        let directory_len = zip.len() as u32 - directory;
+        zip.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        zip.extend([0, 0, 0, 0, 1, 0, 1, 0]);
+        zip.extend(directory_len.to_le_bytes());
+        zip.extend(directory.to_le_bytes());
+        zip.extend([0, 0]);
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let path = std::env::temp_dir().join(format!("listenbrainz-{}.zip", std::process::id()));
+        std::fs::write(&path, &zip).unwrap();
+        let summary = import_listenbrainz(&db, &path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(summary.imported, 1);
+    }
+}
@@ -1 +1,27 @@
+pub mod agent;
+pub mod cache;
+pub mod completions;
+pub mod config;
+pub mod control;
+#[cfg(unix)]
+pub mod dbus;
+pub mod dedupe;
+pub mod error;
+pub mod import;
+pub mod logfile;
+pub mod notify;
+pub mod output;
+pub mod picker;
+pub mod reprocess;
+pub mod retry;
+pub mod scrobble;
+pub mod server;
+pub mod service;
 pub mod sonos;
+pub mod stats;
+pub mod status;
+pub mod update;
+pub mod websocket;
+
+pub use config::Config;
+pub use error::{Error, Result};
@@ -0,0 +1,142 @@
+use crate::config::{LogFileConfig, LogRotation};
+use crate::error::{Error, Result};
+use chrono::{Local, NaiveDate};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A log file that is rotated daily or once it reaches a size. Rotated files
+/// get a numeric suffix, `.1` being the most recent, and only the newest
+/// `keep` of them are kept.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    written: u64,
+    opened_on: NaiveDate,
+}
+
+impl RotatingFile {
+    pub fn open(config: &LogFileConfig) -> Result<Self> {
+        let (file, written) = open_append(&config.path)?;
+        Ok(Self {
+            path: config.path.clone(),
+            rotation: config.rotate,
+            max_bytes: config.max_size_mb * 1024 * 1024,
+            keep: config.keep,
+            file,
+            written,
+            opened_on: Local::now().date_naive(),
+        })
+    }
+
+    fn rotation_due(&self, incoming: usize) -> bool {
+        match self.rotation {
+            LogRotation::Daily => Local::now().date_naive() != self.opened_on,
+            LogRotation::Size => self.written > 0 && self.written + incoming as u64 > self.max_bytes,
+        }
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        let _ = std::fs::remove_file(rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            let _ = std::fs::rename(rotated(n), rotated(n + 1));
+        }
+        if self.keep > 0 {
+            std::fs::rename(&self.path, rotated(1))?;
+        } else {
+            std::fs::remove_file(&self.path)?;
+        }
+
+        let (file, written) = open_append(&self.path).map_err(std::io::Error::other)?;
+        self.file = file;
+        self.written = written;
+        self.opened_on = Local::now().date_naive();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.rotation_due(buf.len()) {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes the log to stderr as usual and to a [`RotatingFile`]. A failing
+/// file doesn't keep the log from reaching stderr.
+pub struct TeeLog {
+    file: RotatingFile,
+}
+
+impl TeeLog {
+    pub fn new(file: RotatingFile) -> Self {
+        Self { file }
+    }
+}
+
+impl Write for TeeLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let _ = self.file.write_all(buf);
+        std::io::stderr().write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let _ = self.file.flush();
+        std::io::stderr().flush()
+    }
+}
+
+fn open_append(path: &Path) -> Result<(File, u64)> {
+    let io = |e: std::io::Error| Error::Config(format!("{}: {}", path.display(), e));
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).map_err(io)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path).map_err(io)?;
+    let written = file.metadata().map_err(io)?.len();
+    Ok((file, written))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("sonos-logs-{}", std::process::id()));
+        let config = LogFileConfig {
+            path: dir.join("scrobbler.log"),
+            rotate: LogRotation::Size,
+            max_size_mb: 1,
+            keep: 2,
+        };
+        let mut log = RotatingFile::open(&config).unwrap();
+        let line = vec![b'x'; 600 * 1024];
+        for _ in 0..4 {
+            log.write_all(&line).unwrap();
+        }
+        log.flush().unwrap();
+
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+        // Four writes of 600 KiB: one per file, the oldest rotated away
+        assert_eq!(files, vec!["scrobbler.log", "scrobbler.log.1", "scrobbler.log.2"]);
+    }
+}
@@ -1,14 +1,350 @@
-use anyhow::Result;
-use log::info;
-use sonos_scrobbler::sonos::{SonosDiscovery, EventSubscriber};
+use anyhow::{anyhow, bail, Result};
+use clap::{CommandFactory, Parser, Subcommand};
+use log::{debug, info, warn};
+use std::io::IsTerminal;
+use std::net::Ipv4Addr;
+use sonos_scrobbler::sonos::{
+    container_network_warning, prune_log_periodically, replay_file, ArtProxy, EventCapture, EventHub, EventLog,
+    EventSubscriber, Library, ListenBudget, NowPlaying, Replay, SleepDetector, SoapClient, SonosDevice, SonosDiscovery,
+    SsdpListener, TaskBudget, TrackDatabase, WakeUps, LOG_PRUNE_INTERVAL,
+};
+use sonos_scrobbler::agent::HubLink;
+use sonos_scrobbler::completions::{self, Shell, ROOM_VALUE_NAME};
+use sonos_scrobbler::control::{self, Controller, PROFILE_CHECK_INTERVAL};
+use sonos_scrobbler::{dedupe, import, reprocess};
+use sonos_scrobbler::logfile::{RotatingFile, TeeLog};
+use sonos_scrobbler::notify::{self, EmailNotifier, Milestones, ScrobbleGuard, Telegram};
+use sonos_scrobbler::output::{self, OutputFormat};
+use sonos_scrobbler::picker;
+use sonos_scrobbler::scrobble::{
+    self, Connectivity, Discogs, LastFm, ListenBrainz, Plex, Profiles, ScrobbleBackend, Scrobbler, Subsonic,
+};
+use sonos_scrobbler::server::{Agents, Audioscrobbler, Server};
+use sonos_scrobbler::service::{ServiceManager, ServiceSpec};
+use sonos_scrobbler::stats::{Digest, Period, Report, ReportFormat, ReportPeriod};
+use sonos_scrobbler::status::{self, Status};
+use sonos_scrobbler::update::{self, Updater};
+use sonos_scrobbler::config::{self, household_room, Config};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
+/// How often quarantined listens are retried for missing metadata
+const QUARANTINE_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long shutdown waits for listens in progress to be scrobbled
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Parser)]
+#[command(name = "sonos-scrobbler", version, about = "Logs and scrobbles what your Sonos speakers play")]
+struct Cli {
+    /// Config file to use instead of ~/.config/sonos-scrobbler/config.toml
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+
+    /// Ignore cached discovery results and rescan the network
+    #[arg(long, global = true)]
+    refresh: bool,
+
+    /// Use the speaker at this address instead of discovering (repeatable,
+    /// overrides `discovery.devices`)
+    #[arg(long = "device", value_name = "IP", global = true)]
+    devices: Vec<Ipv4Addr>,
+
+    /// Write every speaker event to daily JSON Lines files in this directory,
+    /// for `replay` and bug reports
+    #[arg(long, value_name = "DIR")]
+    capture_events: Option<PathBuf>,
+
+    /// Check GitHub for a newer release at startup and once a day, and log
+    /// when there is one
+    #[arg(long)]
+    check_update: bool,
+
+    /// Accept made-up speaker events at `POST /dev/inject-event` of the
+    /// [http] server and log what the listen logic makes of them, for
+    /// trying out rules without a speaker; nothing is scrobbled
+    #[arg(long)]
+    dev: bool,
+
+    /// Print results as text or as JSON for scripts (`discover`,
+    /// `now-playing`, `stats` and `queue`)
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t, global = true)]
+    output: OutputFormat,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Monitor every speaker and log what it plays (default)
+    Run {
+        /// Choose the rooms to monitor from the discovered ones
+        #[arg(long)]
+        pick: bool,
+        /// Save the chosen rooms to the config file as `rooms`
+        #[arg(long, requires = "pick")]
+        save: bool,
+    },
+    /// List the Sonos speakers on the network
+    Discover {
+        /// Scan this IPv4 range (e.g. 192.168.1.0/24) instead of using
+        /// multicast discovery
+        #[arg(long, value_name = "CIDR")]
+        subnet: Option<String>,
+    },
+    /// Show what each speaker is playing right now
+    NowPlaying {
+        /// Only show this room (repeatable)
+        #[arg(long = "room", value_name = ROOM_VALUE_NAME)]
+        rooms: Vec<String>,
+    },
+    /// Show listening statistics
+    Stats {
+        /// Show scrobbling API call counts and error rates instead
+        #[arg(long, conflicts_with_all = ["period", "format"])]
+        api: bool,
+        /// Report the top artists, tracks and rooms of the last complete
+        /// week, month or year instead
+        #[arg(long, value_enum)]
+        period: Option<ReportPeriod>,
+        /// Write the report as Markdown tables or as CSV; implies --period
+        /// month unless given
+        #[arg(long, value_enum)]
+        format: Option<ReportFormat>,
+    },
+    /// Print the listening digest for a month
+    Digest {
+        /// Month as YYYY-MM; defaults to the previous month
+        #[arg(long, value_name = "YYYY-MM")]
+        month: Option<String>,
+        /// Email the digest using the [email] settings instead of printing it
+        #[arg(long)]
+        send: bool,
+    },
+    /// Run a captured event log through the listen logic and print what it
+    /// decides, without network access
+    Replay {
+        /// JSON Lines file of captured NOTIFY requests
+        file: PathBuf,
+    },
+    /// Run the logged speaker events through the listen logic again with
+    /// the current config, and scrobble the listens that now qualify
+    Reprocess {
+        /// First day to reprocess, in local time
+        #[arg(long, value_name = "YYYY-MM-DD")]
+        since: String,
+        /// List the listens that would now be scrobbled without submitting
+        /// them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Load historical listens into the local database, so statistics
+    /// cover the time before the scrobbler ran
+    Import {
+        #[command(subcommand)]
+        source: ImportCommand,
+    },
+    /// Maintain the listens database
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Inspect listens held back from scrobbling
+    Queue {
+        #[command(subcommand)]
+        command: QueueCommand,
+    },
+    /// Show or switch the profile of [profiles], also for the running daemon
+    Profile {
+        #[command(subcommand)]
+        command: ProfileCommand,
+    },
+    /// Download the latest release and replace this binary with it (Linux
+    /// and macOS)
+    #[command(name = "selfupdate")]
+    SelfUpdate,
+    /// Run the daemon as a systemd user service (Linux) or launchd agent
+    /// (macOS)
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommand,
+    },
+    /// Print a completion script for bash, zsh or fish
+    Completions { shell: Shell },
+    /// Print the rooms in the device cache, for the completion scripts
+    #[command(name = "complete-rooms", hide = true)]
+    CompleteRooms,
+}
+
+#[derive(Subcommand)]
+enum ServiceCommand {
+    /// Install and start the service for this binary, config and directory
+    Install,
+    /// Stop and remove the service
+    Uninstall,
+    /// Show whether the service is running
+    Status,
+}
+
+#[derive(Subcommand)]
+enum ImportCommand {
+    /// A ListenBrainz export, the zip file or a JSON Lines file from it
+    Listenbrainz { file: PathBuf },
+    /// A CSV export of Last.fm scrobbles
+    Lastfm { file: PathBuf },
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    /// Merge scrobbles of the same play recorded more than once, e.g. by
+    /// grouped speakers or by an import
+    Dedupe {
+        /// List the duplicates without merging them
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum QueueCommand {
+    /// Listens quarantined for missing artist or title
+    Quarantine {
+        #[command(subcommand)]
+        command: QuarantineCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProfileCommand {
+    /// Show the active profile and the configured ones
+    Show,
+    /// Switch to a profile
+    Set { name: String },
+    /// Switch back to the settings without a profile
+    Clear,
+}
+
+#[derive(Subcommand)]
+enum QuarantineCommand {
+    /// List the quarantined listens
+    List,
+    /// Retry enrichment now and scrobble the listens it completes
+    Retry,
+    /// Scrobble a quarantined listen whatever its confidence, e.g. after
+    /// checking its artist and title by hand
+    Release {
+        /// Id of the listen, as listed
+        id: i64,
+    },
+}
+
+fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    let cli = Cli::parse();
+
+    let config = Config::load(cli.config.as_deref())?;
+    // Before the runtime starts its threads, which may read the environment
+    config.apply_timezone();
+    tokio::runtime::Runtime::new()?.block_on(start(cli, config))
+}
+
+async fn start(cli: Cli, mut config: Config) -> Result<()> {
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(log_file) = &config.log_file {
+        logger.target(env_logger::Target::Pipe(Box::new(TeeLog::new(RotatingFile::open(log_file)?))));
+    }
+    logger.init();
+    if !cli.devices.is_empty() {
+        config.discovery.devices = cli.devices.clone();
+    }
+    if cli.output == OutputFormat::Json
+        && !matches!(
+            cli.command,
+            Some(Command::Discover { .. } | Command::NowPlaying { .. } | Command::Stats { .. } | Command::Queue { .. })
+        )
+    {
+        bail!("--output json is only supported by discover, now-playing, stats and queue");
+    }
+
+    match &cli.command {
+        None => run(&cli, &config, false, false).await,
+        Some(Command::Run { pick, save }) => run(&cli, &config, *pick, *save).await,
+        Some(Command::Discover { subnet }) => discover(&cli, &mut config, subnet.clone()).await,
+        Some(Command::NowPlaying { rooms }) => now_playing(&cli, &config, rooms).await,
+        Some(Command::Stats { period, format, .. }) if period.is_some() || format.is_some() => {
+            if cli.output == OutputFormat::Json {
+                bail!("--output json doesn't apply to reports, use --format");
+            }
+            let period = period.unwrap_or_default().previous(chrono::Local::now());
+            let report = Report::build(&TrackDatabase::new().await?, period).await?;
+            print!("{}", report.render(format.unwrap_or(ReportFormat::Markdown)));
+            Ok(())
+        }
+        Some(Command::Stats { api, .. }) => stats(*api, cli.output).await,
+        Some(Command::Digest { month, send }) => digest(&config, month.as_deref(), *send).await,
+        Some(Command::Replay { file }) => {
+            for decision in replay_file(file, config.scrobble_on, config.threshold.clone())? {
+                println!("{}", decision);
+            }
+            Ok(())
+        }
+        Some(Command::Reprocess { since, dry_run }) => reprocess(&config, since, *dry_run).await,
+        Some(Command::Import { source }) => {
+            let db = TrackDatabase::new().await?;
+            let summary = match source {
+                ImportCommand::Listenbrainz { file } => import::import_listenbrainz(&db, file).await?,
+                ImportCommand::Lastfm { file } => import::import_lastfm(&db, file).await?,
+            };
+            println!("{}", summary);
+            Ok(())
+        }
+        Some(Command::Db { command: DbCommand::Dedupe { dry_run } }) => {
+            let db = TrackDatabase::new().await?;
+            let duplicates = dedupe::find_duplicates(&db).await?;
+            for duplicate in &duplicates {
+                println!("{}", duplicate);
+            }
+            if *dry_run {
+                println!("Found {} duplicate scrobbles, nothing changed", duplicates.len());
+            } else {
+                dedupe::merge_duplicates(&db, &duplicates).await?;
+                println!("Merged {} duplicate scrobbles", duplicates.len());
+            }
+            Ok(())
+        }
+        Some(Command::Queue { command: QueueCommand::Quarantine { command } }) => {
+            quarantine(&config, command, cli.output).await
+        }
+        Some(Command::Profile { command }) => profile(&config, command).await,
+        Some(Command::SelfUpdate) => self_update().await,
+        Some(Command::Service { command }) => service(&cli, command),
+        Some(Command::Completions { shell }) => {
+            print!("{}", completions::generate(*shell, Cli::command()));
+            Ok(())
+        }
+        Some(Command::CompleteRooms) => complete_rooms().await,
+    }
+}
+
+async fn discover_devices(cli: &Cli, config: &Config) -> Result<SonosDiscovery> {
+    let db = TrackDatabase::new().await?;
+    let discovery = if cli.refresh {
+        SonosDiscovery::refresh(&db, &config.discovery).await?
+    } else {
+        SonosDiscovery::cached(&db, &config.discovery).await?
+    };
+    Ok(discovery)
+}
+
+async fn run(cli: &Cli, config: &Config, pick: bool, save: bool) -> Result<()> {
     info!("Starting Sonos Scrobbler...");
+    SonosDiscovery::cache_descriptions(&config.cache);
 
     // Initialize Sonos discovery
-    let discovery = SonosDiscovery::new().await?;
+    let discovery = discover_devices(cli, config).await?;
     
     // Discover and list devices
     let devices = discovery.discover_devices().await?;
@@ -17,30 +353,662 @@ async fn main() -> Result<()> {
         info!("  {}: {}", i + 1, device);
     }
     
-    if devices.is_empty() {
-        info!("No Sonos devices found!");
-        return Ok(());
+    // Other households are found as configured at every start, never cached
+    let mut households = Vec::new();
+    for (name, household) in &config.households {
+        match SonosDiscovery::find(&household.discovery).await {
+            Ok(discovery) => {
+                let found = only_rooms(discovery.discover_devices().await?, &household.rooms);
+                info!("Household {}:", name);
+                for (i, device) in found.iter().enumerate() {
+                    info!("  {}: {}", i + 1, device);
+                }
+                households.push((name.clone(), found));
+            }
+            Err(e) => warn!("No devices found in household {}: {}", name, e),
+        }
+    }
+
+    if devices.is_empty() && households.iter().all(|(_, found)| found.is_empty()) {
+        match container_network_warning() {
+            Some(warning) => warn!("{}", warning),
+            None => info!("No Sonos devices found!"),
+        }
+        // A hub may only scrobble for its agents
+        let hub = config.http.as_ref().is_some_and(|http| http.agent_token.is_some());
+        if !cli.dev && !hub {
+            return Ok(());
+        }
+    }
+
+    let rooms = match pick {
+        true => pick_rooms(&devices)?,
+        false => config.rooms.clone(),
+    };
+    if save {
+        let path = cli.config.clone().or_else(config::default_path);
+        let path = path.ok_or_else(|| anyhow!("no config directory found, pass --config to save the rooms"))?;
+        Config::save_rooms(&path, &rooms)?;
+        info!("Saved the rooms to {}", path.display());
+    }
+    let mut devices = only_rooms(devices, &rooms);
+
+    let db = TrackDatabase::new().await?.with_retry_policy(config.retry.database);
+    let event_log = (config.event_log_days > 0)
+        .then(|| Arc::new(EventLog::new(db.clone(), config.scrobble_on, config.threshold.clone())));
+    let library = (!config.library.mounts.is_empty()).then(|| Arc::new(Library::new(&config.library)));
+    let connectivity = Some(&config.connectivity_check)
+        .filter(|host| !host.is_empty())
+        .map(|host| Arc::new(Connectivity::new(host)));
+    let disabled_backends = db.disabled_backends().await?;
+    if !disabled_backends.is_empty() {
+        info!("Scrobbling to {} is switched off", disabled_backends.join(", "));
+    }
+    let mut scrobbler = scrobbler(config, &db)?
+        .with_usage_log(db.clone())
+        .with_disabled_backends(disabled_backends.clone())
+        .with_worker(&config.submission);
+    if let Some(connectivity) = &connectivity {
+        scrobbler = scrobbler.with_connectivity(connectivity.clone());
+    }
+    // The profile switched to last, or else the configured one
+    let profile = db.active_profile().await?.unwrap_or_else(|| config.profile.clone());
+    match scrobbler.set_profile(profile.as_deref()) {
+        Ok(Some(name)) => info!("Profile {} is active", name),
+        Ok(None) => {}
+        Err(e) => warn!("Starting without a profile: {}", e),
+    }
+    let scrobbler = Arc::new(scrobbler);
+    for (name, found) in households {
+        // Named apart from rooms of the same name in other households
+        let found = found.into_iter().map(|device| SonosDevice { room: household_room(&name, &device.room), ..device });
+        devices.extend(found);
+    }
+    if let Some(lastfm) = LastFm::from_env() {
+        let max_age = Duration::from_secs(config.session_check_hours * 3600);
+        match lastfm?.with_database(db.clone()).verify_session(max_age).await {
+            Ok(true) => info!("Last.fm accepted the session key"),
+            Ok(false) => debug!("Last.fm session key checked recently, not checking again"),
+            Err(e) => warn!("Failed to verify the Last.fm session key: {}", e),
+        }
     }
 
     // Create track pollers for all devices
+    let status = Arc::new(Status::default());
+    status.set_disabled_backends(disabled_backends);
+    status.set_profile(scrobbler.profiles().active());
     let mut handles = Vec::new();
+    let mut pollers = Vec::new();
+    let (shutdown, shutdown_requested) = watch::channel(false);
+    let budget = TaskBudget::new(config.max_concurrent_polls);
+    let mut listen_budget = ListenBudget::new(&config.listen_budgets);
+    if let Some(telegram) = &config.telegram {
+        listen_budget = listen_budget.with_telegram(Arc::new(Telegram::new(telegram)?));
+    }
+    let listen_budget = Arc::new(listen_budget);
+    let mut sleep = config.sleep.as_ref().map(SleepDetector::new).unwrap_or_default();
+    if let (Some(telegram), Some(_)) = (&config.telegram, &config.sleep) {
+        sleep = sleep.with_telegram(Arc::new(Telegram::new(telegram)?));
+    }
+    let sleep = Arc::new(sleep);
     
-    for device_name in devices {
-        info!("Setting up track polling for device: {}", device_name);
-        let subscriber = EventSubscriber::new(&device_name).await?;
+    let controller = Controller::new(&devices, scrobbler.clone())?
+        .with_retry_policy(config.retry.device)
+        .with_database(db.clone())
+        .with_status(status.clone())
+        .with_sleep_detector(sleep.clone())
+        .with_threshold(config.threshold.clone());
+    let controller = Arc::new(controller);
+    if !config.profiles.is_empty() {
+        handles.push(tokio::spawn(control::follow_profile_periodically(controller.clone(), PROFILE_CHECK_INTERVAL)));
+    }
+    if let Some(telegram) = &config.telegram {
+        let bot = Telegram::new(telegram)?;
+        let controller = controller.clone();
+        handles.push(tokio::spawn(async move { bot.handle_commands(controller).await }));
+    }
+    if let Some(dbus) = &config.dbus {
+        #[cfg(unix)]
+        {
+            let service = sonos_scrobbler::dbus::DbusService::new(dbus, controller.clone());
+            handles.push(tokio::spawn(async move {
+                if let Err(e) = service.serve().await {
+                    warn!("D-Bus service stopped: {}", e);
+                }
+            }));
+        }
+        #[cfg(not(unix))]
+        warn!("Ignoring [dbus] ({:?} bus), D-Bus is only supported on Unix", dbus.bus);
+    }
+
+    if cli.capture_events.is_some() && config.http.is_none() {
+        warn!("--capture-events needs the [http] server to receive speaker events");
+    }
+    if cli.dev && config.http.is_none() {
+        warn!("--dev needs the [http] server to accept injected events");
+    }
+
+    // Started before subscribing, so the speakers' initial events arrive
+    let event_token = match config.http.as_ref().and_then(|http| http.event_token.clone()) {
+        Some(token) => token,
+        None => db.event_token().await?,
+    };
+    let events = Arc::new(EventHub::new(event_token));
+    if let Some(http) = &config.http {
+        let mut server = Server::new(http, controller.clone())
+            .with_events(events.clone())
+            .with_database(db.clone())
+            .with_art(ArtProxy::new(db.clone())?);
+        if !http.audioscrobbler.is_empty() {
+            let audioscrobbler = Audioscrobbler::new(&http.audioscrobbler, scrobbler.clone(), db.clone());
+            server = server.with_audioscrobbler(audioscrobbler);
+        }
+        if let Some(token) = http.agent_token.as_ref().filter(|token| !token.is_empty()) {
+            server = server.with_agents(Agents::new(token, scrobbler.clone(), db.clone()));
+            info!("Accepting listens from agents at /agent");
+        }
+        if let Some(dir) = &cli.capture_events {
+            server = server.with_capture(EventCapture::new(dir)?);
+            info!("Capturing speaker events to {}", dir.display());
+        }
+        if cli.dev {
+            server = server.with_dev_mode(Replay::new(config.scrobble_on, config.threshold.clone()));
+            warn!("Dev mode: accepting made-up speaker events at POST /dev/inject-event");
+            if http.trigger_secret.is_none() && http.api_token.is_none() && http.basic_auth.is_none() {
+                warn!("Dev mode needs a trigger secret, API token or basic auth in [http] to accept events");
+            }
+        }
+        let server = Arc::new(server);
+        handles.push(tokio::spawn(async move {
+            if let Err(e) = server.serve().await {
+                warn!("{}", e);
+            }
+        }));
+    }
+
+    // Speaker announcements wake sleeping portable speakers' pollers and
+    // keep the discovery cache current between scans. Configured addresses
+    // are never cached.
+    let wake_ups = Arc::new(WakeUps::default());
+    let is_portable = |device: &SonosDevice| {
+        device.is_portable() || config.portable_rooms.iter().any(|room| room.eq_ignore_ascii_case(&device.room))
+    };
+    let use_cache = config.discovery.devices.is_empty();
+    if use_cache || devices.iter().any(is_portable) {
+        let mut listener = SsdpListener::new(wake_ups.clone());
+        if use_cache {
+            listener = listener.with_registry(db.clone());
+        }
+        handles.push(tokio::spawn(async move {
+            if let Err(e) = listener.listen().await {
+                warn!("{}, relying on discovery scans and periodic checks only", e);
+            }
+        }));
+    }
+
+    for device in devices {
+        info!("Setting up track polling for device: {}", device);
+        let mut subscriber = EventSubscriber::new(&device, db.clone(), &config.retry)?
+            .with_status(status.clone())
+            .with_scrobbler(scrobbler.clone())
+            .with_scrobble_on(config.scrobble_on)
+            .with_threshold(config.threshold.clone())
+            .with_chosen_by_user(config.chosen_by_user.clone())
+            .with_budget(budget.clone())
+            .with_listen_budget(listen_budget.clone())
+            .with_sleep_detector(sleep.clone())
+            .with_shutdown(shutdown_requested.clone());
+        if let Some(event_log) = &event_log {
+            subscriber = subscriber.with_event_log(event_log.clone());
+        }
+        if let Some(library) = &library {
+            subscriber = subscriber.with_library(library.clone());
+        }
+        let on_demand = config.on_demand_rooms.iter().any(|room| room.eq_ignore_ascii_case(subscriber.room()));
+        subscriber = subscriber.with_on_demand(on_demand);
+        let portable = is_portable(&device);
+        subscriber = subscriber.with_portable(portable);
+        if portable {
+            subscriber = subscriber.with_wake_up(wake_ups.register(&device.id));
+        }
+        if let Some(http) = &config.http {
+            let timeout = Duration::from_secs(http.subscription_timeout_secs);
+            subscriber = subscriber.with_events(events.clone(), http.event_port(), timeout, http.speaker_prefix_len);
+        }
         
         let handle = tokio::spawn(async move {
             if let Err(e) = subscriber.poll_current_track().await {
-                info!("Error polling tracks for {}: {}", device_name, e);
+                info!("Error polling tracks for {}: {}", device, e);
             }
         });
         
-        handles.push(handle);
+        pollers.push(handle);
+    }
+
+    if config.status_interval > 0 {
+        let interval = Duration::from_secs(config.status_interval * 60);
+        handles.push(tokio::spawn(status::log_periodically(status.clone(), interval)));
+    }
+
+    if cli.check_update {
+        handles.push(tokio::spawn(update::check_periodically(Updater::new()?, update::CHECK_INTERVAL)));
+    }
+
+    if event_log.is_some() {
+        let days = config.event_log_days;
+        handles.push(tokio::spawn(prune_log_periodically(db.clone(), days, LOG_PRUNE_INTERVAL)));
+    }
+
+    handles.push(tokio::spawn(scrobbler.clone().run_worker()));
+    handles.push(tokio::spawn(scrobble::enrich_periodically(
+        db.clone(),
+        scrobbler.clone(),
+        Discogs::from_env().transpose()?.map(|discogs| discogs.with_cache(&config.cache)),
+        QUARANTINE_RETRY_INTERVAL,
+    )));
+
+    if let Some(connectivity) = connectivity {
+        handles.push(tokio::spawn(scrobble::submit_when_online(
+            db.clone(),
+            scrobbler.clone(),
+            connectivity,
+            status.clone(),
+            scrobble::OUTAGE_CHECK_INTERVAL,
+        )));
+    }
+
+    if let Some(lastfm) = LastFm::from_env() {
+        handles.push(tokio::spawn(scrobble::tag_periodically(db.clone(), lastfm?, scrobble::TAG_INTERVAL)));
+    }
+
+    match (&config.blocklist, LastFm::from_env()) {
+        (Some(blocklist), Some(lastfm)) => handles.push(tokio::spawn(scrobble::sync_blocklist_periodically(
+            db.clone(),
+            lastfm?,
+            blocklist.lastfm_tags.clone(),
+            Duration::from_secs(blocklist.refresh_hours.max(1) * 3600),
+        ))),
+        (Some(_), None) => warn!("[blocklist] needs the Last.fm credentials to sync tags"),
+        _ => {}
+    }
+
+    if let Some(email) = &config.email {
+        let notifier = EmailNotifier::new(email)?;
+        handles.push(tokio::spawn(notify::send_monthly_digests(db.clone(), notifier)));
+        let notifier = EmailNotifier::new(email)?;
+        handles.push(tokio::spawn(notify::send_weekly_new_music(db.clone(), notifier)));
+    }
+
+    if let Some(guard_config) = &config.scrobble_guard {
+        let mut guard = ScrobbleGuard::new(db.clone(), guard_config.clone(), status.clone());
+        if let Some(telegram) = &config.telegram {
+            guard = guard.with_telegram(Telegram::new(telegram)?);
+        }
+        if let Some(email) = &config.email {
+            guard = guard.with_email(EmailNotifier::new(email)?);
+        }
+        handles.push(tokio::spawn(notify::guard_periodically(guard, notify::GUARD_CHECK_INTERVAL)));
+    }
+
+    if let Some(milestones_config) = &config.milestones {
+        let mut milestones = Milestones::new(db.clone(), *milestones_config);
+        if let Some(telegram) = &config.telegram {
+            milestones = milestones.with_telegram(Telegram::new(telegram)?);
+        }
+        if let Some(email) = &config.email {
+            milestones = milestones.with_email(EmailNotifier::new(email)?);
+        }
+        handles.push(tokio::spawn(notify::check_milestones_periodically(
+            milestones,
+            notify::MILESTONE_CHECK_INTERVAL,
+        )));
     }
 
     // Wait for ctrl-c while handling events
     tokio::signal::ctrl_c().await?;
     info!("Shutting down...");
+    shutdown.send_replace(true);
+    let finished = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        for poller in pollers {
+            let _ = poller.await;
+        }
+    });
+    if finished.await.is_err() {
+        warn!("Gave up waiting for listens in progress to be scrobbled");
+    }
+    if !scrobbler.drain(SHUTDOWN_TIMEOUT).await {
+        warn!("Gave up waiting for queued submissions to the scrobble backends");
+    }
+    if let Err(e) = db.flush_pending().await {
+        warn!("Failed to write buffered listens: {}", e);
+    }
     
     Ok(())
 }
+
+/// Asks on the terminal which of the discovered rooms to monitor
+fn pick_rooms(devices: &[SonosDevice]) -> Result<Vec<String>> {
+    if !std::io::stdin().is_terminal() {
+        bail!("--pick needs an interactive terminal");
+    }
+    let mut rooms: Vec<_> = devices.iter().map(|device| device.room.clone()).collect();
+    rooms.sort();
+    rooms.dedup();
+    Ok(picker::pick_rooms(&rooms, std::io::stdin().lock(), std::io::stderr())?)
+}
+
+/// The devices in `rooms`, or all of them when it is empty
+fn only_rooms(devices: Vec<SonosDevice>, rooms: &[String]) -> Vec<SonosDevice> {
+    if rooms.is_empty() {
+        return devices;
+    }
+    for room in rooms {
+        if !devices.iter().any(|device| device.room.eq_ignore_ascii_case(room)) {
+            warn!("Room {} was not discovered, not monitoring it", room);
+        }
+    }
+    devices.into_iter().filter(|device| rooms.iter().any(|room| room.eq_ignore_ascii_case(&device.room))).collect()
+}
+
+/// Backends for which credentials are configured in the environment or `.env`,
+/// plus the Telegram announcements when configured. Last.fm session checks
+/// are remembered in `db`.
+fn scrobbler(config: &Config, db: &TrackDatabase) -> Result<Scrobbler> {
+    if let Some(agent) = &config.agent {
+        info!("Forwarding listens to the hub at {}", agent.hub);
+        // The hub filters, splits and routes them, by room
+        let hub: Vec<Box<dyn ScrobbleBackend>> = vec![Box::new(HubLink::new(agent))];
+        return Ok(Scrobbler::new(hub)
+            .with_shared_room(true)
+            .with_delay(Duration::from_secs(config.scrobble_delay_secs))
+            .with_now_playing_interval(Duration::from_secs(config.now_playing_interval_secs))
+            .with_retry_policy(config.retry.scrobble)
+            .with_profiles(&config.profiles));
+    }
+    let mut backends: Vec<Box<dyn ScrobbleBackend>> = Vec::new();
+    if let Some(lastfm) = LastFm::from_env() {
+        backends.push(Box::new(lastfm?.with_database(db.clone())));
+    }
+    if let Some(listenbrainz) = ListenBrainz::from_env() {
+        backends.push(Box::new(listenbrainz?));
+    }
+    if let Some(telegram) = &config.telegram {
+        backends.push(Box::new(Telegram::new(telegram)?));
+    }
+    if let Some(subsonic) = &config.subsonic {
+        backends.push(Box::new(Subsonic::new(subsonic)?));
+    }
+    if let Some(plex) = &config.plex {
+        backends.push(Box::new(Plex::new(plex)?));
+    }
+    if let Some(funkwhale) = &config.funkwhale {
+        backends.push(Box::new(ListenBrainz::funkwhale(funkwhale)?));
+    }
+
+    let scrobbler = Scrobbler::new(backends)
+        .with_routes(&config.routes)
+        .with_shared_room(config.share_room)
+        .with_artist_separators(config.artist_separators.clone())
+        .with_min_confidence(config.min_confidence)
+        .with_delay(Duration::from_secs(config.scrobble_delay_secs))
+        .with_now_playing_interval(Duration::from_secs(config.now_playing_interval_secs))
+        .with_retry_policy(config.retry.scrobble)
+        .with_profiles(&config.profiles);
+    let mut party: Vec<Box<dyn ScrobbleBackend>> = Vec::new();
+    if let Some(lastfm) = LastFm::party_from_env() {
+        party.push(Box::new(lastfm?.with_database(db.clone())));
+    }
+    let mut scrobbler = scrobbler.with_party(party, config.party.auto);
+    for (name, household) in &config.households {
+        scrobbler = scrobbler.with_household_routes(name, &household.routes);
+    }
+    match scrobbler.backend_names().as_slice() {
+        [] => info!("No scrobbling credentials configured; listens are only logged locally"),
+        names => info!("Scrobbling to {}", names.join(", ")),
+    }
+    Ok(scrobbler)
+}
+
+async fn self_update() -> Result<()> {
+    let updater = Updater::new()?;
+    let Some(release) = updater.newer_release().await? else {
+        println!("Sonos Scrobbler {} is the latest release", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    };
+    let path = updater.install(&release).await?;
+    println!("Updated {} to {}; restart it to use the new version", path.display(), release.version);
+    Ok(())
+}
+
+fn service(cli: &Cli, command: &ServiceCommand) -> Result<()> {
+    let manager = ServiceManager::detect()?;
+    match command {
+        ServiceCommand::Install => {
+            let path = manager.install(&ServiceSpec::current(cli.config.as_deref())?)?;
+            println!("Installed and started {}", path.display());
+        }
+        ServiceCommand::Uninstall => {
+            let path = manager.uninstall()?;
+            println!("Stopped and removed {}", path.display());
+        }
+        ServiceCommand::Status => print!("{}", manager.status()?),
+    }
+    Ok(())
+}
+
+async fn discover(cli: &Cli, config: &mut Config, subnet: Option<String>) -> Result<()> {
+    let discovery = match subnet {
+        Some(cidr) => {
+            config.discovery.subnet = Some(cidr);
+            SonosDiscovery::refresh(&TrackDatabase::new().await?, &config.discovery).await?
+        }
+        None => discover_devices(cli, config).await?,
+    };
+    let devices = discovery.discover_devices().await?;
+    if devices.is_empty() {
+        if let Some(warning) = container_network_warning() {
+            warn!("{}", warning);
+        }
+    }
+    match cli.output {
+        OutputFormat::Text => devices.iter().for_each(|device| println!("{}", device)),
+        OutputFormat::Json => print_json(devices.iter().map(output::device).collect())?,
+    }
+    Ok(())
+}
+
+async fn now_playing(cli: &Cli, config: &Config, rooms: &[String]) -> Result<()> {
+    let discovery = discover_devices(cli, config).await?;
+    let mut speakers = Vec::new();
+    for device in discovery.discover_devices().await? {
+        if !rooms.is_empty() && !rooms.iter().any(|room| room.eq_ignore_ascii_case(&device.room)) {
+            continue;
+        }
+        let soap = SoapClient::new(&device.ip_addr.to_string())?.with_retry_policy(config.retry.device);
+        let playing = soap.get_position_info().await.map(|position| NowPlaying::from_position(&position));
+        match (cli.output, playing) {
+            (OutputFormat::Text, Ok(playing)) => println!("{}: {}", device.room, playing),
+            (OutputFormat::Text, Err(e)) => println!("{}: unavailable ({})", device.room, e),
+            (OutputFormat::Json, playing) => {
+                speakers.push(output::now_playing(&device, playing.as_ref().map_err(|e| e.to_string())))
+            }
+        }
+    }
+    if cli.output == OutputFormat::Json {
+        print_json(speakers.into())?;
+    }
+    Ok(())
+}
+
+/// Prints the cached rooms for shell completion, without creating a database
+/// where there is none
+async fn complete_rooms() -> Result<()> {
+    if !std::path::Path::new("tracks.db").exists() {
+        return Ok(());
+    }
+    for room in TrackDatabase::new().await?.known_rooms().await? {
+        println!("{}", room);
+    }
+    Ok(())
+}
+
+async fn stats(api: bool, format: OutputFormat) -> Result<()> {
+    let db = TrackDatabase::new().await?;
+    if !api {
+        let plays = db.plays_by_context().await?;
+        match format {
+            OutputFormat::Text => {
+                for (context, plays) in &plays {
+                    println!("{}: {} plays", context, plays);
+                }
+            }
+            OutputFormat::Json => {
+                print_json(plays.iter().map(|(context, plays)| output::plays(context, *plays)).collect())?
+            }
+        }
+        return Ok(());
+    }
+
+    let mut usage = Vec::new();
+    for (label, window) in [("24h", 24 * 60 * 60), ("30d", 30 * 24 * 60 * 60)] {
+        for (backend, calls, errors) in db.api_usage(Duration::from_secs(window)).await? {
+            if format == OutputFormat::Json {
+                usage.push(output::api_usage(&backend, label, calls, errors));
+                continue;
+            }
+            println!(
+                "{} (last {}): {} calls, {} errors ({:.1}%)",
+                backend,
+                label,
+                calls,
+                errors,
+                errors as f64 * 100.0 / calls.max(1) as f64
+            );
+        }
+    }
+    if format == OutputFormat::Json {
+        print_json(usage.into())?;
+    }
+    Ok(())
+}
+
+async fn reprocess(config: &Config, since: &str, dry_run: bool) -> Result<()> {
+    let day = chrono::NaiveDate::parse_from_str(since, "%Y-%m-%d")
+        .map_err(|_| anyhow!("invalid date {}, expected YYYY-MM-DD", since))?;
+    let since = sonos_scrobbler::stats::local_midnight(day);
+    let db = TrackDatabase::new().await?;
+    let scrobbles = reprocess::find_new_scrobbles(&db, since, config.scrobble_on, &config.threshold).await?;
+    for scrobble in &scrobbles {
+        println!("{}", reprocess::describe(scrobble));
+    }
+    if dry_run {
+        println!("{} listens would now be scrobbled, nothing submitted", scrobbles.len());
+        return Ok(());
+    }
+    let scrobbler = scrobbler(config, &db)?;
+    let submitted =
+        reprocess::submit_new_scrobbles(&db, &scrobbler, &scrobbles, since, config.scrobble_on, &config.threshold)
+            .await?;
+    db.flush_pending().await?;
+    println!("Scrobbled {} of {} listens that now qualify", submitted, scrobbles.len());
+    Ok(())
+}
+
+async fn digest(config: &Config, month: Option<&str>, send: bool) -> Result<()> {
+    let period = match month {
+        Some(month) => Period::parse(month)
+            .ok_or_else(|| anyhow::anyhow!("invalid month {}, expected YYYY-MM", month))?,
+        None => Period::previous_month(chrono::Local::now()),
+    };
+    let digest = Digest::build(&TrackDatabase::new().await?, period).await?;
+
+    if send {
+        let email = config
+            .email
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no [email] section in the config"))?;
+        let subject = format!("Sonos listening digest for {}", digest.period.name);
+        EmailNotifier::new(email)?.send(&subject, digest.render()).await?;
+    } else {
+        print!("{}", digest.render());
+    }
+    Ok(())
+}
+
+async fn profile(config: &Config, command: &ProfileCommand) -> Result<()> {
+    let db = TrackDatabase::new().await?;
+    let profiles = Profiles::new(config.profiles.clone());
+    match command {
+        ProfileCommand::Show => {
+            let active = db.active_profile().await?.unwrap_or_else(|| config.profile.clone());
+            if let Err(e) = profiles.set(active.as_deref()) {
+                warn!("{}", e);
+            }
+            println!("{}", profiles.describe());
+            match profiles.names().as_slice() {
+                [] => println!("No profiles configured"),
+                names => println!("Profiles: {}", names.join(", ")),
+            }
+        }
+        ProfileCommand::Set { name } => {
+            let name = profiles.set(Some(name))?;
+            db.set_active_profile(name.as_deref()).await?;
+            println!("{}", profiles.describe());
+        }
+        ProfileCommand::Clear => {
+            db.set_active_profile(None).await?;
+            println!("{}", profiles.describe());
+        }
+    }
+    Ok(())
+}
+
+async fn quarantine(config: &Config, command: &QuarantineCommand, format: OutputFormat) -> Result<()> {
+    let db = TrackDatabase::new().await?;
+    match command {
+        QuarantineCommand::List if format == OutputFormat::Json => {
+            print_json(db.quarantined().await?.iter().map(output::quarantined).collect())?;
+        }
+        QuarantineCommand::List => {
+            for listen in db.quarantined().await? {
+                let started = chrono::DateTime::from_timestamp(listen.started_at, 0)
+                    .map(|time| time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default();
+                println!(
+                    "{}\t{}\t{}\t{} ({})",
+                    listen.id,
+                    started,
+                    listen.device,
+                    listen.track_info,
+                    listen.reason()
+                );
+            }
+        }
+        QuarantineCommand::Retry => {
+            let scrobbler = scrobbler(config, &db)?.with_usage_log(db.clone());
+            let discogs = Discogs::from_env().transpose()?.map(|discogs| discogs.with_cache(&config.cache));
+            let released = scrobble::release_enriched(&db, &scrobbler, discogs.as_ref()).await?;
+            db.flush_pending().await?;
+            match format {
+                OutputFormat::Text => println!("Released {} quarantined listens", released),
+                OutputFormat::Json => print_json(serde_json::json!({ "released": released }))?,
+            }
+        }
+        QuarantineCommand::Release { id } => {
+            let scrobbler = scrobbler(config, &db)?.with_usage_log(db.clone());
+            let track = scrobble::release_quarantined(&db, &scrobbler, *id).await?;
+            db.flush_pending().await?;
+            match format {
+                OutputFormat::Text => println!("Released {}", track),
+                OutputFormat::Json => print_json(serde_json::json!({ "released": track }))?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints the JSON for `--output json`
+fn print_json(value: serde_json::Value) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(&value)?);
+    Ok(())
+}
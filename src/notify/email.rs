@@ -0,0 +1,165 @@
+use crate::config::EmailConfig;
+use crate::error::{Error, Result};
+use crate::sonos::TrackDatabase;
+use crate::stats::{Digest, NewMusic, Period};
+use chrono::Local;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{info, warn};
+use std::time::Duration;
+
+/// How often to check whether the monthly digest is due
+const DIGEST_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Port for SMTP over implicit TLS; every other port uses STARTTLS
+const SMTPS_PORT: u16 = 465;
+
+/// Sends plain text notifications through an SMTP server
+pub struct EmailNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl EmailNotifier {
+    pub fn new(config: &EmailConfig) -> Result<Self> {
+        let address = |value: &str| {
+            value
+                .parse::<Mailbox>()
+                .map_err(|e| Error::Config(format!("invalid email address {}: {}", value, e)))
+        };
+        if config.to.is_empty() {
+            return Err(Error::Config("email.to needs at least one recipient".to_string()));
+        }
+
+        let builder = if config.smtp_port == SMTPS_PORT {
+            AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_server)
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_server)
+        }
+        .map_err(|e| Error::Config(format!("email.smtp_server: {}", e)))?
+        .port(config.smtp_port);
+
+        let builder = match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                builder.credentials(Credentials::new(username.clone(), password.clone()))
+            }
+            _ => builder,
+        };
+
+        Ok(Self {
+            transport: builder.build(),
+            from: address(&config.from)?,
+            to: config.to.iter().map(|to| address(to)).collect::<Result<_>>()?,
+        })
+    }
+
+    pub async fn send(&self, subject: &str, body: String) -> Result<()> {
+        let mut message = Message::builder().from(self.from.clone()).subject(subject);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        let message = message.body(body).map_err(|e| Error::Notify(e.to_string()))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| Error::Notify(format!("sending email failed: {}", e)))?;
+        Ok(())
+    }
+
+    /// Emails the digest for the previous month unless it was already sent.
+    /// Months without scrobbles are marked as sent without an email.
+    pub async fn send_digest_if_due(&self, db: &TrackDatabase) -> Result<()> {
+        let period = Period::previous_month(Local::now());
+        if db.digest_sent(&period.name).await? {
+            return Ok(());
+        }
+
+        let digest = Digest::build(db, period).await?;
+        if !digest.is_empty() {
+            let subject = format!("Sonos listening digest for {}", digest.period.name);
+            self.send(&subject, digest.render()).await?;
+            info!("Sent listening digest for {}", digest.period.name);
+        }
+        db.mark_digest_sent(&digest.period.name).await
+    }
+
+    /// Emails the artists and tracks first heard the previous week unless
+    /// that was already sent; weeks without any are marked without an email
+    pub async fn send_new_music_if_due(&self, db: &TrackDatabase) -> Result<()> {
+        let period = Period::previous_week(Local::now());
+        let key = format!("new-music-{}", period.name);
+        if db.digest_sent(&key).await? {
+            return Ok(());
+        }
+
+        let new_music = NewMusic::build(db, &period).await?;
+        if !new_music.is_empty() {
+            self.send(&format!("New music in {}", period.name), new_music.render()).await?;
+            info!("Sent the new music of {}", period.name);
+        }
+        db.mark_digest_sent(&key).await
+    }
+}
+
+/// Sends each monthly digest once the month is over, retrying on the next
+/// check when sending fails
+pub async fn send_monthly_digests(db: TrackDatabase, notifier: EmailNotifier) {
+    let mut ticker = tokio::time::interval(DIGEST_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = notifier.send_digest_if_due(&db).await {
+            warn!("Monthly digest: {}", e);
+        }
+    }
+}
+
+/// Sends the new music of each week once it is over, retrying on the next
+/// check when sending fails
+pub async fn send_weekly_new_music(db: TrackDatabase, notifier: EmailNotifier) {
+    let mut ticker = tokio::time::interval(DIGEST_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = notifier.send_new_music_if_due(&db).await {
+            warn!("Weekly new music: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> EmailConfig {
+        EmailConfig {
+            smtp_server: "smtp.example.com".to_string(),
+            smtp_port: 587,
+            username: None,
+            password: None,
+            from: "Sonos Scrobbler <scrobbler@example.com>".to_string(),
+            to: vec!["listener@example.com".to_string()],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_month_is_marked_without_sending() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        // Nothing listens on the server, so sending would fail
+        let notifier = EmailNotifier::new(&EmailConfig { smtp_server: "127.0.0.1".to_string(), smtp_port: 1, ..config() }).unwrap();
+
+        notifier.send_digest_if_due(&db).await.unwrap();
+        let period = Period::previous_month(Local::now());
+        assert!(db.digest_sent(&period.name).await.unwrap());
+
+        notifier.send_new_music_if_due(&db).await.unwrap();
+        let week = Period::previous_week(Local::now());
+        assert!(db.digest_sent(&format!("new-music-{}", week.name)).await.unwrap());
+    }
+
+    #[test]
+    fn test_rejects_invalid_address() {
+        let result = EmailNotifier::new(&EmailConfig { from: "not an address".to_string(), ..config() });
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+}
@@ -0,0 +1,95 @@
+use crate::config::ScrobbleGuardConfig;
+use crate::error::Result;
+use crate::notify::{EmailNotifier, Telegram};
+use crate::sonos::TrackDatabase;
+use crate::status::Status;
+use chrono::Utc;
+use log::warn;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often rooms are checked for missing scrobbles
+pub const GUARD_CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Days looked back on to tell whether a room usually scrobbles every day
+const HABIT_DAYS: i64 = 7;
+
+/// Warns when a room that scrobbles every day keeps playing without
+/// scrobbling, an early sign that scrobbling broke without any errors
+pub struct ScrobbleGuard {
+    db: TrackDatabase,
+    config: ScrobbleGuardConfig,
+    status: Arc<Status>,
+    telegram: Option<Telegram>,
+    email: Option<EmailNotifier>,
+    /// Devices alerted about, so each silence is only reported once
+    alerted: BTreeSet<String>,
+}
+
+impl ScrobbleGuard {
+    pub fn new(db: TrackDatabase, config: ScrobbleGuardConfig, status: Arc<Status>) -> Self {
+        Self {
+            db,
+            config,
+            status,
+            telegram: None,
+            email: None,
+            alerted: BTreeSet::new(),
+        }
+    }
+
+    pub fn with_telegram(mut self, telegram: Telegram) -> Self {
+        self.telegram = Some(telegram);
+        self
+    }
+
+    pub fn with_email(mut self, email: EmailNotifier) -> Self {
+        self.email = Some(email);
+        self
+    }
+
+    /// Checks every device and alerts about the ones that went silent since
+    /// the last check. Returns those devices.
+    pub async fn check(&mut self) -> Result<Vec<String>> {
+        let since = Utc::now().timestamp() - self.config.hours as i64 * 60 * 60;
+        let habit_start = since - HABIT_DAYS * 24 * 60 * 60;
+        let silent = self.db.silent_devices(since, habit_start, self.config.usual_days).await?;
+        self.status.set_silent_devices(&silent);
+
+        let new: Vec<String> = silent.iter().filter(|device| !self.alerted.contains(*device)).cloned().collect();
+        self.alerted = silent.into_iter().collect();
+        for device in &new {
+            self.alert(device).await;
+        }
+        Ok(new)
+    }
+
+    async fn alert(&self, device: &str) {
+        let text = format!(
+            "{} has been playing without a scrobble for {} hours, although it usually scrobbles every day",
+            device, self.config.hours
+        );
+        warn!("{}", text);
+        if let Some(telegram) = &self.telegram {
+            if let Err(e) = telegram.send_message(&text).await {
+                warn!("Telegram: {}", e);
+            }
+        }
+        if let Some(email) = &self.email {
+            if let Err(e) = email.send(&format!("No scrobbles from {}", device), text.clone()).await {
+                warn!("Scrobble guard: {}", e);
+            }
+        }
+    }
+}
+
+/// Checks for silent rooms every `interval` until the task is dropped
+pub async fn guard_periodically(mut guard: ScrobbleGuard, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = guard.check().await {
+            warn!("Scrobble guard: {}", e);
+        }
+    }
+}
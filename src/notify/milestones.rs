@@ -0,0 +1,180 @@
+use crate::config::MilestonesConfig;
+use crate::error::Result;
+use crate::notify::{EmailNotifier, Telegram};
+use crate::sonos::TrackDatabase;
+use crate::stats::listening_streak;
+use chrono::Local;
+use log::{info, warn};
+use std::fmt;
+use std::time::Duration;
+
+/// How often scrobbles are checked for milestones
+pub const MILESTONE_CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Recorded by the first check, which announces nothing
+const FIRST_CHECK: &str = "first_check";
+
+/// A round number passed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Milestone {
+    /// Scrobbles in total
+    Scrobbles(i64),
+    /// Plays of one track, `Artist - Title`
+    TrackPlays { track: String, plays: i64 },
+    /// Days in a row with scrobbles, since the `YYYY-MM-DD` day the streak
+    /// started, so every new streak is announced again
+    Streak { days: i64, since: String },
+}
+
+impl Milestone {
+    /// Identifies the milestone, so it is announced once
+    pub fn key(&self) -> String {
+        match self {
+            Milestone::Scrobbles(count) => format!("scrobbles:{}", count),
+            Milestone::TrackPlays { track, plays } => format!("track:{}:{}", track.to_lowercase(), plays),
+            Milestone::Streak { days, since } => format!("streak:{}:{}", since, days),
+        }
+    }
+}
+
+impl fmt::Display for Milestone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Milestone::Scrobbles(count) => write!(f, "Scrobble number {}", count),
+            Milestone::TrackPlays { track, plays } => write!(f, "{} played for the {}th time", track, plays),
+            Milestone::Streak { days, .. } => write!(f, "{} days in a row with scrobbles", days),
+        }
+    }
+}
+
+/// The latest multiple of `every` that `value` reached, if any
+fn passed(value: i64, every: i64) -> Option<i64> {
+    (every > 0 && value >= every).then(|| value / every * every)
+}
+
+/// Announces milestones over Telegram and email as scrobbles pass them
+pub struct Milestones {
+    db: TrackDatabase,
+    config: MilestonesConfig,
+    telegram: Option<Telegram>,
+    email: Option<EmailNotifier>,
+}
+
+impl Milestones {
+    pub fn new(db: TrackDatabase, config: MilestonesConfig) -> Self {
+        Self { db, config, telegram: None, email: None }
+    }
+
+    pub fn with_telegram(mut self, telegram: Telegram) -> Self {
+        self.telegram = Some(telegram);
+        self
+    }
+
+    pub fn with_email(mut self, email: EmailNotifier) -> Self {
+        self.email = Some(email);
+        self
+    }
+
+    /// The latest milestone of each kind that the scrobbles passed
+    async fn passed(&self) -> Result<Vec<Milestone>> {
+        let mut milestones = Vec::new();
+        if let Some(count) = passed(self.db.scrobble_count().await?, self.config.scrobbles) {
+            milestones.push(Milestone::Scrobbles(count));
+        }
+        if self.config.track_plays > 0 {
+            for (track, plays) in self.db.track_plays(self.config.track_plays).await? {
+                if let Some(plays) = passed(plays, self.config.track_plays) {
+                    milestones.push(Milestone::TrackPlays { track, plays });
+                }
+            }
+        }
+        let today = Local::now().date_naive();
+        let scrobble_days = self.db.scrobble_days().await?;
+        let streak = listening_streak(&scrobble_days, today);
+        if let Some(days) = passed(streak, self.config.streak_days) {
+            // Latest first, so the streak's first day is the last it counted
+            let today = today.format("%Y-%m-%d").to_string();
+            let since = scrobble_days.into_iter().filter(|day| *day <= today).nth(streak as usize - 1);
+            milestones.push(Milestone::Streak { days, since: since.unwrap_or_default() });
+        }
+        Ok(milestones)
+    }
+
+    /// Announces the milestones passed since the last check. Returns them.
+    ///
+    /// The first check only records the milestones passed before, so
+    /// turning this on with years of history doesn't announce them all.
+    pub async fn check(&self) -> Result<Vec<Milestone>> {
+        let first = !self.db.any_milestones().await?;
+        if first {
+            self.db.record_milestone(FIRST_CHECK).await?;
+        }
+        let mut new = Vec::new();
+        for milestone in self.passed().await? {
+            if self.db.record_milestone(&milestone.key()).await? && !first {
+                self.announce(&milestone).await;
+                new.push(milestone);
+            }
+        }
+        Ok(new)
+    }
+
+    async fn announce(&self, milestone: &Milestone) {
+        let text = milestone.to_string();
+        info!("Milestone: {}", text);
+        if let Some(telegram) = &self.telegram {
+            if let Err(e) = telegram.send_message(&text).await {
+                warn!("Telegram: {}", e);
+            }
+        }
+        if let Some(email) = &self.email {
+            if let Err(e) = email.send("Listening milestone", text.clone()).await {
+                warn!("Milestones: {}", e);
+            }
+        }
+    }
+}
+
+/// Checks for milestones every `interval` until the task is dropped
+pub async fn check_milestones_periodically(milestones: Milestones, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = milestones.check().await {
+            warn!("Milestones: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrobble::Scrobble;
+
+    async fn scrobble(db: &TrackDatabase, title: &str, started_at: i64) {
+        let scrobble = Scrobble::new("Kitchen", "Daft Punk", title, started_at);
+        db.record_scrobble(&scrobble).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_milestones_are_announced_once() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let config = MilestonesConfig { scrobbles: 4, track_plays: 2, streak_days: 0 };
+        let milestones = Milestones::new(db.clone(), config);
+        scrobble(&db, "Get Lucky", 1_700_000_000).await;
+        scrobble(&db, "Get Lucky", 1_700_000_600).await;
+        // Passed before the first check, so only recorded
+        assert!(milestones.check().await.unwrap().is_empty());
+
+        scrobble(&db, "Around the World", 1_700_001_200).await;
+        assert!(milestones.check().await.unwrap().is_empty());
+        scrobble(&db, "Around the World", 1_700_001_800).await;
+        let reached = milestones.check().await.unwrap();
+        let track = "Daft Punk - Around the World".to_string();
+        assert_eq!(reached, vec![Milestone::Scrobbles(4), Milestone::TrackPlays { track, plays: 2 }]);
+        assert!(milestones.check().await.unwrap().is_empty());
+        assert_eq!(reached[0].to_string(), "Scrobble number 4");
+
+        let streak = Milestone::Streak { days: 30, since: "2026-03-01".to_string() };
+        assert_eq!(streak.key(), "streak:2026-03-01:30");
+    }
+}
@@ -0,0 +1,9 @@
+mod email;
+mod guard;
+mod milestones;
+mod telegram;
+
+pub use email::{send_monthly_digests, send_weekly_new_music, EmailNotifier};
+pub use guard::{guard_periodically, ScrobbleGuard, GUARD_CHECK_INTERVAL};
+pub use milestones::{check_milestones_periodically, Milestone, Milestones, MILESTONE_CHECK_INTERVAL};
+pub use telegram::Telegram;
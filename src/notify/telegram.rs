@@ -0,0 +1,198 @@
+use crate::config::TelegramConfig;
+use crate::control::Controller;
+use crate::error::{Error, Result};
+use crate::scrobble::{Scrobble, ScrobbleBackend};
+use async_trait::async_trait;
+use log::{info, warn};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+
+const API_URL: &str = "https://api.telegram.org";
+/// Seconds Telegram holds a `getUpdates` request open while waiting for messages
+const LONG_POLL_SECS: u64 = 30;
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Telegram bot that announces scrobbles to a chat and takes commands from it.
+///
+/// Only messages from the configured chat are acted on, so strangers who find
+/// the bot cannot control the speakers.
+pub struct Telegram {
+    api_url: String,
+    chat_id: i64,
+    client: reqwest::Client,
+}
+
+impl Telegram {
+    pub fn new(config: &TelegramConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(LONG_POLL_SECS + 10))
+            .build()
+            .map_err(|e| Error::Notify(e.to_string()))?;
+
+        Ok(Self {
+            api_url: format!("{}/bot{}", API_URL, config.bot_token),
+            chat_id: config.chat_id,
+            client,
+        })
+    }
+
+    pub async fn send_message(&self, text: &str) -> Result<()> {
+        let chat_id = self.chat_id.to_string();
+        self.call("sendMessage", &[("chat_id", chat_id.as_str()), ("text", text)]).await?;
+        Ok(())
+    }
+
+    /// Answers commands until the task is dropped
+    pub async fn handle_commands(&self, controller: Arc<Controller>) {
+        let mut offset = 0;
+        loop {
+            let updates = match self.updates(offset).await {
+                Ok(updates) => updates,
+                Err(e) => {
+                    warn!("Telegram: {}", e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            for update in updates {
+                offset = offset.max(update["update_id"].as_i64().unwrap_or_default() + 1);
+                let message = &update["message"];
+                if message["chat"]["id"].as_i64() != Some(self.chat_id) {
+                    continue;
+                }
+                let Some((command, argument)) = message["text"].as_str().and_then(parse_command) else {
+                    continue;
+                };
+
+                info!("Telegram command: /{}", command);
+                let reply = run_command(&controller, &command, argument.as_deref()).await;
+                if let Err(e) = self.send_message(&reply).await {
+                    warn!("Telegram: {}", e);
+                }
+            }
+        }
+    }
+
+    async fn updates(&self, offset: i64) -> Result<Vec<Value>> {
+        let offset = offset.to_string();
+        let timeout = LONG_POLL_SECS.to_string();
+        let response = self
+            .call("getUpdates", &[("offset", offset.as_str()), ("timeout", timeout.as_str())])
+            .await?;
+        Ok(response["result"].as_array().cloned().unwrap_or_default())
+    }
+
+    async fn call(&self, method: &str, params: &[(&str, &str)]) -> Result<Value> {
+        let response = self
+            .client
+            .post(format!("{}/{}", self.api_url, method))
+            .form(params)
+            .send()
+            .await
+            .map_err(|e| Error::Notify(format!("{}: {}", method, e.without_url())))?;
+        let body = response
+            .text()
+            .await
+            .map_err(|e| Error::Notify(format!("{}: {}", method, e.without_url())))?;
+
+        let value: Value = serde_json::from_str(&body)
+            .map_err(|e| Error::Notify(format!("{}: invalid response: {}", method, e)))?;
+        if value["ok"] != Value::Bool(true) {
+            let description = value["description"].as_str().unwrap_or("unknown error");
+            return Err(Error::Notify(format!("{}: {}", method, description)));
+        }
+        Ok(value)
+    }
+}
+
+#[async_trait]
+impl ScrobbleBackend for Telegram {
+    fn name(&self) -> &str {
+        "telegram"
+    }
+
+    async fn now_playing(&self, _scrobble: &Scrobble) -> Result<()> {
+        Ok(())
+    }
+
+    async fn scrobble(&self, scrobble: &Scrobble) -> Result<()> {
+        self.send_message(&format!("Scrobbled {} - {}", scrobble.artist, scrobble.title)).await
+    }
+}
+
+async fn run_command(controller: &Controller, command: &str, room: Option<&str>) -> String {
+    match command {
+        "nowplaying" => controller.now_playing().await.join("\n"),
+        "pause" => match controller.pause(room).await {
+            Ok(rooms) => format!("Paused {}", rooms.join(", ")),
+            Err(e) => e.to_string(),
+        },
+        "love" => match controller.love(room).await {
+            Ok(track) => format!("Loved {}", track),
+            Err(e) => e.to_string(),
+        },
+        "dontscrobble" => match controller.dont_scrobble(room) {
+            Ok(track) => format!("Won't scrobble {}", track),
+            Err(e) => e.to_string(),
+        },
+        "party" => controller.party(room).unwrap_or_else(|e| e.to_string()),
+        "awake" => controller.awake(room).unwrap_or_else(|e| e.to_string()),
+        "profile" => controller.profile(room).await.unwrap_or_else(|e| e.to_string()),
+        "backend" => {
+            let mut arguments = room.unwrap_or_default().split_whitespace();
+            controller.backend(arguments.next(), arguments.next()).await.unwrap_or_else(|e| e.to_string())
+        }
+        _ => "Commands: /nowplaying, /pause [room], /love [room], /dontscrobble [room], /party [on|off|auto], \
+              /backend [name] [on|off], /awake [room], /profile [name|none]"
+            .to_string(),
+    }
+}
+
+/// Splits `/command@botname argument` into the command and its argument
+fn parse_command(text: &str) -> Option<(String, Option<String>)> {
+    let text = text.trim().strip_prefix('/')?;
+    let (command, argument) = match text.split_once(char::is_whitespace) {
+        Some((command, argument)) => (command, Some(argument.trim().to_string())),
+        None => (text, None),
+    };
+    let command = command.split('@').next().unwrap_or_default().to_lowercase();
+    Some((command, argument.filter(|argument| !argument.is_empty())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("/nowplaying"), Some(("nowplaying".to_string(), None)));
+        assert_eq!(
+            parse_command("/pause@sonos_bot Living Room"),
+            Some(("pause".to_string(), Some("Living Room".to_string())))
+        );
+        assert_eq!(parse_command("hello"), None);
+    }
+
+    #[tokio::test]
+    async fn test_announce_scrobble() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/bottoken/sendMessage")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("chat_id".into(), "42".into()),
+                mockito::Matcher::UrlEncoded("text".into(), "Scrobbled Daft Punk - Get Lucky".into()),
+            ]))
+            .with_body(r#"{"ok":true,"result":{}}"#)
+            .create_async()
+            .await;
+
+        let config = TelegramConfig { bot_token: "token".to_string(), chat_id: 42 };
+        let telegram = Telegram { api_url: format!("{}/bottoken", server.url()), ..Telegram::new(&config).unwrap() };
+        let scrobble = Scrobble::new("Kitchen", "Daft Punk", "Get Lucky", 0);
+
+        telegram.scrobble(&scrobble).await.unwrap();
+        mock.assert_async().await;
+    }
+}
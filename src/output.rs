@@ -0,0 +1,151 @@
+use crate::scrobble::{MetadataSource, QuarantinedListen};
+use crate::sonos::{NowPlaying, SonosDevice};
+use serde_json::{json, Value};
+use std::fmt;
+
+/// How the CLI prints what a subcommand found
+///
+/// The JSON shapes built here are a stable interface for scripts: fields are
+/// only ever added, never renamed or removed. Durations are whole seconds and
+/// times are Unix timestamps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One pretty-printed JSON document
+    Json,
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+        })
+    }
+}
+
+/// A speaker, as listed by `discover`
+pub fn device(device: &SonosDevice) -> Value {
+    json!({
+        "room": device.room,
+        "ip": device.ip_addr.to_string(),
+        "model": device.model,
+        "id": device.id,
+        "friendly_name": device.friendly_name,
+    })
+}
+
+/// A speaker and what it is playing, as listed by `now-playing`; `playing`
+/// is null and `error` set when the speaker couldn't be asked
+pub fn now_playing(device: &SonosDevice, playing: std::result::Result<&NowPlaying, String>) -> Value {
+    let (playing, error) = match playing {
+        Ok(playing) => (
+            json!({
+                "uri": playing.uri,
+                "artist": playing.artist,
+                "title": playing.title,
+                "album": playing.album,
+                "duration_secs": playing.duration.map(|d| d.as_secs()),
+                "position_secs": playing.position.map(|d| d.as_secs()),
+                "source": source_name(playing.source),
+                "stream": playing.stream,
+            }),
+            None,
+        ),
+        Err(e) => (Value::Null, Some(e)),
+    };
+    json!({ "room": device.room, "id": device.id, "playing": playing, "error": error })
+}
+
+/// Plays per context, as listed by `stats`
+pub fn plays(context: &str, plays: i64) -> Value {
+    json!({ "context": context, "plays": plays })
+}
+
+/// A backend's API calls over a window, as listed by `stats --api`
+pub fn api_usage(backend: &str, window: &str, calls: i64, errors: i64) -> Value {
+    json!({
+        "backend": backend,
+        "window": window,
+        "calls": calls,
+        "errors": errors,
+        "error_rate": errors as f64 / calls.max(1) as f64,
+    })
+}
+
+/// A quarantined listen, as listed by `queue quarantine list`
+pub fn quarantined(listen: &QuarantinedListen) -> Value {
+    json!({
+        "id": listen.id,
+        "device": listen.device,
+        "uri": listen.uri,
+        "track_info": listen.track_info,
+        "artist": listen.artist,
+        "title": listen.title,
+        "album": listen.album,
+        "started_at": listen.started_at,
+        "duration_secs": listen.duration.map(|d| d.as_secs()),
+        "reason": listen.reason(),
+    })
+}
+
+fn source_name(source: MetadataSource) -> &'static str {
+    match source {
+        MetadataSource::FileTags => "file_tags",
+        MetadataSource::Didl => "didl",
+        MetadataSource::StreamFields => "stream_fields",
+        MetadataSource::StreamTitle => "stream_title",
+        MetadataSource::Enrichment => "enrichment",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_now_playing_schema() {
+        let device = SonosDevice {
+            ip_addr: "192.168.1.20".parse().unwrap(),
+            room: "Kitchen".to_string(),
+            model: "Sonos One".to_string(),
+            id: "RINCON_000E58123456".to_string(),
+            friendly_name: "192.168.1.20 - Sonos One - RINCON_000E58123456".to_string(),
+        };
+        let playing = NowPlaying {
+            uri: "x-sonos-spotify:track".to_string(),
+            artist: Some("Daft Punk".to_string()),
+            title: Some("Get Lucky".to_string()),
+            album: None,
+            duration: Some(Duration::from_millis(248_500)),
+            position: Some(Duration::from_secs(12)),
+            source: MetadataSource::Didl,
+            stream: false,
+        };
+
+        assert_eq!(
+            now_playing(&device, Ok(&playing)),
+            json!({
+                "room": "Kitchen",
+                "id": "RINCON_000E58123456",
+                "playing": {
+                    "uri": "x-sonos-spotify:track",
+                    "artist": "Daft Punk",
+                    "title": "Get Lucky",
+                    "album": null,
+                    "duration_secs": 248,
+                    "position_secs": 12,
+                    "source": "didl",
+                    "stream": false,
+                },
+                "error": null,
+            })
+        );
+        let unavailable = now_playing(&device, Err("timed out".to_string()));
+        assert_eq!(unavailable["playing"], Value::Null);
+        assert_eq!(unavailable["error"], "timed out");
+    }
+}
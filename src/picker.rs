@@ -0,0 +1,61 @@
+use std::io::{self, BufRead, Write};
+
+/// Lists `rooms` numbered on `output` and asks which to monitor, until the
+/// answer read from `input` is a list of their numbers or "all". An empty
+/// answer picks every room.
+pub fn pick_rooms(rooms: &[String], mut input: impl BufRead, mut output: impl Write) -> io::Result<Vec<String>> {
+    writeln!(output, "Rooms:")?;
+    for (i, room) in rooms.iter().enumerate() {
+        writeln!(output, "  {}) {}", i + 1, room)?;
+    }
+    loop {
+        write!(output, "Monitor which rooms? Numbers separated by commas or spaces [all]: ")?;
+        output.flush()?;
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no rooms picked"));
+        }
+        match selection(answer.trim(), rooms.len()) {
+            Some(picked) => return Ok(picked.into_iter().map(|i| rooms[i].clone()).collect()),
+            None => writeln!(output, "Expected numbers from 1 to {}, or \"all\"", rooms.len())?,
+        }
+    }
+}
+
+/// Indexes of the rooms picked by `answer`, in listed order; `None` when it
+/// doesn't name `count` rooms' numbers
+fn selection(answer: &str, count: usize) -> Option<Vec<usize>> {
+    if answer.is_empty() || answer.eq_ignore_ascii_case("all") {
+        return Some((0..count).collect());
+    }
+    let mut picked = Vec::new();
+    for number in answer.split(|c: char| c == ',' || c.is_whitespace()).filter(|number| !number.is_empty()) {
+        match number.parse::<usize>() {
+            Ok(number @ 1..) if number <= count => picked.push(number - 1),
+            _ => return None,
+        }
+    }
+    picked.sort_unstable();
+    picked.dedup();
+    Some(picked).filter(|picked| !picked.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_rooms_asks_again_until_valid() {
+        let rooms = vec!["Kitchen".to_string(), "Living Room".to_string(), "Office".to_string()];
+        let mut output = Vec::new();
+
+        let picked = pick_rooms(&rooms, "4\n3, 1 3\n".as_bytes(), &mut output).unwrap();
+        assert_eq!(picked, vec!["Kitchen".to_string(), "Office".to_string()]);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("Rooms:\n  1) Kitchen\n  2) Living Room\n  3) Office\n"));
+        assert!(output.contains("Expected numbers from 1 to 3"));
+
+        assert_eq!(pick_rooms(&rooms, "\n".as_bytes(), io::sink()).unwrap(), rooms);
+        assert!(pick_rooms(&rooms, "".as_bytes(), io::sink()).is_err());
+    }
+}
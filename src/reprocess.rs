@@ -0,0 +1,120 @@
+use crate::config::{ScrobbleOn, ThresholdConfig};
+use crate::error::Result;
+use crate::scrobble::{Scrobble, Scrobbler};
+use crate::sonos::{derive, rebuild, ScrobbleCommand, Stage, TrackDatabase};
+use chrono::{Local, TimeZone};
+use log::debug;
+
+/// Listens played since `since`, a Unix timestamp, that the current rules
+/// scrobble but that weren't scrobbled. Their sessions and decisions are
+/// derived again from the logged playback events with `scrobble_on` and
+/// `threshold`, so fixes and rule changes apply to them.
+pub async fn find_new_scrobbles(
+    db: &TrackDatabase,
+    since: i64,
+    scrobble_on: ScrobbleOn,
+    threshold: &ThresholdConfig,
+) -> Result<Vec<Scrobble>> {
+    let derived = derive(db, Stage::Session, since * 1000, scrobble_on, threshold.clone()).await?;
+    let mut scrobbles = Vec::new();
+    for scrobble in derived.commands.iter().filter_map(ScrobbleCommand::to_scrobble) {
+        if !db.scrobble_recorded(&scrobble).await? {
+            scrobbles.push(scrobble);
+        }
+    }
+    Ok(scrobbles)
+}
+
+/// Submits `scrobbles` as found by [`find_new_scrobbles`] and logs the
+/// sessions and decisions derived again in place of the old ones. Returns
+/// how many were submitted; like live listens, those the scrobbler trusts
+/// too little or that are blocklisted are left out.
+pub async fn submit_new_scrobbles(
+    db: &TrackDatabase,
+    scrobbler: &Scrobbler,
+    scrobbles: &[Scrobble],
+    since: i64,
+    scrobble_on: ScrobbleOn,
+    threshold: &ThresholdConfig,
+) -> Result<usize> {
+    rebuild(db, Stage::Session, since * 1000, scrobble_on, threshold.clone()).await?;
+    let mut submitted = 0;
+    for scrobble in scrobbles {
+        if !scrobbler.confident(scrobble) {
+            debug!("Not submitting {} - {}, not confident enough", scrobble.artist, scrobble.title);
+            continue;
+        }
+        if scrobbler.submit(db, scrobble).await? {
+            submitted += 1;
+        }
+    }
+    Ok(submitted)
+}
+
+/// `scrobble` as listed by `reprocess`, e.g. "Daft Punk - Get Lucky on
+/// Kitchen at 2024-05-01 20:15:00"
+pub fn describe(scrobble: &Scrobble) -> String {
+    let time = Local
+        .timestamp_opt(scrobble.started_at, 0)
+        .single()
+        .map_or_else(|| scrobble.started_at.to_string(), |time| time.format("%Y-%m-%d %H:%M:%S").to_string());
+    format!("{} - {} on {} at {}", scrobble.artist, scrobble.title, scrobble.device, time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sonos::{EventLog, PolledPosition, PositionInfo, RawEvent};
+
+    fn poll(at_secs: i64, uri: &str, title: &str, position: &str) -> RawEvent {
+        RawEvent::Poll(PolledPosition {
+            received_at_ms: at_secs * 1000,
+            device: "Kitchen".to_string(),
+            position: PositionInfo {
+                track_uri: uri.to_string(),
+                duration: "0:03:00".to_string(),
+                position: position.to_string(),
+                title: Some(title.to_string()),
+                artist: Some("Daft Punk".to_string()),
+                ..PositionInfo::default()
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_lowered_threshold_finds_new_scrobbles() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let log = EventLog::new(db.clone(), ScrobbleOn::Threshold, ThresholdConfig::default());
+        log.record(poll(1_700_000_000, "x-sonos-spotify:one", "Get Lucky", "0:00:00")).await;
+        log.record(poll(1_700_000_070, "x-sonos-spotify:one", "Get Lucky", "0:01:10")).await;
+        log.record(poll(1_700_000_080, "x-sonos-spotify:two", "Lose Yourself to Dance", "0:00:00")).await;
+
+        let default = ThresholdConfig::default();
+        assert!(find_new_scrobbles(&db, 1_700_000_000, ScrobbleOn::Threshold, &default).await.unwrap().is_empty());
+        let lowered = ThresholdConfig { max_secs: 60, ..ThresholdConfig::default() };
+        let scrobbles = find_new_scrobbles(&db, 1_700_000_000, ScrobbleOn::Threshold, &lowered).await.unwrap();
+        assert_eq!(scrobbles.len(), 1);
+        assert_eq!((scrobbles[0].title.as_str(), scrobbles[0].started_at), ("Get Lucky", 1_700_000_000));
+
+        let scrobbler = Scrobbler::default();
+        let since = 1_700_000_000;
+        let submitted = submit_new_scrobbles(&db, &scrobbler, &scrobbles, since, ScrobbleOn::Threshold, &lowered);
+        assert_eq!(submitted.await.unwrap(), 1);
+        // Recorded now, so not found again
+        assert!(find_new_scrobbles(&db, 1_700_000_000, ScrobbleOn::Threshold, &lowered).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_live_scrobble_not_found_again() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let log = EventLog::new(db.clone(), ScrobbleOn::Threshold, ThresholdConfig::default());
+        log.record(poll(1_700_000_000, "x-sonos-spotify:one", "Get Lucky", "0:00:00")).await;
+        log.record(poll(1_700_000_100, "x-sonos-spotify:one", "Get Lucky", "0:01:40")).await;
+        // The poller timed the listen a second later than the logged event
+        db.record_scrobble(&Scrobble::new("Kitchen", "Daft Punk", "Get Lucky", 1_700_000_001)).await.unwrap();
+
+        let lowered = ThresholdConfig { max_secs: 60, ..ThresholdConfig::default() };
+        let scrobbles = find_new_scrobbles(&db, 1_700_000_000, ScrobbleOn::Threshold, &lowered).await.unwrap();
+        assert!(scrobbles.is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+use serde::Deserialize;
+use std::future::Future;
+use std::time::Duration;
+
+/// How the delay grows from one retry to the next
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backoff {
+    Constant,
+    /// `delay`, `2 × delay`, `3 × delay`, ...
+    #[default]
+    Linear,
+    /// `delay`, `2 × delay`, `4 × delay`, ...
+    Exponential,
+}
+
+/// How often and how patiently an operation that failed is tried again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 gives up right away
+    pub max_retries: u32,
+    /// Milliseconds before the first retry
+    pub delay_ms: u64,
+    pub backoff: Backoff,
+    /// Longest wait between two attempts, in milliseconds
+    pub max_delay_ms: u64,
+}
+
+impl RetryPolicy {
+    /// SOAP calls to speakers, which reset connections now and then
+    pub const DEVICE: Self = Self { max_retries: 3, delay_ms: 500, backoff: Backoff::Linear, max_delay_ms: 5_000 };
+    /// Renewing an event subscription before falling back to polling
+    pub const SUBSCRIPTION: Self =
+        Self { max_retries: 1, delay_ms: 1_000, backoff: Backoff::Linear, max_delay_ms: 5_000 };
+    /// Submitting to a scrobble backend; failed scrobbles are recorded
+    pub const SCROBBLE: Self =
+        Self { max_retries: 0, delay_ms: 2_000, backoff: Backoff::Exponential, max_delay_ms: 30_000 };
+    /// Writes while SQLite reports the database busy or locked
+    pub const DATABASE: Self = Self { max_retries: 5, delay_ms: 100, backoff: Backoff::Linear, max_delay_ms: 1_000 };
+
+    /// Wait before retry number `attempt`, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = match self.backoff {
+            Backoff::Constant => 1,
+            Backoff::Linear => u64::from(attempt),
+            Backoff::Exponential => 1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::MAX),
+        };
+        Duration::from_millis(self.delay_ms.saturating_mul(factor).min(self.max_delay_ms))
+    }
+
+    /// Runs `op` until it succeeds, fails in a way `retryable` turns down, or
+    /// is out of retries. `retryable` gets the number of the retry it would
+    /// allow, for logging.
+    pub async fn run<T, E, F, Fut>(&self, mut op: F, mut retryable: impl FnMut(&E, u32) -> bool) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match op().await {
+                Err(e) if attempt < self.max_retries && retryable(&e, attempt + 1) => {
+                    attempt += 1;
+                    tokio::time::sleep(self.delay(attempt)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// No retries
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_retries: 0, ..Self::DEVICE }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_backs_off() {
+        let linear = RetryPolicy::DEVICE;
+        assert_eq!(linear.delay(1), Duration::from_millis(500));
+        assert_eq!(linear.delay(3), Duration::from_millis(1_500));
+        assert_eq!(linear.delay(20), Duration::from_millis(5_000));
+
+        let exponential = RetryPolicy { backoff: Backoff::Exponential, ..linear };
+        assert_eq!(exponential.delay(1), Duration::from_millis(500));
+        assert_eq!(exponential.delay(3), Duration::from_millis(2_000));
+        assert_eq!(exponential.delay(100), Duration::from_millis(5_000));
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_at_max_retries() {
+        let policy = RetryPolicy { max_retries: 2, delay_ms: 0, ..RetryPolicy::DEVICE };
+        let mut attempts = 0;
+        let result: Result<(), u32> = policy
+            .run(
+                || {
+                    attempts += 1;
+                    std::future::ready(Err(attempts))
+                },
+                |_, _| true,
+            )
+            .await;
+        assert_eq!(result, Err(3));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::error::{Error, Result};
+use crate::scrobble::LastFm;
+use crate::sonos::TrackDatabase;
+use log::{info, warn};
+use std::time::Duration;
+
+/// Source of the blocklist entries synced from Last.fm tags
+const LASTFM_SOURCE: &str = "lastfm";
+/// Source of the tracks marked "never scrobble" on the dashboard, which
+/// syncing leaves alone
+const DASHBOARD_SOURCE: &str = "dashboard";
+
+/// Replaces the blocklist entries from Last.fm with the tracks and artists
+/// tagged with any of `tags` there. Returns how many there are.
+pub async fn sync_blocklist(db: &TrackDatabase, lastfm: &LastFm, tags: &[String]) -> Result<usize> {
+    let mut entries = Vec::new();
+    for tag in tags {
+        entries.extend(lastfm.tagged(tag).await?);
+    }
+    db.replace_blocklist(LASTFM_SOURCE, &entries).await?;
+    Ok(entries.len())
+}
+
+/// Blocklists the track of the scrobble with `listen_id`, so later listens
+/// of it are never scrobbled. Returns the track as `Artist - Title`.
+pub async fn never_scrobble(db: &TrackDatabase, listen_id: i64) -> Result<String> {
+    let (artist, title) = db
+        .scrobbled_track(listen_id)
+        .await?
+        .ok_or_else(|| Error::Config(format!("no listen {}", listen_id)))?;
+    if db.block_track(&artist, &title, DASHBOARD_SOURCE).await? {
+        info!("Never scrobbling {} - {} again", artist, title);
+    }
+    Ok(format!("{} - {}", artist, title))
+}
+
+/// Undoes [`never_scrobble`] for the track of the scrobble with `listen_id`.
+/// Fails when the track wasn't marked, or is also blocklisted by a Last.fm
+/// tag, which only untagging it there undoes.
+pub async fn scrobble_again(db: &TrackDatabase, listen_id: i64) -> Result<String> {
+    let (artist, title) = db
+        .scrobbled_track(listen_id)
+        .await?
+        .ok_or_else(|| Error::Config(format!("no listen {}", listen_id)))?;
+    let track = format!("{} - {}", artist, title);
+    if !db.unblock_track(&artist, &title, DASHBOARD_SOURCE).await? {
+        return Err(Error::Config(format!("{} wasn't marked never scrobble", track)));
+    }
+    info!("Scrobbling {} again", track);
+    if db.blocklisted(&artist, &title).await? {
+        return Err(Error::Config(format!("{} is still blocklisted by a Last.fm tag", track)));
+    }
+    Ok(track)
+}
+
+/// Runs [`sync_blocklist`] every `interval` until the task is dropped
+pub async fn sync_blocklist_periodically(db: TrackDatabase, lastfm: LastFm, tags: Vec<String>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match sync_blocklist(&db, &lastfm, &tags).await {
+            Ok(entries) => info!("Blocklist synced from Last.fm: {} tracks and artists", entries),
+            Err(e) => warn!("Blocklist sync from Last.fm failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrobble::{Scrobble, Scrobbler};
+
+    #[tokio::test]
+    async fn test_blocklisted_listens_are_not_scrobbled() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let entries = [("White Noise Sleep".to_string(), None), ("Enya".to_string(), Some("Orinoco Flow".to_string()))];
+        db.replace_blocklist(LASTFM_SOURCE, &entries).await.unwrap();
+
+        let listen = |artist: &str, title: &str| Scrobble::new("Kids Room", artist, title, 1_700_000_000);
+        let scrobbler = Scrobbler::default();
+        assert!(!scrobbler.submit(&db, &listen("white noise sleep", "Rain")).await.unwrap());
+        assert!(!scrobbler.submit(&db, &listen("Enya", "Orinoco Flow")).await.unwrap());
+        assert!(scrobbler.submit(&db, &listen("Enya", "Caribbean Blue")).await.unwrap());
+
+        // Synced again without the artist
+        db.replace_blocklist(LASTFM_SOURCE, &entries[1..]).await.unwrap();
+        assert!(scrobbler.submit(&db, &listen("White Noise Sleep", "Rain")).await.unwrap());
+
+        // Marked on the dashboard, which outlasts the next sync
+        let id = db.scrobbles_by_start().await.unwrap()[0].0;
+        assert_eq!(never_scrobble(&db, id).await.unwrap(), "Enya - Caribbean Blue");
+        db.replace_blocklist(LASTFM_SOURCE, &entries).await.unwrap();
+        let later = Scrobble { started_at: 1_700_003_600, ..listen("enya", "caribbean blue") };
+        assert!(!scrobbler.submit(&db, &later).await.unwrap());
+        assert!(matches!(never_scrobble(&db, id + 10).await, Err(Error::Config(_))));
+
+        // And undone there
+        assert_eq!(scrobble_again(&db, id).await.unwrap(), "Enya - Caribbean Blue");
+        assert!(scrobbler.submit(&db, &later).await.unwrap());
+        assert!(matches!(scrobble_again(&db, id).await, Err(Error::Config(_))));
+    }
+}
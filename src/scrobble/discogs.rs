@@ -0,0 +1,158 @@
+use crate::cache::LruCache;
+use crate::config::CacheConfig;
+use crate::error::{Error, Result};
+use crate::sonos::TrackDatabase;
+use log::debug;
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const API_URL: &str = "https://api.discogs.com";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+/// Discogs rejects requests without a user agent
+const USER_AGENT: &str = concat!("sonos-scrobbler/", env!("CARGO_PKG_VERSION"));
+
+/// Releases by artist and title
+type ReleaseCache = Mutex<LruCache<(String, String), Option<Release>>>;
+
+/// The release a track appeared on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    pub album: String,
+    pub year: Option<i64>,
+}
+
+/// Discogs database search, authenticated with a personal access token.
+/// Fills in albums for listens that came without one.
+pub struct Discogs {
+    api_url: String,
+    token: String,
+    client: reqwest::Client,
+    /// Releases by artist and title, in front of the enrichment cache
+    cache: Option<ReleaseCache>,
+}
+
+impl Discogs {
+    pub fn new(token: &str) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(USER_AGENT)
+            .build()
+            .map_err(|e| Error::Scrobble(e.to_string()))?;
+
+        Ok(Self { api_url: API_URL.to_string(), token: token.to_string(), client, cache: None })
+    }
+
+    /// Keeps releases looked up in memory as well, as `config` says
+    pub fn with_cache(mut self, config: &CacheConfig) -> Self {
+        self.cache = Some(Mutex::new(LruCache::from_config(config)));
+        self
+    }
+
+    /// Reads `DISCOGS_TOKEN`. Returns `None` when it is unset.
+    pub fn from_env() -> Option<Result<Self>> {
+        let token = std::env::var("DISCOGS_TOKEN").ok().filter(|v| !v.is_empty())?;
+        Some(Self::new(&token))
+    }
+
+    /// Searches for a release with `title` by `artist`, taking the best match
+    pub async fn search(&self, artist: &str, title: &str) -> Result<Option<Release>> {
+        let response = self
+            .client
+            .get(format!("{}/database/search", self.api_url))
+            .header("Authorization", format!("Discogs token={}", self.token))
+            .query(&[
+                ("type", "release"),
+                ("artist", artist),
+                ("track", title),
+            ])
+            .send()
+            .await
+            .map_err(|e| Error::Scrobble(format!("discogs: {}", e)))?;
+        let body = response.text().await.map_err(|e| Error::Scrobble(format!("discogs: {}", e)))?;
+
+        let value: Value = serde_json::from_str(&body)
+            .map_err(|e| Error::Scrobble(format!("discogs: invalid response: {}", e)))?;
+        if let Some(message) = value["message"].as_str() {
+            return Err(Error::Scrobble(format!("discogs: {}", message)));
+        }
+        Ok(value["results"].as_array().and_then(|results| results.iter().find_map(release)))
+    }
+
+    /// The release for `title` by `artist`, looked up once and then served
+    /// from the enrichment cache, and from memory with a cache. Tracks
+    /// Discogs doesn't know are cached too.
+    pub async fn release(&self, db: &TrackDatabase, artist: &str, title: &str) -> Result<Option<Release>> {
+        let key = (artist.to_string(), title.to_string());
+        if let Some(cached) = self.cache.as_ref().and_then(|cache| cache.lock().unwrap().get(&key)) {
+            return Ok(cached);
+        }
+        let release = match db.cached_release(artist, title).await? {
+            Some(cached) => cached,
+            None => {
+                let release = self.search(artist, title).await?;
+                debug!("Discogs: {} - {}: {:?}", artist, title, release);
+                db.cache_release(artist, title, release.as_ref(), "discogs").await?;
+                release
+            }
+        };
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().insert(key, release.clone());
+        }
+        Ok(release)
+    }
+}
+
+/// Search results are titled "Artist - Album"
+fn release(result: &Value) -> Option<Release> {
+    let title = result["title"].as_str()?;
+    let album = title.split_once(" - ").map_or(title, |(_, album)| album).trim();
+    if album.is_empty() {
+        return None;
+    }
+    // The year is a string, and missing or empty for undated releases
+    let year = match &result["year"] {
+        Value::String(year) => year.parse().ok(),
+        year => year.as_i64(),
+    };
+    Some(Release { album: album.to_string(), year })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    #[tokio::test]
+    async fn test_release_cached() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/database/search")
+            .match_header("Authorization", "Discogs token=token")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("artist".into(), "Boards of Canada".into()),
+                Matcher::UrlEncoded("track".into(), "Dayvan Cowboy".into()),
+            ]))
+            .with_body(r#"{"results":[{"title":"Boards Of Canada - Trans Canada Highway","year":"2006"}]}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let discogs = Discogs { api_url: server.url(), ..Discogs::new("token").unwrap() };
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+
+        let expected = Release { album: "Trans Canada Highway".to_string(), year: Some(2006) };
+        for _ in 0..2 {
+            let release = discogs.release(&db, "Boards of Canada", "Dayvan Cowboy").await.unwrap();
+            assert_eq!(release, Some(expected.clone()));
+        }
+        mock.assert_async().await;
+
+        // Served from memory even where the database doesn't have it
+        let discogs = discogs.with_cache(&CacheConfig::default());
+        discogs.release(&db, "Boards of Canada", "Dayvan Cowboy").await.unwrap();
+        let empty = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let release = discogs.release(&empty, "Boards of Canada", "Dayvan Cowboy").await.unwrap();
+        assert_eq!(release, Some(expected));
+        mock.assert_async().await;
+    }
+}
@@ -0,0 +1,485 @@
+use crate::error::{Error, Result};
+use crate::scrobble::{RateLimit, Scrobble, ScrobbleBackend};
+use crate::sonos::TrackDatabase;
+use async_trait::async_trait;
+use log::{info, warn};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+/// Five calls per second, averaged over five minutes
+const RATE_LIMIT: RateLimit = RateLimit { calls: 5 * 300, per: Duration::from_secs(300) };
+/// Genre tags kept per track, most applied first
+const MAX_TAGS: usize = 3;
+/// Tag weights run from 0 to 100 relative to the track's top tag; below
+/// this a tag is mostly noise
+const MIN_TAG_WEIGHT: i64 = 10;
+/// API error for tracks Last.fm doesn't know
+const TRACK_NOT_FOUND: i64 = 6;
+/// API errors meaning the session or API key is no longer accepted:
+/// authentication failed, invalid session key, invalid or suspended API key
+const AUTH_ERRORS: [i64; 4] = [4, 9, 10, 26];
+/// Tracks or artists fetched per tag, the most the API returns at once
+const TAGGED_LIMIT: usize = 1000;
+
+/// Last.fm scrobbling API client, authenticated with a session key
+pub struct LastFm {
+    name: &'static str,
+    api_url: String,
+    api_key: String,
+    api_secret: String,
+    session_key: String,
+    client: reqwest::Client,
+    /// Where session checks and corrections are remembered
+    db: Option<TrackDatabase>,
+}
+
+impl LastFm {
+    pub fn new(api_key: &str, api_secret: &str, session_key: &str) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| Error::Scrobble(e.to_string()))?;
+
+        Ok(Self {
+            name: "lastfm",
+            api_url: API_URL.to_string(),
+            api_key: api_key.to_string(),
+            api_secret: api_secret.to_string(),
+            session_key: session_key.to_string(),
+            client,
+            db: None,
+        })
+    }
+
+    /// Remembers in `db` when the session key last proved valid, forgetting
+    /// it again once Last.fm rejects the key, and how Last.fm corrects the
+    /// artists and titles of scrobbles, so that later listens and statistics
+    /// use the corrected names
+    pub fn with_database(mut self, db: TrackDatabase) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Checks the session key with Last.fm, unless it proved valid within
+    /// the last `max_age`. Returns whether Last.fm was asked.
+    pub async fn verify_session(&self, max_age: Duration) -> Result<bool> {
+        let fingerprint = format!("{:x}", md5::compute(&self.session_key));
+        if let Some(db) = &self.db {
+            if db.credentials_checked_within(self.name, &fingerprint, max_age).await? {
+                return Ok(false);
+            }
+        }
+        self.call("user.getInfo", BTreeMap::new()).await?;
+        if let Some(db) = &self.db {
+            db.mark_credentials_checked(self.name, &fingerprint).await?;
+        }
+        Ok(true)
+    }
+
+    /// Reads `LASTFM_API_KEY`, `LASTFM_API_SECRET` and `LASTFM_SESSION_KEY`.
+    /// Returns `None` when any of them is unset.
+    pub fn from_env() -> Option<Result<Self>> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Some(Self::new(
+            &var("LASTFM_API_KEY")?,
+            &var("LASTFM_API_SECRET")?,
+            &var("LASTFM_SESSION_KEY")?,
+        ))
+    }
+
+    /// The party account: `LASTFM_PARTY_SESSION_KEY` with the API key and
+    /// secret of [`Self::from_env`]. Returns `None` when any of them is unset.
+    pub fn party_from_env() -> Option<Result<Self>> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let lastfm = Self::new(
+            &var("LASTFM_API_KEY")?,
+            &var("LASTFM_API_SECRET")?,
+            &var("LASTFM_PARTY_SESSION_KEY")?,
+        );
+        Some(lastfm.map(|lastfm| Self { name: "lastfm_party", ..lastfm }))
+    }
+
+    /// Tracks and artists the account tagged with `tag`, as artist and title,
+    /// without a title for artists
+    pub async fn tagged(&self, tag: &str) -> Result<Vec<(String, Option<String>)>> {
+        let user = self.call("user.getInfo", BTreeMap::new()).await?;
+        let Some(user) = user["user"]["name"].as_str() else {
+            return Err(Error::Scrobble("user.getInfo: no user name".to_string()));
+        };
+
+        let mut tagged = Vec::new();
+        for kind in ["track", "artist"] {
+            let mut params = BTreeMap::new();
+            params.insert("user", user.to_string());
+            params.insert("tag", tag.to_string());
+            params.insert("taggingtype", kind.to_string());
+            params.insert("limit", TAGGED_LIMIT.to_string());
+            let response = self.call("user.getPersonalTags", params).await?;
+            let items = &response["taggings"][format!("{}s", kind)][kind];
+            // A single item comes as an object rather than a list
+            let items = items.as_array().map_or(std::slice::from_ref(items), Vec::as_slice);
+            for item in items {
+                let name = item["name"].as_str().map(str::to_string);
+                let entry = match kind {
+                    "track" => item["artist"]["name"].as_str().zip(name).map(|(a, t)| (a.to_string(), Some(t))),
+                    _ => name.map(|artist| (artist, None)),
+                };
+                tagged.extend(entry);
+            }
+        }
+        Ok(tagged)
+    }
+
+    fn track_params(scrobble: &Scrobble) -> BTreeMap<&'static str, String> {
+        let mut params = BTreeMap::new();
+        params.insert("artist", scrobble.artist.clone());
+        params.insert("track", scrobble.title.clone());
+        if let Some(album) = &scrobble.album {
+            params.insert("album", album.clone());
+        }
+        if let Some(duration) = scrobble.duration {
+            params.insert("duration", duration.as_secs().to_string());
+        }
+        // The closest Last.fm has to a field for the room
+        if let Some(room) = &scrobble.room {
+            params.insert("context", room.clone());
+        }
+        params
+    }
+
+    /// The most applied genre tags of a track, lowercased. Unknown tracks
+    /// have none.
+    pub async fn top_tags(&self, artist: &str, title: &str) -> Result<Vec<String>> {
+        let mut params = BTreeMap::new();
+        params.insert("artist", artist.to_string());
+        params.insert("track", title.to_string());
+        params.insert("autocorrect", "1".to_string());
+        let response = self.send("track.getTopTags", params).await?;
+        if response["error"].as_i64() == Some(TRACK_NOT_FOUND) {
+            return Ok(Vec::new());
+        }
+        check(&response, "track.getTopTags")?;
+
+        // A single tag comes as an object rather than an array
+        let tags = match &response["toptags"]["tag"] {
+            Value::Array(tags) => tags.clone(),
+            Value::Null => Vec::new(),
+            tag => vec![tag.clone()],
+        };
+        Ok(tags
+            .iter()
+            .filter(|tag| tag["count"].as_i64().unwrap_or_default() >= MIN_TAG_WEIGHT)
+            .filter_map(|tag| tag["name"].as_str())
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .take(MAX_TAGS)
+            .collect())
+    }
+
+    async fn call(&self, method: &str, params: BTreeMap<&'static str, String>) -> Result<Value> {
+        let value = self.send(method, params).await?;
+        let rejected = value["error"].as_i64().is_some_and(|code| AUTH_ERRORS.contains(&code));
+        if let (true, Some(db)) = (rejected, &self.db) {
+            if let Err(e) = db.forget_credentials_check(self.name).await {
+                warn!("Failed to forget the {} session check: {}", self.name, e);
+            }
+        }
+        check(&value, method)?;
+        Ok(value)
+    }
+
+    async fn send(&self, method: &str, mut params: BTreeMap<&'static str, String>) -> Result<Value> {
+        params.insert("method", method.to_string());
+        params.insert("api_key", self.api_key.clone());
+        params.insert("sk", self.session_key.clone());
+        params.insert("api_sig", sign(&params, &self.api_secret));
+        params.insert("format", "json".to_string());
+
+        let response = self
+            .client
+            .post(&self.api_url)
+            .form(&params)
+            .send()
+            .await
+            .map_err(|e| Error::Scrobble(format!("{}: {}", method, e)))?;
+        let body = response
+            .text()
+            .await
+            .map_err(|e| Error::Scrobble(format!("{}: {}", method, e)))?;
+
+        serde_json::from_str(&body).map_err(|e| Error::Scrobble(format!("{}: invalid response: {}", method, e)))
+    }
+}
+
+/// The artist and title Last.fm scrobbled instead of the submitted ones, if
+/// it corrected either
+fn correction(response: &Value) -> Option<(String, String)> {
+    let scrobble = &response["scrobbles"]["scrobble"];
+    let corrected = |field: &str| scrobble[field]["corrected"].as_str() == Some("1");
+    if !corrected("artist") && !corrected("track") {
+        return None;
+    }
+    let text = |field: &str| scrobble[field]["#text"].as_str().filter(|text| !text.is_empty()).map(str::to_string);
+    Some((text("artist")?, text("track")?))
+}
+
+/// Fails on an API error response
+fn check(value: &Value, method: &str) -> Result<()> {
+    if let Some(code) = value.get("error") {
+        let message = value["message"].as_str().unwrap_or("unknown error");
+        return Err(Error::Scrobble(format!("{}: error {}: {}", method, code, message)));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl ScrobbleBackend for LastFm {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn now_playing(&self, scrobble: &Scrobble) -> Result<()> {
+        self.call("track.updateNowPlaying", Self::track_params(scrobble)).await?;
+        Ok(())
+    }
+
+    async fn scrobble(&self, scrobble: &Scrobble) -> Result<()> {
+        let mut params = Self::track_params(scrobble);
+        params.insert("timestamp", scrobble.started_at.to_string());
+        params.insert("chosenByUser", if scrobble.chosen_by_user { "1" } else { "0" }.to_string());
+
+        let response = self.call("track.scrobble", params).await?;
+        let ignored = &response["scrobbles"]["scrobble"]["ignoredMessage"];
+        if let Some(code) = ignored["code"].as_str().filter(|code| *code != "0") {
+            return Err(Error::Scrobble(format!(
+                "track.scrobble: ignored ({}): {}",
+                code,
+                ignored["#text"].as_str().unwrap_or_default()
+            )));
+        }
+
+        if let (Some((artist, title)), Some(db)) = (correction(&response), &self.db) {
+            info!("Last.fm corrected {} - {} to {} - {}", scrobble.artist, scrobble.title, artist, title);
+            if let Err(e) = db.record_correction(&scrobble.artist, &scrobble.title, &artist, &title).await {
+                warn!("Failed to record the correction of {} - {}: {}", scrobble.artist, scrobble.title, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn love(&self, scrobble: &Scrobble) -> Result<()> {
+        let mut params = BTreeMap::new();
+        params.insert("artist", scrobble.artist.clone());
+        params.insert("track", scrobble.title.clone());
+        self.call("track.love", params).await?;
+        Ok(())
+    }
+
+    fn rate_limit(&self) -> Option<RateLimit> {
+        Some(RATE_LIMIT)
+    }
+}
+
+/// Last.fm request signature: md5 over the parameters sorted by name,
+/// concatenated as name + value, followed by the shared secret
+fn sign(params: &BTreeMap<&'static str, String>, secret: &str) -> String {
+    let mut payload: String = params.iter().map(|(k, v)| format!("{}{}", k, v)).collect();
+    payload.push_str(secret);
+    format!("{:x}", md5::compute(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrobble::Scrobbler;
+    use crate::stats::{GroupBy, ListenFilter};
+    use mockito::Matcher;
+
+    fn scrobble() -> Scrobble {
+        Scrobble {
+            album: Some("Mezzanine".to_string()),
+            duration: Some(Duration::from_secs(329)),
+            ..Scrobble::new("Kitchen", "Massive Attack", "Teardrop", 1_700_000_000)
+        }
+    }
+
+    fn client(url: String) -> LastFm {
+        LastFm { api_url: url, ..LastFm::new("key", "secret", "session").unwrap() }
+    }
+
+    #[tokio::test]
+    async fn test_scrobble_request() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("method".into(), "track.scrobble".into()),
+                Matcher::UrlEncoded("artist".into(), "Massive Attack".into()),
+                Matcher::UrlEncoded("timestamp".into(), "1700000000".into()),
+                Matcher::UrlEncoded("sk".into(), "session".into()),
+                Matcher::Regex("api_sig=[0-9a-f]{32}".into()),
+            ]))
+            .with_body(r##"{"scrobbles":{"@attr":{"accepted":1,"ignored":0},"scrobble":{"ignoredMessage":{"code":"0","#text":""}}}}"##)
+            .create_async()
+            .await;
+
+        client(server.url()).scrobble(&scrobble()).await.unwrap();
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_api_error() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/")
+            .with_status(403)
+            .with_body(r#"{"error":9,"message":"Invalid session key - Please re-authenticate"}"#)
+            .create_async()
+            .await;
+
+        let result = client(server.url()).now_playing(&scrobble()).await;
+        match result {
+            Err(Error::Scrobble(message)) => assert!(message.contains("Invalid session key")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_session_is_cached_until_rejected() {
+        let mut server = mockito::Server::new_async().await;
+        let check = server
+            .mock("POST", "/")
+            .match_body(Matcher::UrlEncoded("method".into(), "user.getInfo".into()))
+            .with_body(r#"{"user":{"name":"me"}}"#)
+            .expect(2)
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/")
+            .match_body(Matcher::UrlEncoded("method".into(), "track.updateNowPlaying".into()))
+            .with_body(r#"{"error":9,"message":"Invalid session key - Please re-authenticate"}"#)
+            .create_async()
+            .await;
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let lastfm = client(server.url()).with_database(db);
+        let max_age = Duration::from_secs(3600);
+        assert!(lastfm.verify_session(max_age).await.unwrap());
+        assert!(!lastfm.verify_session(max_age).await.unwrap());
+
+        assert!(lastfm.now_playing(&scrobble()).await.is_err());
+        assert!(lastfm.verify_session(max_age).await.unwrap());
+        check.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_corrections_apply_to_later_listens() {
+        let mut server = mockito::Server::new_async().await;
+        let corrected = server
+            .mock("POST", "/")
+            .match_body(Matcher::UrlEncoded("artist".into(), "Massive Atack".into()))
+            .with_body(
+                r##"{"scrobbles":{"scrobble":{"artist":{"corrected":"1","#text":"Massive Attack"},
+                    "track":{"corrected":"0","#text":"Teardrop"},"ignoredMessage":{"code":"0","#text":""}}}}"##,
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let correct = server
+            .mock("POST", "/")
+            .match_body(Matcher::UrlEncoded("artist".into(), "Massive Attack".into()))
+            .with_body(r##"{"scrobbles":{"scrobble":{"ignoredMessage":{"code":"0","#text":""}}}}"##)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobbler = Scrobbler::new(vec![Box::new(client(server.url()).with_database(db.clone()))]);
+        let misspelled = Scrobble { artist: "Massive Atack".to_string(), ..scrobble() };
+        assert!(scrobbler.submit(&db, &misspelled).await.unwrap());
+        let later = Scrobble { started_at: misspelled.started_at + 3600, ..misspelled };
+        assert!(scrobbler.submit(&db, &later).await.unwrap());
+        corrected.assert_async().await;
+        correct.assert_async().await;
+
+        let artists = db.aggregate(&ListenFilter::default(), GroupBy::Artist, 10).await.unwrap();
+        assert_eq!(artists, vec![("Massive Attack".to_string(), 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_tagged() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/")
+            .match_body(Matcher::UrlEncoded("method".into(), "user.getInfo".into()))
+            .with_body(r#"{"user":{"name":"me"}}"#)
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("user".into(), "me".into()),
+                Matcher::UrlEncoded("taggingtype".into(), "track".into()),
+            ]))
+            .with_body(
+                r#"{"taggings":{"tracks":{"track":[{"name":"Rain","artist":{"name":"Nature Sounds"}},
+                   {"name":"Orinoco Flow","artist":{"name":"Enya"}}]}}}"#,
+            )
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/")
+            .match_body(Matcher::UrlEncoded("taggingtype".into(), "artist".into()))
+            .with_body(r#"{"taggings":{"artists":{"artist":{"name":"White Noise Sleep"}}}}"#)
+            .create_async()
+            .await;
+
+        let tagged = client(server.url()).tagged("noise").await.unwrap();
+        assert_eq!(
+            tagged,
+            vec![
+                ("Nature Sounds".to_string(), Some("Rain".to_string())),
+                ("Enya".to_string(), Some("Orinoco Flow".to_string())),
+                ("White Noise Sleep".to_string(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_top_tags() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/")
+            .match_body(Matcher::UrlEncoded("method".into(), "track.getTopTags".into()))
+            .with_body(
+                r#"{"toptags":{"tag":[{"count":100,"name":"Trip-Hop"},{"count":62,"name":"electronic"},
+                   {"count":4,"name":"seen live"}],"@attr":{"artist":"Massive Attack","track":"Teardrop"}}}"#,
+            )
+            .create_async()
+            .await;
+        let tags = client(server.url()).top_tags("Massive Attack", "Teardrop").await.unwrap();
+        assert_eq!(tags, vec!["trip-hop".to_string(), "electronic".to_string()]);
+
+        server.reset();
+        server
+            .mock("POST", "/")
+            .with_body(r#"{"error":6,"message":"Track not found"}"#)
+            .create_async()
+            .await;
+        assert!(client(server.url()).top_tags("Nobody", "Nothing").await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sign() {
+        let mut params = BTreeMap::new();
+        params.insert("method", "track.scrobble".to_string());
+        params.insert("api_key", "key".to_string());
+        assert_eq!(
+            sign(&params, "secret"),
+            format!("{:x}", md5::compute("api_keykeymethodtrack.scrobblesecret"))
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use crate::config::FunkwhaleConfig;
+use crate::error::{Error, Result};
+use crate::scrobble::{Scrobble, ScrobbleBackend};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const API_URL: &str = "https://api.listenbrainz.org";
+/// Where Funkwhale serves its ListenBrainz-compatible API
+const FUNKWHALE_API_PATH: &str = "/api/v1/listenbrainz";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// ListenBrainz listen submission, authenticated with a user token. Also
+/// talks to servers with a compatible API, such as Funkwhale.
+pub struct ListenBrainz {
+    name: &'static str,
+    /// Base URL that `/1/submit-listens` is appended to
+    api_url: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl ListenBrainz {
+    pub fn new(token: &str) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| Error::Scrobble(e.to_string()))?;
+
+        Ok(Self { name: "listenbrainz", api_url: API_URL.to_string(), token: token.to_string(), client })
+    }
+
+    /// Reads `LISTENBRAINZ_TOKEN`. Returns `None` when it is unset.
+    pub fn from_env() -> Option<Result<Self>> {
+        let token = std::env::var("LISTENBRAINZ_TOKEN").ok().filter(|v| !v.is_empty())?;
+        Some(Self::new(&token))
+    }
+
+    /// A Funkwhale instance's ListenBrainz-compatible API
+    pub fn funkwhale(config: &FunkwhaleConfig) -> Result<Self> {
+        let api_url = format!("{}{}", config.url.trim_end_matches('/'), FUNKWHALE_API_PATH);
+        Ok(Self { name: "funkwhale", api_url, ..Self::new(&config.token)? })
+    }
+
+    async fn submit(&self, listen_type: &str, listen: Value) -> Result<()> {
+        let body = json!({ "listen_type": listen_type, "payload": [listen] });
+        let response = self
+            .client
+            .post(format!("{}/1/submit-listens", self.api_url))
+            .header("Authorization", format!("Token {}", self.token))
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| Error::Scrobble(format!("{}: {}", self.name, e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            let error: Value = serde_json::from_str(&body).unwrap_or_default();
+            let message = error["error"].as_str().unwrap_or(status.as_str());
+            return Err(Error::Scrobble(format!("{}: {}", self.name, message)));
+        }
+        Ok(())
+    }
+}
+
+/// The `track_metadata` of a listen
+fn track_metadata(scrobble: &Scrobble) -> Value {
+    let mut metadata = json!({
+        "artist_name": scrobble.artist,
+        "track_name": scrobble.title,
+        "additional_info": { "submission_client": "sonos-scrobbler" },
+    });
+    if let Some(album) = &scrobble.album {
+        metadata["release_name"] = json!(album);
+    }
+    if let Some(duration) = scrobble.duration {
+        metadata["additional_info"]["duration_ms"] = json!(duration.as_millis() as u64);
+    }
+    if let Some(room) = &scrobble.room {
+        metadata["additional_info"]["listening_from"] = json!(room);
+    }
+    metadata
+}
+
+#[async_trait]
+impl ScrobbleBackend for ListenBrainz {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn now_playing(&self, scrobble: &Scrobble) -> Result<()> {
+        self.submit("playing_now", json!({ "track_metadata": track_metadata(scrobble) })).await
+    }
+
+    async fn scrobble(&self, scrobble: &Scrobble) -> Result<()> {
+        let listen = json!({ "listened_at": scrobble.started_at, "track_metadata": track_metadata(scrobble) });
+        self.submit("single", listen).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    #[tokio::test]
+    async fn test_funkwhale_submission() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/api/v1/listenbrainz/1/submit-listens")
+            .match_header("Authorization", "Token secret")
+            .match_body(Matcher::PartialJson(json!({
+                "listen_type": "single",
+                "payload": [{
+                    "listened_at": 1_700_000_000,
+                    "track_metadata": {
+                        "artist_name": "Massive Attack",
+                        "track_name": "Teardrop",
+                        "release_name": "Mezzanine",
+                        "additional_info": { "listening_from": "Kitchen" },
+                    },
+                }],
+            })))
+            .with_body(r#"{"status":"ok"}"#)
+            .create_async()
+            .await;
+
+        let config = FunkwhaleConfig { url: format!("{}/", server.url()), token: "secret".to_string() };
+        let funkwhale = ListenBrainz::funkwhale(&config).unwrap();
+        let scrobble = Scrobble {
+            album: Some("Mezzanine".to_string()),
+            duration: Some(Duration::from_secs(329)),
+            room: Some("Kitchen".to_string()),
+            ..Scrobble::new("Kitchen", "Massive Attack", "Teardrop", 1_700_000_000)
+        };
+        funkwhale.scrobble(&scrobble).await.unwrap();
+        mock.assert_async().await;
+    }
+}
@@ -0,0 +1,1136 @@
+mod blocklist;
+mod discogs;
+mod lastfm;
+mod listenbrainz;
+mod outage;
+mod party;
+mod plex;
+mod profile;
+mod quarantine;
+mod subsonic;
+mod tags;
+mod worker;
+
+pub use blocklist::{never_scrobble, scrobble_again, sync_blocklist, sync_blocklist_periodically};
+pub use discogs::{Discogs, Release};
+pub use lastfm::LastFm;
+pub use listenbrainz::ListenBrainz;
+pub use outage::{submit_deferred, submit_when_online, Connectivity, OUTAGE_CHECK_INTERVAL};
+pub use party::PartyMode;
+pub use plex::Plex;
+pub use profile::Profiles;
+pub use quarantine::{enrich_periodically, release_enriched, release_quarantined, QuarantinedListen};
+pub use subsonic::Subsonic;
+pub use tags::{tag_listens, tag_periodically, TAG_INTERVAL};
+pub use worker::Priority;
+
+use crate::config::{household_room, ProfileConfig, SubmissionConfig};
+use crate::error::{Error, Result};
+use crate::retry::RetryPolicy;
+use crate::sonos::TrackDatabase;
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use worker::{Delivery, Job, Worker};
+
+/// A listen ready to be submitted to scrobbling services
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scrobble {
+    pub device: String,
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+    /// Unix timestamp of when the listen started
+    pub started_at: i64,
+    pub duration: Option<Duration>,
+    /// Further artists split off `artist`, kept for statistics only
+    pub featured: Vec<String>,
+    pub confidence: Confidence,
+    /// False for plays the listener didn't pick, like radio or an alarm
+    pub chosen_by_user: bool,
+    /// URI the speaker played, when known; some services identify their
+    /// tracks by it
+    pub track_uri: Option<String>,
+    /// Room the listen was played in; the scrobbler fills it in from the
+    /// device when unset
+    pub room: Option<String>,
+}
+
+impl Scrobble {
+    /// A listen the listener chose, with nothing known about it besides
+    /// what is given
+    pub fn new(
+        device: impl Into<String>,
+        artist: impl Into<String>,
+        title: impl Into<String>,
+        started_at: i64,
+    ) -> Self {
+        Self {
+            device: device.into(),
+            artist: artist.into(),
+            title: title.into(),
+            album: None,
+            started_at,
+            duration: None,
+            featured: Vec::new(),
+            confidence: Confidence::default(),
+            chosen_by_user: true,
+            track_uri: None,
+            room: None,
+        }
+    }
+
+    /// Splits multi-artist strings like "A, B & C" at `separators`, keeping
+    /// the first as the artist and the rest as featured artists. Separators
+    /// match ignoring ASCII case.
+    pub fn split_artists(&mut self, separators: &[String]) {
+        let mut artists = Vec::new();
+        let mut rest = self.artist.as_str();
+        let lowered = self.artist.to_ascii_lowercase();
+        let mut offset = 0;
+        while let Some((at, len)) = separators
+            .iter()
+            .filter(|separator| !separator.is_empty())
+            .filter_map(|separator| {
+                let found = lowered.get(offset..)?.find(&separator.to_ascii_lowercase())?;
+                Some((found, separator.len()))
+            })
+            .min()
+        {
+            artists.push(&rest[..at]);
+            rest = &rest[at + len..];
+            offset += at + len;
+        }
+        artists.push(rest);
+
+        let mut artists = artists.into_iter().map(str::trim).filter(|artist| !artist.is_empty());
+        let Some(primary) = artists.next() else {
+            return;
+        };
+        let primary = primary.to_string();
+        for artist in artists {
+            if !artist.eq_ignore_ascii_case(&primary) && !self.featured.iter().any(|f| f.eq_ignore_ascii_case(artist)) {
+                self.featured.push(artist.to_string());
+            }
+        }
+        self.artist = primary;
+    }
+
+    /// Artist and title normalized for comparing listens from different
+    /// devices: case, punctuation and spacing are ignored
+    pub fn fingerprint(&self) -> String {
+        let normalize = |value: &str| {
+            value
+                .to_lowercase()
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        format!("{}\u{1f}{}", normalize(&self.artist), normalize(&self.title))
+    }
+
+    /// Whether `other` is the same play heard on another speaker, as happens
+    /// when speakers are grouped or a group is re-formed mid-track
+    pub fn same_play_elsewhere(&self, other: &Scrobble) -> bool {
+        self.device != other.device
+            && (self.started_at - other.started_at).abs() <= CROSS_DEVICE_WINDOW.as_secs() as i64
+            && self.fingerprint() == other.fingerprint()
+    }
+}
+
+/// Where a listen's metadata came from, from most to least reliable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataSource {
+    /// Tags embedded in a music library file, read through a mounted share
+    FileTags,
+    /// Separate fields in the track's DIDL-Lite metadata
+    Didl,
+    /// The `TYPE=SNG|TITLE ...|ARTIST ...` stream title some services send
+    StreamFields,
+    /// A plain `Artist - Title` stream title, split at the dash
+    StreamTitle,
+    /// Guessed afterwards, from a title or file name
+    Enrichment,
+}
+
+impl MetadataSource {
+    /// How much a field from this source is trusted, from 0 to 100
+    pub fn confidence(self) -> u8 {
+        match self {
+            MetadataSource::FileTags => 100,
+            MetadataSource::Didl => 100,
+            MetadataSource::StreamFields => 90,
+            MetadataSource::StreamTitle => 60,
+            MetadataSource::Enrichment => 40,
+        }
+    }
+}
+
+/// Confidence in a listen's artist and title, from 0 to 100 each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Confidence {
+    pub artist: u8,
+    pub title: u8,
+}
+
+impl Confidence {
+    /// Both fields taken from `source`
+    pub fn from_source(source: MetadataSource) -> Self {
+        Self { artist: source.confidence(), title: source.confidence() }
+    }
+
+    /// The confidence in the listen as a whole: that of its weakest field
+    pub fn lowest(&self) -> u8 {
+        self.artist.min(self.title)
+    }
+}
+
+/// Full confidence, as for DIDL-Lite metadata
+impl Default for Confidence {
+    fn default() -> Self {
+        Self::from_source(MetadataSource::Didl)
+    }
+}
+
+/// Listens of the same track on different devices that start this close
+/// together are one play
+pub const CROSS_DEVICE_WINDOW: Duration = Duration::from_secs(2 * 60);
+
+/// A published API rate limit: at most `calls` requests per `per`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub calls: u64,
+    pub per: Duration,
+}
+
+/// Share of a rate limit at which a warning is logged, in percent
+const RATE_LIMIT_WARNING_PERCENT: u64 = 80;
+
+/// A service that accepts now-playing updates and scrobbles
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait ScrobbleBackend: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn now_playing(&self, scrobble: &Scrobble) -> Result<()>;
+
+    async fn scrobble(&self, scrobble: &Scrobble) -> Result<()>;
+
+    /// Marks the track as loved; services without the concept ignore it
+    async fn love(&self, _scrobble: &Scrobble) -> Result<()> {
+        Ok(())
+    }
+
+    /// The service's published rate limit, if any
+    fn rate_limit(&self) -> Option<RateLimit> {
+        None
+    }
+}
+
+/// Sends every update to the backends routed for its room, by default all
+/// configured ones. A failing backend is logged and does not keep the others
+/// from receiving the update.
+#[derive(Default)]
+pub struct Scrobbler {
+    backends: Vec<Box<dyn ScrobbleBackend>>,
+    usage: Option<TrackDatabase>,
+    /// Backend names per lowercased room; rooms without an entry use all
+    routes: BTreeMap<String, Vec<String>>,
+    /// Room of each polled device, as scrobbles name the device
+    rooms: Mutex<BTreeMap<String, String>>,
+    /// Separators between artists in multi-artist strings; none disables
+    /// splitting
+    artist_separators: Vec<String>,
+    /// Scrobbles less trustworthy than this are quarantined instead
+    min_confidence: u8,
+    /// Grace period before a listen is submitted, during which it can still
+    /// be cancelled
+    delay: Duration,
+    held: Mutex<Vec<HeldScrobble>>,
+    party: PartyMode,
+    /// Backends that replace all others while party mode is on
+    party_backends: Vec<Box<dyn ScrobbleBackend>>,
+    /// Replace the routes, the minimum confidence and party mode while one
+    /// is active
+    profiles: Profiles,
+    /// For submissions a backend failed
+    retry: RetryPolicy,
+    /// Tells a failure of every backend from the internet being down
+    connectivity: Option<Arc<Connectivity>>,
+    /// Least time between now-playing updates for the same track
+    now_playing_interval: Duration,
+    /// Last now-playing update per device: the track's fingerprint and when
+    /// it was sent
+    now_playing_sent: Mutex<BTreeMap<String, (String, Instant)>>,
+    /// Backends switched off through the control API, which receive nothing
+    disabled: Mutex<BTreeSet<String>>,
+    /// Whether backends are told the room of a listen
+    share_room: bool,
+    /// Set while scrobbling is paused through D-Bus, which drops every
+    /// listen and now-playing update
+    paused: AtomicBool,
+    /// Queues submissions for [`Self::run_worker`] instead of making them
+    /// right away
+    worker: Option<Worker>,
+}
+
+/// A scrobble waiting out the grace period
+struct HeldScrobble {
+    scrobble: Scrobble,
+    /// Position in the queue, for album detection once it is submitted
+    queue_position: Option<u32>,
+    due: Instant,
+}
+
+impl Scrobbler {
+    pub fn new(backends: Vec<Box<dyn ScrobbleBackend>>) -> Self {
+        Self { backends, ..Self::default() }
+    }
+
+    /// Queues now-playing updates and scrobbles per backend instead of
+    /// submitting them right away, so slow services don't hold up the
+    /// caller. Submitted once [`Self::run_worker`] runs.
+    pub fn with_worker(mut self, config: &SubmissionConfig) -> Self {
+        self.worker = Some(Worker::new(config));
+        self
+    }
+
+    /// Limits rooms to the named backends; an empty list keeps a room's
+    /// listens in the local log only. Names of backends that are not
+    /// configured are ignored with a warning.
+    pub fn with_routes(mut self, routes: &BTreeMap<String, Vec<String>>) -> Self {
+        self.routes = self.room_routes(routes);
+        self
+    }
+
+    /// Routes the rooms of `household` like [`Self::with_routes`], by their
+    /// plain names. Its rooms without a route use every backend, whatever the
+    /// main household's routes say about rooms of the same name.
+    pub fn with_household_routes(mut self, household: &str, routes: &BTreeMap<String, Vec<String>>) -> Self {
+        let routes = routes.iter().map(|(room, names)| (household_room(household, room), names.clone())).collect();
+        let routes = self.room_routes(&routes);
+        self.routes.extend(routes);
+        self
+    }
+
+    /// `routes` keyed by lowercased room, warning about backends that are
+    /// not configured
+    fn room_routes(&self, routes: &BTreeMap<String, Vec<String>>) -> BTreeMap<String, Vec<String>> {
+        let mut lowercased = BTreeMap::new();
+        for (room, names) in routes {
+            for name in names.iter().filter(|name| !self.backend_names().contains(&name.as_str())) {
+                warn!("Route for {} names {}, which is not configured", room, name);
+            }
+            lowercased.insert(room.to_lowercase(), names.clone());
+        }
+        lowercased
+    }
+
+    /// Retries submissions a backend failed per `retry`
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Records listens locally while `connectivity` finds the internet down,
+    /// to be submitted by [`submit_when_online`] once it is back
+    pub fn with_connectivity(mut self, connectivity: Arc<Connectivity>) -> Self {
+        self.connectivity = Some(connectivity);
+        self
+    }
+
+    /// Repeats the now-playing update for a track at most once per
+    /// `interval`; a new track is announced right away
+    pub fn with_now_playing_interval(mut self, interval: Duration) -> Self {
+        self.now_playing_interval = interval;
+        self
+    }
+
+    /// Counts every API call in `db`, and warns when a backend gets close to
+    /// its rate limit
+    pub fn with_usage_log(mut self, db: TrackDatabase) -> Self {
+        self.usage = Some(db);
+        self
+    }
+
+    /// Submits only the first of several artists, like "A" for "A & B",
+    /// recording the others as featured artists
+    pub fn with_artist_separators(mut self, separators: Vec<String>) -> Self {
+        self.artist_separators = separators;
+        self
+    }
+
+    /// Quarantines listens whose metadata confidence is below `min_confidence`
+    pub fn with_min_confidence(mut self, min_confidence: u8) -> Self {
+        self.min_confidence = min_confidence;
+        self
+    }
+
+    /// Tells the backends which room a listen was played in, for those with
+    /// a field for it. The room is recorded locally either way.
+    pub fn with_shared_room(mut self, share_room: bool) -> Self {
+        self.share_room = share_room;
+        self
+    }
+
+    /// Starts with the named backends switched off
+    pub fn with_disabled_backends(self, names: impl IntoIterator<Item = String>) -> Self {
+        self.disabled.lock().unwrap().extend(names);
+        self
+    }
+
+    /// Switches the backend called `name` on or off. Listens played while
+    /// it is off are not sent to it later.
+    pub fn set_backend_enabled(&self, name: &str, enabled: bool) -> Result<()> {
+        let known = self.backends.iter().chain(&self.party_backends).any(|backend| backend.name() == name);
+        if !known {
+            return Err(Error::Config(format!("no scrobble backend {}", name)));
+        }
+        let mut disabled = self.disabled.lock().unwrap();
+        match enabled {
+            true => disabled.remove(name),
+            false => disabled.insert(name.to_string()),
+        };
+        Ok(())
+    }
+
+    /// Names of the backends switched off
+    pub fn disabled_backends(&self) -> Vec<String> {
+        self.disabled.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Pauses or resumes scrobbling from every room. Listens played while
+    /// paused are neither recorded nor submitted later.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Whether `scrobble` is trustworthy enough to be submitted
+    pub fn confident(&self, scrobble: &Scrobble) -> bool {
+        scrobble.confidence.lowest() >= self.profiles.min_confidence().unwrap_or(self.min_confidence)
+    }
+
+    /// Holds scrobbles back for `delay` after they reach the threshold, so a
+    /// "don't scrobble" from the HTTP API or Telegram can still cancel them
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Sends scrobbles from every room to `backends` instead while party mode
+    /// is on. With `auto`, party mode turns on by itself while all rooms play
+    /// as one group. Without `backends`, scrobbles keep their usual routes.
+    pub fn with_party(mut self, backends: Vec<Box<dyn ScrobbleBackend>>, auto: bool) -> Self {
+        if auto && backends.is_empty() {
+            warn!("Automatic party mode has no party account (LASTFM_PARTY_SESSION_KEY), scrobbles keep their routes");
+        }
+        self.party = PartyMode::new(auto);
+        self.party_backends = backends;
+        self
+    }
+
+    pub fn party(&self) -> &PartyMode {
+        &self.party
+    }
+
+    /// Turns party mode on or off, or back to automatic with `None`, warning
+    /// when it has no backends to send scrobbles to
+    pub fn set_party(&self, on: Option<bool>) {
+        self.party.set(on);
+        if on == Some(true) && self.party_backends.is_empty() {
+            warn!("Party mode has no party account (LASTFM_PARTY_SESSION_KEY), scrobbles keep their routes");
+        }
+    }
+
+    /// Offers `profiles` to switch to, warning about routes to backends that
+    /// are not configured
+    pub fn with_profiles(mut self, profiles: &BTreeMap<String, ProfileConfig>) -> Self {
+        let profiles = profiles
+            .iter()
+            .map(|(name, profile)| {
+                let routes = profile.routes.as_ref().map(|routes| self.room_routes(routes));
+                (name.clone(), ProfileConfig { routes, ..profile.clone() })
+            })
+            .collect();
+        self.profiles = Profiles::new(profiles);
+        self
+    }
+
+    pub fn profiles(&self) -> &Profiles {
+        &self.profiles
+    }
+
+    /// Switches to the profile called `name`, or back to the configured
+    /// settings with `None`, along with its party mode. Returns the profile's
+    /// name as configured.
+    pub fn set_profile(&self, name: Option<&str>) -> Result<Option<String>> {
+        let name = self.profiles.set(name)?;
+        self.set_party(self.profiles.party());
+        Ok(name)
+    }
+
+    /// Holds `scrobble` for the grace period. Returns false when there is
+    /// none, in which case it should be submitted right away.
+    pub fn hold(&self, scrobble: Scrobble, queue_position: Option<u32>) -> bool {
+        if self.delay.is_zero() {
+            return false;
+        }
+        let due = Instant::now() + self.delay;
+        self.held.lock().unwrap().push(HeldScrobble { scrobble, queue_position, due });
+        true
+    }
+
+    /// Takes the held scrobbles of `device` whose grace period is over, or
+    /// all of them with `all`, oldest first, along with their queue position
+    pub fn take_held(&self, device: &str, all: bool) -> Vec<(Scrobble, Option<u32>)> {
+        let mut held = self.held.lock().unwrap();
+        let now = Instant::now();
+        let (taken, kept) = std::mem::take(&mut *held)
+            .into_iter()
+            .partition(|h| h.scrobble.device.eq_ignore_ascii_case(device) && (all || h.due <= now));
+        *held = kept;
+        taken.into_iter().map(|h: HeldScrobble| (h.scrobble, h.queue_position)).collect()
+    }
+
+    /// Cancels the most recently held scrobble from `room`, or from any room
+    pub fn cancel(&self, room: Option<&str>) -> Option<Scrobble> {
+        let mut held = self.held.lock().unwrap();
+        let index = held
+            .iter()
+            .rposition(|h| room.is_none_or(|room| self.room_of(&h.scrobble.device).eq_ignore_ascii_case(room)))?;
+        Some(held.remove(index).scrobble)
+    }
+
+    /// `scrobble` with its artists split as configured and its room filled in
+    pub(crate) fn prepare(&self, scrobble: &Scrobble) -> Scrobble {
+        let mut scrobble = scrobble.clone();
+        scrobble.split_artists(&self.artist_separators);
+        scrobble.room.get_or_insert_with(|| self.room_of(&scrobble.device));
+        scrobble
+    }
+
+    /// `scrobble` as the backends get it: with its room only when it is shared
+    fn outgoing(&self, scrobble: &Scrobble) -> Scrobble {
+        let room = match self.share_room {
+            true => Some(scrobble.room.clone().unwrap_or_else(|| self.room_of(&scrobble.device))),
+            false => None,
+        };
+        Scrobble { room, ..scrobble.clone() }
+    }
+
+    pub fn backend_names(&self) -> Vec<&str> {
+        self.backends.iter().map(|b| b.name()).collect()
+    }
+
+    /// Notes that scrobbles from `device` were played in `room`, for routing
+    pub fn add_device(&self, device: &str, room: &str) {
+        self.rooms.lock().unwrap().insert(device.to_string(), room.to_string());
+    }
+
+    /// The room of `device`; devices not added are taken to be named after
+    /// their room
+    fn room_of(&self, device: &str) -> String {
+        self.rooms.lock().unwrap().get(device).cloned().unwrap_or_else(|| device.to_string())
+    }
+
+    /// Names of the backends that receive updates from `room`
+    pub fn routed_names(&self, room: &str) -> Vec<&str> {
+        self.routed(room).into_iter().map(|backend| backend.name()).collect()
+    }
+
+    /// The backends that receive updates from `device`, or from a room,
+    /// leaving out those switched off
+    fn routed(&self, device: &str) -> Vec<&dyn ScrobbleBackend> {
+        let disabled = self.disabled.lock().unwrap();
+        let enabled = |backend: &&dyn ScrobbleBackend| !disabled.contains(backend.name());
+        // Without a party account the usual routes apply, rather than none
+        if self.party.active() && !self.party_backends.is_empty() {
+            return self.party_backends.iter().map(|backend| backend.as_ref()).filter(enabled).collect();
+        }
+        // A profile's routes replace those of every household
+        let profile_routes = self.profiles.routes();
+        let routes = profile_routes.as_ref().unwrap_or(&self.routes);
+        let route = routes.get(&self.room_of(device).to_lowercase());
+        self.backends
+            .iter()
+            .map(|backend| backend.as_ref())
+            .filter(|backend| route.is_none_or(|names| names.iter().any(|name| name == backend.name())))
+            .filter(enabled)
+            .collect()
+    }
+
+    pub async fn now_playing(&self, scrobble: &Scrobble) {
+        if self.paused() {
+            return;
+        }
+        let scrobble = &self.prepare(scrobble);
+        if !self.now_playing_due(scrobble) {
+            debug!("Skipping now playing update for {} - {}, sent recently", scrobble.artist, scrobble.title);
+            return;
+        }
+        let scrobble = &self.outgoing(scrobble);
+        if let Some(worker) = &self.worker {
+            for backend in self.routed(&scrobble.device) {
+                let job = Job { priority: Priority::NowPlaying, scrobble: scrobble.clone(), delivery: None };
+                worker.push(backend.name(), job);
+            }
+            return;
+        }
+        for backend in self.routed(&scrobble.device) {
+            self.send_now_playing(backend, scrobble).await;
+        }
+    }
+
+    async fn send_now_playing(&self, backend: &dyn ScrobbleBackend, scrobble: &Scrobble) -> bool {
+        let result = backend.now_playing(scrobble).await;
+        self.record_call(backend, result.is_ok()).await;
+        if let Err(e) = &result {
+            warn!("{}: now playing update failed: {}", backend.name(), e);
+        }
+        result.is_ok()
+    }
+
+    /// Whether a now-playing update for `scrobble` is due, because its device
+    /// moved on to another track or the last update is old enough. Notes the
+    /// update as sent if so.
+    fn now_playing_due(&self, scrobble: &Scrobble) -> bool {
+        let fingerprint = scrobble.fingerprint();
+        let mut sent = self.now_playing_sent.lock().unwrap();
+        match sent.get(&scrobble.device) {
+            Some((last, at)) if *last == fingerprint && at.elapsed() < self.now_playing_interval => false,
+            _ => {
+                sent.insert(scrobble.device.clone(), (fingerprint, Instant::now()));
+                true
+            }
+        }
+    }
+
+    pub async fn love(&self, scrobble: &Scrobble) -> Result<()> {
+        let scrobble = &self.outgoing(&self.prepare(scrobble));
+        for backend in self.routed(&scrobble.device) {
+            let result = backend.love(scrobble).await;
+            self.record_call(backend, result.is_ok()).await;
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Records `scrobble` and submits it to every backend, unless it is a
+    /// duplicate of a recorded one, blocklisted or scrobbling is paused.
+    /// Corrections learned from Last.fm are applied first. Returns whether
+    /// it was submitted, queued for the worker, or recorded for later while
+    /// the internet is down.
+    pub async fn submit(&self, db: &TrackDatabase, scrobble: &Scrobble) -> Result<bool> {
+        if self.paused() {
+            info!("Not scrobbling {} - {}, scrobbling is paused", scrobble.artist, scrobble.title);
+            return Ok(false);
+        }
+        let mut scrobble = self.prepare(scrobble);
+        if let Some((artist, title)) = db.correction(&scrobble.artist, &scrobble.title).await? {
+            (scrobble.artist, scrobble.title) = (artist, title);
+        }
+        let scrobble = &scrobble;
+        if db.blocklisted(&scrobble.artist, &scrobble.title).await? {
+            info!("Not scrobbling {} - {}, it is blocklisted", scrobble.artist, scrobble.title);
+            return Ok(false);
+        }
+        if !db.record_scrobble(scrobble).await? {
+            return Ok(false);
+        }
+
+        let routed = self.routed(&scrobble.device).len();
+        if routed > 0 && self.connectivity.as_ref().is_some_and(|connectivity| connectivity.offline()) {
+            return self.defer(db, scrobble).await;
+        }
+        if let Some(worker) = &self.worker {
+            let (delivery, _) = Delivery::new(scrobble, routed, Some(db.clone()));
+            self.enqueue(worker, Priority::Fresh, scrobble, delivery);
+            return Ok(true);
+        }
+        let accepted = self.scrobble(scrobble).await;
+        self.settle(db, scrobble, routed, accepted).await?;
+        Ok(true)
+    }
+
+    /// Records how a recorded `scrobble` fared with the `routed` backends,
+    /// of which `accepted` took it
+    async fn settle(&self, db: &TrackDatabase, scrobble: &Scrobble, routed: usize, accepted: usize) -> Result<()> {
+        let failures = routed.saturating_sub(accepted);
+        // Every backend failing at once is most likely the internet
+        if let (0, true, Some(connectivity)) = (accepted, failures > 0, &self.connectivity) {
+            if !connectivity.check().await {
+                self.defer(db, scrobble).await?;
+                return Ok(());
+            }
+        }
+        if failures > 0 {
+            db.record_scrobble_failures(scrobble, failures).await?;
+        }
+        Ok(())
+    }
+
+    /// Queues `scrobble` for every backend it is routed to
+    fn enqueue(&self, worker: &Worker, priority: Priority, scrobble: &Scrobble, delivery: Arc<Delivery>) {
+        let outgoing = self.outgoing(scrobble);
+        for backend in self.routed(&scrobble.device) {
+            let job = Job { priority, scrobble: outgoing.clone(), delivery: Some(delivery.clone()) };
+            worker.push(backend.name(), job);
+        }
+    }
+
+    /// Submits a listen recorded while the internet was down, after the
+    /// fresh ones when there is a worker. Returns the number of backends
+    /// that accepted it.
+    pub async fn scrobble_backlog(&self, scrobble: &Scrobble) -> usize {
+        let Some(worker) = &self.worker else {
+            return self.scrobble(scrobble).await;
+        };
+        let routed = self.routed(&scrobble.device).len();
+        if routed == 0 {
+            return 0;
+        }
+        let (delivery, accepted) = Delivery::new(scrobble, routed, None);
+        self.enqueue(worker, Priority::Backlog, scrobble, delivery);
+        accepted.await.unwrap_or_default()
+    }
+
+    /// Makes the queued submissions, each backend's with the configured
+    /// concurrency, until the task is dropped. Returns right away without a
+    /// worker.
+    pub async fn run_worker(self: Arc<Self>) {
+        let Some(worker) = &self.worker else {
+            return;
+        };
+        let mut tasks = JoinSet::new();
+        for backend in self.backends.iter().chain(&self.party_backends) {
+            for _ in 0..worker.concurrency() {
+                let scrobbler = self.clone();
+                let name = backend.name().to_string();
+                tasks.spawn(async move { scrobbler.work(&name).await });
+            }
+        }
+        while tasks.join_next().await.is_some() {}
+    }
+
+    async fn work(&self, name: &str) {
+        let Some(worker) = &self.worker else {
+            return;
+        };
+        let Some(backend) = self.backends.iter().chain(&self.party_backends).find(|backend| backend.name() == name)
+        else {
+            return;
+        };
+        loop {
+            let job = worker.pop(name).await;
+            let ok = match worker.allow(name) {
+                true => {
+                    let ok = match job.delivery {
+                        Some(_) => self.send_scrobble(backend.as_ref(), &job.scrobble).await,
+                        None => self.send_now_playing(backend.as_ref(), &job.scrobble).await,
+                    };
+                    worker.record(name, ok);
+                    ok
+                }
+                false => {
+                    debug!("{}: left alone after failing, not sending {}", name, job.scrobble.title);
+                    false
+                }
+            };
+            if let Some(delivery) = &job.delivery {
+                if let (Some(accepted), Some(db)) = (delivery.answered(ok), &delivery.db) {
+                    if let Err(e) = self.settle(db, &delivery.scrobble, delivery.routed, accepted).await {
+                        warn!("Failed to record how {} fared: {}", delivery.scrobble.title, e);
+                    }
+                }
+            }
+            worker.done();
+        }
+    }
+
+    /// The submissions waiting for each backend with each priority; empty
+    /// without a worker
+    pub fn queued(&self) -> Vec<(String, Priority, usize)> {
+        self.worker.as_ref().map(Worker::queued).unwrap_or_default()
+    }
+
+    /// Submissions queued or in progress
+    pub fn pending_submissions(&self) -> usize {
+        self.worker.as_ref().map_or(0, Worker::pending)
+    }
+
+    /// Waits up to `timeout` for the queued submissions to be made. Returns
+    /// whether they all were.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let Some(worker) = &self.worker else {
+            return true;
+        };
+        let deadline = Instant::now() + timeout;
+        while worker.pending() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        true
+    }
+
+    /// Keeps a recorded `scrobble` for when the internet is back
+    async fn defer(&self, db: &TrackDatabase, scrobble: &Scrobble) -> Result<bool> {
+        info!("Offline, keeping {} - {} to scrobble once the internet is back", scrobble.artist, scrobble.title);
+        db.defer_scrobble(scrobble).await?;
+        Ok(true)
+    }
+
+    /// Returns the number of backends that accepted the scrobble
+    pub async fn scrobble(&self, scrobble: &Scrobble) -> usize {
+        let scrobble = &self.outgoing(scrobble);
+        let mut accepted = 0;
+        for backend in self.routed(&scrobble.device) {
+            if self.send_scrobble(backend, scrobble).await {
+                accepted += 1;
+            }
+        }
+        accepted
+    }
+
+    async fn send_scrobble(&self, backend: &dyn ScrobbleBackend, scrobble: &Scrobble) -> bool {
+        let result = self
+            .retry
+            .run(
+                || backend.scrobble(scrobble),
+                |e, attempt| {
+                    let retries = self.retry.max_retries;
+                    warn!("{}: scrobble failed ({}), retrying ({}/{})", backend.name(), e, attempt, retries);
+                    true
+                },
+            )
+            .await;
+        self.record_call(backend, result.is_ok()).await;
+        match result {
+            Ok(()) => {
+                info!("{}: scrobbled {} - {}", backend.name(), scrobble.artist, scrobble.title);
+                true
+            }
+            Err(e) => {
+                warn!("{}: scrobble failed: {}", backend.name(), e);
+                false
+            }
+        }
+    }
+
+    async fn record_call(&self, backend: &dyn ScrobbleBackend, ok: bool) {
+        let Some(db) = &self.usage else {
+            return;
+        };
+        if let Err(e) = db.record_api_call(backend.name(), ok).await {
+            warn!("{}: failed to record API call: {}", backend.name(), e);
+            return;
+        }
+
+        let Some(limit) = backend.rate_limit() else {
+            return;
+        };
+        let calls = match db.api_usage(limit.per).await {
+            Ok(usage) => usage
+                .into_iter()
+                .find(|(name, _, _)| name == backend.name())
+                .map_or(0, |(_, calls, _)| calls as u64),
+            Err(e) => {
+                warn!("{}: failed to read API usage: {}", backend.name(), e);
+                return;
+            }
+        };
+        if calls * 100 >= limit.calls * RATE_LIMIT_WARNING_PERCENT {
+            warn!(
+                "{}: {} API calls in the last {}s, close to the limit of {}",
+                backend.name(),
+                calls,
+                limit.per.as_secs(),
+                limit.calls
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn scrobble() -> Scrobble {
+        Scrobble {
+            duration: Some(Duration::from_secs(369)),
+            ..Scrobble::new("Kitchen", "Daft Punk", "Get Lucky", 1_700_000_000)
+        }
+    }
+
+    #[test]
+    fn test_same_play_elsewhere() {
+        let kitchen = scrobble();
+        let office = Scrobble {
+            device: "Office".to_string(),
+            artist: "daft punk".to_string(),
+            title: "Get Lucky!".to_string(),
+            started_at: kitchen.started_at + 5,
+            ..scrobble()
+        };
+        assert!(kitchen.same_play_elsewhere(&office));
+
+        // The same device playing the track again is a new play
+        let repeat = Scrobble { started_at: kitchen.started_at + 60, ..scrobble() };
+        assert!(!kitchen.same_play_elsewhere(&repeat));
+
+        let later = Scrobble { started_at: kitchen.started_at + 600, ..office };
+        assert!(!kitchen.same_play_elsewhere(&later));
+    }
+
+    #[test]
+    fn test_held_scrobbles() {
+        assert!(!Scrobbler::default().hold(scrobble(), None));
+
+        let scrobbler = Scrobbler::default().with_delay(Duration::from_secs(60));
+        assert!(scrobbler.hold(scrobble(), Some(3)));
+        assert!(scrobbler.hold(Scrobble { title: "Instant Crush".to_string(), ..scrobble() }, Some(4)));
+        assert!(scrobbler.take_held("Kitchen", false).is_empty());
+        assert!(scrobbler.cancel(Some("Office")).is_none());
+
+        assert_eq!(scrobbler.cancel(Some("kitchen")).unwrap().title, "Instant Crush");
+        assert_eq!(scrobbler.take_held("Kitchen", true), vec![(scrobble(), Some(3))]);
+        assert!(scrobbler.cancel(None).is_none());
+    }
+
+    #[test]
+    fn test_split_artists() {
+        let separators = vec![", ".to_string(), " & ".to_string(), " feat. ".to_string()];
+        let mut multiple = Scrobble { artist: "Artist A, Artist B FEAT. Artist C & Artist B".to_string(), ..scrobble() };
+        multiple.split_artists(&separators);
+        assert_eq!(multiple.artist, "Artist A");
+        assert_eq!(multiple.featured, vec!["Artist B".to_string(), "Artist C".to_string()]);
+
+        let mut single = scrobble();
+        single.split_artists(&separators);
+        assert_eq!((single.artist.as_str(), single.featured.len()), ("Daft Punk", 0));
+    }
+
+    #[tokio::test]
+    async fn test_scrobbler_continues_past_failing_backend() {
+        let mut failing = MockScrobbleBackend::new();
+        failing.expect_name().return_const("failing".to_string());
+        failing
+            .expect_scrobble()
+            .times(1)
+            .returning(|_| Err(Error::Scrobble("service unavailable".to_string())));
+
+        let mut working = MockScrobbleBackend::new();
+        working.expect_name().return_const("working".to_string());
+        working
+            .expect_scrobble()
+            .withf(|s| s.title == "Get Lucky")
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let scrobbler = Scrobbler::new(vec![Box::new(failing), Box::new(working)]);
+        assert_eq!(scrobbler.scrobble(&scrobble()).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_scrobbler_logs_api_usage() {
+        let mut backend = MockScrobbleBackend::new();
+        backend.expect_name().return_const("lastfm".to_string());
+        backend.expect_now_playing().returning(|_| Ok(()));
+        backend
+            .expect_scrobble()
+            .returning(|_| Err(Error::Scrobble("rate limited".to_string())));
+        backend
+            .expect_rate_limit()
+            .return_const(Some(RateLimit { calls: 2, per: Duration::from_secs(60) }));
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobbler = Scrobbler::new(vec![Box::new(backend)]).with_usage_log(db.clone());
+        scrobbler.now_playing(&scrobble()).await;
+        scrobbler.scrobble(&scrobble()).await;
+
+        let usage = db.api_usage(Duration::from_secs(60)).await.unwrap();
+        assert_eq!(usage, vec![("lastfm".to_string(), 2, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_now_playing_is_throttled_per_track() {
+        let mut backend = MockScrobbleBackend::new();
+        backend.expect_name().return_const("lastfm".to_string());
+        backend.expect_now_playing().times(3).returning(|_| Ok(()));
+
+        let scrobbler = Scrobbler::new(vec![Box::new(backend)]).with_now_playing_interval(Duration::from_secs(60));
+        scrobbler.now_playing(&scrobble()).await;
+        scrobbler.now_playing(&scrobble()).await;
+        // Another device, then another track
+        scrobbler.now_playing(&Scrobble { device: "Office".to_string(), ..scrobble() }).await;
+        scrobbler.now_playing(&Scrobble { title: "Instant Crush".to_string(), ..scrobble() }).await;
+    }
+
+    #[tokio::test]
+    async fn test_disabled_backend_receives_nothing() {
+        let mut lastfm = MockScrobbleBackend::new();
+        lastfm.expect_name().return_const("lastfm".to_string());
+        lastfm.expect_scrobble().times(2).returning(|_| Ok(()));
+        let mut listenbrainz = MockScrobbleBackend::new();
+        listenbrainz.expect_name().return_const("listenbrainz".to_string());
+        listenbrainz.expect_scrobble().times(1).returning(|_| Ok(()));
+
+        let scrobbler = Scrobbler::new(vec![Box::new(lastfm), Box::new(listenbrainz)])
+            .with_disabled_backends(["listenbrainz".to_string()]);
+        assert_eq!(scrobbler.scrobble(&scrobble()).await, 1);
+        assert!(matches!(scrobbler.set_backend_enabled("librefm", true), Err(Error::Config(_))));
+
+        scrobbler.set_backend_enabled("listenbrainz", true).unwrap();
+        assert_eq!(scrobbler.scrobble(&scrobble()).await, 2);
+        assert!(scrobbler.disabled_backends().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scrobbler_routes_by_room() {
+        let mut lastfm = MockScrobbleBackend::new();
+        lastfm.expect_name().return_const("lastfm".to_string());
+        lastfm.expect_scrobble().times(1).returning(|_| Ok(()));
+
+        let mut telegram = MockScrobbleBackend::new();
+        telegram.expect_name().return_const("telegram".to_string());
+        telegram.expect_scrobble().never();
+
+        let routes = BTreeMap::from([
+            ("kitchen".to_string(), vec!["lastfm".to_string()]),
+            ("Kids Room".to_string(), Vec::new()),
+        ]);
+        let kitchen_off = BTreeMap::from([("Kitchen".to_string(), Vec::new())]);
+        let away = ProfileConfig { routes: Some(kitchen_off), ..ProfileConfig::default() };
+        let scrobbler = Scrobbler::new(vec![Box::new(lastfm), Box::new(telegram)])
+            .with_routes(&routes)
+            .with_profiles(&BTreeMap::from([("away".to_string(), away)]));
+        assert_eq!(scrobbler.scrobble(&scrobble()).await, 1);
+
+        let kids_room = Scrobble { device: "Kids Room".to_string(), ..scrobble() };
+        assert_eq!(scrobbler.scrobble(&kids_room).await, 0);
+
+        // Polled devices are routed by their room
+        scrobbler.add_device("10.0.0.5 - Sonos One - RINCON_5", "Kids Room");
+        let device = Scrobble { device: "10.0.0.5 - Sonos One - RINCON_5".to_string(), ..scrobble() };
+        assert_eq!(scrobbler.scrobble(&device).await, 0);
+
+        // The profile's routes replace the configured ones
+        scrobbler.set_profile(Some("Away")).unwrap();
+        assert_eq!(scrobbler.scrobble(&scrobble()).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_households_are_routed_separately() {
+        let mut lastfm = MockScrobbleBackend::new();
+        lastfm.expect_name().return_const("lastfm".to_string());
+        lastfm.expect_scrobble().times(2).returning(|_| Ok(()));
+        let mut listenbrainz = MockScrobbleBackend::new();
+        listenbrainz.expect_name().return_const("listenbrainz".to_string());
+        listenbrainz.expect_scrobble().times(2).returning(|_| Ok(()));
+
+        let home = BTreeMap::from([("Kitchen".to_string(), vec!["lastfm".to_string()])]);
+        let office = BTreeMap::from([("Office".to_string(), vec!["listenbrainz".to_string()])]);
+        let office_off = BTreeMap::from([("office/Office".to_string(), Vec::new())]);
+        let away = ProfileConfig { routes: Some(office_off), ..ProfileConfig::default() };
+        let scrobbler = Scrobbler::new(vec![Box::new(lastfm), Box::new(listenbrainz)])
+            .with_routes(&home)
+            .with_household_routes("office", &office)
+            .with_profiles(&BTreeMap::from([("away".to_string(), away)]));
+        assert_eq!(scrobbler.scrobble(&scrobble()).await, 1);
+
+        // The office's kitchen isn't routed, unlike the one at home
+        let device = "10.8.0.20 - Sonos One - RINCON_20";
+        scrobbler.add_device(device, "office/Kitchen");
+        assert_eq!(scrobbler.scrobble(&Scrobble { device: device.to_string(), ..scrobble() }).await, 2);
+        scrobbler.add_device(device, "office/Office");
+        assert_eq!(scrobbler.scrobble(&Scrobble { device: device.to_string(), ..scrobble() }).await, 1);
+
+        // A profile's routes apply to the office too
+        scrobbler.set_profile(Some("away")).unwrap();
+        assert_eq!(scrobbler.scrobble(&Scrobble { device: device.to_string(), ..scrobble() }).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_room_is_recorded_and_shared_on_request() {
+        use crate::stats::{HistorySort, ListenFilter};
+
+        let device = "10.0.0.5 - Sonos One - RINCON_5";
+        let played = |started_at| Scrobble { device: device.to_string(), started_at, ..scrobble() };
+        let mut private = MockScrobbleBackend::new();
+        private.expect_name().return_const("lastfm".to_string());
+        private.expect_scrobble().withf(|s| s.room.is_none()).times(1).returning(|_| Ok(()));
+        let mut shared = MockScrobbleBackend::new();
+        shared.expect_name().return_const("listenbrainz".to_string());
+        shared
+            .expect_scrobble()
+            .withf(|s| s.room.as_deref() == Some("Office"))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobbler = Scrobbler::new(vec![Box::new(private)]);
+        scrobbler.add_device(device, "Office");
+        assert!(scrobbler.submit(&db, &played(1_700_000_000)).await.unwrap());
+        let scrobbler = Scrobbler::new(vec![Box::new(shared)]).with_shared_room(true);
+        scrobbler.add_device(device, "Office");
+        assert!(scrobbler.submit(&db, &played(1_700_000_600)).await.unwrap());
+
+        let filter = ListenFilter { room: Some("office".to_string()), ..ListenFilter::default() };
+        let listens = db.history(&filter, HistorySort::Newest, None, 10).await.unwrap();
+        assert_eq!(listens.len(), 2);
+        assert!(listens.iter().all(|listen| listen.room == "Office"));
+    }
+
+    #[tokio::test]
+    async fn test_party_mode_needs_a_party_backend() {
+        let personal = |times| {
+            let mut lastfm = MockScrobbleBackend::new();
+            lastfm.expect_name().return_const("lastfm".to_string());
+            lastfm.expect_scrobble().times(times).returning(|_| Ok(()));
+            Box::new(lastfm)
+        };
+        let mut party = MockScrobbleBackend::new();
+        party.expect_name().return_const("lastfm-party".to_string());
+        party.expect_scrobble().times(1).returning(|_| Ok(()));
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobbler = Scrobbler::new(vec![personal(0)]).with_party(vec![Box::new(party)], false);
+        scrobbler.set_party(Some(true));
+        assert!(scrobbler.submit(&db, &scrobble()).await.unwrap());
+
+        let scrobbler = Scrobbler::new(vec![personal(1)]).with_party(Vec::new(), false);
+        scrobbler.set_party(Some(true));
+        assert!(scrobbler.party().active());
+        assert_eq!(scrobbler.routed_names("Kitchen"), vec!["lastfm"]);
+        let later = Scrobble { started_at: 1_700_000_600, ..scrobble() };
+        assert!(scrobbler.submit(&db, &later).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_paused_scrobbler_drops_listens() {
+        let mut lastfm = MockScrobbleBackend::new();
+        lastfm.expect_name().return_const("lastfm".to_string());
+        lastfm.expect_now_playing().never();
+        lastfm.expect_scrobble().times(1).returning(|_| Ok(()));
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobbler = Scrobbler::new(vec![Box::new(lastfm)]);
+        scrobbler.set_paused(true);
+        scrobbler.now_playing(&scrobble()).await;
+        assert!(!scrobbler.submit(&db, &scrobble()).await.unwrap());
+
+        scrobbler.set_paused(false);
+        assert!(scrobbler.submit(&db, &scrobble()).await.unwrap());
+    }
+}
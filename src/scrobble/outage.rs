@@ -0,0 +1,138 @@
+use crate::error::Result;
+use crate::scrobble::Scrobbler;
+use crate::sonos::TrackDatabase;
+use crate::status::Status;
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// How often the internet is checked for while listens wait for it
+pub const OUTAGE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tells whether the internet is reachable by opening a TCP connection to a
+/// well-known host, which is cheap enough to do whenever every backend fails
+pub struct Connectivity {
+    /// `name:port` to connect to
+    host: String,
+    offline: AtomicBool,
+}
+
+impl Connectivity {
+    pub fn new(host: &str) -> Self {
+        Self { host: host.to_string(), offline: AtomicBool::new(false) }
+    }
+
+    /// Whether the last check found the internet down
+    pub fn offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    /// Checks now, logging when the internet goes down or comes back.
+    /// Returns whether it is reachable.
+    pub async fn check(&self) -> bool {
+        let connected = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&self.host)).await;
+        let online = matches!(connected, Ok(Ok(_)));
+        match (self.offline.swap(!online, Ordering::Relaxed), online) {
+            (false, false) => warn!(
+                "Cannot reach {}, the internet seems to be down; recording listens locally until it is back",
+                self.host
+            ),
+            (true, true) => info!("{} is reachable again", self.host),
+            _ => {}
+        }
+        online
+    }
+}
+
+/// Submits the listens recorded while the internet was down. Returns how
+/// many there were.
+pub async fn submit_deferred(db: &TrackDatabase, scrobbler: &Scrobbler) -> Result<usize> {
+    let deferred = db.deferred_scrobbles().await?;
+    for scrobble in &deferred {
+        let accepted = scrobbler.scrobble_backlog(scrobble).await;
+        db.record_scrobble_failures(scrobble, scrobbler.routed(&scrobble.device).len().saturating_sub(accepted))
+            .await?;
+    }
+    Ok(deferred.len())
+}
+
+/// Every `interval`, reports the listens waiting for the internet in
+/// `status`, and submits them once `connectivity` finds it back. Runs until
+/// the task is dropped.
+pub async fn submit_when_online(
+    db: TrackDatabase,
+    scrobbler: Arc<Scrobbler>,
+    connectivity: Arc<Connectivity>,
+    status: Arc<Status>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let waiting = match db.deferred_scrobbles().await {
+            Ok(deferred) => deferred.len(),
+            Err(e) => {
+                warn!("Failed to read listens recorded while offline: {}", e);
+                continue;
+            }
+        };
+        status.set_listens_deferred(waiting);
+        if (waiting == 0 && !connectivity.offline()) || !connectivity.check().await {
+            continue;
+        }
+        match submit_deferred(&db, &scrobbler).await {
+            Ok(0) => {}
+            Ok(submitted) => {
+                info!("Submitted {} listens recorded while the internet was down", submitted);
+                status.set_listens_deferred(0);
+            }
+            Err(e) => warn!("Failed to submit listens recorded while offline: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::scrobble::{MockScrobbleBackend, Scrobble};
+    use std::sync::atomic::AtomicU32;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_listens_are_deferred_while_offline() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unreachable = listener.local_addr().unwrap();
+        drop(listener);
+        let connectivity = Arc::new(Connectivity::new(&unreachable.to_string()));
+
+        let mut backend = MockScrobbleBackend::new();
+        backend.expect_name().return_const("lastfm".to_string());
+        let calls = AtomicU32::new(0);
+        backend.expect_scrobble().times(3).returning(move |_| match calls.fetch_add(1, Ordering::Relaxed) {
+            0 => Err(Error::Scrobble("connection refused".to_string())),
+            _ => Ok(()),
+        });
+        let scrobbler = Scrobbler::new(vec![Box::new(backend)]).with_connectivity(connectivity.clone());
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobble = Scrobble {
+            room: Some("Kitchen".to_string()),
+            ..Scrobble::new("Kitchen", "Massive Attack", "Teardrop", 1_700_000_000)
+        };
+        assert!(scrobbler.submit(&db, &scrobble).await.unwrap());
+        assert!(connectivity.offline());
+        // Offline, so not even tried
+        let later = Scrobble { title: "Angel".to_string(), started_at: scrobble.started_at + 400, ..scrobble.clone() };
+        assert!(scrobbler.submit(&db, &later).await.unwrap());
+        assert_eq!(db.deferred_scrobbles().await.unwrap(), vec![scrobble.clone(), later]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        assert!(Connectivity::new(&listener.local_addr().unwrap().to_string()).check().await);
+        assert_eq!(submit_deferred(&db, &scrobbler).await.unwrap(), 2);
+        assert!(db.deferred_scrobbles().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How recently rooms must have been handled to count towards a party
+const PARTY_WINDOW: Duration = Duration::from_secs(30 * 60);
+/// Rooms handled within [`PARTY_WINDOW`] that make it a party
+const PARTY_ROOMS: usize = 3;
+
+/// Whether scrobbles go to the party backends instead of the personal ones.
+/// Party mode is switched on and off by hand, or follows whether every room
+/// is grouped with the others and how many rooms people handle.
+#[derive(Debug, Default)]
+pub struct PartyMode {
+    auto: bool,
+    /// Set by hand; overrides the automatic switch until reset
+    forced: Mutex<Option<bool>>,
+    /// Whether each device follows another one's playback
+    grouped: Mutex<BTreeMap<String, bool>>,
+    /// When each room was last handled by hand
+    handled: Mutex<BTreeMap<String, Instant>>,
+}
+
+impl PartyMode {
+    /// With `auto`, party mode is on while all rooms play as one group, or
+    /// while several rooms are turned up, down or skipped by hand
+    pub fn new(auto: bool) -> Self {
+        Self { auto, ..Self::default() }
+    }
+
+    /// Turns party mode on or off, or back to automatic with `None`
+    pub fn set(&self, on: Option<bool>) {
+        *self.forced.lock().unwrap() = on;
+    }
+
+    /// Notes whether `device` is grouped with and follows another device
+    pub fn device_grouped(&self, device: &str, grouped: bool) {
+        self.grouped.lock().unwrap().insert(device.to_string(), grouped);
+    }
+
+    /// Notes that someone handled `room`, e.g. turned it up
+    pub fn interaction(&self, room: &str) {
+        self.interaction_at(room, Instant::now());
+    }
+
+    fn interaction_at(&self, room: &str, at: Instant) {
+        self.handled.lock().unwrap().insert(room.to_string(), at);
+    }
+
+    pub fn active(&self) -> bool {
+        if let Some(on) = *self.forced.lock().unwrap() {
+            return on;
+        }
+        if !self.auto {
+            return false;
+        }
+        // One coordinator, every other device following it
+        let grouped = self.grouped.lock().unwrap();
+        let all_grouped = grouped.len() > 1 && grouped.values().filter(|grouped| !**grouped).count() == 1;
+        let handled = self.handled.lock().unwrap();
+        all_grouped || handled.values().filter(|at| at.elapsed() < PARTY_WINDOW).count() >= PARTY_ROOMS
+    }
+
+    /// "on", "off", and whether that was set by hand
+    pub fn describe(&self) -> String {
+        let state = if self.active() { "on" } else { "off" };
+        match *self.forced.lock().unwrap() {
+            Some(_) => format!("Party mode is {}", state),
+            None => format!("Party mode is {} (automatic)", state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_party_when_all_rooms_grouped() {
+        let party = PartyMode::new(true);
+        party.device_grouped("Kitchen", false);
+        party.device_grouped("Office", false);
+        assert!(!party.active());
+
+        party.device_grouped("Office", true);
+        assert!(party.active());
+
+        party.set(Some(false));
+        assert!(!party.active());
+        party.set(None);
+        assert!(party.active());
+    }
+
+    #[test]
+    fn test_party_when_rooms_are_handled() {
+        let party = PartyMode::new(true);
+        party.interaction("Kitchen");
+        party.interaction("Patio");
+        party.interaction_at("Office", Instant::now() - PARTY_WINDOW);
+        assert!(!party.active());
+
+        party.interaction("Office");
+        assert!(party.active());
+        assert!(!PartyMode::new(false).active());
+    }
+}
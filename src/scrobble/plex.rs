@@ -0,0 +1,97 @@
+use crate::config::PlexConfig;
+use crate::error::{Error, Result};
+use crate::scrobble::{Scrobble, ScrobbleBackend};
+use async_trait::async_trait;
+use log::debug;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+/// Plex's name for its media library, which `/:/scrobble` needs
+const LIBRARY_IDENTIFIER: &str = "com.plexapp.plugins.library";
+
+/// Marks tracks played from a Plex library as played on the Plex server,
+/// which increments their play count. Tracks from elsewhere are skipped.
+pub struct Plex {
+    url: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl Plex {
+    pub fn new(config: &PlexConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| Error::Scrobble(e.to_string()))?;
+
+        Ok(Self { url: config.url.trim_end_matches('/').to_string(), token: config.token.clone(), client })
+    }
+}
+
+#[async_trait]
+impl ScrobbleBackend for Plex {
+    fn name(&self) -> &str {
+        "plex"
+    }
+
+    async fn now_playing(&self, _scrobble: &Scrobble) -> Result<()> {
+        Ok(())
+    }
+
+    async fn scrobble(&self, scrobble: &Scrobble) -> Result<()> {
+        let Some(key) = scrobble.track_uri.as_deref().and_then(rating_key) else {
+            debug!("Plex: {} - {} wasn't played from Plex", scrobble.artist, scrobble.title);
+            return Ok(());
+        };
+        let response = self
+            .client
+            .get(format!("{}/:/scrobble", self.url))
+            .header("X-Plex-Token", &self.token)
+            .query(&[("key", key.as_str()), ("identifier", LIBRARY_IDENTIFIER)])
+            .send()
+            .await
+            .map_err(|e| Error::Scrobble(format!("plex: {}", e)))?;
+        if !response.status().is_success() {
+            return Err(Error::Scrobble(format!("plex: marking {} as played: {}", key, response.status())));
+        }
+        Ok(())
+    }
+}
+
+/// The library item id in the URI of a track played from Plex, e.g.
+/// `x-sonos-http:library%2fmetadata%2f12345.mp3?sid=212`
+fn rating_key(uri: &str) -> Option<String> {
+    let uri = percent_encoding::percent_decode_str(uri).decode_utf8_lossy();
+    let (_, rest) = uri.split_once("library/metadata/")?;
+    let key: String = rest.chars().take_while(char::is_ascii_digit).collect();
+    (!key.is_empty()).then_some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    #[tokio::test]
+    async fn test_mark_played() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/:/scrobble")
+            .match_header("X-Plex-Token", "token")
+            .match_query(Matcher::UrlEncoded("key".into(), "168474".into()))
+            .expect(1)
+            .create_async()
+            .await;
+
+        let plex = Plex::new(&PlexConfig { url: server.url(), token: "token".to_string() }).unwrap();
+        let mut scrobble = Scrobble {
+            track_uri: Some("x-sonos-http:library%2fmetadata%2f168474.mp3?sid=212&flags=8224&sn=5".to_string()),
+            ..Scrobble::new("Kitchen", "Massive Attack", "Teardrop", 1_700_000_000)
+        };
+        plex.scrobble(&scrobble).await.unwrap();
+
+        scrobble.track_uri = Some("x-sonos-spotify:spotify%3atrack%3a67Hna13dNDkZvBpTXRIaOJ".to_string());
+        plex.scrobble(&scrobble).await.unwrap();
+        mock.assert_async().await;
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::{ProfileConfig, ThresholdConfig};
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// The configured profiles and which one is active. Without an active
+/// profile every setting is as configured.
+#[derive(Debug, Default)]
+pub struct Profiles {
+    /// By name as configured, with routes keyed by lowercased room
+    profiles: BTreeMap<String, ProfileConfig>,
+    active: Mutex<Option<String>>,
+}
+
+impl Profiles {
+    pub fn new(profiles: BTreeMap<String, ProfileConfig>) -> Self {
+        Self { profiles, ..Self::default() }
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.profiles.keys().map(String::as_str).collect()
+    }
+
+    /// Name of the active profile, if any
+    pub fn active(&self) -> Option<String> {
+        self.active.lock().unwrap().clone()
+    }
+
+    /// Activates the profile called `name`, in any case, or none. Returns
+    /// its name as configured.
+    pub fn set(&self, name: Option<&str>) -> Result<Option<String>> {
+        let name = match name {
+            Some(name) => Some(self.find(name)?.to_string()),
+            None => None,
+        };
+        *self.active.lock().unwrap() = name.clone();
+        Ok(name)
+    }
+
+    /// The configured name of the profile called `name` in any case
+    pub fn find(&self, name: &str) -> Result<&str> {
+        self.profiles
+            .keys()
+            .find(|profile| profile.eq_ignore_ascii_case(name))
+            .map(String::as_str)
+            .ok_or_else(|| Error::Config(format!("no profile {}, use one of {}", name, self.names().join(", "))))
+    }
+
+    pub fn threshold(&self) -> Option<ThresholdConfig> {
+        self.with_active(|profile| profile.threshold.clone())
+    }
+
+    pub fn min_confidence(&self) -> Option<u8> {
+        self.with_active(|profile| profile.min_confidence)
+    }
+
+    /// Backend names per lowercased room, replacing the configured routes
+    pub fn routes(&self) -> Option<BTreeMap<String, Vec<String>>> {
+        self.with_active(|profile| profile.routes.clone())
+    }
+
+    /// Party mode while the active profile is; automatic when it doesn't say
+    pub fn party(&self) -> Option<bool> {
+        self.with_active(|profile| profile.party)
+    }
+
+    pub fn describe(&self) -> String {
+        match self.active() {
+            Some(name) => format!("Profile {} is active", name),
+            None => "No profile is active".to_string(),
+        }
+    }
+
+    fn with_active<T>(&self, setting: impl Fn(&ProfileConfig) -> Option<T>) -> Option<T> {
+        let active = self.active.lock().unwrap();
+        active.as_ref().and_then(|name| self.profiles.get(name)).and_then(setting)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active_profile_replaces_settings() {
+        let party = ProfileConfig { min_confidence: Some(80), party: Some(true), ..ProfileConfig::default() };
+        let profiles = Profiles::new(BTreeMap::from([
+            ("home".to_string(), ProfileConfig::default()),
+            ("Party".to_string(), party),
+        ]));
+        assert_eq!(profiles.min_confidence(), None);
+
+        assert_eq!(profiles.set(Some("party")).unwrap().as_deref(), Some("Party"));
+        assert_eq!((profiles.min_confidence(), profiles.party()), (Some(80), Some(true)));
+        assert_eq!(profiles.describe(), "Profile Party is active");
+
+        assert!(matches!(profiles.set(Some("away")), Err(Error::Config(_))));
+        assert_eq!(profiles.active().as_deref(), Some("Party"));
+        profiles.set(Some("home")).unwrap();
+        assert_eq!((profiles.min_confidence(), profiles.party()), (None, None));
+        profiles.set(None).unwrap();
+        assert_eq!(profiles.describe(), "No profile is active");
+    }
+}
@@ -0,0 +1,229 @@
+use crate::error::{Error, Result};
+use crate::scrobble::{Confidence, Discogs, MetadataSource, Scrobble, Scrobbler};
+use crate::sonos::TrackDatabase;
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A listen that reached the scrobble threshold without a usable artist or
+/// title, or with metadata too unreliable to scrobble. It is kept aside
+/// instead of being scrobbled as "Unknown Artist", and scrobbled later if
+/// enrichment finds the missing metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuarantinedListen {
+    /// Row id; 0 until stored
+    pub id: i64,
+    pub device: String,
+    pub uri: String,
+    pub track_info: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub started_at: i64,
+    pub duration: Option<Duration>,
+    pub confidence: Confidence,
+}
+
+impl QuarantinedListen {
+    pub fn reason(&self) -> &'static str {
+        match (&self.artist, &self.title) {
+            (None, None) => "missing artist and title",
+            (None, Some(_)) => "missing artist",
+            (Some(_), None) => "missing title",
+            (Some(_), Some(_)) => "low confidence",
+        }
+    }
+
+    /// Fills in the missing artist or title from what is known about the
+    /// listen, returning the completed scrobble
+    pub fn enrich(&self) -> Option<Scrobble> {
+        let guessed = Confidence::from_source(MetadataSource::Enrichment);
+        let ((artist, title), confidence) = match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => ((artist.clone(), title.clone()), self.confidence),
+            // Titles like "Artist - Title" from sources without separate fields
+            (None, Some(title)) => (split_artist_title(title).or_else(|| from_file_name(&self.uri))?, guessed),
+            _ => (from_file_name(&self.uri)?, guessed),
+        };
+
+        Some(Scrobble {
+            album: self.album.clone(),
+            duration: self.duration,
+            confidence,
+            track_uri: Some(self.uri.clone()),
+            ..Scrobble::new(self.device.clone(), artist, title, self.started_at)
+        })
+    }
+}
+
+fn split_artist_title(value: &str) -> Option<(String, String)> {
+    let (artist, title) = value.split_once(" - ")?;
+    let (artist, title) = (artist.trim(), title.trim());
+    (!artist.is_empty() && !title.is_empty()).then(|| (artist.to_string(), title.to_string()))
+}
+
+/// `Artist - Title.ext` file names, as found on NAS shares
+fn from_file_name(uri: &str) -> Option<(String, String)> {
+    let name = uri.split(['?', '#']).next()?.rsplit('/').next()?;
+    let name = percent_decode(name);
+    let stem = name.rsplit_once('.').map_or(name.as_str(), |(stem, _)| stem);
+    split_artist_title(stem)
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (bytes[i], hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Retries enrichment for every quarantined listen and scrobbles the ones
+/// that could be completed with enough confidence. Missing albums are looked
+/// up on `discogs`. Returns how many were released.
+pub async fn release_enriched(
+    db: &TrackDatabase,
+    scrobbler: &Scrobbler,
+    discogs: Option<&Discogs>,
+) -> Result<usize> {
+    let mut released = 0;
+    for listen in db.quarantined().await? {
+        let Some(mut scrobble) = listen.enrich().filter(|scrobble| scrobbler.confident(scrobble)) else {
+            continue;
+        };
+        if let (None, Some(discogs)) = (&scrobble.album, discogs) {
+            match discogs.release(db, &scrobble.artist, &scrobble.title).await {
+                Ok(release) => scrobble.album = release.map(|release| release.album),
+                // Scrobbled without an album rather than held back
+                Err(e) => warn!("{}", e),
+            }
+        }
+        info!("Released quarantined listen: {} - {}", scrobble.artist, scrobble.title);
+        scrobbler.submit(db, &scrobble).await?;
+        db.release_quarantined(listen.id).await?;
+        released += 1;
+    }
+    Ok(released)
+}
+
+/// Scrobbles the quarantined listen `id` as it is, however little its
+/// metadata is trusted, e.g. once its artist and title were checked by hand.
+/// Missing fields are still filled in where they can be. Returns the
+/// scrobbled track.
+pub async fn release_quarantined(db: &TrackDatabase, scrobbler: &Scrobbler, id: i64) -> Result<String> {
+    let listen = db
+        .quarantined()
+        .await?
+        .into_iter()
+        .find(|listen| listen.id == id)
+        .ok_or_else(|| Error::Config(format!("no quarantined listen {}", id)))?;
+    let scrobble = listen
+        .enrich()
+        .map(|scrobble| Scrobble { confidence: Confidence::default(), ..scrobble })
+        .ok_or_else(|| Error::Config(format!("quarantined listen {} is {}", id, listen.reason())))?;
+    info!("Released quarantined listen by hand: {} - {}", scrobble.artist, scrobble.title);
+    scrobbler.submit(db, &scrobble).await?;
+    db.release_quarantined(listen.id).await?;
+    Ok(format!("{} - {}", scrobble.artist, scrobble.title))
+}
+
+/// Runs [`release_enriched`] every `interval` until the task is dropped
+pub async fn enrich_periodically(
+    db: TrackDatabase,
+    scrobbler: Arc<Scrobbler>,
+    discogs: Option<Discogs>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = release_enriched(&db, &scrobbler, discogs.as_ref()).await {
+            warn!("Quarantine enrichment failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listen(uri: &str, artist: Option<&str>, title: Option<&str>) -> QuarantinedListen {
+        QuarantinedListen {
+            id: 0,
+            device: "Kitchen".to_string(),
+            uri: uri.to_string(),
+            track_info: "Unknown Track".to_string(),
+            artist: artist.map(str::to_string),
+            title: title.map(str::to_string),
+            album: None,
+            started_at: 1_700_000_000,
+            duration: None,
+            confidence: Confidence::default(),
+        }
+    }
+
+    #[test]
+    fn test_enrich() {
+        let scrobble = listen("x-sonos-http:track.mp3", None, Some("Bonobo - Kerala")).enrich().unwrap();
+        assert_eq!((scrobble.artist.as_str(), scrobble.title.as_str()), ("Bonobo", "Kerala"));
+
+        let file = listen("x-file-cifs://nas/Music/Nils%20Frahm%20-%20Says.flac", None, None);
+        assert_eq!(file.reason(), "missing artist and title");
+        let scrobble = file.enrich().unwrap();
+        assert_eq!((scrobble.artist.as_str(), scrobble.title.as_str()), ("Nils Frahm", "Says"));
+
+        assert!(listen("x-sonos-http:track.mp3", None, Some("Kerala")).enrich().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_release_enriched() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        db.quarantine_listen(&listen("x-file-cifs://nas/Moderat%20-%20Reminder.mp3", None, None))
+            .await
+            .unwrap();
+        db.quarantine_listen(&listen("x-sonos-http:track.mp3", None, Some("Reminder")))
+            .await
+            .unwrap();
+        assert_eq!(db.quarantined().await.unwrap().len(), 2);
+
+        // Guessed from the file name, which isn't trusted enough here
+        let cautious = Scrobbler::default().with_min_confidence(MetadataSource::StreamTitle.confidence());
+        assert_eq!(release_enriched(&db, &cautious, None).await.unwrap(), 0);
+
+        let released = release_enriched(&db, &Scrobbler::default(), None).await.unwrap();
+        assert_eq!(released, 1);
+        assert_eq!(db.quarantined().await.unwrap()[0].reason(), "missing artist");
+    }
+
+    #[tokio::test]
+    async fn test_release_low_confidence_by_hand() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let unsure = QuarantinedListen {
+            confidence: Confidence::from_source(MetadataSource::StreamTitle),
+            ..listen("x-rincon-mp3radio://stream", Some("Moderat"), Some("Reminder"))
+        };
+        db.quarantine_listen(&unsure).await.unwrap();
+        db.quarantine_listen(&listen("x-sonos-http:track.mp3", None, Some("Reminder"))).await.unwrap();
+        let quarantined = db.quarantined().await.unwrap();
+        let cautious = Scrobbler::default().with_min_confidence(MetadataSource::StreamFields.confidence());
+        assert_eq!(release_enriched(&db, &cautious, None).await.unwrap(), 0);
+
+        let low = quarantined.iter().find(|listen| listen.reason() == "low confidence").unwrap();
+        assert_eq!(release_quarantined(&db, &cautious, low.id).await.unwrap(), "Moderat - Reminder");
+        let missing = quarantined.iter().find(|listen| listen.reason() == "missing artist").unwrap();
+        assert!(matches!(release_quarantined(&db, &cautious, missing.id).await, Err(Error::Config(_))));
+        assert!(matches!(release_quarantined(&db, &cautious, low.id).await, Err(Error::Config(_))));
+        assert_eq!(db.quarantined().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::config::SubsonicConfig;
+use crate::error::{Error, Result};
+use crate::scrobble::{Scrobble, ScrobbleBackend};
+use async_trait::async_trait;
+use log::info;
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+/// Oldest API version with token authentication
+const API_VERSION: &str = "1.13.0";
+const CLIENT_NAME: &str = "sonos-scrobbler";
+/// Songs searched for a title; the one by the scrobbled artist is used
+const SEARCH_LIMIT: &str = "20";
+
+/// Reports plays to a Subsonic-compatible server such as Navidrome, so its
+/// play counts include what is played on Sonos from the same library.
+/// Tracks the server doesn't have are skipped.
+pub struct Subsonic {
+    url: String,
+    username: String,
+    password: String,
+    client: reqwest::Client,
+    /// Song id found for the last track, by fingerprint, so the scrobble
+    /// after a now-playing update doesn't search again
+    last_song: Mutex<Option<(String, Option<String>)>>,
+}
+
+impl Subsonic {
+    pub fn new(config: &SubsonicConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| Error::Scrobble(e.to_string()))?;
+
+        Ok(Self {
+            url: config.url.trim_end_matches('/').to_string(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            client,
+            last_song: Mutex::new(None),
+        })
+    }
+
+    async fn call(&self, method: &str, params: &[(&str, &str)]) -> Result<Value> {
+        // A fresh salt per request; it only has to be unpredictable enough
+        // that the token can't be replayed for other requests
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let salt = format!("{:x}", md5::compute(nanos.to_string()))[..12].to_string();
+        let token = format!("{:x}", md5::compute(format!("{}{}", self.password, salt)));
+
+        let response = self
+            .client
+            .get(format!("{}/rest/{}", self.url, method))
+            .query(&[
+                ("u", self.username.as_str()),
+                ("t", &token),
+                ("s", &salt),
+                ("v", API_VERSION),
+                ("c", CLIENT_NAME),
+                ("f", "json"),
+            ])
+            .query(params)
+            .send()
+            .await
+            .map_err(|e| Error::Scrobble(format!("subsonic {}: {}", method, e)))?;
+        let body = response
+            .text()
+            .await
+            .map_err(|e| Error::Scrobble(format!("subsonic {}: {}", method, e)))?;
+
+        let value: Value = serde_json::from_str(&body)
+            .map_err(|e| Error::Scrobble(format!("subsonic {}: invalid response: {}", method, e)))?;
+        let response = &value["subsonic-response"];
+        if response["status"] != "ok" {
+            let message = response["error"]["message"].as_str().unwrap_or("unknown error");
+            return Err(Error::Scrobble(format!("subsonic {}: {}", method, message)));
+        }
+        Ok(response.clone())
+    }
+
+    /// Id of the library song with the scrobble's artist and title
+    async fn song_id(&self, scrobble: &Scrobble) -> Result<Option<String>> {
+        let fingerprint = scrobble.fingerprint();
+        if let Some((_, id)) = self.last_song.lock().unwrap().as_ref().filter(|(last, _)| *last == fingerprint) {
+            return Ok(id.clone());
+        }
+
+        let response = self
+            .call(
+                "search3",
+                &[("query", &scrobble.title), ("songCount", SEARCH_LIMIT), ("artistCount", "0"), ("albumCount", "0")],
+            )
+            .await?;
+        let songs = response["searchResult3"]["song"].as_array().cloned().unwrap_or_default();
+        let id = songs
+            .iter()
+            .filter(|song| {
+                let matches =
+                    |field: &str, value: &str| song[field].as_str().is_some_and(|f| f.eq_ignore_ascii_case(value));
+                matches("title", &scrobble.title) && matches("artist", &scrobble.artist)
+            })
+            .find_map(|song| song["id"].as_str().map(str::to_string));
+        *self.last_song.lock().unwrap() = Some((fingerprint, id.clone()));
+        Ok(id)
+    }
+
+    async fn submit(&self, scrobble: &Scrobble, submission: bool) -> Result<()> {
+        let Some(id) = self.song_id(scrobble).await? else {
+            if submission {
+                info!("Subsonic: {} - {} is not in the library", scrobble.artist, scrobble.title);
+            }
+            return Ok(());
+        };
+        let time = (scrobble.started_at * 1000).to_string();
+        let submission = submission.to_string();
+        self.call("scrobble", &[("id", &id), ("time", &time), ("submission", &submission)]).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ScrobbleBackend for Subsonic {
+    fn name(&self) -> &str {
+        "subsonic"
+    }
+
+    async fn now_playing(&self, scrobble: &Scrobble) -> Result<()> {
+        self.submit(scrobble, false).await
+    }
+
+    async fn scrobble(&self, scrobble: &Scrobble) -> Result<()> {
+        self.submit(scrobble, true).await
+    }
+
+    /// Stars the song, Subsonic's closest match to loving a track
+    async fn love(&self, scrobble: &Scrobble) -> Result<()> {
+        if let Some(id) = self.song_id(scrobble).await? {
+            self.call("star", &[("id", &id)]).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    #[tokio::test]
+    async fn test_scrobble_library_song() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/rest/search3")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("u".into(), "me".into()),
+                Matcher::UrlEncoded("query".into(), "Teardrop".into()),
+            ]))
+            .with_body(
+                r#"{"subsonic-response":{"status":"ok","version":"1.16.1","searchResult3":{"song":[
+                    {"id":"cover","title":"Teardrop","artist":"Newton Faulkner"},
+                    {"id":"tr-1","title":"Teardrop","artist":"Massive Attack"}]}}}"#,
+            )
+            .expect(1)
+            .create_async()
+            .await;
+        let scrobbled = server
+            .mock("GET", "/rest/scrobble")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("id".into(), "tr-1".into()),
+                Matcher::UrlEncoded("time".into(), "1700000000000".into()),
+            ]))
+            .with_body(r#"{"subsonic-response":{"status":"ok","version":"1.16.1"}}"#)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let config = SubsonicConfig { url: server.url(), username: "me".to_string(), password: "secret".to_string() };
+        let subsonic = Subsonic::new(&config).unwrap();
+        let scrobble = Scrobble::new("Kitchen", "Massive Attack", "Teardrop", 1_700_000_000);
+        subsonic.now_playing(&scrobble).await.unwrap();
+        subsonic.scrobble(&scrobble).await.unwrap();
+        scrobbled.assert_async().await;
+    }
+}
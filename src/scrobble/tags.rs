@@ -0,0 +1,56 @@
+use crate::error::Result;
+use crate::scrobble::LastFm;
+use crate::sonos::TrackDatabase;
+use log::{debug, warn};
+use std::time::Duration;
+
+/// How often new scrobbles are tagged with their genres
+pub const TAG_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Scrobbles tagged per run, to spread a backlog over several runs
+const TAG_BATCH: u32 = 100;
+/// Runs in which a scrobble's lookup may fail before it is left untagged
+const TAG_ATTEMPTS: u32 = 5;
+/// Failed lookups in a row after which Last.fm is taken to be down and the
+/// run ends
+const TAG_FAILURES_IN_A_ROW: u32 = 3;
+
+/// Looks up the genre tags of scrobbles that have none yet. Tracks tagged
+/// before reuse their tags without asking Last.fm. A scrobble whose lookup
+/// fails is passed over, so it doesn't hold up those after it, and given up
+/// after [`TAG_ATTEMPTS`] runs. Returns how many scrobbles were tagged.
+pub async fn tag_listens(db: &TrackDatabase, lastfm: &LastFm) -> Result<usize> {
+    let (mut tagged, mut failures) = (0, 0);
+    for scrobble in db.untagged_scrobbles(TAG_BATCH, TAG_ATTEMPTS).await? {
+        let tags = match db.known_tags(&scrobble.artist, &scrobble.title).await? {
+            Some(tags) => Ok(tags),
+            None => lastfm.top_tags(&scrobble.artist, &scrobble.title).await,
+        };
+        match tags {
+            Ok(tags) => {
+                debug!("Tagged {} - {}: {}", scrobble.artist, scrobble.title, tags.join(", "));
+                db.tag_scrobble(&scrobble, &tags).await?;
+                (tagged, failures) = (tagged + 1, 0);
+            }
+            Err(e) => {
+                warn!("Genre tags of {} - {} not found: {}", scrobble.artist, scrobble.title, e);
+                db.record_tag_failure(&scrobble).await?;
+                failures += 1;
+                if failures == TAG_FAILURES_IN_A_ROW {
+                    return Err(e);
+                }
+            }
+        }
+    }
+    Ok(tagged)
+}
+
+/// Runs [`tag_listens`] every `interval` until the task is dropped
+pub async fn tag_periodically(db: TrackDatabase, lastfm: LastFm, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = tag_listens(&db, &lastfm).await {
+            warn!("Genre tagging failed: {}", e);
+        }
+    }
+}
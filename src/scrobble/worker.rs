@@ -0,0 +1,293 @@
+use crate::config::SubmissionConfig;
+use crate::scrobble::Scrobble;
+use crate::sonos::TrackDatabase;
+use log::{info, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Notify};
+
+/// Which submissions go first while a backend is busy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Listens recorded while the internet was down
+    Backlog,
+    /// Listens just played
+    Fresh,
+    /// Now-playing updates, useless once the track is over
+    NowPlaying,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::NowPlaying, Priority::Fresh, Priority::Backlog];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Backlog => "backlog",
+            Priority::Fresh => "fresh",
+            Priority::NowPlaying => "now_playing",
+        }
+    }
+}
+
+/// A submission to one backend
+pub(super) struct Job {
+    pub(super) priority: Priority,
+    /// The listen as the backend gets it
+    pub(super) scrobble: Scrobble,
+    /// The scrobble this is part of; none for now-playing updates
+    pub(super) delivery: Option<Arc<Delivery>>,
+}
+
+/// A scrobble submitted to several backends, settled once all of them
+/// answered
+pub(super) struct Delivery {
+    /// The listen as it was recorded
+    pub(super) scrobble: Scrobble,
+    pub(super) routed: usize,
+    /// Where the outcome is recorded, for fresh scrobbles; the backlog's
+    /// outcome goes to whoever waits for it
+    pub(super) db: Option<TrackDatabase>,
+    remaining: AtomicUsize,
+    accepted: AtomicUsize,
+    done: Mutex<Option<oneshot::Sender<usize>>>,
+}
+
+impl Delivery {
+    /// Also returns what tells the number of backends that accepted it
+    pub(super) fn new(
+        scrobble: &Scrobble,
+        routed: usize,
+        db: Option<TrackDatabase>,
+    ) -> (Arc<Self>, oneshot::Receiver<usize>) {
+        let (done, accepted) = oneshot::channel();
+        let delivery = Self {
+            scrobble: scrobble.clone(),
+            routed,
+            db,
+            remaining: AtomicUsize::new(routed),
+            accepted: AtomicUsize::new(0),
+            done: Mutex::new(Some(done)),
+        };
+        (Arc::new(delivery), accepted)
+    }
+
+    /// Notes one backend's answer. Returns the number of backends that
+    /// accepted once the last one answered.
+    pub(super) fn answered(&self, ok: bool) -> Option<usize> {
+        if ok {
+            self.accepted.fetch_add(1, Ordering::SeqCst);
+        }
+        if self.remaining.fetch_sub(1, Ordering::SeqCst) != 1 {
+            return None;
+        }
+        let accepted = self.accepted.load(Ordering::SeqCst);
+        if let Some(done) = self.done.lock().unwrap().take() {
+            let _ = done.send(accepted);
+        }
+        Some(accepted)
+    }
+}
+
+/// The submissions waiting for one backend, by priority
+#[derive(Default)]
+struct Lane {
+    jobs: Mutex<[VecDeque<Job>; 3]>,
+    ready: Notify,
+}
+
+/// Failed calls to a backend in a row, and until when it is left alone
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// The queues and circuit breakers of the backends
+pub(super) struct Worker {
+    config: SubmissionConfig,
+    lanes: Mutex<BTreeMap<String, Arc<Lane>>>,
+    breakers: Mutex<BTreeMap<String, Breaker>>,
+    /// Jobs queued or in progress
+    pending: AtomicUsize,
+}
+
+impl Worker {
+    pub(super) fn new(config: &SubmissionConfig) -> Self {
+        Self {
+            config: *config,
+            lanes: Mutex::default(),
+            breakers: Mutex::default(),
+            pending: AtomicUsize::new(0),
+        }
+    }
+
+    pub(super) fn concurrency(&self) -> usize {
+        self.config.concurrency.max(1)
+    }
+
+    fn lane(&self, backend: &str) -> Arc<Lane> {
+        self.lanes.lock().unwrap().entry(backend.to_string()).or_default().clone()
+    }
+
+    pub(super) fn push(&self, backend: &str, job: Job) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let lane = self.lane(backend);
+        lane.jobs.lock().unwrap()[job.priority as usize].push_back(job);
+        lane.ready.notify_one();
+    }
+
+    /// The next job for `backend`, waiting for one if there is none
+    pub(super) async fn pop(&self, backend: &str) -> Job {
+        let lane = self.lane(backend);
+        loop {
+            {
+                let mut jobs = lane.jobs.lock().unwrap();
+                if let Some(job) = jobs.iter_mut().rev().find_map(VecDeque::pop_front) {
+                    // Another of the backend's workers may be waiting
+                    if jobs.iter().any(|queue| !queue.is_empty()) {
+                        lane.ready.notify_one();
+                    }
+                    return job;
+                }
+            }
+            lane.ready.notified().await;
+        }
+    }
+
+    /// Notes a popped job as done
+    pub(super) fn done(&self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub(super) fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// The jobs waiting for each backend with each priority, not counting
+    /// those in progress
+    pub(super) fn queued(&self) -> Vec<(String, Priority, usize)> {
+        let lanes = self.lanes.lock().unwrap();
+        let mut queued = Vec::new();
+        for (backend, lane) in lanes.iter() {
+            let jobs = lane.jobs.lock().unwrap();
+            for priority in Priority::ALL {
+                queued.push((backend.clone(), priority, jobs[priority as usize].len()));
+            }
+        }
+        queued
+    }
+
+    /// Whether `backend` may be called now. Once its cooldown is over, a
+    /// single call is let through to see whether it recovered.
+    pub(super) fn allow(&self, backend: &str) -> bool {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(backend.to_string()).or_default();
+        match breaker.open_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                breaker.open_until = Some(Instant::now() + self.cooldown());
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Notes the outcome of a call to `backend`
+    pub(super) fn record(&self, backend: &str, ok: bool) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(backend.to_string()).or_default();
+        if ok {
+            if breaker.open_until.take().is_some() {
+                info!("{}: answering again, resuming submissions", backend);
+            }
+            breaker.failures = 0;
+            return;
+        }
+        breaker.failures += 1;
+        if self.config.breaker_failures > 0 && breaker.failures == self.config.breaker_failures {
+            warn!(
+                "{}: {} failed calls in a row, leaving it alone for {}s",
+                backend,
+                breaker.failures,
+                self.cooldown().as_secs()
+            );
+            breaker.open_until = Some(Instant::now() + self.cooldown());
+        }
+    }
+
+    fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.config.breaker_cooldown_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::scrobble::{MockScrobbleBackend, Scrobbler};
+
+    fn scrobble(title: &str) -> Scrobble {
+        Scrobble::new("Kitchen", "Daft Punk", title, 1_700_000_000)
+    }
+
+    #[tokio::test]
+    async fn test_jobs_are_taken_by_priority() {
+        let worker = Worker::new(&SubmissionConfig::default());
+        for (priority, title) in [(Priority::Backlog, "Old"), (Priority::Fresh, "New"), (Priority::NowPlaying, "Now")] {
+            worker.push("lastfm", Job { priority, scrobble: scrobble(title), delivery: None });
+        }
+        worker.push("lastfm", Job { priority: Priority::Fresh, scrobble: scrobble("Newer"), delivery: None });
+
+        let queued = worker.queued();
+        assert!(queued.contains(&("lastfm".to_string(), Priority::Fresh, 2)));
+        assert!(queued.contains(&("lastfm".to_string(), Priority::Backlog, 1)));
+
+        let mut titles = Vec::new();
+        for _ in 0..4 {
+            titles.push(worker.pop("lastfm").await.scrobble.title);
+            worker.done();
+        }
+        assert_eq!(titles, ["Now", "New", "Newer", "Old"]);
+        assert_eq!(worker.pending(), 0);
+    }
+
+    #[test]
+    fn test_breaker_opens_after_failures_in_a_row() {
+        let worker = Worker::new(&SubmissionConfig { breaker_failures: 2, ..SubmissionConfig::default() });
+        worker.record("lastfm", false);
+        worker.record("lastfm", true);
+        worker.record("lastfm", false);
+        assert!(worker.allow("lastfm"));
+        worker.record("lastfm", false);
+        assert!(!worker.allow("lastfm"));
+        assert!(worker.allow("listenbrainz"));
+
+        // A trial call once the cooldown is over
+        let config = SubmissionConfig { breaker_failures: 1, breaker_cooldown_secs: 0, ..Default::default() };
+        let worker = Worker::new(&config);
+        worker.record("lastfm", false);
+        assert!(worker.allow("lastfm"));
+        worker.record("lastfm", true);
+        assert!(worker.allow("lastfm"));
+    }
+
+    #[tokio::test]
+    async fn test_submissions_are_queued_for_the_worker() {
+        let mut backend = MockScrobbleBackend::new();
+        backend.expect_name().return_const("lastfm".to_string());
+        backend.expect_now_playing().times(1).returning(|_| Ok(()));
+        backend.expect_scrobble().times(1).returning(|_| Err(Error::Scrobble("service unavailable".to_string())));
+        let scrobbler = Arc::new(Scrobbler::new(vec![Box::new(backend)]).with_worker(&SubmissionConfig::default()));
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+
+        scrobbler.now_playing(&scrobble("Get Lucky")).await;
+        assert!(scrobbler.submit(&db, &scrobble("Get Lucky")).await.unwrap());
+        let worker = tokio::spawn(scrobbler.clone().run_worker());
+        assert!(scrobbler.drain(Duration::from_secs(5)).await);
+        worker.abort();
+
+        assert_eq!(db.scrobbles_by_room(0, i64::MAX).await.unwrap(), vec![("Kitchen".to_string(), 1, 1)]);
+    }
+}
@@ -0,0 +1,1040 @@
+mod agent;
+mod audioscrobbler;
+mod dashboard;
+mod graphql;
+mod grpc;
+mod tls;
+
+pub use agent::Agents;
+pub use audioscrobbler::Audioscrobbler;
+
+use crate::config::{BasicAuth, HttpConfig, TlsConfig};
+use crate::control::Controller;
+use crate::error::{Error, Result};
+use crate::scrobble;
+use crate::sonos::{
+    new_token, ArtProxy, CapturedEvent, EventCapture, EventHub, Notification, Replay, SyntheticEvent, TrackDatabase,
+};
+use crate::stats::{Charts, GroupBy, HistoryCursor, HistorySort, Listen, ListenFilter, NewMusic, Period};
+use crate::websocket::{self, WebSocket};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Local;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Body, Bytes};
+use hyper::header::{
+    HeaderMap, AUTHORIZATION, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE, WWW_AUTHENTICATE,
+};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+/// Header carrying the trigger secret, as an alternative to `?secret=`
+const SECRET_HEADER: &str = "x-trigger-secret";
+const DEFAULT_AGGREGATE_LIMIT: u32 = 10;
+const MAX_AGGREGATE_LIMIT: u32 = 1000;
+const DEFAULT_HISTORY_LIMIT: u32 = 50;
+const MAX_HISTORY_LIMIT: u32 = 500;
+/// Recent listens on the dashboard
+const DASHBOARD_LISTENS: u32 = 20;
+/// Largest request body read; requests are small JSON documents or forms
+const MAX_BODY: usize = 64 * 1024;
+
+/// HTTP server for remote control.
+///
+/// The `/trigger/*` endpoints are plain GET requests with query parameters so
+/// iOS Shortcuts and similar tools can call them without building JSON
+/// bodies. They are only enabled when a trigger secret, API token or basic
+/// auth credentials are configured, and any of them is accepted.
+///
+/// `GET /api/queue/<room>` returns the room's upcoming queue as JSON, each
+/// item marked with whether it would be scrobbled. It takes the same
+/// credentials as the triggers.
+///
+/// `GET /api/aggregate?by=artist` counts scrobbles by room, artist, track,
+/// album, genre, day or month, optionally filtered by `room`, `artist` and a
+/// `from`/`to` date range, returning the top `limit` as JSON.
+///
+/// `/api/graphql` answers GraphQL queries for the same listens and counts,
+/// as described at [`Server::graphql_request`].
+///
+/// `GET /api/history` pages through scrobbles with the same filters, sorted
+/// `newest` or `oldest` first. Each page carries a `next` cursor to pass as
+/// `?cursor=` for the page after it.
+///
+/// `GET /api/charts` returns scrobbles per day and per room and by weekday
+/// and hour as JSON, with the same filters. `GET /dashboard` draws them as
+/// charts and lists the recent listens, each with a button that posts
+/// `id=<listen id>` to `/trigger/neverscrobble`, which blocklists its track,
+/// or to `/trigger/scrobbleagain`, which undoes that. Instead of credentials
+/// these take the page's `form_token`, which other sites can't read, so
+/// they can't press the buttons for a logged-in browser.
+///
+/// `GET /metrics` reports the listens in progress and the depths of the
+/// internal queues as Prometheus gauges, to tell when the service falls
+/// behind. It takes the same credentials as the triggers.
+///
+/// With an [`ArtProxy`], `GET /art/<listen id>` serves the cover of a
+/// scrobble, fetched from the speaker it played on once and cached, so
+/// dashboards and notifiers can show it without reaching the speaker. It
+/// takes the same credentials as the triggers.
+///
+/// With an [`Audioscrobbler`] proxy, other players scrobble through `/2.0/`
+/// as they would to Last.fm.
+///
+/// With [`Agents`], agents near remote speakers open a WebSocket at `/agent`,
+/// authenticated with `Authorization: Bearer <agent token>`, and forward
+/// their listens over it.
+///
+/// With `grpc_listen` configured, the control API is also served over gRPC
+/// on a separate plaintext HTTP/2 listener, as described in
+/// `proto/control.proto`.
+///
+/// Speakers send their GENA event notifications to `/notify/<token>/<device id>`,
+/// with the [`EventHub`]'s token.
+/// With TLS configured, everything else is served over HTTPS and the events
+/// arrive on a separate plain-HTTP listener, since Sonos can't send them
+/// over HTTPS.
+///
+/// In dev mode, `POST /dev/inject-event` takes a [`SyntheticEvent`] as JSON
+/// and runs it through the listen logic like a replayed one, answering with
+/// the decisions it led to as `{"decisions": [...]}`. Nothing is scrobbled.
+/// It takes the same credentials as the triggers.
+///
+/// Every request is logged at debug level with its status and how long it
+/// took; the query string is left out since it may carry the secret.
+pub struct Server {
+    listen: SocketAddr,
+    controller: Arc<Controller>,
+    trigger_secret: Option<String>,
+    api_token: Option<String>,
+    basic_auth: Option<BasicAuth>,
+    events: Option<Arc<EventHub>>,
+    capture: Option<EventCapture>,
+    audioscrobbler: Option<Audioscrobbler>,
+    agents: Option<Arc<Agents>>,
+    db: Option<TrackDatabase>,
+    art: Option<ArtProxy>,
+    tls: Option<TlsConfig>,
+    dev: Option<Mutex<Replay>>,
+    grpc_listen: Option<SocketAddr>,
+    /// Authorizes the dashboard's buttons; only shown on the dashboard
+    form_token: String,
+}
+
+/// Which requests a listener serves
+#[derive(Debug, Clone, Copy)]
+enum Listener {
+    All,
+    /// The plain-HTTP listener for speaker events next to an HTTPS one
+    EventsOnly,
+}
+
+impl Server {
+    pub fn new(config: &HttpConfig, controller: Arc<Controller>) -> Self {
+        Self {
+            listen: config.listen,
+            controller,
+            trigger_secret: config.trigger_secret.clone().filter(|secret| !secret.is_empty()),
+            api_token: config.api_token.clone().filter(|token| !token.is_empty()),
+            basic_auth: config.basic_auth.clone(),
+            events: None,
+            capture: None,
+            audioscrobbler: None,
+            agents: None,
+            db: None,
+            art: None,
+            tls: config.tls.clone(),
+            dev: None,
+            grpc_listen: config.grpc_listen,
+            form_token: new_token(),
+        }
+    }
+
+    /// Accepts event notifications from speakers and hands them to `hub`
+    pub fn with_events(mut self, hub: Arc<EventHub>) -> Self {
+        self.events = Some(hub);
+        self
+    }
+
+    /// Also writes every event notification to `capture`, for replaying
+    pub fn with_capture(mut self, capture: EventCapture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Accepts scrobbles from other players at `/2.0/`
+    pub fn with_audioscrobbler(mut self, audioscrobbler: Audioscrobbler) -> Self {
+        self.audioscrobbler = Some(audioscrobbler);
+        self
+    }
+
+    /// Accepts listens forwarded by agents at `/agent`
+    pub fn with_agents(mut self, agents: Agents) -> Self {
+        self.agents = Some(Arc::new(agents));
+        self
+    }
+
+    /// Answers history queries from `db`
+    pub fn with_database(mut self, db: TrackDatabase) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Serves the covers of scrobbles at `/art/<listen id>`
+    pub fn with_art(mut self, art: ArtProxy) -> Self {
+        self.art = Some(art);
+        self
+    }
+
+    /// Accepts synthetic events at `/dev/inject-event` and feeds them to
+    /// `replay`
+    pub fn with_dev_mode(mut self, replay: Replay) -> Self {
+        self.dev = Some(Mutex::new(replay));
+        self
+    }
+
+    pub async fn serve(self: Arc<Self>) -> Result<()> {
+        match self.grpc_listen {
+            Some(listen) => {
+                tokio::try_join!(self.clone().serve_http(), self.clone().serve_grpc(listen))?;
+                Ok(())
+            }
+            None => self.serve_http().await,
+        }
+    }
+
+    async fn serve_http(self: Arc<Self>) -> Result<()> {
+        let Some(tls) = &self.tls else {
+            return self.clone().serve_on(self.listen, None, Listener::All).await;
+        };
+
+        let acceptor = tls::acceptor(tls)?;
+        tokio::try_join!(
+            self.clone().serve_on(tls.event_listen, None, Listener::EventsOnly),
+            self.clone().serve_on(self.listen, Some(acceptor), Listener::All),
+        )?;
+        Ok(())
+    }
+
+    /// Accepts connections on `listen`, over TLS when given an acceptor
+    async fn serve_on(
+        self: Arc<Self>,
+        listen: SocketAddr,
+        acceptor: Option<TlsAcceptor>,
+        listener: Listener,
+    ) -> Result<()> {
+        let tcp = TcpListener::bind(listen)
+            .await
+            .map_err(|e| Error::Config(format!("cannot listen on {}: {}", listen, e)))?;
+        info!("{} server listening on {}", if acceptor.is_some() { "HTTPS" } else { "HTTP" }, listen);
+
+        loop {
+            let (stream, peer) = match tcp.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+            let (server, acceptor) = (self.clone(), acceptor.clone());
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => server.serve_connection(stream, peer, listener).await,
+                        Err(e) => debug!("TLS handshake with {} failed: {}", peer, e),
+                    },
+                    None => server.serve_connection(stream, peer, listener).await,
+                }
+            });
+        }
+    }
+
+    async fn serve_connection<S>(self: Arc<Self>, stream: S, peer: SocketAddr, listener: Listener)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let service = service_fn(move |request| {
+            let server = self.clone();
+            async move { Ok::<_, Infallible>(server.handle(request, listener).await) }
+        });
+        let connection = http1::Builder::new().serve_connection(TokioIo::new(stream), service).with_upgrades();
+        if let Err(e) = connection.await {
+            debug!("Connection from {} failed: {}", peer, e);
+        }
+    }
+
+    /// Logs the request and its outcome around [`Server::dispatch`]
+    async fn handle<B: Body>(&self, request: Request<B>, listener: Listener) -> Response<Full<Bytes>> {
+        let (method, path) = (request.method().clone(), redacted(request.uri().path()));
+        let started = Instant::now();
+        let response = self.dispatch(request, listener).await;
+        debug!("{} {} {} in {:?}", method, path, response.status().as_u16(), started.elapsed());
+        response
+    }
+
+    async fn dispatch<B: Body>(&self, mut request: Request<B>, listener: Listener) -> Response<Full<Bytes>> {
+        if request.method().as_str() == "NOTIFY" {
+            let status = self.notify(request).await;
+            return Response::builder().status(status).body(Full::default()).unwrap_or_default();
+        }
+        if let Listener::EventsOnly = listener {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Full::default())
+                .unwrap_or_default();
+        }
+
+        let mut query: HashMap<String, String> = request
+            .uri()
+            .query()
+            .map(|query| form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+            .unwrap_or_default();
+        if let (Some(audioscrobbler), "/2.0/" | "/2.0") = (&self.audioscrobbler, request.uri().path()) {
+            // Clients post their calls as a form
+            let body = request.into_body().collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+            query.extend(form_urlencoded::parse(&body).into_owned());
+            let reply = audioscrobbler.handle(&query).await;
+            return Response::builder()
+                .status(reply.status)
+                .header("Content-Type", reply.content_type)
+                .body(Full::from(reply.body))
+                .unwrap_or_default();
+        }
+        if let (Some(agents), "/agent") = (&self.agents, request.uri().path()) {
+            return accept_agent_upgrade(agents.clone(), &mut request);
+        }
+        if let (Some(replay), "/dev/inject-event") = (&self.dev, request.uri().path()) {
+            let refusal = self.refuse("/dev/inject-event", &query, request.headers());
+            let (status, body) = match (refusal, request.method() == Method::POST) {
+                (Some(refusal), _) => refusal,
+                (None, true) => match read_body(request.into_body()).await {
+                    Ok(body) => self.inject_event(replay, &body),
+                    Err(refusal) => refusal,
+                },
+                (None, false) => (StatusCode::METHOD_NOT_ALLOWED, "use POST\n".to_string()),
+            };
+            let content_type = if status == StatusCode::OK { "application/json" } else { "text/plain; charset=utf-8" };
+            let mut response = Response::builder().status(status).header("Content-Type", content_type);
+            if status == StatusCode::UNAUTHORIZED && self.basic_auth.is_some() {
+                response = response.header(WWW_AUTHENTICATE, "Basic realm=\"sonos-scrobbler\"");
+            }
+            return response.body(Full::from(body)).unwrap_or_default();
+        }
+        if let Some(id) = request.uri().path().strip_prefix("/art/") {
+            return self.art(id, &query, request.headers()).await;
+        }
+        let path = request.uri().path().to_string();
+        let (status, body) = match path.as_str() {
+            graphql::GRAPHQL_PATH => self.graphql_request(request, &query).await,
+            _ => {
+                let (parts, body) = request.into_parts();
+                // The dashboard's buttons post their fields as a form
+                let form = match parts.method == Method::POST {
+                    true => read_body(body).await,
+                    false => Ok(Bytes::new()),
+                };
+                match form {
+                    Ok(form) => {
+                        query.extend(form_urlencoded::parse(&form).into_owned());
+                        self.route(&parts.method, &path, &query, &parts.headers).await
+                    }
+                    Err(refusal) => refusal,
+                }
+            }
+        };
+        let content_type = match (status == StatusCode::OK, path.as_str()) {
+            (true, "/metrics") => "text/plain; version=0.0.4",
+            (true, "/dashboard") => "text/html; charset=utf-8",
+            (true, path) if path.starts_with("/api/") => "application/json",
+            _ => "text/plain; charset=utf-8",
+        };
+        let mut response = Response::builder().status(status).header("Content-Type", content_type);
+        if status == StatusCode::UNAUTHORIZED && self.basic_auth.is_some() {
+            // Lets browsers prompt for the username and password
+            response = response.header(WWW_AUTHENTICATE, "Basic realm=\"sonos-scrobbler\"");
+        }
+        response.body(Full::from(body)).unwrap_or_default()
+    }
+
+    async fn route(
+        &self,
+        method: &Method,
+        path: &str,
+        query: &HashMap<String, String>,
+        headers: &HeaderMap,
+    ) -> (StatusCode, String) {
+        let action = match path {
+            "/metrics" => Some("metrics"),
+            "/dashboard" => Some("dashboard"),
+            _ => path.strip_prefix("/trigger/").or_else(|| path.strip_prefix("/api/")),
+        };
+        let Some(action) = action else {
+            return (StatusCode::NOT_FOUND, "not found\n".to_string());
+        };
+        if matches!(action, "neverscrobble" | "scrobbleagain") {
+            if method != Method::POST {
+                return (StatusCode::METHOD_NOT_ALLOWED, "use POST\n".to_string());
+            }
+            let token = query.get("form_token").map_or("", String::as_str);
+            if !secrets_match(token, &self.form_token) {
+                warn!("Rejected {} without the dashboard's form token", path);
+                return (StatusCode::FORBIDDEN, "missing or wrong form token\n".to_string());
+            }
+        } else if method != Method::GET {
+            return (StatusCode::METHOD_NOT_ALLOWED, "use GET\n".to_string());
+        } else if let Some(refusal) = self.refuse(path, query, headers) {
+            return refusal;
+        }
+
+        let room = query.get("room").map(String::as_str).filter(|room| !room.is_empty());
+        let result = match action {
+            "love" => self.controller.love(room).await.map(|track| format!("Loved {}", track)),
+            "pause" => self
+                .controller
+                .pause(room)
+                .await
+                .map(|rooms| format!("Paused {}", rooms.join(", "))),
+            "dontscrobble" => self.controller.dont_scrobble(room).map(|track| format!("Won't scrobble {}", track)),
+            "party" => self.controller.party(query.get("mode").map(String::as_str)),
+            "awake" => self.controller.awake(room),
+            "profile" => {
+                let name = query.get("name").map(String::as_str).filter(|name| !name.is_empty());
+                self.controller.profile(name).await
+            }
+            "backend" => {
+                let name = query.get("name").map(String::as_str).filter(|name| !name.is_empty());
+                self.controller.backend(name, query.get("mode").map(String::as_str)).await
+            }
+            "aggregate" => self.aggregate(query).await,
+            "history" => self.history(query).await,
+            "charts" => self.charts(query).await.and_then(|charts| {
+                serde_json::to_string(&charts).map_err(|e| Error::Config(e.to_string()))
+            }),
+            "dashboard" => self.dashboard(query).await,
+            "neverscrobble" => self.never_scrobble(query).await,
+            "scrobbleagain" => self.scrobble_again(query).await,
+            "metrics" => Ok(self.metrics().await),
+            _ if action.starts_with("queue/") => {
+                let room = percent_encoding::percent_decode_str(&action["queue/".len()..]).decode_utf8_lossy();
+                self.controller.queue_preview(&room).await.and_then(|queue| {
+                    serde_json::to_string(&queue).map_err(|e| Error::Config(e.to_string()))
+                })
+            }
+            _ => return (StatusCode::NOT_FOUND, format!("unknown trigger {}\n", action)),
+        };
+
+        match result {
+            Ok(message) => (StatusCode::OK, message + "\n"),
+            Err(e @ Error::Discovery(_)) => (StatusCode::NOT_FOUND, format!("{}\n", e)),
+            Err(e @ Error::Config(_)) => (StatusCode::BAD_REQUEST, format!("{}\n", e)),
+            Err(e) => (StatusCode::BAD_GATEWAY, format!("{}\n", e)),
+        }
+    }
+}
+
+impl Server {
+    /// Why a request to `path` is turned away, unless it may go ahead
+    fn refuse(
+        &self,
+        path: &str,
+        query: &HashMap<String, String>,
+        headers: &HeaderMap,
+    ) -> Option<(StatusCode, String)> {
+        if self.trigger_secret.is_none() && self.api_token.is_none() && self.basic_auth.is_none() {
+            return Some((StatusCode::NOT_FOUND, "triggers are disabled\n".to_string()));
+        }
+        if !self.authorized(query, headers) {
+            warn!("Rejected {} with missing or wrong credentials", path);
+            return Some((StatusCode::UNAUTHORIZED, "missing or wrong credentials\n".to_string()));
+        }
+        None
+    }
+
+    /// The cover of the scrobble with row id `id` for `/art/<id>`
+    async fn art(&self, id: &str, query: &HashMap<String, String>, headers: &HeaderMap) -> Response<Full<Bytes>> {
+        let path = format!("/art/{}", id);
+        let (status, body) = match (self.refuse(&path, query, headers), &self.art, id.parse::<i64>()) {
+            (Some(refusal), ..) => refusal,
+            (None, Some(art), Ok(id)) => match art.album_art(id).await {
+                Ok(Some(art)) => {
+                    return Response::builder()
+                        .header("Content-Type", art.content_type)
+                        .header("X-Content-Type-Options", "nosniff")
+                        .body(Full::from(art.image))
+                        .unwrap_or_default();
+                }
+                Ok(None) => (StatusCode::NOT_FOUND, "no cover for this listen\n".to_string()),
+                Err(e) => (StatusCode::BAD_GATEWAY, format!("{}\n", e)),
+            },
+            _ => (StatusCode::NOT_FOUND, "not found\n".to_string()),
+        };
+        let mut response = Response::builder().status(status).header("Content-Type", "text/plain; charset=utf-8");
+        if status == StatusCode::UNAUTHORIZED && self.basic_auth.is_some() {
+            response = response.header(WWW_AUTHENTICATE, "Basic realm=\"sonos-scrobbler\"");
+        }
+        response.body(Full::from(body)).unwrap_or_default()
+    }
+
+    /// Scrobble counts for `/api/aggregate`, as `[{"key": ..., "count": ...}]`
+    async fn aggregate(&self, query: &HashMap<String, String>) -> Result<String> {
+        let db = self.database()?;
+        let by = GroupBy::parse(query.get("by").map_or("artist", String::as_str))?;
+        let filter = ListenFilter::from_query(query)?;
+        let limit = limit(query, DEFAULT_AGGREGATE_LIMIT, MAX_AGGREGATE_LIMIT)?;
+        let counts: Vec<_> = db
+            .aggregate(&filter, by, limit)
+            .await?
+            .into_iter()
+            .map(|(key, count)| serde_json::json!({ "key": key, "count": count }))
+            .collect();
+        Ok(serde_json::Value::from(counts).to_string())
+    }
+
+    /// What `/api/charts` and `/dashboard` show, for the scrobbles matching
+    /// the query's filters
+    async fn charts(&self, query: &HashMap<String, String>) -> Result<Charts> {
+        Charts::build(self.database()?, &ListenFilter::from_query(query)?).await
+    }
+
+    /// The `/dashboard` page
+    async fn dashboard(&self, query: &HashMap<String, String>) -> Result<String> {
+        let db = self.database()?;
+        let charts = self.charts(query).await?;
+        let new_music = NewMusic::build(db, &Period::week(Local::now())).await?;
+        let filter = ListenFilter::from_query(query)?;
+        let mut recent = Vec::new();
+        for listen in db.history(&filter, HistorySort::Newest, None, DASHBOARD_LISTENS).await? {
+            let blocklisted = db.blocklisted(&listen.artist, &listen.title).await?;
+            recent.push((listen, blocklisted));
+        }
+        Ok(dashboard::render(&charts, &new_music, &recent, &self.form_token))
+    }
+
+    /// Blocklists the track of the listen `id` for the dashboard's "never
+    /// scrobble" button
+    async fn never_scrobble(&self, form: &HashMap<String, String>) -> Result<String> {
+        let track = scrobble::never_scrobble(self.database()?, listen_id(form)?).await?;
+        Ok(format!("Won't scrobble {} again", track))
+    }
+
+    /// Takes the track of the listen `id` off the blocklist for the
+    /// dashboard's "scrobble again" button
+    async fn scrobble_again(&self, form: &HashMap<String, String>) -> Result<String> {
+        let track = scrobble::scrobble_again(self.database()?, listen_id(form)?).await?;
+        Ok(format!("Scrobbling {} again", track))
+    }
+
+    /// The gauges for `/metrics`, including the speakers' event queues
+    async fn metrics(&self) -> String {
+        let mut gauges = self.controller.gauges().await;
+        if let Some(events) = &self.events {
+            gauges.event_queues = events.queued();
+        }
+        gauges.to_prometheus()
+    }
+
+    /// A page of `/api/history`, as `{"listens": [...], "next": cursor}`.
+    /// `next` is null on the last page.
+    async fn history(&self, query: &HashMap<String, String>) -> Result<String> {
+        let filter = ListenFilter::from_query(query)?;
+        let sort = query.get("sort").map_or(Ok(HistorySort::default()), |sort| HistorySort::parse(sort))?;
+        let after = query.get("cursor").filter(|cursor| !cursor.is_empty()).map(|c| c.parse()).transpose()?;
+        let limit = limit(query, DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT)?;
+
+        let (listens, next) = self.history_page(&filter, sort, after, limit).await?;
+        let next = next.map(|cursor| cursor.to_string());
+        Ok(serde_json::json!({ "listens": listens, "next": next }).to_string())
+    }
+
+    /// Up to `limit` listens after `after`, and the cursor of the page
+    /// following them unless this is the last
+    async fn history_page(
+        &self,
+        filter: &ListenFilter,
+        sort: HistorySort,
+        after: Option<HistoryCursor>,
+        limit: u32,
+    ) -> Result<(Vec<Listen>, Option<HistoryCursor>)> {
+        // One more than asked for tells whether there is a next page
+        let mut listens = self.database()?.history(filter, sort, after, limit + 1).await?;
+        let next = if listens.len() > limit as usize {
+            listens.truncate(limit as usize);
+            listens.last().map(HistoryCursor::from)
+        } else {
+            None
+        };
+        Ok((listens, next))
+    }
+
+    /// Runs a synthetic event from `/dev/inject-event` through `replay`
+    fn inject_event(&self, replay: &Mutex<Replay>, body: &[u8]) -> (StatusCode, String) {
+        let event = match serde_json::from_slice::<SyntheticEvent>(body) {
+            Ok(event) => event.to_captured(),
+            Err(e) => Err(Error::Config(format!("invalid event: {}", e))),
+        };
+        let decisions = match event {
+            Ok(event) => {
+                if let Some(capture) = &self.capture {
+                    if let Err(e) = capture.write(&event) {
+                        warn!("Failed to capture event: {}", e);
+                    }
+                }
+                replay.lock().unwrap().feed(&event)
+            }
+            Err(e) => Err(e),
+        };
+        match decisions {
+            Ok(decisions) => {
+                decisions.iter().for_each(|decision| info!("Injected event: {}", decision));
+                (StatusCode::OK, serde_json::json!({ "decisions": decisions }).to_string())
+            }
+            Err(e) => (StatusCode::BAD_REQUEST, format!("{}\n", e)),
+        }
+    }
+
+    fn database(&self) -> Result<&TrackDatabase> {
+        self.db.as_ref().ok_or_else(|| Error::Discovery("no listens database".to_string()))
+    }
+
+    /// Whether the request carries the trigger secret, as `?secret=` or
+    /// header, the API token as a bearer token, or the basic auth credentials
+    fn authorized(&self, query: &HashMap<String, String>, headers: &HeaderMap) -> bool {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+        if let Some(expected) = &self.trigger_secret {
+            let given = header(SECRET_HEADER).or(query.get("secret").map(String::as_str));
+            if given.is_some_and(|given| secrets_match(given, expected)) {
+                return true;
+            }
+        }
+
+        let Some((scheme, credentials)) = header(AUTHORIZATION.as_str()).and_then(|value| value.split_once(' ')) else {
+            return false;
+        };
+        match (scheme.to_ascii_lowercase().as_str(), &self.api_token, &self.basic_auth) {
+            ("bearer", Some(token), _) => secrets_match(credentials.trim(), token),
+            ("basic", _, Some(auth)) => {
+                let expected = STANDARD.encode(format!("{}:{}", auth.username, auth.password));
+                secrets_match(credentials.trim(), &expected)
+            }
+            _ => false,
+        }
+    }
+
+    /// Passes a GENA NOTIFY on to the subscriber of the addressed speaker.
+    /// Notifications nobody subscribed to get 412, which tells the speaker to
+    /// drop the subscription.
+    async fn notify<B: Body>(&self, request: Request<B>) -> StatusCode {
+        let Some(events) = &self.events else {
+            return StatusCode::NOT_FOUND;
+        };
+        let address = request.uri().path().strip_prefix("/notify/").and_then(|path| path.split_once('/'));
+        let Some((token, device_id)) = address else {
+            return StatusCode::NOT_FOUND;
+        };
+        if !secrets_match(token, events.token()) {
+            warn!("Rejected an event notification with a wrong token");
+            return StatusCode::NOT_FOUND;
+        }
+        let device_id = device_id.to_string();
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let (Some(sid), Some(seq)) = (header("SID"), header("SEQ").and_then(|seq| seq.parse().ok())) else {
+            return StatusCode::PRECONDITION_FAILED;
+        };
+        let Ok(body) = request.into_body().collect().await.map(|body| body.to_bytes()) else {
+            return StatusCode::BAD_REQUEST;
+        };
+
+        if let Some(capture) = &self.capture {
+            let text = String::from_utf8_lossy(&body);
+            if let Err(e) = capture.write(&CapturedEvent::now(&device_id, &sid, seq, &text)) {
+                warn!("Failed to capture event: {}", e);
+            }
+        }
+
+        if events.deliver(&device_id, Notification { sid, seq, body }) {
+            StatusCode::OK
+        } else {
+            StatusCode::PRECONDITION_FAILED
+        }
+    }
+}
+
+/// The body of a request, unless it is larger than [`MAX_BODY`] or breaks
+/// off, in which case the response to send instead
+async fn read_body<B: Body>(body: B) -> std::result::Result<Bytes, (StatusCode, String)> {
+    let body = Limited::new(body.map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof)), MAX_BODY);
+    match body.collect().await {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(e) if e.is::<LengthLimitError>() => Err((StatusCode::PAYLOAD_TOO_LARGE, "request too large\n".to_string())),
+        Err(e) => Err((StatusCode::BAD_REQUEST, format!("{}\n", e))),
+    }
+}
+
+fn listen_id(form: &HashMap<String, String>) -> Result<i64> {
+    form.get("id")
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| Error::Config("id must be a listen id".to_string()))
+}
+
+/// `?limit=`, capped at `max`
+fn limit(query: &HashMap<String, String>, default: u32, max: u32) -> Result<u32> {
+    match query.get("limit") {
+        Some(limit) => limit
+            .parse::<u32>()
+            .map(|limit| limit.min(max))
+            .map_err(|_| Error::Config(format!("invalid limit {}", limit))),
+        None => Ok(default),
+    }
+}
+
+/// Switches an agent's request over to a WebSocket served by `agents`, once
+/// its token checks out
+fn accept_agent_upgrade<B>(agents: Arc<Agents>, request: &mut Request<B>) -> Response<Full<Bytes>> {
+    let headers = request.headers();
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    let refuse = |status| Response::builder().status(status).body(Full::default()).unwrap_or_default();
+    let token = header(AUTHORIZATION).strip_prefix("Bearer ");
+    if !token.is_some_and(|token| secrets_match(token, agents.token())) {
+        warn!("Refused an agent with a wrong token");
+        return refuse(StatusCode::UNAUTHORIZED);
+    }
+    let key = header(SEC_WEBSOCKET_KEY).to_string();
+    if !header(UPGRADE).eq_ignore_ascii_case("websocket") || key.is_empty() {
+        return refuse(StatusCode::BAD_REQUEST);
+    }
+
+    let upgraded = hyper::upgrade::on(request);
+    tokio::spawn(async move {
+        match upgraded.await {
+            Ok(upgraded) => agents.serve(WebSocket::server(TokioIo::new(upgraded))).await,
+            Err(e) => debug!("Agent connection upgrade failed: {}", e),
+        }
+    });
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, websocket::accept_key(&key))
+        .body(Full::default())
+        .unwrap_or_default()
+}
+
+/// `path` as logged: the event token of `/notify/<token>/<device id>` is
+/// left out, since whoever reads the log could send events with it
+fn redacted(path: &str) -> String {
+    match path.strip_prefix("/notify/") {
+        Some(rest) => format!("/notify/<token>/{}", rest.split_once('/').map_or("", |(_, device)| device)),
+        None => path.to_string(),
+    }
+}
+
+/// Compares without returning early, so response timing does not reveal how
+/// much of the secret was right
+fn secrets_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrobble::Scrobbler;
+    use crate::sonos::SonosDevice;
+
+    fn server(secret: Option<&str>) -> Server {
+        let devices = vec![SonosDevice {
+            ip_addr: "127.0.0.1".parse().unwrap(),
+            room: "Kitchen".to_string(),
+            model: "Sonos One".to_string(),
+            id: "RINCON_1".to_string(),
+            friendly_name: "127.0.0.1 - Sonos One - RINCON_1".to_string(),
+        }];
+        let controller = Controller::new(&devices, Arc::new(Scrobbler::default())).unwrap();
+        let config = HttpConfig { trigger_secret: secret.map(str::to_string), ..HttpConfig::default() };
+        Server::new(&config, Arc::new(controller))
+    }
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[tokio::test]
+    async fn test_trigger_requires_secret() {
+        let server = server(Some("s3cret"));
+        let none = HeaderMap::new();
+        let (status, _) = server.route(&Method::GET, "/trigger/pause", &query(&[]), &none).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let wrong = query(&[("secret", "guess"), ("room", "Kitchen")]);
+        let (status, _) = server.route(&Method::GET, "/trigger/pause", &wrong, &none).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let unknown_room = query(&[("secret", "s3cret"), ("room", "Garage")]);
+        let (status, body) = server.route(&Method::GET, "/trigger/pause", &unknown_room, &none).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("Garage"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_report_event_queues() {
+        let hub = Arc::new(EventHub::default());
+        let _notifications = hub.register("RINCON_1");
+        hub.deliver("RINCON_1", Notification { sid: "uuid:sub-1".to_string(), seq: 0, body: Bytes::new() });
+        let server = server(Some("s3cret")).with_events(hub);
+
+        let (status, _) = server.route(&Method::GET, "/metrics", &query(&[]), &HeaderMap::new()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let secret = query(&[("secret", "s3cret")]);
+        let (status, body) = server.route(&Method::GET, "/metrics", &secret, &HeaderMap::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("sonos_scrobbler_event_queue_depth{device=\"RINCON_1\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_buttons_need_post_and_form_token() {
+        use crate::scrobble::Scrobble;
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        db.record_scrobble(&Scrobble::new("Kitchen", "Enya", "Caribbean Blue", 1_700_000_000)).await.unwrap();
+        let id = db.scrobbles_by_start().await.unwrap()[0].0;
+        let server = server(Some("s3cret")).with_database(db.clone());
+        let press = |method: Method, action: &str, form: String| {
+            Request::builder()
+                .method(method)
+                .uri(format!("/trigger/{}?secret=s3cret", action))
+                .body(Full::new(Bytes::from(form)))
+                .unwrap()
+        };
+        let form = |token: &str| format!("id={}&form_token={}", id, token);
+
+        // As an image on another site would, with the browser's credentials
+        let response = server.handle(press(Method::GET, "neverscrobble", String::new()), Listener::All).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let response = server.handle(press(Method::POST, "neverscrobble", form("guess")), Listener::All).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!db.blocklisted("Enya", "Caribbean Blue").await.unwrap());
+
+        let secret = query(&[("secret", "s3cret")]);
+        let (status, page) = server.route(&Method::GET, "/dashboard", &secret, &HeaderMap::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!page.contains("s3cret"));
+        let token = server.form_token.clone();
+        assert!(page.contains(&token));
+        let response = server.handle(press(Method::POST, "neverscrobble", form(&token)), Listener::All).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(db.blocklisted("Enya", "Caribbean Blue").await.unwrap());
+        let response = server.handle(press(Method::POST, "scrobbleagain", form(&token)), Listener::All).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!db.blocklisted("Enya", "Caribbean Blue").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_triggers_disabled_without_secret() {
+        let (status, _) = server(None)
+            .route(&Method::GET, "/trigger/love", &query(&[]), &HeaderMap::new())
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_bearer_token_and_basic_auth() {
+        let config = HttpConfig {
+            api_token: Some("t0ken".to_string()),
+            basic_auth: Some(BasicAuth { username: "me".to_string(), password: "pw".to_string() }),
+            ..HttpConfig::default()
+        };
+        let devices = vec![SonosDevice {
+            ip_addr: "127.0.0.1".parse().unwrap(),
+            room: "Kitchen".to_string(),
+            model: "Sonos One".to_string(),
+            id: "RINCON_1".to_string(),
+            friendly_name: "127.0.0.1 - Sonos One - RINCON_1".to_string(),
+        }];
+        let controller = Controller::new(&devices, Arc::new(Scrobbler::default())).unwrap();
+        let server = Server::new(&config, Arc::new(controller));
+        let authorization = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+
+        assert!(server.authorized(&query(&[]), &authorization("Bearer t0ken")));
+        assert!(!server.authorized(&query(&[]), &authorization("Bearer guess")));
+        // "me:pw"
+        assert!(server.authorized(&query(&[]), &authorization("Basic bWU6cHc=")));
+        assert!(!server.authorized(&query(&[("secret", "t0ken")]), &HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn test_notify_is_delivered_to_subscriber() {
+        let hub = Arc::new(EventHub::new("t0ken"));
+        let server = server(None).with_events(hub.clone());
+        let notify = |seq: &str| {
+            Request::builder()
+                .method(Method::from_bytes(b"NOTIFY").unwrap())
+                .uri(if seq == "guess" { "/notify/guess/RINCON_1" } else { "/notify/t0ken/RINCON_1" })
+                .header("SID", "uuid:sub-1")
+                .header("SEQ", seq)
+                .body(Full::new(Bytes::from("<e:propertyset/>")))
+                .unwrap()
+        };
+        assert_eq!(server.notify(notify("0")).await, StatusCode::PRECONDITION_FAILED);
+
+        let mut notifications = hub.register("RINCON_1");
+        assert_eq!(server.notify(notify("guess")).await, StatusCode::NOT_FOUND);
+        assert_eq!(server.notify(notify("1")).await, StatusCode::OK);
+        assert_eq!(server.notify(notify("x")).await, StatusCode::PRECONDITION_FAILED);
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!((notification.seq, &notification.body[..]), (1, &b"<e:propertyset/>"[..]));
+
+        assert_eq!(redacted("/notify/t0ken/RINCON_1"), "/notify/<token>/RINCON_1");
+        assert_eq!(redacted("/notify/t0ken"), "/notify/<token>/");
+        assert_eq!(redacted("/status"), "/status");
+    }
+
+    #[tokio::test]
+    async fn test_events_listener_only_serves_notify() {
+        let server = server(Some("s3cret"));
+        let request = |method: &[u8], uri: &str| {
+            Request::builder()
+                .method(Method::from_bytes(method).unwrap())
+                .uri(uri)
+                .body(Full::new(Bytes::new()))
+                .unwrap()
+        };
+
+        let response = server.handle(request(b"GET", "/trigger/love?secret=s3cret"), Listener::EventsOnly).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        // Without SID and SEQ headers, but routed to the events handler
+        let response = server.handle(request(b"NOTIFY", "/notify/t0ken/RINCON_1"), Listener::EventsOnly).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let server = server.with_events(Arc::new(EventHub::new("t0ken")));
+        let response = server.handle(request(b"NOTIFY", "/notify/t0ken/RINCON_1"), Listener::EventsOnly).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        let response = server.handle(request(b"GET", "/trigger/dance?secret=s3cret"), Listener::All).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"unknown trigger dance\n");
+    }
+
+    #[tokio::test]
+    async fn test_injected_events_need_dev_mode() {
+        let inject = |body: &str, secret: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/dev/inject-event")
+                .header(SECRET_HEADER, secret)
+                .body(Full::new(Bytes::from(body.to_string())))
+                .unwrap()
+        };
+        let event = r#"{"device": "Kitchen", "state": "PLAYING", "artist": "Daft Punk", "title": "Get Lucky"}"#;
+
+        let response = server(Some("s3cret")).handle(inject(event, "s3cret"), Listener::All).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let server = server(Some("s3cret")).with_dev_mode(Replay::new(Default::default(), Default::default()));
+        let response = server.handle(inject(event, "guess"), Listener::All).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = server.handle(inject(event, "s3cret"), Listener::All).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], br#"{"decisions":["Kitchen: started Daft Punk - Get Lucky"]}"#);
+        let response = server.handle(inject(r#"{"device": "Kitchen"}"#, "s3cret"), Listener::All).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = server.handle(inject(&" ".repeat(MAX_BODY + 1), "s3cret"), Listener::All).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_agent_forwards_over_websocket() {
+        use crate::agent::HubLink;
+        use crate::config::AgentConfig;
+        use crate::scrobble::{Scrobble, ScrobbleBackend};
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let agents = Agents::new("t0ken", Arc::new(Scrobbler::default()), db.clone());
+        let listen = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let config = HttpConfig { listen, ..HttpConfig::default() };
+        let controller = Arc::new(Controller::new(&[], Arc::new(Scrobbler::default())).unwrap());
+        let server = Arc::new(Server::new(&config, controller).with_agents(agents));
+        tokio::spawn(server.serve());
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let scrobble = Scrobble {
+            room: Some("Office".to_string()),
+            ..Scrobble::new("10.8.0.20 - Sonos One - RINCON_20", "Bonobo", "Kerala", 1_700_000_000)
+        };
+        let hub = format!("ws://{}/agent", listen);
+        let intruder = HubLink::new(&AgentConfig { hub: hub.clone(), token: "guess".to_string(), ca_cert: None });
+        assert!(intruder.scrobble(&scrobble).await.is_err());
+        let agent = HubLink::new(&AgentConfig { hub, token: "t0ken".to_string(), ca_cert: None });
+        agent.scrobble(&scrobble).await.unwrap();
+        agent.scrobble(&Scrobble { started_at: 1_700_000_600, ..scrobble }).await.unwrap();
+
+        let listens = db.history(&ListenFilter::default(), HistorySort::Oldest, None, 10).await.unwrap();
+        assert_eq!(listens.len(), 2);
+        assert_eq!(listens[0].room, "Office");
+    }
+
+    #[tokio::test]
+    async fn test_agent_forwards_over_tls() {
+        use crate::agent::HubLink;
+        use crate::config::{AgentConfig, TlsConfig};
+        use crate::scrobble::{Scrobble, ScrobbleBackend};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("sonos-scrobbler-hub-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let agents = Agents::new("t0ken", Arc::new(Scrobbler::default()), db.clone());
+        let listen = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let tls = TlsConfig {
+            cert: Some(dir.join("cert.pem")),
+            key: Some(dir.join("key.pem")),
+            event_listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+        };
+        let config = HttpConfig { listen, tls: Some(tls), ..HttpConfig::default() };
+        let controller = Arc::new(Controller::new(&[], Arc::new(Scrobbler::default())).unwrap());
+        let server = Arc::new(Server::new(&config, controller).with_agents(agents));
+        tokio::spawn(server.serve());
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let scrobble = Scrobble::new("10.8.0.20 - Sonos One - RINCON_20", "Bonobo", "Kerala", 1_700_000_000);
+        let hub = format!("wss://localhost:{}/agent", listen.port());
+        let untrusting = HubLink::new(&AgentConfig { hub: hub.clone(), token: "t0ken".to_string(), ca_cert: None });
+        assert!(untrusting.scrobble(&scrobble).await.is_err());
+        let agent = HubLink::new(&AgentConfig { hub, token: "t0ken".to_string(), ca_cert: Some(dir.join("cert.pem")) });
+        agent.scrobble(&scrobble).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let listens = db.history(&ListenFilter::default(), HistorySort::Oldest, None, 10).await.unwrap();
+        assert_eq!(listens.len(), 1);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::agent::{ForwardKind, Forwarded};
+use crate::error::{Error, Result};
+use crate::scrobble::{Scrobble, Scrobbler};
+use crate::sonos::TrackDatabase;
+use crate::websocket::WebSocket;
+use log::{debug, info, warn};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// The hub's end of the agents' WebSockets at `/agent`. Agents forward the
+/// listens of their speakers, which are then filtered, recorded and routed
+/// like those of the hub's own speakers.
+pub struct Agents {
+    token: String,
+    scrobbler: Arc<Scrobbler>,
+    db: TrackDatabase,
+}
+
+impl Agents {
+    /// Accepts agents that present `token` as a bearer token
+    pub fn new(token: &str, scrobbler: Arc<Scrobbler>, db: TrackDatabase) -> Self {
+        Self { token: token.to_string(), scrobbler, db }
+    }
+
+    pub(super) fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Answers an agent's messages until it disconnects
+    pub(super) async fn serve<S: AsyncRead + AsyncWrite + Unpin>(&self, mut socket: WebSocket<S>) {
+        info!("Agent connected");
+        loop {
+            match socket.recv().await {
+                Ok(Some(message)) => {
+                    if let Err(e) = socket.send(&self.handle(&message).await).await {
+                        warn!("Agent connection broke: {}", e);
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Agent connection broke: {}", e);
+                    return;
+                }
+            }
+        }
+        info!("Agent disconnected");
+    }
+
+    /// The reply to a message from an agent
+    pub(super) async fn handle(&self, message: &str) -> String {
+        let result = match serde_json::from_str(message) {
+            Ok(forwarded) => self.apply(forwarded).await,
+            Err(e) => Err(Error::Scrobble(format!("invalid message: {}", e))),
+        };
+        match result {
+            Ok(()) => json!({ "ok": true }),
+            Err(e) => json!({ "ok": false, "error": e.to_string() }),
+        }
+        .to_string()
+    }
+
+    async fn apply(&self, forwarded: Forwarded) -> Result<()> {
+        let scrobble = Scrobble::from(forwarded.listen);
+        if let Some(room) = &scrobble.room {
+            self.scrobbler.add_device(&scrobble.device, room);
+        }
+        match forwarded.kind {
+            ForwardKind::NowPlaying => self.scrobbler.now_playing(&scrobble).await,
+            ForwardKind::Love => self.scrobbler.love(&scrobble).await?,
+            ForwardKind::Scrobble if !self.scrobbler.confident(&scrobble) => {
+                debug!("Ignoring {} - {} from an agent, not confident enough", scrobble.artist, scrobble.title);
+            }
+            ForwardKind::Scrobble => {
+                self.scrobbler.submit(&self.db, &scrobble).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{HistorySort, ListenFilter};
+
+    #[tokio::test]
+    async fn test_forwarded_scrobble_is_recorded() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let agents = Agents::new("t0ken", Arc::new(Scrobbler::default()), db.clone());
+        let message = json!({
+            "type": "scrobble",
+            "listen": {
+                "device": "10.8.0.20 - Sonos One - RINCON_20",
+                "room": "Office",
+                "artist": "Bonobo",
+                "title": "Kerala",
+                "album": null,
+                "started_at": 1_700_000_000,
+                "duration_secs": 254,
+                "artist_confidence": 100,
+                "title_confidence": 100,
+                "chosen_by_user": true,
+                "track_uri": null,
+            },
+        });
+
+        assert_eq!(agents.handle(&message.to_string()).await, r#"{"ok":true}"#);
+        let listens = db.history(&ListenFilter::default(), HistorySort::Newest, None, 10).await.unwrap();
+        assert_eq!((listens[0].room.as_str(), listens[0].title.as_str()), ("Office", "Kerala"));
+
+        let reply: serde_json::Value = serde_json::from_str(&agents.handle(r#"{"type":"skip"}"#).await).unwrap();
+        assert_eq!(reply["ok"], false);
+    }
+}
@@ -0,0 +1,256 @@
+use super::secrets_match;
+use crate::config::AudioscrobblerClient;
+use crate::scrobble::{Confidence, Scrobble, Scrobbler};
+use crate::sonos::TrackDatabase;
+use hyper::StatusCode;
+use log::{info, warn};
+use quick_xml::escape::escape;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Most scrobbles one `track.scrobble` call may carry
+const MAX_BATCH: usize = 50;
+
+/// Last.fm API error codes the proxy answers with
+const INVALID_METHOD: u32 = 3;
+const AUTHENTICATION_FAILED: u32 = 4;
+const INVALID_PARAMETERS: u32 = 6;
+const OPERATION_FAILED: u32 = 8;
+const INVALID_SESSION_KEY: u32 = 9;
+
+/// The subset of the Last.fm (Audioscrobbler 2.0) API that scrobbling
+/// clients use, so other players in the house can scrobble through this
+/// service: their listens are filtered, recorded and routed like the
+/// speakers' own. Clients log in with `auth.getMobileSession`.
+pub struct Audioscrobbler {
+    clients: Vec<AudioscrobblerClient>,
+    scrobbler: Arc<Scrobbler>,
+    db: TrackDatabase,
+}
+
+/// An API response, as JSON or Last.fm's XML
+pub(super) struct Reply {
+    pub status: StatusCode,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Audioscrobbler {
+    /// Scrobbles from each client are recorded under its username and
+    /// routed as its room
+    pub fn new(clients: &[AudioscrobblerClient], scrobbler: Arc<Scrobbler>, db: TrackDatabase) -> Self {
+        for client in clients {
+            scrobbler.add_device(&client.username, client.room.as_deref().unwrap_or(&client.username));
+        }
+        Self { clients: clients.to_vec(), scrobbler, db }
+    }
+
+    /// Answers an API call, given its form or query parameters
+    pub(super) async fn handle(&self, params: &HashMap<String, String>) -> Reply {
+        let json = params.get("format").is_some_and(|format| format == "json");
+        let method = params.get("method").map(String::as_str).unwrap_or_default();
+        let result = match method {
+            "auth.getMobileSession" => self.session(params).await,
+            "track.updateNowPlaying" | "track.scrobble" => match self.client(params).await {
+                Ok(Some(client)) if method == "track.scrobble" => self.scrobble(client, params).await,
+                Ok(Some(client)) => self.now_playing(client, params).await,
+                Ok(None) => Err((INVALID_SESSION_KEY, "Invalid session key".to_string())),
+                Err(e) => Err((OPERATION_FAILED, e.to_string())),
+            },
+            _ => Err((INVALID_METHOD, format!("Invalid method {}", method))),
+        };
+
+        let (status, body) = match result {
+            Ok(body) if json => (StatusCode::OK, body.to_string()),
+            Ok(body) => (StatusCode::OK, format!("<lfm status=\"ok\">{}</lfm>", xml(&body))),
+            Err((code, message)) => {
+                let status = match code {
+                    AUTHENTICATION_FAILED | INVALID_SESSION_KEY => StatusCode::FORBIDDEN,
+                    OPERATION_FAILED => StatusCode::BAD_GATEWAY,
+                    _ => StatusCode::BAD_REQUEST,
+                };
+                let body = match json {
+                    true => json!({ "error": code, "message": message }).to_string(),
+                    false => {
+                        format!("<lfm status=\"failed\"><error code=\"{}\">{}</error></lfm>", code, escape(&message))
+                    }
+                };
+                (status, body)
+            }
+        };
+        let content_type = if json { "application/json" } else { "text/xml; charset=utf-8" };
+        Reply { status, content_type, body }
+    }
+
+    /// Logs a client in with a new random session key, which stays valid
+    /// across restarts until the client is removed from the configuration
+    async fn session(&self, params: &HashMap<String, String>) -> Result<serde_json::Value, (u32, String)> {
+        let (username, password) = (params.get("username"), params.get("password"));
+        let client = self
+            .clients
+            .iter()
+            .find(|client| {
+                let password_matches = password.is_some_and(|given| secrets_match(given, &client.password));
+                Some(&client.username) == username && password_matches
+            })
+            .ok_or((AUTHENTICATION_FAILED, "Invalid username or password".to_string()))?;
+        let key = self.db.new_audioscrobbler_session(&client.username).await;
+        let key = key.map_err(|e| (OPERATION_FAILED, e.to_string()))?;
+        info!("Audioscrobbler client {} logged in", client.username);
+        Ok(json!({ "session": { "name": client.username, "key": key, "subscriber": 0 } }))
+    }
+
+    /// The client whose session key the call carries
+    async fn client(&self, params: &HashMap<String, String>) -> crate::error::Result<Option<&AudioscrobblerClient>> {
+        let Some(key) = params.get("sk") else {
+            return Ok(None);
+        };
+        let username = self.db.audioscrobbler_session(key).await?;
+        Ok(username.and_then(|username| self.clients.iter().find(|client| client.username == username)))
+    }
+
+    async fn now_playing(
+        &self,
+        client: &AudioscrobblerClient,
+        params: &HashMap<String, String>,
+    ) -> Result<serde_json::Value, (u32, String)> {
+        let mut scrobble =
+            listen(client, params, None).ok_or((INVALID_PARAMETERS, "Missing artist or track".to_string()))?;
+        scrobble.started_at = chrono::Utc::now().timestamp();
+        self.scrobbler.now_playing(&scrobble).await;
+        Ok(json!({ "nowplaying": { "artist": { "#text": scrobble.artist }, "track": { "#text": scrobble.title } } }))
+    }
+
+    async fn scrobble(
+        &self,
+        client: &AudioscrobblerClient,
+        params: &HashMap<String, String>,
+    ) -> Result<serde_json::Value, (u32, String)> {
+        let listens: Vec<Option<Scrobble>> = match params.contains_key("artist[0]") {
+            true => (0..MAX_BATCH)
+                .take_while(|i| params.contains_key(&format!("artist[{}]", i)))
+                .map(|i| listen(client, params, Some(i)))
+                .collect(),
+            false => vec![listen(client, params, None)],
+        };
+
+        let (mut accepted, mut ignored) = (0, 0);
+        for scrobble in listens {
+            let scrobble = scrobble.filter(|scrobble| scrobble.started_at > 0 && self.scrobbler.confident(scrobble));
+            let Some(scrobble) = scrobble else {
+                ignored += 1;
+                continue;
+            };
+            match self.scrobbler.submit(&self.db, &scrobble).await {
+                Ok(true) => accepted += 1,
+                Ok(false) => ignored += 1,
+                Err(e) => {
+                    let track = format!("{} - {}", scrobble.artist, scrobble.title);
+                    warn!("Failed to scrobble {} from {}: {}", track, client.username, e);
+                    ignored += 1;
+                }
+            }
+        }
+        Ok(json!({ "scrobbles": { "@attr": { "accepted": accepted, "ignored": ignored } } }))
+    }
+}
+
+/// The listen in the `index`th entry of a batch, or in the unindexed
+/// parameters; `None` without an artist and track
+fn listen(client: &AudioscrobblerClient, params: &HashMap<String, String>, index: Option<usize>) -> Option<Scrobble> {
+    let field = |name: &str| {
+        let key = match index {
+            Some(i) => format!("{}[{}]", name, i),
+            None => name.to_string(),
+        };
+        params.get(&key).map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+    };
+    Some(Scrobble {
+        device: client.username.clone(),
+        artist: field("artist")?,
+        title: field("track")?,
+        album: field("album"),
+        started_at: field("timestamp").and_then(|timestamp| timestamp.parse().ok()).unwrap_or_default(),
+        duration: field("duration").and_then(|secs| secs.parse().ok()).map(Duration::from_secs),
+        featured: Vec::new(),
+        confidence: Confidence::default(),
+        chosen_by_user: field("chosenByUser").is_none_or(|chosen| chosen != "0"),
+        track_uri: None,
+        room: None,
+    })
+}
+
+/// Last.fm's XML rendering of a JSON response: objects become elements,
+/// `@attr` members attributes and `#text` members text
+fn xml(value: &serde_json::Value) -> String {
+    let serde_json::Value::Object(members) = value else {
+        return escape(&scalar(value)).into_owned();
+    };
+    let mut out = String::new();
+    for (name, value) in members.iter().filter(|(name, _)| !name.starts_with(['@', '#'])) {
+        let attributes: String = value["@attr"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(attr, value)| format!(" {}=\"{}\"", attr, escape(&scalar(value))))
+            .collect();
+        let text = match &value["#text"] {
+            serde_json::Value::Null => xml(value),
+            text => escape(&scalar(text)).into_owned(),
+        };
+        out.push_str(&format!("<{name}{attributes}>{text}</{name}>"));
+    }
+    out
+}
+
+fn scalar(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(value) => value.clone(),
+        serde_json::Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[tokio::test]
+    async fn test_client_scrobbles_through_proxy() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let client = AudioscrobblerClient { username: "mpd".to_string(), password: "pw".to_string(), room: None };
+        let proxy = Audioscrobbler::new(&[client], Arc::new(Scrobbler::default()), db.clone());
+
+        let wrong = proxy.handle(&params(&[("method", "auth.getMobileSession"), ("username", "mpd")])).await;
+        assert_eq!(wrong.status, StatusCode::FORBIDDEN);
+        let login = &[("method", "auth.getMobileSession"), ("username", "mpd"), ("password", "pw"), ("format", "json")];
+        let session: serde_json::Value = serde_json::from_str(&proxy.handle(&params(login)).await.body).unwrap();
+        let key = session["session"]["key"].as_str().unwrap();
+        let again: serde_json::Value = serde_json::from_str(&proxy.handle(&params(login)).await.body).unwrap();
+        assert_ne!(again["session"]["key"].as_str().unwrap(), key);
+        let forged = proxy.handle(&params(&[("method", "track.updateNowPlaying"), ("sk", "0123")])).await;
+        assert_eq!(forged.status, StatusCode::FORBIDDEN);
+
+        let reply = proxy
+            .handle(&params(&[
+                ("method", "track.scrobble"),
+                ("sk", key),
+                ("artist[0]", "Boards of Canada"),
+                ("track[0]", "Roygbiv"),
+                ("timestamp[0]", "1700000000"),
+                ("artist[1]", "Boards of Canada"),
+                ("timestamp[1]", "1700000300"),
+            ]))
+            .await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.body, r#"<lfm status="ok"><scrobbles accepted="1" ignored="1"></scrobbles></lfm>"#);
+        db.flush_pending().await.unwrap();
+        assert!(db.last_submitted("mpd").await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::stats::{Charts, Listen, NewMusic};
+use chrono::{DateTime, Local};
+use std::fmt::Write;
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+/// Height of the plays per day chart, in pixels
+const CHART_HEIGHT: i64 = 160;
+const BAR_WIDTH: usize = 6;
+const ROW_HEIGHT: usize = 20;
+const CELL_SIZE: usize = 18;
+
+/// The dashboard page: plays per day, plays per room and the hour of day
+/// heatmap, drawn as inline SVG so it works without scripts or assets, the
+/// music first heard this week, and the recent listens, each with whether it
+/// is blocklisted. Their "never scrobble" and "scrobble again" buttons post
+/// `form_token` rather than credentials, which stay out of the page.
+pub fn render(charts: &Charts, new_music: &NewMusic, recent: &[(Listen, bool)], form_token: &str) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Sonos Scrobbler</title>\n\
+         <style>body{font-family:sans-serif;margin:2em}svg{display:block;margin-bottom:2em}\
+         text{font-size:11px}</style></head><body>\n<h1>Listening history</h1>\n",
+    );
+    html += &plays_per_day(&charts.per_day);
+    html += &plays_per_room(&charts.per_room);
+    html += &heatmap(&charts.heatmap);
+    html += &new_this_week(new_music);
+    html += &recent_listens(recent, form_token);
+    html += "</body></html>";
+    html
+}
+
+fn plays_per_day(days: &[(String, i64)]) -> String {
+    let max = days.iter().map(|(_, count)| *count).max().unwrap_or(0).max(1);
+    let width = (days.len() * BAR_WIDTH).max(BAR_WIDTH);
+    let mut svg = format!(
+        "<h2>Plays per day</h2>\n<svg width=\"{}\" height=\"{}\">\n",
+        width,
+        CHART_HEIGHT
+    );
+    for (i, (day, count)) in days.iter().enumerate() {
+        let height = count * CHART_HEIGHT / max;
+        let _ = writeln!(
+            svg,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"steelblue\"><title>{}: {}</title></rect>",
+            i * BAR_WIDTH,
+            CHART_HEIGHT - height,
+            BAR_WIDTH - 1,
+            height,
+            escape(day),
+            count
+        );
+    }
+    svg + "</svg>\n"
+}
+
+fn plays_per_room(rooms: &[(String, i64)]) -> String {
+    let max = rooms.iter().map(|(_, count)| *count).max().unwrap_or(0).max(1);
+    let mut svg = format!("<h2>Plays per room</h2>\n<svg width=\"600\" height=\"{}\">\n", rooms.len() * ROW_HEIGHT);
+    for (i, (room, count)) in rooms.iter().enumerate() {
+        let y = i * ROW_HEIGHT;
+        let _ = writeln!(
+            svg,
+            "<text x=\"0\" y=\"{}\">{}</text><rect x=\"150\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"steelblue\"/>\
+             <text x=\"{}\" y=\"{}\">{}</text>",
+            y + 14,
+            escape(room),
+            y + 2,
+            count * 400 / max,
+            ROW_HEIGHT - 4,
+            count * 400 / max + 155,
+            y + 14,
+            count
+        );
+    }
+    svg + "</svg>\n"
+}
+
+fn heatmap(heatmap: &[[i64; 24]; 7]) -> String {
+    let max = heatmap.iter().flatten().copied().max().unwrap_or(0).max(1);
+    let mut svg = format!(
+        "<h2>Plays by hour</h2>\n<svg width=\"{}\" height=\"{}\">\n",
+        40 + 24 * CELL_SIZE,
+        20 + 7 * CELL_SIZE
+    );
+    for hour in (0..24).step_by(3) {
+        let _ = writeln!(svg, "<text x=\"{}\" y=\"12\">{}</text>", 40 + hour * CELL_SIZE, hour);
+    }
+    for (weekday, hours) in heatmap.iter().enumerate() {
+        let y = 20 + weekday * CELL_SIZE;
+        let _ = writeln!(svg, "<text x=\"0\" y=\"{}\">{}</text>", y + 13, WEEKDAYS[weekday]);
+        for (hour, count) in hours.iter().enumerate() {
+            let _ = writeln!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"steelblue\" fill-opacity=\"{:.2}\">\
+                 <title>{} {}:00: {}</title></rect>",
+                40 + hour * CELL_SIZE,
+                y,
+                CELL_SIZE - 2,
+                CELL_SIZE - 2,
+                0.05 + 0.95 * *count as f64 / max as f64,
+                WEEKDAYS[weekday],
+                hour,
+                count
+            );
+        }
+    }
+    svg + "</svg>\n"
+}
+
+fn new_this_week(new_music: &NewMusic) -> String {
+    let mut html = String::from("<h2>New this week</h2>\n");
+    if new_music.is_empty() {
+        return html + "<p>Nothing new yet.</p>\n";
+    }
+    for (heading, entries) in [("Artists", &new_music.artists), ("Tracks", &new_music.tracks)] {
+        if entries.is_empty() {
+            continue;
+        }
+        let _ = write!(html, "<h3>{}</h3>\n<ul>\n", heading);
+        for (name, plays) in entries {
+            let _ = writeln!(html, "<li>{} ({})</li>", escape(name), plays);
+        }
+        html += "</ul>\n";
+    }
+    html
+}
+
+fn recent_listens(listens: &[(Listen, bool)], form_token: &str) -> String {
+    let mut html = String::from("<h2>Recent listens</h2>\n");
+    if listens.is_empty() {
+        return html + "<p>Nothing scrobbled yet.</p>\n";
+    }
+    let button = |action: &str, id: i64, label: &str| {
+        format!(
+            "<form method=\"post\" action=\"/trigger/{}\"><input type=\"hidden\" name=\"id\" value=\"{}\">\
+             <input type=\"hidden\" name=\"form_token\" value=\"{}\"><button>{}</button></form>",
+            action,
+            id,
+            escape(form_token),
+            label
+        )
+    };
+    html += "<table>\n";
+    for (listen, blocklisted) in listens {
+        let started = DateTime::from_timestamp(listen.started_at, 0)
+            .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let action = match blocklisted {
+            true => format!("never scrobbled {}", button("scrobbleagain", listen.id, "Scrobble again")),
+            false => button("neverscrobble", listen.id, "Never scrobble"),
+        };
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{} - {}</td><td>{}</td></tr>",
+            started,
+            escape(&listen.room),
+            escape(&listen.artist),
+            escape(&listen.title),
+            action
+        );
+    }
+    html + "</table>\n"
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_dashboard() {
+        let mut charts = Charts {
+            per_day: vec![("2026-09-07".to_string(), 4), ("2026-09-08".to_string(), 2)],
+            per_room: vec![("Kids <3".to_string(), 6)],
+            ..Charts::default()
+        };
+        charts.heatmap[0][20] = 6;
+        let new_music = NewMusic { artists: vec![("Justice".to_string(), 2)], tracks: Vec::new() };
+        let listen = |id, title: &str| Listen {
+            id,
+            room: "Kitchen".to_string(),
+            artist: "Justice".to_string(),
+            title: title.to_string(),
+            album: None,
+            started_at: 1_757_268_900,
+            file: None,
+            new_artist: false,
+            new_track: false,
+        };
+        let recent = [(listen(7, "Genesis"), false), (listen(6, "D.A.N.C.E."), true)];
+        let html = render(&charts, &new_music, &recent, "f0rm");
+
+        assert!(html.contains("<rect x=\"0\" y=\"0\" width=\"5\" height=\"160\" fill=\"steelblue\">"));
+        assert!(html.contains("<title>2026-09-08: 2</title>"));
+        assert!(html.contains(">Kids &lt;3</text>"));
+        assert!(html.contains("fill-opacity=\"1.00\"><title>Mon 20:00: 6</title>"));
+        assert!(html.contains("fill-opacity=\"0.05\"><title>Sun 23:00: 0</title>"));
+        assert!(html.contains("<h3>Artists</h3>\n<ul>\n<li>Justice (2)</li>\n</ul>\n<h2>Recent listens</h2>"));
+        let form = "<td>Justice - Genesis</td><td><form method=\"post\" action=\"/trigger/neverscrobble\">\
+                    <input type=\"hidden\" name=\"id\" value=\"7\"><input type=\"hidden\" name=\"form_token\" \
+                    value=\"f0rm\"><button>Never scrobble</button></form></td>";
+        assert!(html.contains(form));
+        assert!(html.contains("<td>Justice - D.A.N.C.E.</td><td>never scrobbled <form method=\"post\" \
+                               action=\"/trigger/scrobbleagain\"><input type=\"hidden\" name=\"id\" value=\"6\">"));
+    }
+}
@@ -0,0 +1,615 @@
+use super::{
+    limit, Server, DEFAULT_AGGREGATE_LIMIT, DEFAULT_HISTORY_LIMIT, MAX_AGGREGATE_LIMIT, MAX_HISTORY_LIMIT,
+};
+use crate::error::{Error, Result};
+use crate::stats::{GroupBy, HistorySort, ListenFilter};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes};
+use hyper::{Method, Request, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+pub(super) const GRAPHQL_PATH: &str = "/api/graphql";
+
+/// A request as sent by GraphQL clients
+#[derive(Debug, Default, Deserialize)]
+struct GraphqlRequest {
+    query: String,
+    #[serde(default)]
+    variables: Option<Map<String, Value>>,
+}
+
+impl Server {
+    /// Answers `/api/graphql`, taking the same credentials as the triggers.
+    /// The query comes as a `{"query": ..., "variables": {...}}` POST body,
+    /// or as `?query=` and `?variables=` on a GET.
+    ///
+    /// The schema is a read-only view of the listening history:
+    ///
+    /// ```graphql
+    /// type Query {
+    ///   listens(room: String, artist: String, from: String, to: String,
+    ///           sort: Sort, cursor: String, limit: Int): ListenPage!
+    ///   aggregate(by: GroupBy!, room: String, artist: String, from: String,
+    ///             to: String, limit: Int): [Count!]!
+    /// }
+    /// type ListenPage { listens: [Listen!]!, next: String }
+    /// type Listen { id: Int!, room: String!, artist: String!, title: String!, album: String,
+    ///               started_at: Int!, file: String, new_artist: Boolean!, new_track: Boolean! }
+    /// type Count { key: String!, count: Int! }
+    /// enum Sort { NEWEST, OLDEST }
+    /// enum GroupBy { ROOM, ARTIST, TRACK, ALBUM, GENRE, DAY, MONTH }
+    /// ```
+    ///
+    /// The arguments work like the query parameters of `/api/history` and
+    /// `/api/aggregate`. Variables are supported, fragments and directives
+    /// are not. Introspection answers `__schema` and `__type(name:)` with the
+    /// types above, but without fragments the full query GraphiQL sends is
+    /// refused; clients have to spell out the fields they want.
+    pub(super) async fn graphql_request<B: Body>(
+        &self,
+        request: Request<B>,
+        query: &HashMap<String, String>,
+    ) -> (StatusCode, String) {
+        if let Some(refusal) = self.refuse(GRAPHQL_PATH, query, request.headers()) {
+            return refusal;
+        }
+        let body = match *request.method() {
+            Method::GET => Bytes::new(),
+            Method::POST => request.into_body().collect().await.map(|body| body.to_bytes()).unwrap_or_default(),
+            _ => return (StatusCode::METHOD_NOT_ALLOWED, "use GET or POST\n".to_string()),
+        };
+        // Errors are reported in the response document, as GraphQL clients expect
+        let response = match self.graphql(query, &body).await {
+            Ok(data) => json!({ "data": data }),
+            Err(Error::Config(message)) => json!({ "errors": [{ "message": message }] }),
+            Err(e) => json!({ "errors": [{ "message": e.to_string() }] }),
+        };
+        (StatusCode::OK, response.to_string() + "\n")
+    }
+
+    async fn graphql(&self, query: &HashMap<String, String>, body: &[u8]) -> Result<Value> {
+        let invalid = |e: serde_json::Error| Error::Config(format!("invalid GraphQL request: {}", e));
+        let request = if body.is_empty() {
+            GraphqlRequest {
+                query: query.get("query").cloned().unwrap_or_default(),
+                variables: query.get("variables").map(|json| serde_json::from_str(json)).transpose().map_err(invalid)?,
+            }
+        } else {
+            serde_json::from_slice(body).map_err(invalid)?
+        };
+
+        let mut data = Map::new();
+        for field in parse(&request.query, request.variables.unwrap_or_default())? {
+            let value = match field.name.as_str() {
+                "__typename" => Value::from("Query"),
+                "__schema" => select(&schema(), &field, "__Schema")?,
+                "__type" => {
+                    let name = field.arguments().get("name").cloned().unwrap_or_default();
+                    let schema = schema();
+                    let mut types = schema["types"].as_array().into_iter().flatten();
+                    match types.find(|found| found["name"] == name.as_str()) {
+                        Some(found) => select(found, &field, "__Type")?,
+                        None => Value::Null,
+                    }
+                }
+                "listens" => self.graphql_listens(&field).await?,
+                "aggregate" => self.graphql_aggregate(&field).await?,
+                name => return Err(Error::Config(format!("unknown field {} on Query", name))),
+            };
+            data.insert(field.key().to_string(), value);
+        }
+        Ok(Value::Object(data))
+    }
+
+    async fn graphql_listens(&self, field: &Field) -> Result<Value> {
+        let args = field.arguments();
+        let filter = ListenFilter::from_query(&args)?;
+        let sort = args.get("sort").map_or(Ok(HistorySort::default()), |sort| HistorySort::parse(sort))?;
+        let after = args.get("cursor").map(|cursor| cursor.parse()).transpose()?;
+        let limit = limit(&args, DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT)?;
+
+        let (listens, next) = self.history_page(&filter, sort, after, limit).await?;
+        let page = json!({ "listens": listens, "next": next.map(|cursor| cursor.to_string()) });
+        select(&page, field, "ListenPage")
+    }
+
+    async fn graphql_aggregate(&self, field: &Field) -> Result<Value> {
+        let args = field.arguments();
+        let by = args.get("by").ok_or_else(|| Error::Config("aggregate needs an argument by".to_string()))?;
+        let by = GroupBy::parse(by)?;
+        let filter = ListenFilter::from_query(&args)?;
+        let limit = limit(&args, DEFAULT_AGGREGATE_LIMIT, MAX_AGGREGATE_LIMIT)?;
+
+        let counts: Vec<_> = self
+            .database()?
+            .aggregate(&filter, by, limit)
+            .await?
+            .into_iter()
+            .map(|(key, count)| json!({ "key": key, "count": count }))
+            .collect();
+        select(&Value::from(counts), field, "Count")
+    }
+}
+
+/// A field of a selection set, its arguments resolved against the variables
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    alias: Option<String>,
+    name: String,
+    arguments: Vec<(String, Value)>,
+    selection: Vec<Field>,
+}
+
+impl Field {
+    /// Name of the field in the response
+    fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+
+    /// The arguments as query parameters, enum values in lowercase. Null
+    /// arguments are left out, as if not given.
+    fn arguments(&self) -> HashMap<String, String> {
+        let text = |value: &Value| match value {
+            Value::String(text) => Some(text.clone()),
+            Value::Null => None,
+            value => Some(value.to_string()),
+        };
+        let enums = ["by", "sort"];
+        self.arguments
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = text(value)?;
+                Some((name.clone(), if enums.contains(&name.as_str()) { value.to_lowercase() } else { value }))
+            })
+            .collect()
+    }
+}
+
+/// The parts of `value`, of type `type_name` or a list of it, that `field`
+/// selects
+fn select(value: &Value, field: &Field, type_name: &str) -> Result<Value> {
+    match value {
+        Value::Null => Ok(Value::Null),
+        Value::Array(items) => items.iter().map(|item| select(item, field, type_name)).collect(),
+        Value::Object(object) => {
+            if field.selection.is_empty() {
+                return Err(Error::Config(format!("field {} of type {} needs a selection", field.name, type_name)));
+            }
+            let mut selected = Map::new();
+            for child in &field.selection {
+                let value = match (child.name.as_str(), object.get(&child.name)) {
+                    ("__typename", _) => Value::from(type_name),
+                    (name, Some(value)) => select(value, child, field_type(type_name, name))?,
+                    (name, None) => return Err(Error::Config(format!("unknown field {} on {}", name, type_name))),
+                };
+                selected.insert(child.key().to_string(), value);
+            }
+            Ok(Value::Object(selected))
+        }
+        scalar if field.selection.is_empty() => Ok(scalar.clone()),
+        _ => Err(Error::Config(format!("field {} is a scalar and takes no selection", field.name))),
+    }
+}
+
+/// Type of the object field `name` of `type_name`
+fn field_type(type_name: &str, name: &str) -> &'static str {
+    match (type_name, name) {
+        ("ListenPage", "listens") => "Listen",
+        ("__Schema", "queryType" | "types") | ("__Field" | "__InputValue", "type") | ("__Type", "ofType") => "__Type",
+        ("__Type", "fields") => "__Field",
+        ("__Field", "args") => "__InputValue",
+        ("__Type", "enumValues") => "__EnumValue",
+        _ => "",
+    }
+}
+
+/// The schema in the shape of the introspection types
+fn schema() -> Value {
+    let named = |kind: &str, name: &str| json!({ "kind": kind, "name": name, "ofType": null });
+    let required = |of: Value| json!({ "kind": "NON_NULL", "name": null, "ofType": of });
+    let list = |of: Value| json!({ "kind": "LIST", "name": null, "ofType": of });
+    let (string, int, boolean) = (named("SCALAR", "String"), named("SCALAR", "Int"), named("SCALAR", "Boolean"));
+    let input = |name: &str, kind: &Value| {
+        json!({ "name": name, "description": null, "type": kind, "defaultValue": null })
+    };
+    let field = |name: &str, args: Vec<Value>, kind: Value| {
+        json!({
+            "name": name, "description": null, "args": args, "type": kind,
+            "isDeprecated": false, "deprecationReason": null,
+        })
+    };
+    let object = |name: &str, fields: Vec<Value>| {
+        json!({
+            "kind": "OBJECT", "name": name, "description": null, "fields": fields, "interfaces": [],
+            "possibleTypes": null, "enumValues": null, "inputFields": null,
+        })
+    };
+    let enumeration = |name: &str, values: &[&str]| {
+        let values: Vec<_> = values
+            .iter()
+            .map(|value| {
+                json!({ "name": value, "description": null, "isDeprecated": false, "deprecationReason": null })
+            })
+            .collect();
+        json!({
+            "kind": "ENUM", "name": name, "description": null, "fields": null, "interfaces": null,
+            "possibleTypes": null, "enumValues": values, "inputFields": null,
+        })
+    };
+    let scalar = |name: &str| {
+        json!({
+            "kind": "SCALAR", "name": name, "description": null, "fields": null, "interfaces": null,
+            "possibleTypes": null, "enumValues": null, "inputFields": null,
+        })
+    };
+    let filters = || ["room", "artist", "from", "to"].map(|name| input(name, &string));
+
+    let mut listens_args = filters().to_vec();
+    listens_args.extend([input("sort", &named("ENUM", "Sort")), input("cursor", &string), input("limit", &int)]);
+    let mut aggregate_args = vec![input("by", &required(named("ENUM", "GroupBy")))];
+    aggregate_args.extend(filters());
+    aggregate_args.push(input("limit", &int));
+    let types = vec![
+        object(
+            "Query",
+            vec![
+                field("listens", listens_args, required(named("OBJECT", "ListenPage"))),
+                field("aggregate", aggregate_args, required(list(required(named("OBJECT", "Count"))))),
+            ],
+        ),
+        object(
+            "ListenPage",
+            vec![
+                field("listens", vec![], required(list(required(named("OBJECT", "Listen"))))),
+                field("next", vec![], string.clone()),
+            ],
+        ),
+        object(
+            "Listen",
+            vec![
+                field("id", vec![], required(int.clone())),
+                field("room", vec![], required(string.clone())),
+                field("artist", vec![], required(string.clone())),
+                field("title", vec![], required(string.clone())),
+                field("album", vec![], string.clone()),
+                field("started_at", vec![], required(int.clone())),
+                field("file", vec![], string.clone()),
+                field("new_artist", vec![], required(boolean.clone())),
+                field("new_track", vec![], required(boolean.clone())),
+            ],
+        ),
+        object("Count", vec![field("key", vec![], required(string)), field("count", vec![], required(int))]),
+        enumeration("Sort", &["NEWEST", "OLDEST"]),
+        enumeration("GroupBy", &["ROOM", "ARTIST", "TRACK", "ALBUM", "GENRE", "DAY", "MONTH"]),
+        scalar("String"),
+        scalar("Int"),
+        scalar("Boolean"),
+    ];
+    json!({
+        "queryType": { "name": "Query" },
+        "mutationType": null,
+        "subscriptionType": null,
+        "types": types,
+        "directives": [],
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punctuator(char),
+    Spread,
+    Name(String),
+    Str(String),
+    Number(String),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let syntax = |message: String| Error::Config(format!("GraphQL syntax error: {}", message));
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // Commas are insignificant, like whitespace
+            c if c.is_whitespace() || c == ',' || c == '\u{feff}' => {}
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '{' | '}' | '(' | ')' | '[' | ']' | ':' | '$' | '!' | '=' | '@' => tokens.push(Token::Punctuator(c)),
+            '.' if chars.next_if_eq(&'.').is_some() && chars.next_if_eq(&'.').is_some() => tokens.push(Token::Spread),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some('r') => text.push('\r'),
+                            Some('u') => {
+                                let hex: String = (0..4).filter_map(|_| chars.next()).collect();
+                                let escaped = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                                text.push(escaped.ok_or_else(|| syntax(format!("invalid escape \\u{}", hex)))?);
+                            }
+                            Some(c) => text.push(c),
+                            None => return Err(syntax("unterminated string".to_string())),
+                        },
+                        Some(c) => text.push(c),
+                        None => return Err(syntax("unterminated string".to_string())),
+                    }
+                }
+                tokens.push(Token::Str(text));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut number = c.to_string();
+                while let Some(c) = chars.next_if(|&c| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-')) {
+                    number.push(c);
+                }
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = c.to_string();
+                while let Some(c) = chars.next_if(|&c| c.is_ascii_alphanumeric() || c == '_') {
+                    name.push(c);
+                }
+                tokens.push(Token::Name(name));
+            }
+            c => return Err(syntax(format!("unexpected character {:?}", c))),
+        }
+    }
+    Ok(tokens)
+}
+
+/// The fields selected by the single query in `source`
+fn parse(source: &str, variables: Map<String, Value>) -> Result<Vec<Field>> {
+    let mut parser = Parser { tokens: tokenize(source)?, position: 0, variables };
+    let selection = match parser.peek() {
+        Some(Token::Punctuator('{')) => parser.selection_set()?,
+        Some(Token::Name(keyword)) if keyword == "query" => {
+            parser.next();
+            if let Some(Token::Name(_)) = parser.peek() {
+                parser.next();
+            }
+            if parser.peek() == Some(&Token::Punctuator('(')) {
+                parser.variable_definitions()?;
+            }
+            parser.selection_set()?
+        }
+        Some(Token::Name(operation)) if operation == "mutation" || operation == "subscription" => {
+            return Err(Error::Config(format!("{} operations are not supported", operation)));
+        }
+        _ => return Err(parser.unexpected("a query")),
+    };
+    if parser.peek().is_some() {
+        return Err(Error::Config("only one operation per request is supported".to_string()));
+    }
+    Ok(selection)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    variables: Map<String, Value>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn unexpected(&self, expected: &str) -> Error {
+        let found = match self.peek() {
+            Some(Token::Punctuator(c)) => c.to_string(),
+            Some(Token::Spread) => "...".to_string(),
+            Some(Token::Name(text) | Token::Str(text) | Token::Number(text)) => text.clone(),
+            None => "the end".to_string(),
+        };
+        Error::Config(format!("GraphQL syntax error: expected {}, found {}", expected, found))
+    }
+
+    fn expect(&mut self, punctuator: char) -> Result<()> {
+        match self.peek() {
+            Some(Token::Punctuator(c)) if *c == punctuator => {
+                self.next();
+                Ok(())
+            }
+            _ => Err(self.unexpected(&format!("{:?}", punctuator))),
+        }
+    }
+
+    fn name(&mut self) -> Result<String> {
+        match self.peek() {
+            Some(Token::Name(name)) => {
+                let name = name.clone();
+                self.next();
+                Ok(name)
+            }
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    /// `($room: String = "Kitchen", ...)`, whose defaults fill in variables
+    /// that weren't given. The types aren't checked.
+    fn variable_definitions(&mut self) -> Result<()> {
+        self.expect('(')?;
+        while self.peek() != Some(&Token::Punctuator(')')) {
+            self.expect('$')?;
+            let name = self.name()?;
+            self.expect(':')?;
+            while let Some(Token::Name(_) | Token::Punctuator('[' | ']' | '!')) = self.peek() {
+                self.next();
+            }
+            if self.peek() == Some(&Token::Punctuator('=')) {
+                self.next();
+                let default = self.value()?;
+                self.variables.entry(name).or_insert(default);
+            }
+        }
+        self.expect(')')
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Field>> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        while self.peek() != Some(&Token::Punctuator('}')) {
+            fields.push(self.field()?);
+        }
+        self.expect('}')?;
+        Ok(fields)
+    }
+
+    fn field(&mut self) -> Result<Field> {
+        match self.peek() {
+            Some(Token::Spread) => return Err(Error::Config("fragments are not supported".to_string())),
+            Some(Token::Punctuator('@')) => return Err(Error::Config("directives are not supported".to_string())),
+            _ => {}
+        }
+        let mut name = self.name()?;
+        let mut alias = None;
+        if self.peek() == Some(&Token::Punctuator(':')) {
+            self.next();
+            alias = Some(std::mem::replace(&mut name, self.name()?));
+        }
+        let mut arguments = Vec::new();
+        if self.peek() == Some(&Token::Punctuator('(')) {
+            self.next();
+            while self.peek() != Some(&Token::Punctuator(')')) {
+                let argument = self.name()?;
+                self.expect(':')?;
+                arguments.push((argument, self.value()?));
+            }
+            self.expect(')')?;
+        }
+        if self.peek() == Some(&Token::Punctuator('@')) {
+            return Err(Error::Config("directives are not supported".to_string()));
+        }
+        let selection = match self.peek() {
+            Some(Token::Punctuator('{')) => self.selection_set()?,
+            _ => Vec::new(),
+        };
+        Ok(Field { alias, name, arguments, selection })
+    }
+
+    /// A literal or variable; enum values are read as strings
+    fn value(&mut self) -> Result<Value> {
+        match self.next() {
+            Some(Token::Punctuator('$')) => {
+                let name = self.name()?;
+                Ok(self.variables.get(&name).cloned().unwrap_or(Value::Null))
+            }
+            Some(Token::Str(text)) => Ok(Value::String(text)),
+            Some(Token::Number(number)) => serde_json::from_str::<serde_json::Number>(&number)
+                .map(Value::Number)
+                .map_err(|_| Error::Config(format!("GraphQL syntax error: invalid number {}", number))),
+            Some(Token::Name(name)) => Ok(match name.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                _ => Value::String(name),
+            }),
+            Some(Token::Punctuator('[')) => {
+                let mut items = Vec::new();
+                while self.peek() != Some(&Token::Punctuator(']')) {
+                    items.push(self.value()?);
+                }
+                self.expect(']')?;
+                Ok(Value::Array(items))
+            }
+            _ => {
+                self.position -= 1;
+                Err(self.unexpected("a value"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::control::Controller;
+    use crate::scrobble::{Scrobble, Scrobbler};
+    use crate::sonos::TrackDatabase;
+    use http_body_util::Full;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_graphql_queries_history() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobble = Scrobble::new("Kitchen", "Daft Punk", "Get Lucky", 1_700_000_000);
+        let later = Scrobble { title: "Instant Crush".to_string(), started_at: 1_700_100_000, ..scrobble.clone() };
+        let elsewhere = Scrobble { device: "Office".to_string(), artist: "Air".to_string(), ..later.clone() };
+        for scrobble in [&scrobble, &later, &elsewhere] {
+            db.record_scrobble(scrobble).await.unwrap();
+        }
+        let controller = Controller::new(&[], Arc::new(Scrobbler::default())).unwrap();
+        let config = HttpConfig { api_token: Some("t0ken".to_string()), ..HttpConfig::default() };
+        let server = Server::new(&config, Arc::new(controller)).with_database(db);
+        let call = |token: &str, body: Value| {
+            Request::post(GRAPHQL_PATH)
+                .header("authorization", format!("Bearer {}", token))
+                .body(Full::new(Bytes::from(body.to_string())))
+                .unwrap()
+        };
+
+        let query = r#"query Kitchen($room: String = "Office", $first: Int) {
+            rooms: aggregate(by: ROOM) { key count }
+            listens(room: $room, sort: OLDEST, limit: $first) {
+                listens { __typename title room }
+                next
+            }
+        }"#;
+        let request = call("t0ken", json!({ "query": query, "variables": { "room": "kitchen", "first": 1 } }));
+        let (status, body) = server.graphql_request(request, &HashMap::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            response["data"],
+            json!({
+                "rooms": [{ "key": "Kitchen", "count": 2 }, { "key": "Office", "count": 1 }],
+                "listens": {
+                    "listens": [{ "__typename": "Listen", "title": "Get Lucky", "room": "Kitchen" }],
+                    "next": "1700000000.1"
+                }
+            })
+        );
+
+        let request = call("t0ken", json!({ "query": "{ listens { listens { id ...on Listen { title } } } }" }));
+        let (_, body) = server.graphql_request(request, &HashMap::new()).await;
+        assert_eq!(body, "{\"errors\":[{\"message\":\"fragments are not supported\"}]}\n");
+        let request = call("t0ken", json!({ "query": "{ aggregate(by: ARTIST) { key plays } }" }));
+        let (_, body) = server.graphql_request(request, &HashMap::new()).await;
+        assert!(body.contains("unknown field plays on Count"));
+
+        let query = r#"{
+            __schema { queryType { name } types { name } }
+            __type(name: "Count") { kind fields { name type { kind ofType { name } } } }
+            missing: __type(name: "Track") { name }
+        }"#;
+        let request = call("t0ken", json!({ "query": query }));
+        let (_, body) = server.graphql_request(request, &HashMap::new()).await;
+        let response: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(response["data"]["__schema"]["queryType"]["name"], "Query");
+        assert_eq!(response["data"]["__schema"]["types"][2], json!({ "name": "Listen" }));
+        assert_eq!(
+            response["data"]["__type"],
+            json!({
+                "kind": "OBJECT",
+                "fields": [
+                    { "name": "key", "type": { "kind": "NON_NULL", "ofType": { "name": "String" } } },
+                    { "name": "count", "type": { "kind": "NON_NULL", "ofType": { "name": "Int" } } }
+                ]
+            })
+        );
+        assert_eq!(response["data"]["missing"], Value::Null);
+
+        let request = call("wrong", json!({ "query": "{ __typename }" }));
+        let (status, _) = server.graphql_request(request, &HashMap::new()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,473 @@
+use super::{Server, DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT};
+use crate::error::{Error, Result};
+use crate::stats::{HistorySort, ListenFilter};
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::body::{Body, Bytes, Frame};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::http2;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use log::{debug, info, warn};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+/// Path prefix of the methods of the `Control` service
+const SERVICE: &str = "/sonos_scrobbler.control.v1.Control/";
+/// Largest request accepted; requests are a few dozen bytes
+const MAX_REQUEST: usize = 64 * 1024;
+/// What `grpc-message` percent-encodes besides non-ASCII
+const MESSAGE_ESCAPES: &AsciiSet = &CONTROLS.add(b'%');
+
+// Status codes, from grpc/status.proto
+const OK: u32 = 0;
+const INVALID_ARGUMENT: u32 = 3;
+const NOT_FOUND: u32 = 5;
+const RESOURCE_EXHAUSTED: u32 = 8;
+const UNIMPLEMENTED: u32 = 12;
+const UNAVAILABLE: u32 = 14;
+const UNAUTHENTICATED: u32 = 16;
+
+/// The encoded reply of a call, or its status code and message
+type Reply = std::result::Result<Vec<u8>, (u32, String)>;
+
+impl Server {
+    /// Serves the `Control` service of `proto/control.proto` over plaintext
+    /// HTTP/2 on `listen`. Only unary calls without compression are
+    /// supported, which is all the service has.
+    pub(super) async fn serve_grpc(self: Arc<Self>, listen: SocketAddr) -> Result<()> {
+        let tcp = TcpListener::bind(listen)
+            .await
+            .map_err(|e| Error::Config(format!("cannot listen on {}: {}", listen, e)))?;
+        info!("gRPC server listening on {}", listen);
+
+        loop {
+            let (stream, peer) = match tcp.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |request| {
+                    let server = server.clone();
+                    async move { Ok::<_, Infallible>(server.grpc_call(request).await) }
+                });
+                let connection =
+                    http2::Builder::new(TokioExecutor::new()).serve_connection(TokioIo::new(stream), service);
+                if let Err(e) = connection.await {
+                    debug!("gRPC connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Answers a call, logging it like [`Server::handle`] does requests
+    async fn grpc_call<B: Body>(&self, request: Request<B>) -> Response<GrpcBody> {
+        let path = request.uri().path().to_string();
+        let started = Instant::now();
+        let reply = self.grpc_reply(&path, request).await;
+        let status = reply.as_ref().map_or_else(|(status, _)| *status, |_| OK);
+        debug!("gRPC {} status {} in {:?}", path, status, started.elapsed());
+        response(reply)
+    }
+
+    async fn grpc_reply<B: Body>(&self, path: &str, request: Request<B>) -> Reply {
+        if !self.authorized(&HashMap::new(), request.headers()) {
+            warn!("Rejected gRPC call {} with a missing or wrong API token", path);
+            return Err((UNAUTHENTICATED, "missing or wrong API token".to_string()));
+        }
+        let body = request.into_body().map_err(|_| Error::Config("gRPC request broke off".to_string()));
+        // The message and its five byte frame header
+        let body = match Limited::new(body, MAX_REQUEST + 5).collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(e) if e.is::<LengthLimitError>() => {
+                return Err((RESOURCE_EXHAUSTED, "gRPC request too large".to_string()));
+            }
+            Err(e) => return Err((INVALID_ARGUMENT, e.to_string())),
+        };
+        let fields = Fields::decode(unframe(&body).map_err(status)?).map_err(status)?;
+        let room = fields.string(1);
+        let required = |method| room.ok_or_else(|| (INVALID_ARGUMENT, format!("{} needs a room", method)));
+
+        let reply = match path.strip_prefix(SERVICE).unwrap_or_default() {
+            "NowPlaying" => Ok(self.grpc_now_playing().await),
+            "History" => self.grpc_history(&fields).await,
+            "Pause" => self
+                .controller
+                .pause(room)
+                .await
+                .map(|rooms| rooms.iter().fold(Message::default(), |reply, room| reply.string(1, room))),
+            "Love" => self.controller.love(room).await.map(|track| Message::default().string(1, &track)),
+            "QueuePreview" => self.grpc_queue_preview(room).await,
+            "DontScrobble" => self.controller.dont_scrobble(room).map(|track| Message::default().string(1, &track)),
+            "Skip" => self.controller.skip(required("Skip")?).await.map(|room| Message::default().string(1, &room)),
+            "RemoveFromQueue" => {
+                let position = u32::try_from(fields.varint(2)).unwrap_or(u32::MAX);
+                let removed = self.controller.remove_from_queue(required("RemoveFromQueue")?, position).await;
+                removed.map(|room| Message::default().string(1, &room))
+            }
+            "ClearQueue" => {
+                let cleared = self.controller.clear_queue(required("ClearQueue")?).await;
+                cleared.map(|room| Message::default().string(1, &room))
+            }
+            _ => return Err((UNIMPLEMENTED, format!("unknown method {}", path))),
+        };
+        reply.map(|reply| reply.0).map_err(status)
+    }
+
+    async fn grpc_now_playing(&self) -> Message {
+        let mut reply = Message::default();
+        for (room, playing) in self.controller.playing().await {
+            let speaker = match playing {
+                Ok(playing) => Message::default()
+                    .string(1, &room)
+                    .string(2, &playing.uri)
+                    .string(3, or_empty(&playing.artist))
+                    .string(4, or_empty(&playing.title))
+                    .string(5, or_empty(&playing.album))
+                    .varint(6, secs(playing.duration))
+                    .varint(7, secs(playing.position)),
+                Err(e) => Message::default().string(1, &room).string(8, &e.to_string()),
+            };
+            reply = reply.message(1, speaker);
+        }
+        reply
+    }
+
+    async fn grpc_history(&self, fields: &Fields) -> Result<Message> {
+        let time = |field| Some(fields.varint(field) as i64).filter(|&time| time != 0);
+        let filter = ListenFilter {
+            room: fields.string(1).map(str::to_string),
+            artist: fields.string(2).map(str::to_string),
+            from: time(3),
+            to: time(4),
+        };
+        let sort = if fields.varint(6) != 0 { HistorySort::Oldest } else { HistorySort::Newest };
+        let after = fields.string(7).map(str::parse).transpose()?;
+        let limit = match fields.varint(5) {
+            0 => DEFAULT_HISTORY_LIMIT,
+            limit => limit.min(MAX_HISTORY_LIMIT as u64) as u32,
+        };
+
+        let (listens, next) = self.history_page(&filter, sort, after, limit).await?;
+        let reply = listens.iter().fold(Message::default(), |reply, listen| {
+            let listen = Message::default()
+                .varint(1, listen.id as u64)
+                .string(2, &listen.room)
+                .string(3, &listen.artist)
+                .string(4, &listen.title)
+                .string(5, or_empty(&listen.album))
+                .varint(6, listen.started_at as u64)
+                .string(7, or_empty(&listen.file))
+                .varint(8, listen.new_artist as u64)
+                .varint(9, listen.new_track as u64);
+            reply.message(1, listen)
+        });
+        Ok(reply.string(2, &next.map(|cursor| cursor.to_string()).unwrap_or_default()))
+    }
+
+    async fn grpc_queue_preview(&self, room: Option<&str>) -> Result<Message> {
+        let room = room.ok_or_else(|| Error::Config("QueuePreview needs a room".to_string()))?;
+        let queue = self.controller.queue_preview(room).await?;
+        Ok(queue.iter().fold(Message::default(), |reply, item| {
+            let item = Message::default()
+                .varint(1, item.position.into())
+                .string(2, &item.track)
+                .string(3, or_empty(&item.artist))
+                .string(4, or_empty(&item.title))
+                .string(5, or_empty(&item.album))
+                .varint(6, item.duration_secs.unwrap_or_default())
+                .varint(7, item.scrobble.into())
+                .string(8, or_empty(&item.reason));
+            reply.message(1, item)
+        }))
+    }
+}
+
+/// The gRPC status for a failed call, along the lines of the HTTP API's
+fn status(e: Error) -> (u32, String) {
+    match e {
+        Error::Discovery(_) => (NOT_FOUND, e.to_string()),
+        Error::Config(_) => (INVALID_ARGUMENT, e.to_string()),
+        _ => (UNAVAILABLE, e.to_string()),
+    }
+}
+
+fn or_empty(value: &Option<String>) -> &str {
+    value.as_deref().unwrap_or_default()
+}
+
+fn secs(duration: Option<Duration>) -> u64 {
+    duration.map_or(0, |duration| duration.as_secs())
+}
+
+/// The message of a request body: a flag byte for compression, the length as
+/// four big-endian bytes, then the message
+fn unframe(body: &[u8]) -> Result<&[u8]> {
+    let invalid = || Error::Config("invalid gRPC request".to_string());
+    let (&compressed, rest) = body.split_first().ok_or_else(invalid)?;
+    if compressed != 0 {
+        return Err(Error::Config("compressed requests are not supported".to_string()));
+    }
+    let (len, message) = rest.split_first_chunk::<4>().ok_or_else(invalid)?;
+    let len = u32::from_be_bytes(*len) as usize;
+    if len > MAX_REQUEST {
+        return Err(Error::Config("gRPC request too large".to_string()));
+    }
+    message.get(..len).ok_or_else(invalid)
+}
+
+fn frame(message: &[u8]) -> Bytes {
+    let mut framed = Vec::with_capacity(message.len() + 5);
+    framed.push(0);
+    framed.extend((message.len() as u32).to_be_bytes());
+    framed.extend(message);
+    framed.into()
+}
+
+fn response(reply: Reply) -> Response<GrpcBody> {
+    let (message, status, text) = match reply {
+        Ok(message) => (Some(frame(&message)), OK, String::new()),
+        Err((status, text)) => (None, status, text),
+    };
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(status));
+    if let Ok(text) = HeaderValue::from_str(&utf8_percent_encode(&text, MESSAGE_ESCAPES).to_string()) {
+        if !text.is_empty() {
+            trailers.insert("grpc-message", text);
+        }
+    }
+    Response::builder()
+        .header(CONTENT_TYPE, "application/grpc")
+        .body(GrpcBody { message, trailers: Some(trailers) })
+        .unwrap_or_default()
+}
+
+/// The body of a unary reply: the message, if the call succeeded, then the
+/// status as trailers
+#[derive(Default)]
+struct GrpcBody {
+    message: Option<Bytes>,
+    trailers: Option<HeaderMap>,
+}
+
+impl Body for GrpcBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Bytes>, Infallible>>> {
+        let frame = match self.message.take() {
+            Some(message) => Frame::data(message),
+            None => match self.trailers.take() {
+                Some(trailers) => Frame::trailers(trailers),
+                None => return Poll::Ready(None),
+            },
+        };
+        Poll::Ready(Some(Ok(frame)))
+    }
+}
+
+/// A protobuf message being encoded. Fields holding their type's default
+/// are left out, as proto3 does.
+#[derive(Debug, Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(mut self, field: u32, value: u64) -> Self {
+        if value != 0 {
+            put_varint(&mut self.0, u64::from(field) << 3);
+            put_varint(&mut self.0, value);
+        }
+        self
+    }
+
+    fn string(self, field: u32, value: &str) -> Self {
+        match value.is_empty() {
+            true => self,
+            false => self.bytes(field, value.as_bytes()),
+        }
+    }
+
+    /// Appends `message`, also when empty since it may be a repeated field's
+    fn message(self, field: u32, message: Message) -> Self {
+        self.bytes(field, &message.0)
+    }
+
+    fn bytes(mut self, field: u32, value: &[u8]) -> Self {
+        put_varint(&mut self.0, u64::from(field) << 3 | 2);
+        put_varint(&mut self.0, value.len() as u64);
+        self.0.extend(value);
+        self
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// A decoded protobuf message's varint and length-delimited fields by
+/// number; of repeated fields, the last. Other fields are skipped.
+#[derive(Debug, Default)]
+struct Fields {
+    varints: HashMap<u32, u64>,
+    bytes: HashMap<u32, Vec<u8>>,
+}
+
+impl Fields {
+    fn decode(mut data: &[u8]) -> Result<Self> {
+        let mut fields = Fields::default();
+        while !data.is_empty() {
+            let key = take_varint(&mut data)?;
+            let field = (key >> 3) as u32;
+            match key & 7 {
+                0 => {
+                    fields.varints.insert(field, take_varint(&mut data)?);
+                }
+                1 => drop(take(&mut data, 8)?),
+                2 => {
+                    let len = take_varint(&mut data)? as usize;
+                    fields.bytes.insert(field, take(&mut data, len)?.to_vec());
+                }
+                5 => drop(take(&mut data, 4)?),
+                wire_type => return Err(Error::Config(format!("unsupported protobuf wire type {}", wire_type))),
+            }
+        }
+        Ok(fields)
+    }
+
+    /// A string field, unless empty or not UTF-8
+    fn string(&self, field: u32) -> Option<&str> {
+        let value = self.bytes.get(&field)?;
+        std::str::from_utf8(value).ok().filter(|value| !value.is_empty())
+    }
+
+    fn varint(&self, field: u32) -> u64 {
+        self.varints.get(&field).copied().unwrap_or_default()
+    }
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if len > data.len() {
+        return Err(Error::Config("truncated protobuf message".to_string()));
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Ok(taken)
+}
+
+fn take_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = take(data, 1)?[0];
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::Config("protobuf varint too long".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::control::Controller;
+    use crate::scrobble::{Scrobble, Scrobbler};
+    use crate::sonos::{SonosDevice, TrackDatabase};
+
+    #[test]
+    fn test_message_round_trip() {
+        let message = Message::default()
+            .string(1, "Living Room")
+            .string(2, "")
+            .varint(3, -5i64 as u64)
+            .varint(5, 300)
+            .message(9, Message::default());
+
+        let fields = Fields::decode(unframe(&frame(&message.0)).unwrap()).unwrap();
+        assert_eq!(fields.string(1), Some("Living Room"));
+        assert_eq!(fields.string(2), None);
+        assert_eq!(fields.varint(3) as i64, -5);
+        assert_eq!(fields.varint(4), 0);
+        assert_eq!(fields.varint(5), 300);
+        assert!(Fields::decode(&[0x0A, 0x05, b'a']).is_err());
+        assert!(unframe(&[1, 0, 0, 0, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_history_call_needs_the_api_token() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobble = Scrobble {
+            room: Some("Office".to_string()),
+            ..Scrobble::new("10.8.0.20 - Sonos One - RINCON_20", "Bonobo", "Kerala", 1_700_000_000)
+        };
+        db.record_scrobble(&scrobble).await.unwrap();
+        let controller = Controller::new(&[], Arc::new(Scrobbler::default())).unwrap();
+        let config = HttpConfig { api_token: Some("t0ken".to_string()), ..HttpConfig::default() };
+        let server = Server::new(&config, Arc::new(controller)).with_database(db);
+        let call = |token: &str| {
+            Request::post(format!("{}History", SERVICE))
+                .header("authorization", format!("Bearer {}", token))
+                .body(http_body_util::Full::new(frame(&Message::default().varint(5, 10).0)))
+                .unwrap()
+        };
+
+        let reply = server.grpc_call(call("t0ken")).await.into_body().collect().await.unwrap();
+        assert_eq!(reply.trailers().unwrap()["grpc-status"], "0");
+        let reply = reply.to_bytes();
+        let listen = Fields::decode(unframe(&reply).unwrap()).unwrap().bytes[&1].clone();
+        let listen = Fields::decode(&listen).unwrap();
+        assert_eq!((listen.string(2), listen.string(4)), (Some("Office"), Some("Kerala")));
+
+        let reply = server.grpc_call(call("wrong")).await.into_body().collect().await.unwrap();
+        assert_eq!(reply.trailers().unwrap()["grpc-status"], "16");
+        assert!(reply.to_bytes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queue_calls_are_checked() {
+        let devices = vec![SonosDevice {
+            ip_addr: "127.0.0.1".parse().unwrap(),
+            room: "Kitchen".to_string(),
+            model: "Sonos One".to_string(),
+            id: "RINCON_1".to_string(),
+            friendly_name: "127.0.0.1 - Sonos One - RINCON_1".to_string(),
+        }];
+        let controller = Controller::new(&devices, Arc::new(Scrobbler::default())).unwrap();
+        let config = HttpConfig { api_token: Some("t0ken".to_string()), ..HttpConfig::default() };
+        let server = Server::new(&config, Arc::new(controller));
+        let server = &server;
+        let call = |method: &'static str, body: Bytes| async move {
+            let request = Request::post(format!("{}{}", SERVICE, method))
+                .header("authorization", "Bearer t0ken")
+                .body(http_body_util::Full::new(body))
+                .unwrap();
+            let reply = server.grpc_call(request).await.into_body().collect().await.unwrap();
+            reply.trailers().unwrap()["grpc-status"].to_str().unwrap().to_string()
+        };
+
+        let first = frame(&Message::default().string(1, "kitchen").0);
+        assert_eq!(call("RemoveFromQueue", first).await, "3");
+        assert_eq!(call("ClearQueue", frame(&[])).await, "3");
+        let elsewhere = frame(&Message::default().string(1, "Garage").varint(2, 2).0);
+        assert_eq!(call("RemoveFromQueue", elsewhere).await, "5");
+        assert_eq!(call("Skip", Bytes::from(vec![0; MAX_REQUEST + 6])).await, "8");
+    }
+}
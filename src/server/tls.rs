@@ -0,0 +1,83 @@
+use crate::config::TlsConfig;
+use crate::error::{Error, Result};
+use log::info;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Accepts TLS connections with the configured certificate, or a self-signed
+/// one when none is configured
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let (certs, key) = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => (load_certs(cert)?, load_key(key)?),
+        (None, None) => self_signed()?,
+        _ => return Err(Error::Config("http.tls needs both cert and key, or neither".to_string())),
+    };
+
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::Config(format!("invalid TLS certificate: {}", e)))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(Error::Config(format!("{}: no certificates found", path.display())));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader) {
+            Ok(Some(rustls_pemfile::Item::PKCS8Key(key)))
+            | Ok(Some(rustls_pemfile::Item::RSAKey(key)))
+            | Ok(Some(rustls_pemfile::Item::ECKey(key))) => return Ok(PrivateKey(key)),
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(Error::Config(format!("{}: no private key found", path.display()))),
+            Err(e) => return Err(Error::Config(format!("{}: {}", path.display(), e))),
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+}
+
+/// A certificate for `localhost` and this host's name, valid until restart
+fn self_signed() -> Result<(Vec<Certificate>, PrivateKey)> {
+    let mut names = vec!["localhost".to_string()];
+    if let Ok(hostname) = std::env::var("HOSTNAME") {
+        names.push(hostname);
+    }
+    let cert = rcgen::generate_simple_self_signed(names)
+        .map_err(|e| Error::Config(format!("failed to generate a TLS certificate: {}", e)))?;
+    let der = cert
+        .serialize_der()
+        .map_err(|e| Error::Config(format!("failed to generate a TLS certificate: {}", e)))?;
+    info!("Serving HTTPS with a self-signed certificate");
+    Ok((vec![Certificate(der)], PrivateKey(cert.serialize_private_key_der())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acceptor_needs_cert_and_key() {
+        assert!(acceptor(&TlsConfig::default()).is_ok());
+
+        let config = TlsConfig { cert: Some("cert.pem".into()), ..TlsConfig::default() };
+        assert!(matches!(acceptor(&config), Err(Error::Config(_))));
+    }
+}
@@ -0,0 +1,242 @@
+use crate::error::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const UNIT_NAME: &str = "sonos-scrobbler.service";
+const LAUNCHD_LABEL: &str = "com.harperreed.sonos-scrobbler";
+
+/// What the installed service runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceSpec {
+    pub binary: PathBuf,
+    pub config: Option<PathBuf>,
+    /// Where `.env` and `tracks.db` are found
+    pub working_dir: PathBuf,
+}
+
+impl ServiceSpec {
+    /// The running binary, started from the current directory
+    pub fn current(config: Option<&Path>) -> Result<Self> {
+        let io = |e: std::io::Error| Error::Config(format!("cannot locate the binary: {}", e));
+        Ok(Self {
+            binary: std::env::current_exe().map_err(io)?,
+            config: config.map(absolute).transpose()?,
+            working_dir: std::env::current_dir().map_err(io)?,
+        })
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![self.binary.display().to_string()];
+        if let Some(config) = &self.config {
+            args.push("--config".to_string());
+            args.push(config.display().to_string());
+        }
+        args.push("run".to_string());
+        args
+    }
+}
+
+/// The per-user service manager of this platform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceManager {
+    Systemd,
+    Launchd,
+}
+
+impl ServiceManager {
+    pub fn detect() -> Result<Self> {
+        match std::env::consts::OS {
+            "linux" => Ok(ServiceManager::Systemd),
+            "macos" => Ok(ServiceManager::Launchd),
+            os => Err(Error::Config(format!("services are not supported on {}", os))),
+        }
+    }
+
+    /// Where the unit or property list is installed
+    pub fn path(self) -> Result<PathBuf> {
+        let home = std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .ok_or_else(|| Error::Config("HOME is not set".to_string()))?;
+        Ok(match self {
+            ServiceManager::Systemd => home.join(".config/systemd/user").join(UNIT_NAME),
+            ServiceManager::Launchd => home.join("Library/LaunchAgents").join(format!("{}.plist", LAUNCHD_LABEL)),
+        })
+    }
+
+    pub fn render(self, spec: &ServiceSpec) -> String {
+        match self {
+            ServiceManager::Systemd => systemd_unit(spec),
+            ServiceManager::Launchd => launchd_plist(spec),
+        }
+    }
+
+    /// Writes the service definition and starts the service, also at login.
+    /// Returns where it was written.
+    pub fn install(self, spec: &ServiceSpec) -> Result<PathBuf> {
+        let path = self.path()?;
+        let io = |e: std::io::Error| Error::Config(format!("{}: {}", path.display(), e));
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(io)?;
+        }
+        std::fs::write(&path, self.render(spec)).map_err(io)?;
+
+        let path_arg = path.display().to_string();
+        match self {
+            ServiceManager::Systemd => {
+                run("systemctl", &["--user", "daemon-reload"])?;
+                run("systemctl", &["--user", "enable", "--now", UNIT_NAME])?;
+            }
+            ServiceManager::Launchd => run("launchctl", &["load", "-w", &path_arg])?,
+        }
+        Ok(path)
+    }
+
+    /// Stops the service and removes its definition
+    pub fn uninstall(self) -> Result<PathBuf> {
+        let path = self.path()?;
+        if !path.exists() {
+            return Err(Error::Config(format!("no service installed at {}", path.display())));
+        }
+
+        let path_arg = path.display().to_string();
+        match self {
+            ServiceManager::Systemd => run("systemctl", &["--user", "disable", "--now", UNIT_NAME])?,
+            ServiceManager::Launchd => run("launchctl", &["unload", "-w", &path_arg])?,
+        }
+        std::fs::remove_file(&path).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        if self == ServiceManager::Systemd {
+            run("systemctl", &["--user", "daemon-reload"])?;
+        }
+        Ok(path)
+    }
+
+    /// The service manager's own status report
+    pub fn status(self) -> Result<String> {
+        let output = match self {
+            ServiceManager::Systemd => Command::new("systemctl").args(["--user", "status", UNIT_NAME]).output(),
+            ServiceManager::Launchd => Command::new("launchctl").args(["list", LAUNCHD_LABEL]).output(),
+        }
+        .map_err(|e| Error::Config(format!("cannot query the service manager: {}", e)))?;
+        // systemctl exits non-zero for stopped services, which is still a status
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned() + &String::from_utf8_lossy(&output.stderr))
+    }
+}
+
+fn systemd_unit(spec: &ServiceSpec) -> String {
+    let exec = spec.args().iter().map(|arg| quote_systemd(arg)).collect::<Vec<_>>().join(" ");
+    format!(
+        "[Unit]\n\
+         Description=Sonos Scrobbler\n\
+         After=network-online.target\n\
+         Wants=network-online.target\n\
+         \n\
+         [Service]\n\
+         ExecStart={}\n\
+         WorkingDirectory={}\n\
+         Restart=on-failure\n\
+         RestartSec=10\n\
+         \n\
+         [Install]\n\
+         WantedBy=default.target\n",
+        exec,
+        escape_specifiers(&spec.working_dir.display().to_string())
+    )
+}
+
+fn launchd_plist(spec: &ServiceSpec) -> String {
+    let args: String = spec
+        .args()
+        .iter()
+        .map(|arg| format!("        <string>{}</string>\n", escape_xml(arg)))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n\
+         \x20   <key>Label</key>\n\
+         \x20   <string>{}</string>\n\
+         \x20   <key>ProgramArguments</key>\n\
+         \x20   <array>\n\
+         {}\
+         \x20   </array>\n\
+         \x20   <key>WorkingDirectory</key>\n\
+         \x20   <string>{}</string>\n\
+         \x20   <key>RunAtLoad</key>\n\
+         \x20   <true/>\n\
+         \x20   <key>KeepAlive</key>\n\
+         \x20   <true/>\n\
+         </dict>\n\
+         </plist>\n",
+        LAUNCHD_LABEL,
+        args,
+        escape_xml(&spec.working_dir.display().to_string())
+    )
+}
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| Error::Config(format!("cannot run {}: {}", program, e)))?;
+    if !status.success() {
+        return Err(Error::Config(format!("{} {} failed with {}", program, args.join(" "), status)));
+    }
+    Ok(())
+}
+
+fn absolute(path: &Path) -> Result<PathBuf> {
+    std::fs::canonicalize(path).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+}
+
+/// Quotes `value` for a systemd command line when it contains spaces
+fn quote_systemd(value: &str) -> String {
+    let value = escape_specifiers(value).replace('$', "$$");
+    if value.contains(char::is_whitespace) || value.contains('"') {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        value
+    }
+}
+
+/// Escapes the `%` of unit file specifiers. Paths outside `ExecStart` are
+/// taken literally, spaces included, so they need nothing else.
+fn escape_specifiers(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+fn escape_xml(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_service_definitions() {
+        let spec = ServiceSpec {
+            binary: PathBuf::from("/opt/sonos scrobbler/sonos-scrobbler"),
+            config: Some(PathBuf::from("/etc/sonos/config.toml")),
+            working_dir: PathBuf::from("/var/lib/sonos"),
+        };
+
+        let unit = ServiceManager::Systemd.render(&spec);
+        assert!(unit.contains(
+            "ExecStart=\"/opt/sonos scrobbler/sonos-scrobbler\" --config /etc/sonos/config.toml run\n"
+        ));
+        assert!(unit.contains("WorkingDirectory=/var/lib/sonos\n"));
+
+        let unit = ServiceManager::Systemd.render(&ServiceSpec {
+            binary: PathBuf::from("/opt/100%/$bin"),
+            working_dir: PathBuf::from("/srv/sonos 100%"),
+            ..spec.clone()
+        });
+        assert!(unit.contains("ExecStart=/opt/100%%/$$bin --config"));
+        assert!(unit.contains("WorkingDirectory=/srv/sonos 100%%\n"));
+
+        let plist = ServiceManager::Launchd.render(&spec);
+        assert!(plist.contains("        <string>/opt/sonos scrobbler/sonos-scrobbler</string>\n"));
+        assert!(plist.contains("    <string>/var/lib/sonos</string>\n"));
+    }
+}
@@ -0,0 +1,72 @@
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDate, NaiveDateTime, NaiveTime};
+use std::time::Duration;
+
+/// A Sonos alarm, as listed by the speakers' AlarmClock service
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alarm {
+    /// Device id of the speaker the alarm plays on, e.g. `RINCON_...`
+    pub room_id: String,
+    pub start: NaiveTime,
+    /// How long the alarm plays unless it is stopped earlier
+    pub duration: Duration,
+    /// `DAILY`, `WEEKDAYS`, `WEEKENDS`, `ONCE`, or `ON_` followed by day
+    /// numbers counting from Sunday as 0
+    pub recurrence: String,
+    pub enabled: bool,
+}
+
+impl Alarm {
+    /// Whether a listen that started at `at` on `device_id` was started by
+    /// this alarm, i.e. started while the alarm was playing
+    pub fn started(&self, device_id: &str, at: NaiveDateTime) -> bool {
+        if !self.enabled || self.room_id != device_id {
+            return false;
+        }
+        let duration = ChronoDuration::from_std(self.duration).unwrap_or_default();
+        // An alarm shortly before midnight plays on into the next day
+        [at.date(), at.date() - ChronoDuration::days(1)].into_iter().any(|day| {
+            let rang = day.and_time(self.start);
+            self.recurs_on(day) && rang <= at && at < rang + duration
+        })
+    }
+
+    fn recurs_on(&self, day: NaiveDate) -> bool {
+        let weekday = day.weekday().num_days_from_sunday();
+        match self.recurrence.as_str() {
+            "WEEKDAYS" => (1..=5).contains(&weekday),
+            "WEEKENDS" => weekday == 0 || weekday == 6,
+            // Which day a one-off alarm rang isn't listed
+            "DAILY" | "ONCE" => true,
+            recurrence => recurrence
+                .strip_prefix("ON_")
+                .is_some_and(|days| days.chars().any(|day| day.to_digit(10) == Some(weekday))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sonos::soap::parse_alarms;
+
+    #[test]
+    fn test_alarm_started_listen() {
+        let xml = r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body>
+            <u:ListAlarmsResponse xmlns:u="urn:schemas-upnp-org:service:AlarmClock:1">
+            <CurrentAlarmList>&lt;Alarms&gt;&lt;Alarm ID="4" StartTime="07:00:00" Duration="01:00:00"
+              Recurrence="WEEKDAYS" Enabled="1" RoomUUID="RINCON_1" ProgramURI="x-rincon-buzzer:0"/&gt;&lt;/Alarms&gt;</CurrentAlarmList>
+            <CurrentAlarmListVersion>RINCON_1:12</CurrentAlarmListVersion>
+            </u:ListAlarmsResponse></s:Body></s:Envelope>"#;
+        let alarms = parse_alarms(xml).unwrap();
+        assert_eq!(alarms.len(), 1);
+        let alarm = &alarms[0];
+
+        // A Monday
+        let monday = NaiveDate::from_ymd_opt(2026, 10, 12).unwrap();
+        assert!(alarm.started("RINCON_1", monday.and_hms_opt(7, 20, 0).unwrap()));
+        assert!(!alarm.started("RINCON_1", monday.and_hms_opt(8, 0, 0).unwrap()));
+        assert!(!alarm.started("RINCON_2", monday.and_hms_opt(7, 20, 0).unwrap()));
+        let sunday = monday - ChronoDuration::days(1);
+        assert!(!alarm.started("RINCON_1", sunday.and_hms_opt(7, 20, 0).unwrap()));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::scrobble::Scrobble;
+
+/// Consecutive tracks of one album that count as listening to the album
+pub const MIN_ALBUM_TRACKS: u32 = 4;
+
+/// Tracks of one album played one after another, in queue order
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlbumListen {
+    pub device: String,
+    pub album: String,
+    /// The album artist, or "Various Artists" when the tracks disagree
+    pub artist: String,
+    /// Unix timestamp of when the first track started
+    pub started_at: i64,
+    pub tracks: u32,
+    last_position: u32,
+}
+
+impl AlbumListen {
+    fn start(scrobble: &Scrobble, album: &str, position: u32) -> Self {
+        Self {
+            device: scrobble.device.clone(),
+            album: album.to_string(),
+            artist: scrobble.artist.clone(),
+            started_at: scrobble.started_at,
+            tracks: 1,
+            last_position: position,
+        }
+    }
+
+    /// Whether the scrobble is the next track of this album
+    fn continued_by(&self, album: &str, position: u32) -> bool {
+        self.album.eq_ignore_ascii_case(album) && position == self.last_position + 1
+    }
+}
+
+/// Follows the scrobbles of one speaker to spot albums played through
+#[derive(Debug, Default)]
+pub struct AlbumDetector {
+    current: Option<AlbumListen>,
+}
+
+impl AlbumDetector {
+    /// Adds a scrobbled track at `queue_position`. Returns the album listen
+    /// once enough tracks were played in order, and again with every further
+    /// track so its count stays current.
+    pub fn track_played(&mut self, scrobble: &Scrobble, queue_position: Option<u32>) -> Option<&AlbumListen> {
+        let (Some(album), Some(position)) = (scrobble.album.as_deref().filter(|a| !a.is_empty()), queue_position)
+        else {
+            self.current = None;
+            return None;
+        };
+
+        match self.current.as_mut() {
+            Some(run) if run.continued_by(album, position) => {
+                run.tracks += 1;
+                run.last_position = position;
+                if !run.artist.eq_ignore_ascii_case(&scrobble.artist) {
+                    run.artist = "Various Artists".to_string();
+                }
+            }
+            _ => self.current = Some(AlbumListen::start(scrobble, album, position)),
+        }
+        self.current.as_ref().filter(|run| run.tracks >= MIN_ALBUM_TRACKS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(title: &str, album: &str) -> Scrobble {
+        Scrobble {
+            album: Some(album.to_string()),
+            ..Scrobble::new("Kitchen", "Daft Punk", title, 1_700_000_000)
+        }
+    }
+
+    #[test]
+    fn test_album_played_in_order() {
+        let mut detector = AlbumDetector::default();
+        for position in 1..MIN_ALBUM_TRACKS {
+            assert!(detector.track_played(&track("Track", "Discovery"), Some(position)).is_none());
+        }
+        let album = detector.track_played(&track("Track", "Discovery"), Some(MIN_ALBUM_TRACKS)).unwrap();
+        assert_eq!((album.album.as_str(), album.tracks), ("Discovery", MIN_ALBUM_TRACKS));
+
+        // Skipping ahead in the queue starts over
+        assert!(detector.track_played(&track("Track", "Discovery"), Some(9)).is_none());
+        assert!(detector.track_played(&track("Track", "Homework"), Some(10)).is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::error::{Error, Result};
+use crate::sonos::TrackDatabase;
+use log::{debug, warn};
+use std::net::IpAddr;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Covers larger than this are not served
+const MAX_ART_SIZE: usize = 5 << 20;
+
+/// A cover image as the speaker served it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlbumArt {
+    pub content_type: String,
+    pub image: Vec<u8>,
+}
+
+/// Where the speaker at `ip_addr` serves the cover it reports as `uri`.
+/// Most are paths on the speaker; some services give a URL of their own.
+pub fn art_url(ip_addr: IpAddr, uri: &str) -> String {
+    match uri.starts_with("http://") || uri.starts_with("https://") {
+        true => uri.to_string(),
+        false => format!("http://{}:1400/{}", ip_addr, uri.trim_start_matches('/')),
+    }
+}
+
+/// Fetches and caches the cover of scrobbles
+pub struct ArtProxy {
+    db: TrackDatabase,
+    client: reqwest::Client,
+}
+
+impl ArtProxy {
+    pub fn new(db: TrackDatabase) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        Ok(Self { db, client })
+    }
+
+    /// The cover of the scrobble with `listen_id`, fetched from the speaker
+    /// the first time and from the database after that. `None` when the
+    /// speaker reported no cover for it, or one that isn't an image or is
+    /// larger than [`MAX_ART_SIZE`]. Those are remembered as empty, so they
+    /// aren't fetched again.
+    pub async fn album_art(&self, listen_id: i64) -> Result<Option<AlbumArt>> {
+        let Some(url) = self.db.album_art_url(listen_id).await? else {
+            return Ok(None);
+        };
+        if let Some(art) = self.db.cached_album_art(&url).await? {
+            return Ok(Some(art).filter(|art| !art.image.is_empty()));
+        }
+        let mut response = self.client.get(&url).send().await?;
+        if !response.status().is_success() {
+            return Err(Error::Soap(format!("{} answered {}", url, response.status())));
+        }
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("image/jpeg")
+            .to_string();
+        // Served from the dashboard's origin, so anything else could run scripts there
+        if !content_type.starts_with("image/") {
+            warn!("Not serving cover art from {}, it is {} rather than an image", url, content_type);
+            self.db.cache_album_art(&url, &AlbumArt { content_type, image: Vec::new() }).await?;
+            return Ok(None);
+        }
+        let mut image = Vec::new();
+        if response.content_length().is_none_or(|length| length <= MAX_ART_SIZE as u64) {
+            while let Some(chunk) = response.chunk().await? {
+                if image.len() + chunk.len() > MAX_ART_SIZE {
+                    image.clear();
+                    break;
+                }
+                image.extend_from_slice(&chunk);
+            }
+        }
+        if image.is_empty() {
+            warn!("Not serving cover art from {}, it is empty or larger than {} bytes", url, MAX_ART_SIZE);
+            self.db.cache_album_art(&url, &AlbumArt { content_type, image }).await?;
+            return Ok(None);
+        }
+        debug!("Fetched {} bytes of cover art from {}", image.len(), url);
+        let art = AlbumArt { content_type, image };
+        self.db.cache_album_art(&url, &art).await?;
+        Ok(Some(art))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrobble::Scrobble;
+
+    #[tokio::test]
+    async fn test_album_art_is_fetched_once() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/getaa?s=1&u=x-sonos-spotify%3atrack")
+            .with_header("Content-Type", "image/png")
+            .with_body("png")
+            .expect(1)
+            .create_async()
+            .await;
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobble = Scrobble::new("Kitchen", "Daft Punk", "Get Lucky", 1_700_000_000);
+        db.record_scrobble(&scrobble).await.unwrap();
+        let url = format!("{}/getaa?s=1&u=x-sonos-spotify%3atrack", server.url());
+        db.record_album_art("Kitchen", 1_700_000_000, &url).await.unwrap();
+        let id = db.scrobbles_by_start().await.unwrap()[0].0;
+
+        let proxy = ArtProxy::new(db).unwrap();
+        let expected = AlbumArt { content_type: "image/png".to_string(), image: b"png".to_vec() };
+        for _ in 0..2 {
+            assert_eq!(proxy.album_art(id).await.unwrap(), Some(expected.clone()));
+        }
+        assert_eq!(proxy.album_art(id + 1).await.unwrap(), None);
+        mock.assert_async().await;
+
+        // A page posing as a cover is refused, and only fetched once
+        let page = server
+            .mock("GET", "/cover.jpg")
+            .with_header("Content-Type", "text/html")
+            .with_body("<script>alert(1)</script>")
+            .expect(1)
+            .create_async()
+            .await;
+        let later = Scrobble { started_at: 1_700_000_600, ..scrobble };
+        proxy.db.record_scrobble(&later).await.unwrap();
+        proxy.db.record_album_art("Kitchen", later.started_at, &format!("{}/cover.jpg", server.url())).await.unwrap();
+        for _ in 0..2 {
+            assert_eq!(proxy.album_art(id + 1).await.unwrap(), None);
+        }
+        page.assert_async().await;
+
+        let ip = "192.168.1.20".parse().unwrap();
+        assert_eq!(art_url(ip, "/getaa?s=1&u=x"), "http://192.168.1.20:1400/getaa?s=1&u=x");
+        assert_eq!(art_url(ip, "https://i.scdn.co/image/ab67"), "https://i.scdn.co/image/ab67");
+    }
+}
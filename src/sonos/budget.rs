@@ -0,0 +1,48 @@
+use std::sync::Arc;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Limits how many speakers are polled at once. Every poll opens connections
+/// to its speaker and the database, so on large installs unbounded polling
+/// can run out of file descriptors; speakers over the budget wait their turn.
+#[derive(Clone)]
+pub struct TaskBudget {
+    permits: Arc<Semaphore>,
+}
+
+impl TaskBudget {
+    /// At most `max_concurrent` polls at a time; 0 means no limit
+    pub fn new(max_concurrent: usize) -> Self {
+        let permits = match max_concurrent {
+            0 => Semaphore::MAX_PERMITS,
+            n => n,
+        };
+        Self { permits: Arc::new(Semaphore::new(permits)) }
+    }
+
+    /// Waits for a free slot, held until the permit is dropped
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.permits.acquire().await.expect("task budget semaphore is never closed")
+    }
+}
+
+impl Default for TaskBudget {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_budget_limits_concurrent_polls() {
+        let budget = TaskBudget::new(2);
+        let first = budget.acquire().await;
+        let _second = budget.acquire().await;
+        assert!(budget.permits.try_acquire().is_err());
+
+        drop(first);
+        assert!(budget.permits.try_acquire().is_ok());
+    }
+}
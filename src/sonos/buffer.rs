@@ -0,0 +1,176 @@
+use crate::scrobble::Scrobble;
+use crate::sonos::session::PlayContext;
+use log::warn;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Writes kept in memory at most; beyond this the oldest are dropped
+const MAX_PENDING_WRITES: usize = 10_000;
+/// Delay between attempts to persist buffered writes
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a write may wait for others to join its batch
+const BATCH_INTERVAL: Duration = Duration::from_secs(2);
+/// Writes per batch; a full batch is committed right away
+const MAX_BATCH_SIZE: usize = 100;
+
+/// A database write that could not be applied yet, with the timestamps it
+/// would have been written with
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PendingWrite {
+    Track {
+        device_name: String,
+        track_info: String,
+        context: PlayContext,
+        played_at: i64,
+    },
+    Scrobble {
+        scrobble: Scrobble,
+        scrobbled_at: i64,
+    },
+    ScrobbleFailures {
+        scrobble: Scrobble,
+        failures: usize,
+        /// Held back until the internet is reachable again
+        deferred: bool,
+    },
+}
+
+impl PendingWrite {
+    /// Whether both writes log the same listen or record the same play
+    fn duplicates(&self, other: &PendingWrite) -> bool {
+        match (self, other) {
+            (
+                PendingWrite::Track { device_name, track_info, played_at, .. },
+                PendingWrite::Track {
+                    device_name: other_device,
+                    track_info: other_info,
+                    played_at: other_played_at,
+                    ..
+                },
+            ) => {
+                // Same rule as the database check: one log per track and hour
+                device_name == other_device
+                    && track_info == other_info
+                    && (played_at - other_played_at).abs() < 3600
+            }
+            (PendingWrite::Scrobble { scrobble, .. }, PendingWrite::Scrobble { scrobble: other, .. }) => {
+                scrobble.same_play_elsewhere(other)
+                    || (scrobble.device == other.device
+                        && scrobble.artist == other.artist
+                        && scrobble.title == other.title
+                        && scrobble.started_at == other.started_at)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// In-memory queue of writes waiting to be committed as one batch.
+///
+/// Writes also wait here while the database is locked, full or otherwise
+/// unavailable, and are committed in order once it recovers.
+#[derive(Debug, Default)]
+pub(crate) struct WriteBuffer {
+    writes: VecDeque<PendingWrite>,
+    first_queued: Option<Instant>,
+    last_failure: Option<Instant>,
+}
+
+impl WriteBuffer {
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn writes(&self) -> impl Iterator<Item = &PendingWrite> {
+        self.writes.iter()
+    }
+
+    pub fn contains_duplicate(&self, write: &PendingWrite) -> bool {
+        self.writes.iter().any(|queued| queued.duplicates(write))
+    }
+
+    pub fn push(&mut self, write: PendingWrite) {
+        if self.writes.len() >= MAX_PENDING_WRITES {
+            warn!("Database write buffer is full, dropping the oldest buffered write");
+            self.writes.pop_front();
+        }
+        self.first_queued.get_or_insert_with(Instant::now);
+        self.writes.push_back(write);
+    }
+
+    /// Empties the buffer after its writes were committed; returns how many
+    /// there were
+    pub fn clear(&mut self) -> usize {
+        let written = self.writes.len();
+        *self = Self::default();
+        written
+    }
+
+    pub fn mark_failed(&mut self) {
+        self.last_failure = Some(Instant::now());
+    }
+
+    /// Whether the last commit failed
+    pub fn failed(&self) -> bool {
+        self.last_failure.is_some()
+    }
+
+    /// Whether the batch should be committed: it is full or old enough, and
+    /// after a failure the retry interval has passed
+    pub fn flush_due(&self) -> bool {
+        if let Some(failed) = self.last_failure {
+            return failed.elapsed() >= RETRY_INTERVAL;
+        }
+        self.writes.len() >= MAX_BATCH_SIZE
+            || self.first_queued.is_some_and(|queued| queued.elapsed() >= BATCH_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(n: usize) -> PendingWrite {
+        PendingWrite::Track {
+            device_name: "Kitchen".to_string(),
+            track_info: format!("Track {}", n),
+            context: PlayContext::default(),
+            played_at: n as i64,
+        }
+    }
+
+    #[test]
+    fn test_buffer_drops_oldest_when_full() {
+        let mut buffer = WriteBuffer::default();
+        for n in 0..=MAX_PENDING_WRITES {
+            buffer.push(write(n));
+        }
+        assert_eq!(buffer.len(), MAX_PENDING_WRITES);
+        assert_eq!(buffer.writes().next(), Some(&write(1)));
+
+        // Full batches are due right away, but not while retrying
+        assert!(buffer.flush_due());
+        buffer.mark_failed();
+        assert!(!buffer.flush_due());
+    }
+
+    #[test]
+    fn test_batch_due_after_interval() {
+        let mut buffer = WriteBuffer::default();
+        assert!(!buffer.flush_due());
+
+        buffer.push(write(0));
+        assert!(!buffer.flush_due());
+        assert!(buffer.contains_duplicate(&write(0)));
+        assert!(!buffer.contains_duplicate(&write(1)));
+
+        buffer.first_queued = Some(Instant::now() - BATCH_INTERVAL);
+        assert!(buffer.flush_due());
+        assert_eq!(buffer.clear(), 1);
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,56 @@
+use crate::error::Result;
+use crate::sonos::alarm::Alarm;
+use crate::sonos::firmware::SoftwareVersion;
+use crate::sonos::soap::{MediaInfo, PositionInfo, SoapClient, TransportState};
+use async_trait::async_trait;
+
+/// The speaker calls the listen logic relies on. [`SoapClient`] talks to a
+/// real speaker; tests drive the logic with a mock instead.
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait SonosClient: Send + Sync {
+    async fn get_position_info(&self) -> Result<PositionInfo>;
+
+    async fn get_media_info(&self) -> Result<MediaInfo>;
+
+    async fn get_transport_info(&self) -> Result<TransportState>;
+
+    async fn get_volume(&self) -> Result<u16>;
+
+    async fn pause(&self) -> Result<()>;
+
+    async fn list_alarms(&self) -> Result<Vec<Alarm>>;
+
+    async fn software_version(&self) -> Result<SoftwareVersion>;
+}
+
+#[async_trait]
+impl SonosClient for SoapClient {
+    async fn get_position_info(&self) -> Result<PositionInfo> {
+        SoapClient::get_position_info(self).await
+    }
+
+    async fn get_media_info(&self) -> Result<MediaInfo> {
+        SoapClient::get_media_info(self).await
+    }
+
+    async fn get_transport_info(&self) -> Result<TransportState> {
+        SoapClient::get_transport_info(self).await
+    }
+
+    async fn get_volume(&self) -> Result<u16> {
+        SoapClient::get_volume(self).await
+    }
+
+    async fn pause(&self) -> Result<()> {
+        SoapClient::pause(self).await
+    }
+
+    async fn list_alarms(&self) -> Result<Vec<Alarm>> {
+        SoapClient::list_alarms(self).await
+    }
+
+    async fn software_version(&self) -> Result<SoftwareVersion> {
+        SoapClient::software_version(self).await
+    }
+}
@@ -1,14 +1,57 @@
-use anyhow::Result;
-use sqlx::{sqlite::SqlitePool, Row};
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::error::{Error, Result};
+use crate::retry::RetryPolicy;
+use crate::scrobble::{Confidence, QuarantinedListen, Release, Scrobble, CROSS_DEVICE_WINDOW};
+use crate::sonos::album::AlbumListen;
+use crate::sonos::art::AlbumArt;
+use crate::sonos::buffer::{PendingWrite, WriteBuffer};
+use crate::sonos::pipeline::{LogEntry, Stage};
+use crate::sonos::session::{Interaction, PlayContext};
+use crate::sonos::library::share_path;
+use crate::stats::{GroupBy, HistoryCursor, HistorySort, Listen, ListenFilter};
+use log::{info, warn};
+use rusty_sonos::discovery::BasicSpeakerInfo;
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePool, SqliteSynchronous,
+};
+use sqlx::Row;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
 
+/// How long SQLite waits for a lock held by another connection
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long interactions are kept: long enough for the stats of the year
+/// before
+const INTERACTION_RETENTION_SECS: i64 = 2 * 366 * 24 * 60 * 60;
+
+/// Serializes writes from every `TrackDatabase` in the process, so pollers
+/// never compete with each other for SQLite's single write lock
+static WRITE_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Clone)]
 pub struct TrackDatabase {
     pool: SqlitePool,
+    /// Listen and scrobble writes waiting to be committed, see [`Self::queue`]
+    pending: Arc<Mutex<WriteBuffer>>,
+    /// For writes that still fail with SQLITE_BUSY or SQLITE_LOCKED
+    retry: RetryPolicy,
 }
 
 impl TrackDatabase {
     pub async fn new() -> Result<Self> {
-        let pool = SqlitePool::connect("sqlite:tracks.db").await?;
+        Self::connect("sqlite:tracks.db?mode=rwc").await
+    }
+
+    pub async fn connect(url: &str) -> Result<Self> {
+        // WAL lets readers such as the stats commands run alongside the writer
+        let options = SqliteConnectOptions::from_str(url)?
+            .busy_timeout(BUSY_TIMEOUT)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal);
+        let pool = SqlitePool::connect_with(options).await?;
         
         sqlx::query(
             "CREATE TABLE IF NOT EXISTS tracks (
@@ -22,45 +65,344 @@ impl TrackDatabase {
         .execute(&pool)
         .await?;
 
-        Ok(Self { pool })
-    }
+        // Play context columns, added after the table was first released
+        add_column(&pool, "tracks", "context_kind", "TEXT").await?;
+        add_column(&pool, "tracks", "context_name", "TEXT").await?;
+        add_column(&pool, "tracks", "context_uri", "TEXT").await?;
+        add_column(&pool, "tracks", "queue_position", "INTEGER").await?;
 
-    pub async fn log_track(&self, device_name: &str, track_info: &str) -> Result<bool> {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)?
-            .as_secs() as i64;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS devices (
+                ip_addr TEXT PRIMARY KEY,
+                friendly_name TEXT NOT NULL,
+                room_name TEXT NOT NULL,
+                discovered_at INTEGER NOT NULL
+            )"
+        )
+        .execute(&pool)
+        .await?;
 
-        // Check if we've logged this track in the last hour
-        let recent_play = sqlx::query(
-            "SELECT 1 FROM tracks 
-             WHERE device_name = ? 
-             AND track_info = ? 
-             AND played_at > ?"
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS scrobbles (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_name TEXT NOT NULL,
+                artist TEXT NOT NULL,
+                title TEXT NOT NULL,
+                album TEXT,
+                started_at INTEGER NOT NULL,
+                scrobbled_at INTEGER NOT NULL,
+                UNIQUE(device_name, artist, title, started_at)
+            )"
         )
-        .bind(device_name)
-        .bind(track_info)
-        .bind(now - 3600) // Last hour
-        .fetch_optional(&self.pool)
+        .execute(&pool)
+        .await?;
+        add_column(&pool, "scrobbles", "failures", "INTEGER NOT NULL DEFAULT 0").await?;
+        add_column(&pool, "scrobbles", "fingerprint", "TEXT").await?;
+        add_column(&pool, "scrobbles", "artist_confidence", "INTEGER").await?;
+        add_column(&pool, "scrobbles", "title_confidence", "INTEGER").await?;
+        add_column(&pool, "scrobbles", "tagged_at", "INTEGER").await?;
+        add_column(&pool, "scrobbles", "tag_attempts", "INTEGER NOT NULL DEFAULT 0").await?;
+        add_column(&pool, "scrobbles", "deferred", "INTEGER NOT NULL DEFAULT 0").await?;
+        add_column(&pool, "scrobbles", "room", "TEXT").await?;
+        // Where music library files were played from, see `share_path`
+        add_column(&pool, "scrobbles", "file_path", "TEXT").await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS scrobbles_fingerprint ON scrobbles (fingerprint, started_at)"
+        )
+        .execute(&pool)
+        .await?;
+        // History pages are ordered by start time, optionally for one artist
+        sqlx::query("CREATE INDEX IF NOT EXISTS scrobbles_started_at ON scrobbles (started_at)")
+            .execute(&pool)
+            .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS scrobbles_artist ON scrobbles (artist COLLATE NOCASE, started_at)"
+        )
+        .execute(&pool)
         .await?;
 
-        if recent_play.is_some() {
-            return Ok(false);
-        }
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS quarantine (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_name TEXT NOT NULL,
+                uri TEXT NOT NULL,
+                track_info TEXT NOT NULL,
+                artist TEXT,
+                title TEXT,
+                album TEXT,
+                started_at INTEGER NOT NULL,
+                duration INTEGER,
+                quarantined_at INTEGER NOT NULL,
+                released_at INTEGER,
+                UNIQUE(device_name, uri, started_at)
+            )"
+        )
+        .execute(&pool)
+        .await?;
+        add_column(&pool, "quarantine", "artist_confidence", "INTEGER").await?;
+        add_column(&pool, "quarantine", "title_confidence", "INTEGER").await?;
 
         sqlx::query(
-            "INSERT INTO tracks (device_name, track_info, played_at) 
-             VALUES (?, ?, ?)"
+            "CREATE TABLE IF NOT EXISTS featured_artists (
+                device_name TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                artist TEXT NOT NULL,
+                PRIMARY KEY (device_name, started_at, artist)
+            )"
         )
-        .bind(device_name)
-        .bind(track_info)
-        .bind(now)
-        .execute(&self.pool)
+        .execute(&pool)
+        .await?;
+
+        // Genre tags of each scrobble, looked up after it was submitted
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS scrobble_tags (
+                device_name TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                tag TEXT NOT NULL,
+                PRIMARY KEY (device_name, started_at, tag)
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // The latest scrobble per device, to recognize a listen that was
+        // already submitted when the daemon restarts in the middle of it
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS last_submitted (
+                device_name TEXT PRIMARY KEY,
+                fingerprint TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                ends_at INTEGER NOT NULL
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS album_listens (
+                device_name TEXT NOT NULL,
+                artist TEXT NOT NULL,
+                album TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                tracks INTEGER NOT NULL,
+                PRIMARY KEY (device_name, started_at)
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS api_calls (
+                backend TEXT NOT NULL,
+                minute INTEGER NOT NULL,
+                calls INTEGER NOT NULL,
+                errors INTEGER NOT NULL,
+                PRIMARY KEY(backend, minute)
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS digests (
+                period TEXT PRIMARY KEY,
+                sent_at INTEGER NOT NULL
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // When a backend last accepted its credentials, identified by a
+        // fingerprint so that changed credentials are checked again
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS credential_checks (
+                backend TEXT PRIMARY KEY,
+                fingerprint TEXT NOT NULL,
+                checked_at INTEGER NOT NULL
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // Secrets generated once and kept across restarts
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS secrets (
+                name TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // Session keys handed to Audioscrobbler API clients at login
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS audioscrobbler_sessions (
+                key TEXT PRIMARY KEY,
+                username TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // Scrobble backends switched off through the control API
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS disabled_backends (
+                name TEXT PRIMARY KEY,
+                disabled_at INTEGER NOT NULL
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // The profile switched to last; a single row, none without one
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS active_profile (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                name TEXT NOT NULL,
+                switched_at INTEGER NOT NULL
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // Artists and titles as Last.fm corrected them
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS corrections (
+                artist TEXT NOT NULL COLLATE NOCASE,
+                title TEXT NOT NULL COLLATE NOCASE,
+                corrected_artist TEXT NOT NULL,
+                corrected_title TEXT NOT NULL,
+                corrected_at INTEGER NOT NULL,
+                PRIMARY KEY(artist, title)
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // Tracks never to scrobble, or every track of an artist when the
+        // title is empty, by where the entry came from
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS blocklist (
+                artist TEXT NOT NULL COLLATE NOCASE,
+                title TEXT NOT NULL COLLATE NOCASE,
+                source TEXT NOT NULL,
+                PRIMARY KEY(artist, title, source)
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // Albums looked up for tracks that came without one. A NULL album
+        // means the lookup found nothing.
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS enrichment_cache (
+                artist TEXT NOT NULL COLLATE NOCASE,
+                title TEXT NOT NULL COLLATE NOCASE,
+                album TEXT,
+                year INTEGER,
+                source TEXT NOT NULL,
+                looked_up_at INTEGER NOT NULL,
+                PRIMARY KEY(artist, title)
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // Milestones announced, like "scrobbles:10000"
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS milestones (
+                key TEXT PRIMARY KEY,
+                reached_at INTEGER NOT NULL
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // Volume changes, skips and other signs of someone handling a room
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS interactions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_name TEXT NOT NULL,
+                room TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                at INTEGER NOT NULL
+            )"
+        )
+        .execute(&pool)
         .await?;
 
-        Ok(true)
+        // Where the speaker served the cover of each listen, and the covers
+        // fetched from there
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS album_art (
+                device_name TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                url TEXT NOT NULL,
+                PRIMARY KEY(device_name, started_at)
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS album_art_cache (
+                url TEXT PRIMARY KEY,
+                content_type TEXT NOT NULL,
+                image BLOB NOT NULL,
+                fetched_at INTEGER NOT NULL
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        // The events of every stage of the listen pipeline, as JSON, so later
+        // stages can be derived again
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS event_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                stage TEXT NOT NULL,
+                device TEXT NOT NULL,
+                at_ms INTEGER NOT NULL,
+                payload TEXT NOT NULL
+            )"
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS event_log_stage ON event_log (stage, at_ms)")
+            .execute(&pool)
+            .await?;
+
+        Ok(Self { pool, pending: Arc::default(), retry: RetryPolicy::DATABASE })
+    }
+
+    /// How writes are retried while the database is busy; clones made
+    /// afterwards share it
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Logs a track unless it was logged on the device within the last hour.
+    /// Returns whether it was logged.
+    pub async fn log_track(
+        &self,
+        device_name: &str,
+        track_info: &str,
+        context: &PlayContext,
+    ) -> Result<bool> {
+        self.queue(PendingWrite::Track {
+            device_name: device_name.to_string(),
+            track_info: track_info.to_string(),
+            context: context.clone(),
+            played_at: unix_now(),
+        })
+        .await
     }
 
     pub async fn get_last_track(&self, device_name: &str) -> Result<Option<String>> {
+        self.sync().await;
         let record = sqlx::query(
             "SELECT track_info FROM tracks 
              WHERE device_name = ? 
@@ -73,6 +415,1602 @@ impl TrackDatabase {
 
         Ok(record.map(|row| row.get(0)))
     }
+
+    /// Number of logged tracks per play context, keyed by the playlist or
+    /// station name, or the kind of context when it has no name
+    pub async fn plays_by_context(&self) -> Result<BTreeMap<String, i64>> {
+        self.sync().await;
+        let rows = sqlx::query(
+            "SELECT COALESCE(context_name, context_kind, 'unknown'), COUNT(*) FROM tracks
+             GROUP BY 1"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Records a listen that reached the scrobble threshold. Returns `false`
+    /// when the same listen was already recorded.
+    pub async fn record_scrobble(&self, scrobble: &Scrobble) -> Result<bool> {
+        self.queue(PendingWrite::Scrobble {
+            scrobble: scrobble.clone(),
+            scrobbled_at: unix_now(),
+        })
+        .await
+    }
+
+    /// Records `scrobble` even when it duplicates one on another speaker, as
+    /// happened before those were caught on the way in
+    #[cfg(test)]
+    pub(crate) async fn record_duplicate_scrobble(&self, scrobble: &Scrobble) -> Result<()> {
+        let mut buffer = self.pending.lock().await;
+        buffer.push(PendingWrite::Scrobble { scrobble: scrobble.clone(), scrobbled_at: unix_now() });
+        self.flush(&mut buffer).await?;
+        Ok(())
+    }
+
+    /// Whether `scrobble` was recorded already, on its speaker or on one
+    /// grouped with it. Listens derived again from logged events start a
+    /// little apart from those the poller timed, so any scrobble of the same
+    /// track within [`CROSS_DEVICE_WINDOW`] counts.
+    pub async fn scrobble_recorded(&self, scrobble: &Scrobble) -> Result<bool> {
+        self.sync().await;
+        let window = CROSS_DEVICE_WINDOW.as_secs() as i64;
+        let row = sqlx::query("SELECT 1 FROM scrobbles WHERE fingerprint = ? AND started_at BETWEEN ? AND ?")
+            .bind(scrobble.fingerprint())
+            .bind(scrobble.started_at - window)
+            .bind(scrobble.started_at + window)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    /// Notes how many backends rejected a recorded scrobble
+    pub async fn record_scrobble_failures(&self, scrobble: &Scrobble, failures: usize) -> Result<()> {
+        self.queue(PendingWrite::ScrobbleFailures {
+            scrobble: scrobble.clone(),
+            failures,
+            deferred: false,
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Marks a recorded scrobble as waiting for the internet to come back
+    pub async fn defer_scrobble(&self, scrobble: &Scrobble) -> Result<()> {
+        self.queue(PendingWrite::ScrobbleFailures {
+            scrobble: scrobble.clone(),
+            failures: 0,
+            deferred: true,
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Scrobbles recorded while the internet was down, oldest first
+    pub async fn deferred_scrobbles(&self) -> Result<Vec<Scrobble>> {
+        self.sync().await;
+        let rows = sqlx::query(
+            "SELECT device_name, artist, title, album, started_at, room FROM scrobbles
+             WHERE deferred = 1 ORDER BY started_at, id"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let (device, artist, title): (String, String, String) = (row.get(0), row.get(1), row.get(2));
+                Scrobble { album: row.get(3), room: row.get(5), ..Scrobble::new(device, artist, title, row.get(4)) }
+            })
+            .collect())
+    }
+
+    /// Queues `write` for the next batch and returns whether it is new.
+    ///
+    /// Batches are committed in a single transaction once they are full or a
+    /// short interval has passed, which keeps the number of writes down on
+    /// SD cards. While the database is locked, full or otherwise unavailable
+    /// the writes stay in memory and are retried, so listening and scrobbling
+    /// carry on while storage problems are resolved.
+    async fn queue(&self, write: PendingWrite) -> Result<bool> {
+        let mut buffer = self.pending.lock().await;
+
+        let is_new = match self.is_new(&write, &buffer).await {
+            Ok(is_new) => is_new,
+            // Rather count a duplicate than lose a listen
+            Err(Error::Db(e)) => {
+                warn!("Database unavailable, treating write as new: {}", e);
+                true
+            }
+            Err(e) => return Err(e),
+        };
+        if is_new {
+            buffer.push(write);
+        }
+
+        if buffer.flush_due() {
+            let _ = self.flush(&mut buffer).await;
+        }
+        Ok(is_new)
+    }
+
+    /// Whether `write` adds a listen or scrobble that is neither in the
+    /// database nor queued
+    async fn is_new(&self, write: &PendingWrite, buffer: &WriteBuffer) -> Result<bool> {
+        if buffer.contains_duplicate(write) {
+            return Ok(false);
+        }
+
+        let existing = match write {
+            PendingWrite::Track { device_name, track_info, played_at, .. } => {
+                // Check if we've logged this track in the last hour
+                sqlx::query(
+                    "SELECT 1 FROM tracks 
+                     WHERE device_name = ? 
+                     AND track_info = ? 
+                     AND played_at > ?"
+                )
+                .bind(device_name)
+                .bind(track_info)
+                .bind(played_at - 3600) // Last hour
+                .fetch_optional(&self.pool)
+                .await?
+            }
+            PendingWrite::Scrobble { scrobble, .. } => {
+                // The same listen again, or the same play on a grouped speaker
+                let window = CROSS_DEVICE_WINDOW.as_secs() as i64;
+                sqlx::query(
+                    "SELECT 1 FROM scrobbles
+                     WHERE (device_name = ? AND artist = ? AND title = ? AND started_at = ?)
+                     OR (device_name != ? AND fingerprint = ? AND started_at BETWEEN ? AND ?)"
+                )
+                .bind(&scrobble.device)
+                .bind(&scrobble.artist)
+                .bind(&scrobble.title)
+                .bind(scrobble.started_at)
+                .bind(&scrobble.device)
+                .bind(scrobble.fingerprint())
+                .bind(scrobble.started_at - window)
+                .bind(scrobble.started_at + window)
+                .fetch_optional(&self.pool)
+                .await?
+            }
+            PendingWrite::ScrobbleFailures { .. } => None,
+        };
+        Ok(existing.is_none())
+    }
+
+    /// Commits queued writes once their batch is due, or once the retry
+    /// interval has passed after a failure. The poll loop calls this so
+    /// batches are written even when no new writes arrive.
+    pub async fn flush_due(&self) {
+        let mut buffer = self.pending.lock().await;
+        if buffer.flush_due() {
+            let _ = self.flush(&mut buffer).await;
+        }
+    }
+
+    /// Commits all queued writes now
+    pub async fn flush_pending(&self) -> Result<usize> {
+        self.flush(&mut *self.pending.lock().await).await
+    }
+
+    pub async fn pending_writes(&self) -> usize {
+        self.pending.lock().await.len()
+    }
+
+    /// Commits queued writes before a read, so reads see this instance's own
+    /// writes. A failure leaves them queued and the read sees the database as is.
+    async fn sync(&self) {
+        let _ = self.flush_pending().await;
+    }
+
+    async fn flush(&self, buffer: &mut WriteBuffer) -> Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        match self.write(|| self.commit(buffer.writes())).await {
+            Ok(()) => {
+                if buffer.failed() {
+                    info!("Database recovered, wrote {} buffered writes", buffer.len());
+                }
+                Ok(buffer.clear())
+            }
+            Err(e) => {
+                warn!("Database unavailable, {} writes kept in memory: {}", buffer.len(), e);
+                buffer.mark_failed();
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs the write `op` as the process's only writer, retrying it while
+    /// SQLite reports the database busy or locked. SQLite returns busy without
+    /// waiting out the busy timeout in some cases, e.g. when a read
+    /// transaction cannot be upgraded, so the timeout alone is not enough.
+    async fn write<T, F, Fut>(&self, op: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let _writer = WRITE_LOCK.lock().await;
+        self.retry
+            .run(op, |e, attempt| {
+                let Error::Db(e) = e else {
+                    return false;
+                };
+                if is_busy(e) {
+                    warn!("Database busy, retrying write ({}/{})", attempt, self.retry.max_retries);
+                }
+                is_busy(e)
+            })
+            .await
+    }
+
+    async fn commit(&self, writes: impl Iterator<Item = &PendingWrite>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for write in writes {
+            apply(&mut tx, write).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Scrobbles started in `[start, end)` per room, and how many of them at
+    /// least one backend rejected. Scrobbles recorded without their room are
+    /// listed under the device's room in the discovery cache, or else under
+    /// the device name.
+    pub async fn scrobbles_by_room(&self, start: i64, end: i64) -> Result<Vec<(String, i64, i64)>> {
+        self.sync().await;
+        let rows = sqlx::query(&format!(
+            "SELECT {SCROBBLE_ROOM}, COUNT(*), SUM(s.failures > 0) FROM scrobbles s {ROOM_JOIN}
+             WHERE s.started_at >= ? AND s.started_at < ?
+             GROUP BY 1 ORDER BY 2 DESC, 1"
+        ))
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect())
+    }
+
+    /// A page of up to `limit` scrobbles matching `filter` in `sort` order,
+    /// starting after `after`
+    pub async fn history(
+        &self,
+        filter: &ListenFilter,
+        sort: HistorySort,
+        after: Option<HistoryCursor>,
+        limit: u32,
+    ) -> Result<Vec<Listen>> {
+        self.sync().await;
+        let (beyond, order) = match sort {
+            HistorySort::Newest => ("<", "DESC"),
+            HistorySort::Oldest => (">", "ASC"),
+        };
+        let rows = sqlx::query(&format!(
+            "SELECT s.id, {SCROBBLE_ROOM}, s.artist, s.title, s.album, s.started_at, s.file_path,
+                    NOT EXISTS ({EARLIER_ARTIST}), NOT EXISTS ({EARLIER_ARTIST} AND e.title = s.title COLLATE NOCASE)
+             FROM scrobbles s {ROOM_JOIN}
+             WHERE {LISTEN_FILTER} AND (? IS NULL OR (s.started_at, s.id) {beyond} (?, ?))
+             ORDER BY s.started_at {order}, s.id {order} LIMIT ?"
+        ))
+        .bind(&filter.room)
+        .bind(&filter.room)
+        .bind(&filter.artist)
+        .bind(&filter.artist)
+        .bind(filter.from)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.to)
+        .bind(after.map(|cursor| cursor.started_at))
+        .bind(after.map(|cursor| cursor.started_at))
+        .bind(after.map(|cursor| cursor.id))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| Listen {
+                id: row.get(0),
+                room: row.get(1),
+                artist: row.get(2),
+                title: row.get(3),
+                album: row.get(4),
+                started_at: row.get(5),
+                file: row.get(6),
+                new_artist: row.get(7),
+                new_track: row.get(8),
+            })
+            .collect())
+    }
+
+    /// Scrobbles matching `filter`, counted per `by` value, most first
+    pub async fn aggregate(&self, filter: &ListenFilter, by: GroupBy, limit: u32) -> Result<Vec<(String, i64)>> {
+        self.sync().await;
+        let (key, join) = match by {
+            GroupBy::Room => (SCROBBLE_ROOM, ""),
+            GroupBy::Artist => ("s.artist", ""),
+            GroupBy::Track => ("s.artist || ' - ' || s.title", ""),
+            GroupBy::Album => ("s.artist || ' - ' || s.album", ""),
+            GroupBy::Genre => (
+                "g.tag",
+                "JOIN scrobble_tags g ON g.device_name = s.device_name AND g.started_at = s.started_at",
+            ),
+            GroupBy::Day => ("date(s.started_at, 'unixepoch', 'localtime')", ""),
+            GroupBy::Month => ("strftime('%Y-%m', s.started_at, 'unixepoch', 'localtime')", ""),
+        };
+        let rows = sqlx::query(&format!(
+            "SELECT {key}, COUNT(*) FROM scrobbles s {ROOM_JOIN} {join}
+             WHERE {LISTEN_FILTER} AND {key} IS NOT NULL
+             GROUP BY 1 ORDER BY 2 DESC, 1 LIMIT ?"
+        ))
+        .bind(&filter.room)
+        .bind(&filter.room)
+        .bind(&filter.artist)
+        .bind(&filter.artist)
+        .bind(filter.from)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.to)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Scrobbles matching `filter` per local day, `YYYY-MM-DD`, oldest first.
+    /// Days without any are left out.
+    pub async fn plays_per_day(&self, filter: &ListenFilter) -> Result<Vec<(String, i64)>> {
+        self.sync().await;
+        let rows = sqlx::query(&format!(
+            "SELECT date(s.started_at, 'unixepoch', 'localtime'), COUNT(*) FROM scrobbles s {ROOM_JOIN}
+             WHERE {LISTEN_FILTER}
+             GROUP BY 1 ORDER BY 1"
+        ))
+        .bind(&filter.room)
+        .bind(&filter.room)
+        .bind(&filter.artist)
+        .bind(&filter.artist)
+        .bind(filter.from)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Scrobbles matching `filter` per local weekday, Monday being 0, and
+    /// hour of the day
+    pub async fn plays_per_hour(&self, filter: &ListenFilter) -> Result<Vec<(u32, u32, i64)>> {
+        self.sync().await;
+        let rows = sqlx::query(&format!(
+            "SELECT (CAST(strftime('%w', s.started_at, 'unixepoch', 'localtime') AS INTEGER) + 6) % 7,
+                    CAST(strftime('%H', s.started_at, 'unixepoch', 'localtime') AS INTEGER),
+                    COUNT(*)
+             FROM scrobbles s {ROOM_JOIN}
+             WHERE {LISTEN_FILTER}
+             GROUP BY 1, 2 ORDER BY 1, 2"
+        ))
+        .bind(&filter.room)
+        .bind(&filter.room)
+        .bind(&filter.artist)
+        .bind(&filter.artist)
+        .bind(filter.from)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(filter.to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect())
+    }
+
+    /// Artists first scrobbled during `[start, end)` with their scrobbles
+    /// then, most first
+    pub async fn new_artists(&self, start: i64, end: i64, limit: u32) -> Result<Vec<(String, i64)>> {
+        self.first_heard("s.artist", "", start, end, limit).await
+    }
+
+    /// Tracks first scrobbled during `[start, end)`, as `Artist - Title`,
+    /// with their scrobbles then, most first
+    pub async fn new_tracks(&self, start: i64, end: i64, limit: u32) -> Result<Vec<(String, i64)>> {
+        let title = "AND e.title = s.title COLLATE NOCASE";
+        self.first_heard("s.artist || ' - ' || s.title", title, start, end, limit).await
+    }
+
+    async fn first_heard(&self, key: &str, same: &str, start: i64, end: i64, limit: u32) -> Result<Vec<(String, i64)>> {
+        self.sync().await;
+        let rows = sqlx::query(&format!(
+            "SELECT {key}, COUNT(*) FROM scrobbles s
+             WHERE s.started_at >= ? AND s.started_at < ?
+             AND NOT EXISTS (SELECT 1 FROM scrobbles e WHERE e.artist = s.artist COLLATE NOCASE {same}
+                             AND e.started_at < ?)
+             GROUP BY lower({key}) ORDER BY 2 DESC, 1 LIMIT ?"
+        ))
+        .bind(start)
+        .bind(end)
+        .bind(start)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    pub async fn scrobble_count(&self) -> Result<i64> {
+        self.sync().await;
+        let row = sqlx::query("SELECT COUNT(*) FROM scrobbles").fetch_one(&self.pool).await?;
+        Ok(row.get(0))
+    }
+
+    /// Tracks scrobbled at least `min` times, as `Artist - Title`, with
+    /// their play counts
+    pub async fn track_plays(&self, min: i64) -> Result<Vec<(String, i64)>> {
+        self.sync().await;
+        let rows = sqlx::query(
+            "SELECT artist || ' - ' || title, COUNT(*) FROM scrobbles
+             GROUP BY artist COLLATE NOCASE, title COLLATE NOCASE HAVING COUNT(*) >= ? ORDER BY 2 DESC, 1"
+        )
+        .bind(min)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// The local days with scrobbles, `YYYY-MM-DD`, latest first
+    pub async fn scrobble_days(&self) -> Result<Vec<String>> {
+        self.sync().await;
+        let rows = sqlx::query(
+            "SELECT DISTINCT date(started_at, 'unixepoch', 'localtime') FROM scrobbles ORDER BY 1 DESC"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Whether any milestone was recorded yet
+    pub async fn any_milestones(&self) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM milestones LIMIT 1").fetch_optional(&self.pool).await?;
+        Ok(row.is_some())
+    }
+
+    /// Records milestone `key`. Returns `false` when it was recorded before.
+    pub async fn record_milestone(&self, key: &str) -> Result<bool> {
+        self.write(|| async {
+            let result = sqlx::query("INSERT OR IGNORE INTO milestones (key, reached_at) VALUES (?, ?)")
+                .bind(key)
+                .bind(unix_now())
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        })
+        .await
+    }
+
+    /// Devices that logged listens since `since` without scrobbling any,
+    /// although they scrobbled on at least `min_days` days during
+    /// `[habit_start, since)`
+    pub async fn silent_devices(&self, since: i64, habit_start: i64, min_days: u32) -> Result<Vec<String>> {
+        self.sync().await;
+        let rows = sqlx::query(
+            "SELECT t.device_name FROM tracks t
+             WHERE t.played_at >= ?
+             AND NOT EXISTS (
+                 SELECT 1 FROM scrobbles s WHERE s.device_name = t.device_name AND s.scrobbled_at >= ?
+             )
+             AND (
+                 SELECT COUNT(DISTINCT s.scrobbled_at / 86400) FROM scrobbles s
+                 WHERE s.device_name = t.device_name AND s.scrobbled_at >= ? AND s.scrobbled_at < ?
+             ) >= ?
+             GROUP BY 1 ORDER BY 1"
+        )
+        .bind(since)
+        .bind(since)
+        .bind(habit_start)
+        .bind(since)
+        .bind(min_days)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Most scrobbled tracks in `room` during `[start, end)`, as `Artist - Title`
+    pub async fn top_tracks(&self, room: &str, start: i64, end: i64, limit: u32) -> Result<Vec<(String, i64)>> {
+        self.top_scrobbled("s.artist || ' - ' || s.title", room, start, end, limit).await
+    }
+
+    /// Most scrobbled artists in `room` during `[start, end)`, counting
+    /// featured artists too
+    pub async fn top_artists(&self, room: &str, start: i64, end: i64, limit: u32) -> Result<Vec<(String, i64)>> {
+        self.sync().await;
+        let rows = sqlx::query(&format!(
+            "SELECT s.artist, COUNT(*) FROM (
+                 SELECT artist, device_name, started_at FROM scrobbles
+                 UNION ALL
+                 SELECT artist, device_name, started_at FROM featured_artists
+             ) s {ROOM_JOIN}
+             WHERE {ROOM} = ? AND s.started_at >= ? AND s.started_at < ?
+             GROUP BY 1 ORDER BY 2 DESC, 1 LIMIT ?"
+        ))
+        .bind(room)
+        .bind(start)
+        .bind(end)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Most scrobbled genre tags in `room` during `[start, end)`
+    pub async fn top_genres(&self, room: &str, start: i64, end: i64, limit: u32) -> Result<Vec<(String, i64)>> {
+        self.sync().await;
+        let rows = sqlx::query(&format!(
+            "SELECT s.tag, COUNT(*) FROM scrobble_tags s {ROOM_JOIN}
+             WHERE {ROOM} = ? AND s.started_at >= ? AND s.started_at < ?
+             GROUP BY 1 ORDER BY 2 DESC, 1 LIMIT ?"
+        ))
+        .bind(room)
+        .bind(start)
+        .bind(end)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Scrobbles whose genre tags were not looked up yet, oldest first,
+    /// leaving out those whose lookup failed `max_attempts` times
+    pub async fn untagged_scrobbles(&self, limit: u32, max_attempts: u32) -> Result<Vec<Scrobble>> {
+        self.sync().await;
+        let rows = sqlx::query(
+            "SELECT device_name, artist, title, album, started_at FROM scrobbles
+             WHERE tagged_at IS NULL AND tag_attempts < ? ORDER BY started_at LIMIT ?"
+        )
+        .bind(max_attempts)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let (device, artist, title): (String, String, String) = (row.get(0), row.get(1), row.get(2));
+                Scrobble { album: row.get(3), ..Scrobble::new(device, artist, title, row.get(4)) }
+            })
+            .collect())
+    }
+
+    /// Tags of an earlier listen of the same track, or `None` when the
+    /// track was never tagged
+    pub async fn known_tags(&self, artist: &str, title: &str) -> Result<Option<Vec<String>>> {
+        let tagged = sqlx::query(
+            "SELECT device_name, started_at FROM scrobbles
+             WHERE artist = ? AND title = ? AND tagged_at IS NOT NULL LIMIT 1"
+        )
+        .bind(artist)
+        .bind(title)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(tagged) = tagged else {
+            return Ok(None);
+        };
+        let rows = sqlx::query("SELECT tag FROM scrobble_tags WHERE device_name = ? AND started_at = ? ORDER BY rowid")
+            .bind(tagged.get::<String, _>(0))
+            .bind(tagged.get::<i64, _>(1))
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(Some(rows.into_iter().map(|row| row.get(0)).collect()))
+    }
+
+    /// Every scrobble with its row id, in the order they started
+    pub async fn scrobbles_by_start(&self) -> Result<Vec<(i64, Scrobble)>> {
+        self.sync().await;
+        let rows = sqlx::query(
+            "SELECT id, device_name, artist, title, album, started_at FROM scrobbles ORDER BY started_at, id"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let (device, artist, title): (String, String, String) = (row.get(1), row.get(2), row.get(3));
+                let scrobble = Scrobble { album: row.get(4), ..Scrobble::new(device, artist, title, row.get(5)) };
+                (row.get(0), scrobble)
+            })
+            .collect())
+    }
+
+    /// Deletes scrobble `duplicate`, moving its album, featured artists,
+    /// genre tags and cover over to scrobble `kept` where that lacks them
+    pub async fn merge_scrobble(&self, kept: i64, duplicate: i64) -> Result<()> {
+        self.write(|| async {
+            let mut tx = self.pool.begin().await?;
+            let key = "SELECT device_name, started_at FROM scrobbles WHERE id = ?";
+            let (Some(to), Some(from)) = (
+                sqlx::query(key).bind(kept).fetch_optional(&mut *tx).await?,
+                sqlx::query(key).bind(duplicate).fetch_optional(&mut *tx).await?,
+            ) else {
+                return Ok(());
+            };
+            let (to_device, to_start): (String, i64) = (to.get(0), to.get(1));
+            let (from_device, from_start): (String, i64) = (from.get(0), from.get(1));
+
+            sqlx::query(
+                "UPDATE scrobbles SET
+                 album = COALESCE(album, (SELECT album FROM scrobbles WHERE id = ?)),
+                 tagged_at = COALESCE(tagged_at, (SELECT tagged_at FROM scrobbles WHERE id = ?))
+                 WHERE id = ?"
+            )
+            .bind(duplicate)
+            .bind(duplicate)
+            .bind(kept)
+            .execute(&mut *tx)
+            .await?;
+            // The cover only when the kept scrobble has none, as album_art
+            // allows one per scrobble
+            for (table, column) in [("featured_artists", "artist"), ("scrobble_tags", "tag"), ("album_art", "url")] {
+                sqlx::query(&format!(
+                    "INSERT OR IGNORE INTO {table} (device_name, started_at, {column})
+                     SELECT ?, ?, {column} FROM {table} WHERE device_name = ? AND started_at = ?"
+                ))
+                .bind(&to_device)
+                .bind(to_start)
+                .bind(&from_device)
+                .bind(from_start)
+                .execute(&mut *tx)
+                .await?;
+                // Unless both scrobbles share the key, as respelled ones
+                // on the same device at the same time would
+                if (&from_device, from_start) != (&to_device, to_start) {
+                    sqlx::query(&format!("DELETE FROM {table} WHERE device_name = ? AND started_at = ?"))
+                        .bind(&from_device)
+                        .bind(from_start)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+            }
+            sqlx::query("DELETE FROM scrobbles WHERE id = ?").bind(duplicate).execute(&mut *tx).await?;
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Counts a failed lookup of the genre tags of a scrobble
+    pub async fn record_tag_failure(&self, scrobble: &Scrobble) -> Result<()> {
+        self.write(|| async {
+            sqlx::query(
+                "UPDATE scrobbles SET tag_attempts = tag_attempts + 1
+                 WHERE device_name = ? AND artist = ? AND title = ? AND started_at = ?"
+            )
+            .bind(&scrobble.device)
+            .bind(&scrobble.artist)
+            .bind(&scrobble.title)
+            .bind(scrobble.started_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Stores the genre tags of a scrobble, marking it as tagged even when
+    /// `tags` is empty
+    pub async fn tag_scrobble(&self, scrobble: &Scrobble, tags: &[String]) -> Result<()> {
+        self.write(|| async {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query(
+                "UPDATE scrobbles SET tagged_at = ?
+                 WHERE device_name = ? AND artist = ? AND title = ? AND started_at = ?"
+            )
+            .bind(unix_now())
+            .bind(&scrobble.device)
+            .bind(&scrobble.artist)
+            .bind(&scrobble.title)
+            .bind(scrobble.started_at)
+            .execute(&mut *tx)
+            .await?;
+            for tag in tags {
+                sqlx::query("INSERT OR IGNORE INTO scrobble_tags (device_name, started_at, tag) VALUES (?, ?, ?)")
+                    .bind(&scrobble.device)
+                    .bind(scrobble.started_at)
+                    .bind(tag)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn top_scrobbled(
+        &self,
+        key: &str,
+        room: &str,
+        start: i64,
+        end: i64,
+        limit: u32,
+    ) -> Result<Vec<(String, i64)>> {
+        self.sync().await;
+        let rows = sqlx::query(&format!(
+            "SELECT {key}, COUNT(*) FROM scrobbles s {ROOM_JOIN}
+             WHERE {SCROBBLE_ROOM} = ? AND s.started_at >= ? AND s.started_at < ?
+             GROUP BY 1 ORDER BY 2 DESC, 1 LIMIT ?"
+        ))
+        .bind(room)
+        .bind(start)
+        .bind(end)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Records an album played through, or updates its track count when it
+    /// was recorded before
+    pub async fn record_album_listen(&self, listen: &AlbumListen) -> Result<()> {
+        self.write(|| async {
+            sqlx::query(
+                "INSERT INTO album_listens (device_name, artist, album, started_at, tracks)
+                 VALUES (?, ?, ?, ?, ?)
+                 ON CONFLICT (device_name, started_at) DO UPDATE SET artist = excluded.artist, tracks = excluded.tracks"
+            )
+            .bind(&listen.device)
+            .bind(&listen.artist)
+            .bind(&listen.album)
+            .bind(listen.started_at)
+            .bind(listen.tracks)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Fingerprint of the last scrobble submitted from `device` and when that
+    /// listen ends, at the latest
+    pub async fn last_submitted(&self, device: &str) -> Result<Option<(String, i64)>> {
+        let row = sqlx::query("SELECT fingerprint, ends_at FROM last_submitted WHERE device_name = ?")
+            .bind(device)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+
+    /// Albums played through during `[start, end)` as room, artist, album and
+    /// number of tracks, oldest first
+    pub async fn album_listens(&self, start: i64, end: i64) -> Result<Vec<(String, String, String, i64)>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {ROOM}, s.artist, s.album, s.tracks FROM album_listens s {ROOM_JOIN}
+             WHERE s.started_at >= ? AND s.started_at < ?
+             ORDER BY s.started_at"
+        ))
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1), row.get(2), row.get(3))).collect())
+    }
+
+    /// Keeps a listen without usable metadata out of the scrobbles
+    pub async fn quarantine_listen(&self, listen: &QuarantinedListen) -> Result<()> {
+        self.write(|| async {
+            sqlx::query(
+                "INSERT OR IGNORE INTO quarantine (device_name, uri, track_info, artist, title, album,
+                                                  started_at, duration, quarantined_at,
+                                                  artist_confidence, title_confidence)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            )
+            .bind(&listen.device)
+            .bind(&listen.uri)
+            .bind(&listen.track_info)
+            .bind(&listen.artist)
+            .bind(&listen.title)
+            .bind(&listen.album)
+            .bind(listen.started_at)
+            .bind(listen.duration.map(|d| d.as_secs() as i64))
+            .bind(unix_now())
+            .bind(listen.confidence.artist)
+            .bind(listen.confidence.title)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Quarantined listens that have not been released, oldest first
+    pub async fn quarantined(&self) -> Result<Vec<QuarantinedListen>> {
+        let rows = sqlx::query(
+            "SELECT id, device_name, uri, track_info, artist, title, album, started_at, duration,
+                    artist_confidence, title_confidence
+             FROM quarantine WHERE released_at IS NULL
+             ORDER BY id"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| QuarantinedListen {
+                id: row.get(0),
+                device: row.get(1),
+                uri: row.get(2),
+                track_info: row.get(3),
+                artist: row.get(4),
+                title: row.get(5),
+                album: row.get(6),
+                started_at: row.get(7),
+                duration: row.get::<Option<i64>, _>(8).map(|secs| Duration::from_secs(secs as u64)),
+                // Listens quarantined before confidence was recorded had
+                // missing fields, not untrusted ones
+                confidence: match (row.get(9), row.get(10)) {
+                    (Some(artist), Some(title)) => Confidence { artist, title },
+                    _ => Confidence::default(),
+                },
+            })
+            .collect())
+    }
+
+    /// Marks a quarantined listen as scrobbled after enrichment
+    pub async fn release_quarantined(&self, id: i64) -> Result<()> {
+        self.write(|| async {
+            sqlx::query("UPDATE quarantine SET released_at = ? WHERE id = ?")
+                .bind(unix_now())
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Counts a call to a scrobbling service API, bucketed per minute
+    pub async fn record_api_call(&self, backend: &str, ok: bool) -> Result<()> {
+        let now = unix_now();
+        self.write(|| async {
+            sqlx::query(
+                "INSERT INTO api_calls (backend, minute, calls, errors) VALUES (?, ?, 1, ?)
+                 ON CONFLICT(backend, minute)
+                 DO UPDATE SET calls = calls + 1, errors = errors + excluded.errors"
+            )
+            .bind(backend)
+            .bind(now - now % 60)
+            .bind(i64::from(!ok))
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Calls and failed calls per backend within the last `window`
+    pub async fn api_usage(&self, window: Duration) -> Result<Vec<(String, i64, i64)>> {
+        let rows = sqlx::query(
+            "SELECT backend, SUM(calls), SUM(errors) FROM api_calls
+             WHERE minute >= ?
+             GROUP BY backend ORDER BY backend"
+        )
+        .bind(unix_now() - window.as_secs() as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect())
+    }
+
+    pub async fn digest_sent(&self, period: &str) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM digests WHERE period = ?")
+            .bind(period)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    pub async fn mark_digest_sent(&self, period: &str) -> Result<()> {
+        self.write(|| async {
+            sqlx::query("INSERT OR REPLACE INTO digests (period, sent_at) VALUES (?, ?)")
+                .bind(period)
+                .bind(unix_now())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Whether `backend` accepted the credentials with `fingerprint` within
+    /// the last `max_age`
+    pub async fn credentials_checked_within(
+        &self,
+        backend: &str,
+        fingerprint: &str,
+        max_age: Duration,
+    ) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM credential_checks WHERE backend = ? AND fingerprint = ? AND checked_at > ?")
+        .bind(backend)
+        .bind(fingerprint)
+        .bind(unix_now() - max_age.as_secs() as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.is_some())
+    }
+
+    pub async fn mark_credentials_checked(&self, backend: &str, fingerprint: &str) -> Result<()> {
+        self.write(|| async {
+            sqlx::query("INSERT OR REPLACE INTO credential_checks (backend, fingerprint, checked_at) VALUES (?, ?, ?)")
+                .bind(backend)
+                .bind(fingerprint)
+                .bind(unix_now())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// The token in event callback URLs, generated on first use
+    pub async fn event_token(&self) -> Result<String> {
+        self.write(|| async {
+            sqlx::query("INSERT OR IGNORE INTO secrets (name, value) VALUES ('event_token', ?)")
+                .bind(crate::sonos::gena::new_token())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await?;
+        let row = sqlx::query("SELECT value FROM secrets WHERE name = 'event_token'").fetch_one(&self.pool).await?;
+        Ok(row.get(0))
+    }
+
+    /// A new random session key for the Audioscrobbler client `username`
+    pub async fn new_audioscrobbler_session(&self, username: &str) -> Result<String> {
+        let key = crate::sonos::gena::new_token();
+        self.write(|| async {
+            sqlx::query("INSERT INTO audioscrobbler_sessions (key, username, created_at) VALUES (?, ?, ?)")
+                .bind(&key)
+                .bind(username)
+                .bind(unix_now())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await?;
+        Ok(key)
+    }
+
+    /// The Audioscrobbler client the session `key` was handed to
+    pub async fn audioscrobbler_session(&self, key: &str) -> Result<Option<String>> {
+        self.sync().await;
+        let row = sqlx::query("SELECT username FROM audioscrobbler_sessions WHERE key = ?")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Names of the scrobble backends switched off
+    pub async fn disabled_backends(&self) -> Result<Vec<String>> {
+        let rows = sqlx::query("SELECT name FROM disabled_backends ORDER BY name").fetch_all(&self.pool).await?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Remembers whether the backend called `name` is switched off
+    pub async fn set_backend_disabled(&self, name: &str, disabled: bool) -> Result<()> {
+        self.write(|| async {
+            let query = match disabled {
+                true => sqlx::query("INSERT OR IGNORE INTO disabled_backends (name, disabled_at) VALUES (?, ?)")
+                    .bind(name)
+                    .bind(unix_now()),
+                false => sqlx::query("DELETE FROM disabled_backends WHERE name = ?").bind(name),
+            };
+            query.execute(&self.pool).await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// The profile switched to last, `Some(None)` when that was back to no
+    /// profile, or `None` before any switch
+    pub async fn active_profile(&self) -> Result<Option<Option<String>>> {
+        self.sync().await;
+        let row = sqlx::query("SELECT name FROM active_profile").fetch_optional(&self.pool).await?;
+        Ok(row.map(|row| Some(row.get::<String, _>(0)).filter(|name| !name.is_empty())))
+    }
+
+    /// Remembers the switch to the profile called `name`, or to none
+    pub async fn set_active_profile(&self, name: Option<&str>) -> Result<()> {
+        self.write(|| async {
+            sqlx::query("INSERT OR REPLACE INTO active_profile (id, name, switched_at) VALUES (1, ?, ?)")
+                .bind(name.unwrap_or_default())
+                .bind(unix_now())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Makes the next startup check `backend`'s credentials again
+    pub async fn forget_credentials_check(&self, backend: &str) -> Result<()> {
+        self.write(|| async {
+            sqlx::query("DELETE FROM credential_checks WHERE backend = ?")
+                .bind(backend)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// The artist and title Last.fm corrected `artist` and `title` to, if it
+    /// did
+    pub async fn correction(&self, artist: &str, title: &str) -> Result<Option<(String, String)>> {
+        let row = sqlx::query(
+            "SELECT corrected_artist, corrected_title FROM corrections WHERE artist = ? AND title = ?"
+        )
+        .bind(artist)
+        .bind(title)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+
+    /// Remembers that Last.fm scrobbles `artist` - `title` as
+    /// `corrected_artist` - `corrected_title`, and renames the scrobbles
+    /// recorded so far to match. Returns how many were renamed.
+    pub async fn record_correction(
+        &self,
+        artist: &str,
+        title: &str,
+        corrected_artist: &str,
+        corrected_title: &str,
+    ) -> Result<u64> {
+        // The scrobble just corrected may still be buffered
+        self.sync().await;
+        self.write(|| async {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query(
+                "INSERT OR REPLACE INTO corrections (artist, title, corrected_artist, corrected_title, corrected_at)
+                 VALUES (?, ?, ?, ?, ?)"
+            )
+            .bind(artist)
+            .bind(title)
+            .bind(corrected_artist)
+            .bind(corrected_title)
+            .bind(unix_now())
+            .execute(&mut *tx)
+            .await?;
+            // A listen recorded under both names stays as it is
+            let renamed = sqlx::query(
+                "UPDATE OR IGNORE scrobbles SET artist = ?, title = ? WHERE artist = ? AND title = ?"
+            )
+            .bind(corrected_artist)
+            .bind(corrected_title)
+            .bind(artist)
+            .bind(title)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+            tx.commit().await?;
+            Ok(renamed)
+        })
+        .await
+    }
+
+    /// Whether `artist` - `title` or every track of `artist` is blocklisted
+    pub async fn blocklisted(&self, artist: &str, title: &str) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM blocklist WHERE artist = ? AND (title = '' OR title = ?)")
+            .bind(artist)
+            .bind(title)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    /// Blocklists `artist` - `title` as an entry from `source`. Returns
+    /// `false` when it was blocklisted from there before.
+    pub async fn block_track(&self, artist: &str, title: &str, source: &str) -> Result<bool> {
+        self.write(|| async {
+            let result = sqlx::query("INSERT OR IGNORE INTO blocklist (artist, title, source) VALUES (?, ?, ?)")
+                .bind(artist)
+                .bind(title)
+                .bind(source)
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        })
+        .await
+    }
+
+    /// Removes the blocklist entry for `artist` - `title` from `source`.
+    /// Returns `false` when there was none.
+    pub async fn unblock_track(&self, artist: &str, title: &str, source: &str) -> Result<bool> {
+        self.write(|| async {
+            let result = sqlx::query("DELETE FROM blocklist WHERE artist = ? AND title = ? AND source = ?")
+                .bind(artist)
+                .bind(title)
+                .bind(source)
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        })
+        .await
+    }
+
+    /// Replaces the blocklist entries from `source` with `entries`, as artist
+    /// and title, without a title to block every track of the artist
+    pub async fn replace_blocklist(&self, source: &str, entries: &[(String, Option<String>)]) -> Result<()> {
+        self.write(|| async {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("DELETE FROM blocklist WHERE source = ?")
+                .bind(source)
+                .execute(&mut *tx)
+                .await?;
+            for (artist, title) in entries {
+                sqlx::query("INSERT OR IGNORE INTO blocklist (artist, title, source) VALUES (?, ?, ?)")
+                    .bind(artist)
+                    .bind(title.as_deref().unwrap_or_default())
+                    .bind(source)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// The cached release of a track: `None` when it was never looked up,
+    /// `Some(None)` when the lookup found nothing
+    pub async fn cached_release(&self, artist: &str, title: &str) -> Result<Option<Option<Release>>> {
+        let row = sqlx::query("SELECT album, year FROM enrichment_cache WHERE artist = ? AND title = ?")
+            .bind(artist)
+            .bind(title)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| {
+            row.get::<Option<String>, _>(0).map(|album| Release { album, year: row.get(1) })
+        }))
+    }
+
+    /// Caches the result of looking up a track's release with `source`
+    pub async fn cache_release(
+        &self,
+        artist: &str,
+        title: &str,
+        release: Option<&Release>,
+        source: &str,
+    ) -> Result<()> {
+        self.write(|| async {
+            sqlx::query(
+                "INSERT OR REPLACE INTO enrichment_cache (artist, title, album, year, source, looked_up_at)
+                 VALUES (?, ?, ?, ?, ?, ?)"
+            )
+            .bind(artist)
+            .bind(title)
+            .bind(release.map(|release| &release.album))
+            .bind(release.and_then(|release| release.year))
+            .bind(source)
+            .bind(unix_now())
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Notes where the cover of the listen that started at `started_at` on
+    /// `device_name` is served
+    pub async fn record_album_art(&self, device_name: &str, started_at: i64, url: &str) -> Result<()> {
+        self.write(|| async {
+            sqlx::query("INSERT OR REPLACE INTO album_art (device_name, started_at, url) VALUES (?, ?, ?)")
+                .bind(device_name)
+                .bind(started_at)
+                .bind(url)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Artist and title of scrobble `id`
+    pub async fn scrobbled_track(&self, id: i64) -> Result<Option<(String, String)>> {
+        self.sync().await;
+        let row = sqlx::query("SELECT artist, title FROM scrobbles WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+
+    /// Where the cover of scrobble `id` is served, if the speaker reported one
+    pub async fn album_art_url(&self, id: i64) -> Result<Option<String>> {
+        self.sync().await;
+        let row = sqlx::query(
+            "SELECT a.url FROM scrobbles s
+             JOIN album_art a ON a.device_name = s.device_name AND a.started_at = s.started_at
+             WHERE s.id = ?"
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// The cover fetched from `url` before
+    pub async fn cached_album_art(&self, url: &str) -> Result<Option<AlbumArt>> {
+        let row = sqlx::query("SELECT content_type, image FROM album_art_cache WHERE url = ?")
+            .bind(url)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| AlbumArt { content_type: row.get(0), image: row.get(1) }))
+    }
+
+    pub async fn cache_album_art(&self, url: &str, art: &AlbumArt) -> Result<()> {
+        self.write(|| async {
+            sqlx::query(
+                "INSERT OR REPLACE INTO album_art_cache (url, content_type, image, fetched_at) VALUES (?, ?, ?, ?)"
+            )
+            .bind(url)
+            .bind(&art.content_type)
+            .bind(&art.image)
+            .bind(unix_now())
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Records `interaction` and forgets those too old for the stats
+    pub async fn record_interaction(&self, device_name: &str, room: &str, interaction: Interaction) -> Result<()> {
+        self.write(|| async {
+            let now = unix_now();
+            sqlx::query("INSERT INTO interactions (device_name, room, kind, at) VALUES (?, ?, ?, ?)")
+                .bind(device_name)
+                .bind(room)
+                .bind(interaction.as_str())
+                .bind(now)
+                .execute(&self.pool)
+                .await?;
+            sqlx::query("DELETE FROM interactions WHERE at < ?")
+                .bind(now - INTERACTION_RETENTION_SECS)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Interactions during `[start, end)` by room and kind, as `Room: kind`,
+    /// most first
+    pub async fn interactions(&self, start: i64, end: i64) -> Result<Vec<(String, i64)>> {
+        self.sync().await;
+        let rows = sqlx::query(
+            "SELECT room || ': ' || kind, COUNT(*) FROM interactions
+             WHERE at >= ? AND at < ?
+             GROUP BY room, kind ORDER BY 2 DESC, 1"
+        )
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Appends `entries` to the event log
+    pub async fn append_events(&self, entries: &[LogEntry]) -> Result<()> {
+        self.write(|| async {
+            let mut tx = self.pool.begin().await?;
+            insert_events(&mut tx, entries).await?;
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// The logged events of `stage` from `since_ms` on, oldest first
+    pub async fn logged_events(&self, stage: Stage, since_ms: i64) -> Result<Vec<LogEntry>> {
+        let rows = sqlx::query(
+            "SELECT device, at_ms, payload FROM event_log WHERE stage = ? AND at_ms >= ? ORDER BY at_ms, id"
+        )
+        .bind(stage.as_str())
+        .bind(since_ms)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| LogEntry { stage, device: row.get(0), at_ms: row.get(1), payload: row.get(2) })
+            .collect())
+    }
+
+    /// Replaces the logged events of `stages` from `since_ms` on with
+    /// `entries`, as derived again
+    pub async fn replace_events(&self, stages: &[Stage], since_ms: i64, entries: &[LogEntry]) -> Result<()> {
+        self.write(|| async {
+            let mut tx = self.pool.begin().await?;
+            for stage in stages {
+                sqlx::query("DELETE FROM event_log WHERE stage = ? AND at_ms >= ?")
+                    .bind(stage.as_str())
+                    .bind(since_ms)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            insert_events(&mut tx, entries).await?;
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes the events logged before `before_ms`, returning how many
+    pub async fn prune_events(&self, before_ms: i64) -> Result<u64> {
+        self.write(|| async {
+            let result = sqlx::query("DELETE FROM event_log WHERE at_ms < ?")
+                .bind(before_ms)
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected())
+        })
+        .await
+    }
+
+    /// Replaces the cached discovery results with `devices`
+    pub async fn save_devices(&self, devices: &[BasicSpeakerInfo]) -> Result<()> {
+        self.write(|| self.replace_devices(devices)).await
+    }
+
+    async fn replace_devices(&self, devices: &[BasicSpeakerInfo]) -> Result<()> {
+        let now = unix_now();
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM devices").execute(&mut *tx).await?;
+        for device in devices {
+            sqlx::query(
+                "INSERT INTO devices (ip_addr, friendly_name, room_name, discovered_at)
+                 VALUES (?, ?, ?, ?)"
+            )
+            .bind(device.ip_addr.to_string())
+            .bind(&device.friendly_name)
+            .bind(&device.room_name)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Adds or replaces the cached entry of the device with `device_id`
+    pub async fn save_device(&self, device: &BasicSpeakerInfo, device_id: &str) -> Result<()> {
+        self.write(|| async {
+            let mut tx = self.pool.begin().await?;
+            // Friendly names end in the device id and start with the address,
+            // which may have changed
+            sqlx::query("DELETE FROM devices WHERE friendly_name LIKE ? OR ip_addr = ?")
+                .bind(format!("% - {}", device_id))
+                .bind(device.ip_addr.to_string())
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(
+                "INSERT INTO devices (ip_addr, friendly_name, room_name, discovered_at) VALUES (?, ?, ?, ?)"
+            )
+            .bind(device.ip_addr.to_string())
+            .bind(&device.friendly_name)
+            .bind(&device.room_name)
+            .bind(unix_now())
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes the cached entry of the device with `device_id`
+    pub async fn forget_device(&self, device_id: &str) -> Result<()> {
+        self.write(|| async {
+            sqlx::query("DELETE FROM devices WHERE friendly_name LIKE ?")
+                .bind(format!("% - {}", device_id))
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the cached discovery results, or an empty list when they are
+    /// older than `max_age`
+    pub async fn load_devices(&self, max_age: Duration) -> Result<Vec<BasicSpeakerInfo>> {
+        let rows = sqlx::query(
+            "SELECT ip_addr, friendly_name, room_name FROM devices
+             WHERE discovered_at > ?
+             ORDER BY room_name"
+        )
+        .bind(unix_now() - max_age.as_secs() as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(BasicSpeakerInfo {
+                    ip_addr: row.get::<String, _>(0).parse().ok()?,
+                    friendly_name: row.get(1),
+                    room_name: row.get(2),
+                })
+            })
+            .collect())
+    }
+
+    /// Returns the rooms of every cached device, however old
+    pub async fn known_rooms(&self) -> Result<Vec<String>> {
+        let rows = sqlx::query("SELECT DISTINCT room_name FROM devices ORDER BY room_name")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+}
+
+/// SQLITE_BUSY and SQLITE_LOCKED, including their extended codes
+fn is_busy(e: &sqlx::Error) -> bool {
+    let sqlx::Error::Database(e) = e else {
+        return false;
+    };
+    e.code()
+        .and_then(|code| code.parse::<i32>().ok())
+        .is_some_and(|code| matches!(code & 0xff, 5 | 6))
+}
+
+async fn apply(conn: &mut SqliteConnection, write: &PendingWrite) -> Result<()> {
+    match write {
+        PendingWrite::Track { device_name, track_info, context, played_at } => {
+            sqlx::query(
+                "INSERT INTO tracks (device_name, track_info, played_at,
+                                     context_kind, context_name, context_uri, queue_position)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)"
+            )
+            .bind(device_name)
+            .bind(track_info)
+            .bind(played_at)
+            .bind(context.kind())
+            .bind(&context.name)
+            .bind(&context.uri)
+            .bind(context.queue_position)
+            .execute(conn)
+            .await?;
+        }
+        PendingWrite::Scrobble { scrobble, scrobbled_at } => {
+            sqlx::query(
+                "INSERT OR IGNORE INTO scrobbles (device_name, artist, title, album, started_at,
+                                                  scrobbled_at, fingerprint, artist_confidence,
+                                                  title_confidence, room, file_path)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            )
+            .bind(&scrobble.device)
+            .bind(&scrobble.artist)
+            .bind(&scrobble.title)
+            .bind(&scrobble.album)
+            .bind(scrobble.started_at)
+            .bind(scrobbled_at)
+            .bind(scrobble.fingerprint())
+            .bind(scrobble.confidence.artist)
+            .bind(scrobble.confidence.title)
+            .bind(&scrobble.room)
+            .bind(scrobble.track_uri.as_deref().and_then(share_path))
+            .execute(&mut *conn)
+            .await?;
+
+            let length = scrobble.duration.unwrap_or(UNKNOWN_TRACK_LENGTH).as_secs() as i64;
+            sqlx::query(
+                "INSERT OR REPLACE INTO last_submitted (device_name, fingerprint, started_at, ends_at)
+                 VALUES (?, ?, ?, ?)"
+            )
+            .bind(&scrobble.device)
+            .bind(scrobble.fingerprint())
+            .bind(scrobble.started_at)
+            .bind(scrobble.started_at + length)
+            .execute(&mut *conn)
+            .await?;
+
+            for artist in &scrobble.featured {
+                sqlx::query("INSERT OR IGNORE INTO featured_artists (device_name, started_at, artist) VALUES (?, ?, ?)")
+                    .bind(&scrobble.device)
+                    .bind(scrobble.started_at)
+                    .bind(artist)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+        PendingWrite::ScrobbleFailures { scrobble, failures, deferred } => {
+            sqlx::query(
+                "UPDATE scrobbles SET failures = ?, deferred = ?
+                 WHERE device_name = ? AND artist = ? AND title = ? AND started_at = ?"
+            )
+            .bind(*failures as i64)
+            .bind(deferred)
+            .bind(&scrobble.device)
+            .bind(&scrobble.artist)
+            .bind(&scrobble.title)
+            .bind(scrobble.started_at)
+            .execute(conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// How long a listen of unknown length, like a song in a stream, is assumed
+/// to last when checking for it after a restart
+const UNKNOWN_TRACK_LENGTH: Duration = Duration::from_secs(10 * 60);
+
+/// Room of a row recorded by device, falling back to the device name
+const ROOM: &str = "COALESCE(d.room_name, s.device_name)";
+/// [`ROOM`] for rows of the scrobbles table, which record their room
+const SCROBBLE_ROOM: &str = "COALESCE(s.room, d.room_name, s.device_name)";
+const ROOM_JOIN: &str = "LEFT JOIN devices d ON d.friendly_name = s.device_name";
+/// Scrobbles of the artist of scrobble `s` before it
+const EARLIER_ARTIST: &str =
+    "SELECT 1 FROM scrobbles e WHERE e.artist = s.artist COLLATE NOCASE AND e.started_at < s.started_at";
+/// Conditions of a [`ListenFilter`], each bound twice: room, artist, from, to
+const LISTEN_FILTER: &str = "(? IS NULL OR COALESCE(s.room, d.room_name, s.device_name) = ? COLLATE NOCASE)
+     AND (? IS NULL OR s.artist = ? COLLATE NOCASE)
+     AND (? IS NULL OR s.started_at >= ?)
+     AND (? IS NULL OR s.started_at < ?)";
+
+/// Adds `column` to `table` when a database created by an older version
+/// lacks it
+async fn insert_events(conn: &mut SqliteConnection, entries: &[LogEntry]) -> Result<()> {
+    for entry in entries {
+        sqlx::query("INSERT INTO event_log (stage, device, at_ms, payload) VALUES (?, ?, ?, ?)")
+            .bind(entry.stage.as_str())
+            .bind(&entry.device)
+            .bind(entry.at_ms)
+            .bind(&entry.payload)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn add_column(pool: &SqlitePool, table: &str, column: &str, definition: &str) -> Result<()> {
+    let exists = sqlx::query("SELECT 1 FROM pragma_table_info(?) WHERE name = ?")
+        .bind(table)
+        .bind(column)
+        .fetch_optional(pool)
+        .await?
+        .is_some();
+
+    if !exists {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
 }
 
 #[cfg(test)]
@@ -82,10 +2020,10 @@ mod tests {
 
     #[tokio::test]
     async fn test_database_operations() {
-        let db = TrackDatabase::new().await.unwrap();
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
         
         // Test logging a track
-        let logged = db.log_track("Test Device", "Test Track").await.unwrap();
+        let logged = db.log_track("Test Device", "Test Track", &PlayContext::default()).await.unwrap();
         assert!(logged);
 
         // Test getting last track
@@ -93,7 +2031,241 @@ mod tests {
         assert_eq!(last_track, Some("Test Track".to_string()));
 
         // Test duplicate prevention
-        let logged_again = db.log_track("Test Device", "Test Track").await.unwrap();
+        let logged_again = db.log_track("Test Device", "Test Track", &PlayContext::default()).await.unwrap();
         assert!(!logged_again);
     }
+
+    #[tokio::test]
+    async fn test_writes_buffered_while_unavailable() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let context = PlayContext::default();
+        sqlx::query("ALTER TABLE tracks RENAME TO tracks_moved").execute(&db.pool).await.unwrap();
+
+        assert!(db.log_track("Kitchen", "Moderat - Bad Kingdom", &context).await.unwrap());
+        assert!(db.log_track("Kitchen", "Bonobo - Kerala", &context).await.unwrap());
+        assert_eq!(db.pending_writes().await, 2);
+
+        sqlx::query("ALTER TABLE tracks_moved RENAME TO tracks").execute(&db.pool).await.unwrap();
+        assert_eq!(db.flush_pending().await.unwrap(), 2);
+
+        assert_eq!(db.pending_writes().await, 0);
+        assert_eq!(db.get_last_track("Kitchen").await.unwrap(), Some("Bonobo - Kerala".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_plays_by_context() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let playlist = PlayContext {
+            uri: "file:///jffs/settings/savedqueues.rsq#3".to_string(),
+            name: Some("Sunday Morning".to_string()),
+            queue_position: Some(2),
+        };
+        db.log_track("Kitchen", "Nils Frahm - Says", &playlist).await.unwrap();
+        db.log_track("Kitchen", "Bonobo - Kerala", &playlist).await.unwrap();
+        db.log_track("Kitchen", "Moderat - Bad Kingdom", &PlayContext::default()).await.unwrap();
+
+        let plays = db.plays_by_context().await.unwrap();
+        assert_eq!(plays["Sunday Morning"], 2);
+        assert_eq!(plays["unknown"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_silent_devices() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let now = unix_now();
+        for device in ["Kitchen", "Office"] {
+            db.log_track(device, "Bonobo - Kerala", &PlayContext::default()).await.unwrap();
+            for day in 1..=3 {
+                sqlx::query(
+                    "INSERT INTO scrobbles (device_name, artist, title, started_at, scrobbled_at)
+                     VALUES (?, 'Bonobo', 'Kerala', ?, ?)"
+                )
+                .bind(device)
+                .bind(now - day * 86400)
+                .bind(now - day * 86400)
+                .execute(&db.pool)
+                .await
+                .unwrap();
+            }
+        }
+        let mut scrobble = Scrobble::new("Office", "Bonobo", "Kerala", now);
+        db.record_scrobble(&scrobble).await.unwrap();
+
+        let since = now - 6 * 3600;
+        assert_eq!(db.silent_devices(since, now - 7 * 86400, 3).await.unwrap(), vec!["Kitchen".to_string()]);
+        // Rooms that don't scrobble that often aren't expected to
+        assert!(db.silent_devices(since, now - 7 * 86400, 4).await.unwrap().is_empty());
+
+        // A different song, or it would count as the Office listen on a grouped speaker
+        scrobble.device = "Kitchen".to_string();
+        scrobble.title = "Cirrus".to_string();
+        db.record_scrobble(&scrobble).await.unwrap();
+        assert!(db.silent_devices(since, now - 7 * 86400, 3).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_record_scrobble_once() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobble = Scrobble {
+            featured: vec!["Pharrell Williams".to_string()],
+            ..Scrobble::new("Kitchen", "Daft Punk", "Get Lucky", 1_700_000_000)
+        };
+
+        assert!(db.record_scrobble(&scrobble).await.unwrap());
+        assert!(!db.record_scrobble(&scrobble).await.unwrap());
+        db.flush_pending().await.unwrap();
+        let last = db.last_submitted("Kitchen").await.unwrap().unwrap();
+        assert_eq!(last, (scrobble.fingerprint(), scrobble.started_at + 10 * 60));
+        let artists = db.top_artists("Kitchen", 0, i64::MAX, 5).await.unwrap();
+        assert_eq!(artists, vec![("Daft Punk".to_string(), 1), ("Pharrell Williams".to_string(), 1)]);
+
+        // Grouped speakers report the same play; both while it is queued and
+        // once it is committed
+        let grouped = Scrobble { device: "Office".to_string(), started_at: scrobble.started_at + 4, ..scrobble.clone() };
+        assert!(!db.record_scrobble(&grouped).await.unwrap());
+        db.flush_pending().await.unwrap();
+        let regrouped = Scrobble { device: "Patio".to_string(), started_at: scrobble.started_at + 30, ..scrobble };
+        assert!(!db.record_scrobble(&regrouped).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_history_and_aggregate_filtered() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let scrobble = Scrobble::new("Kitchen", "Daft Punk", "Get Lucky", 1_700_000_000);
+        let later = Scrobble {
+            title: "Instant Crush".to_string(),
+            started_at: 1_700_100_000,
+            track_uri: Some("x-file-cifs://nas/Music/Daft%20Punk/Instant%20Crush.flac".to_string()),
+            ..scrobble.clone()
+        };
+        let elsewhere = Scrobble { device: "Office".to_string(), artist: "Air".to_string(), ..later.clone() };
+        for scrobble in [&scrobble, &later, &elsewhere] {
+            assert!(db.record_scrobble(scrobble).await.unwrap());
+        }
+        db.flush_pending().await.unwrap();
+
+        let all = ListenFilter::default();
+        let page = db.history(&all, HistorySort::Newest, None, 2).await.unwrap();
+        assert_eq!(page.iter().map(|listen| listen.room.as_str()).collect::<Vec<_>>(), ["Office", "Kitchen"]);
+        let rest = db.history(&all, HistorySort::Newest, Some((&page[1]).into()), 2).await.unwrap();
+        assert_eq!(rest.iter().map(|listen| listen.title.as_str()).collect::<Vec<_>>(), ["Get Lucky"]);
+        assert_eq!(page[1].file.as_deref(), Some("//nas/Music/Daft Punk/Instant Crush.flac"));
+        assert_eq!(rest[0].file, None);
+        assert_eq!((page[0].new_artist, page[1].new_artist, page[1].new_track), (true, false, true));
+        assert!(rest[0].new_track);
+
+        let new = db.new_tracks(1_700_050_000, 1_700_200_000, 10).await.unwrap();
+        let expected = [("Air - Instant Crush".to_string(), 1), ("Daft Punk - Instant Crush".to_string(), 1)];
+        assert_eq!(new, expected);
+        let artists = db.new_artists(1_700_050_000, 1_700_200_000, 10).await.unwrap();
+        assert_eq!(artists, vec![("Air".to_string(), 1)]);
+
+        let rooms = db.aggregate(&all, GroupBy::Room, 10).await.unwrap();
+        assert_eq!(rooms, vec![("Kitchen".to_string(), 2), ("Office".to_string(), 1)]);
+
+        let filter = ListenFilter { room: Some("kitchen".to_string()), from: Some(1_700_050_000), ..all };
+        let tracks = db.aggregate(&filter, GroupBy::Track, 10).await.unwrap();
+        assert_eq!(tracks, vec![("Daft Punk - Instant Crush".to_string(), 1)]);
+        assert!(db.aggregate(&filter, GroupBy::Album, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_write_waits_for_other_connection() {
+        let path = std::env::temp_dir().join(format!("sonos-scrobbler-busy-{}.db", std::process::id()));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        let first = TrackDatabase::connect(&url).await.unwrap();
+        let second = TrackDatabase::connect(&url).await.unwrap();
+
+        let mode: String = sqlx::query_scalar("PRAGMA journal_mode").fetch_one(&first.pool).await.unwrap();
+        assert_eq!(mode, "wal");
+
+        // Hold the write lock from another connection for a moment
+        let mut tx = first.pool.begin().await.unwrap();
+        sqlx::query("INSERT INTO digests (period, sent_at) VALUES ('2026-01', 0)")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let holder = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            tx.commit().await.unwrap();
+        });
+
+        second.record_api_call("lastfm", true).await.unwrap();
+        holder.await.unwrap();
+        assert!(second.digest_sent("2026-01").await.unwrap());
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_api_usage() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        db.record_api_call("lastfm", true).await.unwrap();
+        db.record_api_call("lastfm", false).await.unwrap();
+        db.record_api_call("telegram", true).await.unwrap();
+
+        let usage = db.api_usage(Duration::from_secs(3600)).await.unwrap();
+        assert_eq!(usage, vec![("lastfm".to_string(), 2, 1), ("telegram".to_string(), 1, 0)]);
+    }
+
+    #[tokio::test]
+    async fn test_device_cache() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let devices = vec![BasicSpeakerInfo {
+            ip_addr: "192.168.1.100".parse().unwrap(),
+            friendly_name: "192.168.1.100 - Sonos One".to_string(),
+            room_name: "Kitchen".to_string(),
+        }];
+
+        db.save_devices(&devices).await.unwrap();
+        let cached = db.load_devices(Duration::from_secs(60)).await.unwrap();
+        assert_eq!(cached, devices);
+        assert_eq!(cached[0].room_name, "Kitchen");
+
+        // Entries written in the current second are not older than zero
+        // seconds ago, so a zero TTL always misses
+        let expired = db.load_devices(Duration::ZERO).await.unwrap();
+        assert!(expired.is_empty());
+        // Completion still offers the rooms of expired entries
+        assert_eq!(db.known_rooms().await.unwrap(), vec!["Kitchen".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_event_token_is_kept() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let token = db.event_token().await.unwrap();
+        assert_eq!(token.len(), 32);
+        assert_eq!(db.event_token().await.unwrap(), token);
+    }
+
+    #[tokio::test]
+    async fn test_old_interactions_are_pruned() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        sqlx::query("INSERT INTO interactions (device_name, room, kind, at) VALUES ('Kitchen', 'Kitchen', 'skip', ?)")
+            .bind(unix_now() - INTERACTION_RETENTION_SECS - 1)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        db.record_interaction("Kitchen", "Kitchen", Interaction::Volume).await.unwrap();
+        assert_eq!(db.interactions(0, i64::MAX).await.unwrap(), vec![("Kitchen: volume".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_failing_tag_lookups_are_given_up() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let failing = Scrobble::new("Kitchen", "Autechre", "Gantz Graf", 1_700_000_000);
+        let next = Scrobble::new("Kitchen", "Bonobo", "Kerala", 1_700_000_300);
+        db.record_scrobble(&failing).await.unwrap();
+        db.record_scrobble(&next).await.unwrap();
+        db.flush_pending().await.unwrap();
+
+        db.record_tag_failure(&failing).await.unwrap();
+        assert_eq!(db.untagged_scrobbles(1, 2).await.unwrap()[0].title, "Gantz Graf");
+        db.record_tag_failure(&failing).await.unwrap();
+        assert_eq!(db.untagged_scrobbles(1, 2).await.unwrap()[0].title, "Kerala");
+        // Given up, it doesn't count as tagged for later listens of the track
+        assert_eq!(db.known_tags("Autechre", "Gantz Graf").await.unwrap(), None);
+    }
 }
@@ -1,15 +1,413 @@
-use anyhow::Result;
-use log::info;
-use rusty_sonos::discovery::{discover_devices, BasicSpeakerInfo};
+use crate::cache::LruCache;
+use crate::config::{CacheConfig, DiscoveryConfig};
+use crate::error::{Error, Result};
+use crate::sonos::TrackDatabase;
+use log::{debug, info, warn};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use rusty_sonos::discovery::{discover_devices, get_speaker_info, BasicSpeakerInfo};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+/// Service type newer Sonos firmware advertises over mDNS
+const MDNS_SERVICE_TYPE: &str = "_sonos._tcp.local.";
+const MDNS_BROWSE_TIME: Duration = Duration::from_secs(2);
+/// How long a unicast probe waits for a device description
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Smallest prefix length accepted for subnet scans (a /16 is 65534 hosts)
+const MIN_SUBNET_PREFIX: u32 = 16;
+/// Port every Sonos device serves its UPnP description and services on
+const SONOS_PORT: u16 = 1400;
+/// How long a subnet scan waits for a TCP connection before moving on
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Files container runtimes create inside every container (Docker, Podman)
+const CONTAINER_MARKERS: &[&str] = &["/.dockerenv", "/run/.containerenv"];
+/// SSDP multicast group, used to find the interface discovery goes out on
+const SSDP_MULTICAST_ADDR: &str = "239.255.255.250:1900";
+
+/// Results of the last scan in this process, shared by every caller
+static DEVICE_CACHE: Mutex<Option<(Instant, Vec<BasicSpeakerInfo>)>> = Mutex::new(None);
+/// Friendly and room names from device descriptions by address, once
+/// [`SonosDiscovery::cache_descriptions`] was called
+static DESCRIPTION_CACHE: Mutex<Option<LruCache<Ipv4Addr, (String, String)>>> = Mutex::new(None);
+
+/// A discovered speaker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SonosDevice {
+    pub ip_addr: Ipv4Addr,
+    pub room: String,
+    /// Model name, e.g. "Sonos One"
+    pub model: String,
+    /// UPnP device id, e.g. "RINCON_000E58123456"
+    pub id: String,
+    /// Name the speaker reports, "IP - Model - RINCON_ID"; listens and
+    /// scrobbles are logged under it
+    pub friendly_name: String,
+}
+
+impl From<&BasicSpeakerInfo> for SonosDevice {
+    fn from(info: &BasicSpeakerInfo) -> Self {
+        let mut parts = info.friendly_name.split(" - ").skip(1);
+        let model = parts.next().unwrap_or_default().trim().to_string();
+        let id = parts.next().map(str::trim).unwrap_or(&info.friendly_name).to_string();
+        Self {
+            ip_addr: info.ip_addr,
+            room: info.room_name.clone(),
+            model,
+            id,
+            friendly_name: info.friendly_name.clone(),
+        }
+    }
+}
+
+impl SonosDevice {
+    /// Battery-powered speakers, which sleep when left alone
+    pub fn is_portable(&self) -> bool {
+        let model = self.model.to_lowercase();
+        model.contains("roam") || model.contains("move")
+    }
+}
+
+impl fmt::Display for SonosDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {}", self.friendly_name, self.room)
+    }
+}
 
 pub struct SonosDiscovery {
-    devices: Vec<BasicSpeakerInfo>,
+    pub(crate) devices: Vec<BasicSpeakerInfo>,
+}
+
+impl SonosDiscovery {
+    /// Discovers devices over SSDP and mDNS, merging both result sets. Fails
+    /// only when neither method could run.
+    pub async fn new() -> Result<Self> {
+        let (ssdp, mdns) = tokio::join!(
+            async { discover_devices(2, 5).await.map_err(Error::Discovery) },
+            discover_mdns_devices(MDNS_BROWSE_TIME),
+        );
+
+        let devices = match (ssdp, mdns) {
+            (Ok(ssdp), Ok(mdns)) => merge_devices(ssdp, mdns),
+            (Ok(devices), Err(e)) => {
+                warn!("mDNS discovery failed, using SSDP results only: {}", e);
+                devices
+            }
+            (Err(e), Ok(devices)) => {
+                warn!("SSDP discovery failed, using mDNS results only: {}", e);
+                devices
+            }
+            (Err(e), Err(_)) => return Err(e),
+        };
+        
+        Ok(Self { devices })
+    }
+
+    /// Finds devices the way `config` asks for: the configured addresses, a
+    /// subnet scan, or multicast discovery.
+    pub async fn scan(config: &DiscoveryConfig) -> Result<Self> {
+        let discovery = Self::find(config).await?;
+        *DEVICE_CACHE.lock().unwrap() = Some((Instant::now(), copy_devices(&discovery.devices)));
+        Ok(discovery)
+    }
+
+    /// Finds devices like [`Self::scan`] without caching them, for speakers
+    /// of other households
+    pub async fn find(config: &DiscoveryConfig) -> Result<Self> {
+        if !config.devices.is_empty() {
+            Self::from_addresses(&config.devices).await
+        } else if let Some(cidr) = &config.subnet {
+            Self::scan_subnet(cidr, config.scan_concurrency).await
+        } else {
+            Self::new().await
+        }
+    }
+
+    /// Keeps the device descriptions fetched by [`SonosDiscovery::probe`] in
+    /// memory as `config` says, so configured speakers and repeated
+    /// announcements aren't asked again every time
+    pub fn cache_descriptions(config: &CacheConfig) {
+        *DESCRIPTION_CACHE.lock().unwrap() = Some(LruCache::from_config(config));
+    }
+
+    /// Checks a single address for a Sonos device by fetching its device
+    /// description, without any multicast traffic.
+    pub async fn probe(ip_addr: Ipv4Addr) -> Result<BasicSpeakerInfo> {
+        let cached = DESCRIPTION_CACHE.lock().unwrap().as_mut().and_then(|cache| cache.get(&ip_addr));
+        if let Some((friendly_name, room_name)) = cached {
+            return Ok(BasicSpeakerInfo { ip_addr, friendly_name, room_name });
+        }
+        let ip = ip_addr.to_string();
+        let info = match tokio::time::timeout(PROBE_TIMEOUT, get_speaker_info(&ip)).await {
+            Ok(result) => result.map_err(|e| Error::Discovery(format!("{}: {}", ip, e)))?,
+            Err(_) => return Err(Error::Discovery(format!("{}: no response", ip))),
+        };
+        if let Some(cache) = DESCRIPTION_CACHE.lock().unwrap().as_mut() {
+            cache.insert(ip_addr, (info.friendly_name.clone(), info.room_name.clone()));
+        }
+        Ok(info)
+    }
+
+    /// Builds the device list from statically configured addresses.
+    /// Addresses that do not answer are logged and skipped.
+    pub async fn from_addresses(addresses: &[Ipv4Addr]) -> Result<Self> {
+        let devices = probe_all(addresses.to_vec()).await;
+        if devices.is_empty() {
+            return Err(Error::Discovery("none of the configured devices responded".to_string()));
+        }
+        Ok(Self { devices })
+    }
+
+    /// Scans an IPv4 CIDR range such as `192.168.1.0/24` for devices, for
+    /// networks that block multicast. Hosts are first checked for an open
+    /// port 1400, at most `concurrency` at a time, and only those are probed.
+    pub async fn scan_subnet(cidr: &str, concurrency: usize) -> Result<Self> {
+        let hosts = subnet_hosts(cidr)?;
+        info!("Scanning {} addresses in {}", hosts.len(), cidr);
+
+        let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+        let mut checks = tokio::task::JoinSet::new();
+        for addr in hosts {
+            let permits = permits.clone();
+            checks.spawn(async move {
+                let _permit = permits.acquire_owned().await.ok()?;
+                port_open(SocketAddr::from((addr, SONOS_PORT)), CONNECT_TIMEOUT)
+                    .await
+                    .then_some(addr)
+            });
+        }
+
+        let mut open = Vec::new();
+        while let Some(result) = checks.join_next().await {
+            if let Ok(Some(addr)) = result {
+                open.push(addr);
+            }
+        }
+
+        debug!("{} hosts in {} have port {} open", open.len(), cidr, SONOS_PORT);
+        Ok(Self { devices: probe_all(open).await })
+    }
+
+    /// Reuses discovery results younger than the configured TTL, looking in
+    /// memory first and then in the database, and only scans the network when
+    /// both are stale. Statically configured devices are never cached.
+    pub async fn cached(db: &TrackDatabase, config: &DiscoveryConfig) -> Result<Self> {
+        if !config.devices.is_empty() {
+            return Self::scan(config).await;
+        }
+
+        let ttl = Duration::from_secs(config.cache_ttl_secs);
+        if let Some((scanned_at, devices)) = DEVICE_CACHE.lock().unwrap().as_ref() {
+            if scanned_at.elapsed() < ttl && !devices.is_empty() {
+                debug!("Using {} devices from memory cache", devices.len());
+                return Ok(Self { devices: copy_devices(devices) });
+            }
+        }
+
+        let devices = db.load_devices(ttl).await?;
+        if !devices.is_empty() {
+            debug!("Using {} devices from database cache", devices.len());
+            *DEVICE_CACHE.lock().unwrap() = Some((Instant::now(), copy_devices(&devices)));
+            return Ok(Self { devices });
+        }
+
+        Self::refresh(db, config).await
+    }
+
+    /// Adds a device that announced itself at `ip_addr` to both caches, or
+    /// updates its address and room
+    pub async fn announced(db: &TrackDatabase, ip_addr: Ipv4Addr) -> Result<SonosDevice> {
+        let info = Self::probe(ip_addr).await?;
+        let device = SonosDevice::from(&info);
+        if let Some((_, devices)) = DEVICE_CACHE.lock().unwrap().as_mut() {
+            devices.retain(|cached| SonosDevice::from(cached).id != device.id);
+            devices.extend(copy_devices(std::slice::from_ref(&info)));
+        }
+        db.save_device(&info, &device.id).await?;
+        Ok(device)
+    }
+
+    /// Drops a device that announced it is leaving the network from both
+    /// caches
+    pub async fn departed(db: &TrackDatabase, device_id: &str) -> Result<()> {
+        if let Some((_, devices)) = DEVICE_CACHE.lock().unwrap().as_mut() {
+            devices.retain(|cached| SonosDevice::from(cached).id != device_id);
+        }
+        db.forget_device(device_id).await
+    }
+
+    /// Scans the network and replaces both caches with the results
+    pub async fn refresh(db: &TrackDatabase, config: &DiscoveryConfig) -> Result<Self> {
+        let discovery = Self::scan(config).await?;
+        db.save_devices(&discovery.devices).await?;
+        Ok(discovery)
+    }
+
+    pub async fn discover_devices(&self) -> Result<Vec<SonosDevice>> {
+        info!("Discovering Sonos devices...");
+        
+        let devices: Vec<SonosDevice> = self.devices.iter().map(SonosDevice::from).collect();
+
+        info!("Found {} Sonos devices", devices.len());
+        Ok(devices)
+    }
+}
+
+/// Browses for `_sonos._tcp` services and looks up each advertised address
+/// the same way SSDP responses are resolved.
+async fn discover_mdns_devices(browse_time: Duration) -> Result<Vec<BasicSpeakerInfo>> {
+    let daemon = ServiceDaemon::new().map_err(|e| Error::Discovery(e.to_string()))?;
+    let receiver = daemon
+        .browse(MDNS_SERVICE_TYPE)
+        .map_err(|e| Error::Discovery(e.to_string()))?;
+
+    let deadline = tokio::time::Instant::now() + browse_time;
+    let mut addresses: Vec<Ipv4Addr> = Vec::new();
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, receiver.recv_async()).await {
+        if let ServiceEvent::ServiceResolved(service) = event {
+            debug!("mDNS resolved {}", service.get_fullname());
+            for addr in service.get_addresses_v4() {
+                if !addresses.contains(&addr) {
+                    addresses.push(addr);
+                }
+            }
+        }
+    }
+
+    if let Err(e) = daemon.shutdown() {
+        debug!("Failed to shut down mDNS daemon: {}", e);
+    }
+
+    let mut devices = Vec::new();
+    for addr in addresses {
+        match get_speaker_info(&addr.to_string()).await {
+            Ok(device) => devices.push(device),
+            Err(e) => debug!("Ignoring mDNS result {}: {}", addr, e),
+        }
+    }
+
+    Ok(devices)
+}
+
+/// Explains an empty discovery result when running in a container.
+///
+/// On a bridge network (Docker's default) multicast never reaches the LAN, so
+/// discovery finds nothing without any error. Returns `None` outside containers.
+pub fn container_network_warning() -> Option<String> {
+    bridge_warning(in_container(), multicast_source_addr())
+}
+
+/// Whether this process runs inside a Docker or Podman container
+pub(crate) fn in_container() -> bool {
+    CONTAINER_MARKERS.iter().any(|marker| Path::new(marker).exists())
+}
+
+fn bridge_warning(in_container: bool, local_addr: Option<IpAddr>) -> Option<String> {
+    if !in_container {
+        return None;
+    }
+
+    let local = local_addr.map_or_else(|| "unknown".to_string(), |addr| addr.to_string());
+    Some(format!(
+        "No Sonos devices found from inside a container (local address {}). \
+         Multicast discovery does not cross container bridge networks: run the \
+         container with host networking (docker run --network host), or list the \
+         speakers in discovery.devices or set discovery.subnet in the config.",
+        local
+    ))
+}
+
+/// Local address the OS would send SSDP traffic from
+fn multicast_source_addr() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(SSDP_MULTICAST_ADDR).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+async fn port_open(addr: SocketAddr, timeout: Duration) -> bool {
+    matches!(tokio::time::timeout(timeout, TcpStream::connect(addr)).await, Ok(Ok(_)))
+}
+
+async fn probe_all(addresses: Vec<Ipv4Addr>) -> Vec<BasicSpeakerInfo> {
+    let mut probes = tokio::task::JoinSet::new();
+    for addr in addresses {
+        probes.spawn(SonosDiscovery::probe(addr));
+    }
+
+    let mut devices = Vec::new();
+    while let Some(result) = probes.join_next().await {
+        match result {
+            Ok(Ok(device)) => devices.push(device),
+            Ok(Err(e)) => debug!("Probe failed: {}", e),
+            Err(e) => warn!("Probe task failed: {}", e),
+        }
+    }
+
+    devices.sort_by_key(|d| d.ip_addr);
+    devices
+}
+
+/// Host addresses of an IPv4 CIDR range, excluding the network and broadcast
+/// addresses for prefixes shorter than /31
+fn subnet_hosts(cidr: &str) -> Result<Vec<Ipv4Addr>> {
+    let invalid = || Error::Discovery(format!("Invalid subnet: {}", cidr));
+
+    let (addr, prefix) = cidr.split_once('/').ok_or_else(invalid)?;
+    let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+    let prefix: u32 = prefix.parse().map_err(|_| invalid())?;
+    if !(MIN_SUBNET_PREFIX..=32).contains(&prefix) {
+        return Err(Error::Discovery(format!(
+            "Subnet {} is too large to scan, use a /{} or smaller",
+            cidr, MIN_SUBNET_PREFIX
+        )));
+    }
+
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    let network = u32::from(addr) & mask;
+    let broadcast = network | !mask;
+
+    let hosts = if prefix >= 31 {
+        network..=broadcast
+    } else {
+        network + 1..=broadcast - 1
+    };
+    Ok(hosts.map(Ipv4Addr::from).collect())
+}
+
+// BasicSpeakerInfo does not implement Clone
+fn copy_devices(devices: &[BasicSpeakerInfo]) -> Vec<BasicSpeakerInfo> {
+    devices
+        .iter()
+        .map(|d| BasicSpeakerInfo {
+            ip_addr: d.ip_addr,
+            friendly_name: d.friendly_name.clone(),
+            room_name: d.room_name.clone(),
+        })
+        .collect()
+}
+
+/// Keeps `devices` and adds those of `others` at new addresses. A speaker found
+/// by both may be named differently by each, e.g. mDNS uses its host name.
+fn merge_devices(
+    mut devices: Vec<BasicSpeakerInfo>,
+    others: Vec<BasicSpeakerInfo>,
+) -> Vec<BasicSpeakerInfo> {
+    for device in others {
+        if !devices.iter().any(|known| known.ip_addr == device.ip_addr) {
+            devices.push(device);
+        }
+    }
+    devices
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use mockall::predicate::*;
     
     #[tokio::test]
     async fn test_discovery_new() {
@@ -19,40 +417,74 @@ mod tests {
 
     #[tokio::test]
     async fn test_discover_devices_formats_correctly() {
-        let mut devices = Vec::new();
-        devices.push(BasicSpeakerInfo {
+        let devices = vec![BasicSpeakerInfo {
             ip_addr: "192.168.1.100".parse().unwrap(),
-            friendly_name: "Living Room".to_string(),
+            friendly_name: "192.168.1.100 - Sonos Play:1 - RINCON_123456".to_string(),
             room_name: "Living Room".to_string(),
-            device_type: "ZPS1".to_string(),
-        });
+        }];
 
         let discovery = SonosDiscovery { devices };
         let result = discovery.discover_devices().await.unwrap();
         
         assert_eq!(result.len(), 1);
-        assert_eq!(result[0], "Living Room, Living Room");
+        assert_eq!(result[0].to_string(), "192.168.1.100 - Sonos Play:1 - RINCON_123456, Living Room");
+        assert_eq!((result[0].model.as_str(), result[0].id.as_str()), ("Sonos Play:1", "RINCON_123456"));
     }
-}
 
-impl SonosDiscovery {
-    pub async fn new() -> Result<Self> {
-        let devices = discover_devices(2, 5)
-            .await
-            .map_err(|e| anyhow::anyhow!("{}", e))?;
-        
-        Ok(Self { devices })
+    #[test]
+    fn test_merge_devices_dedupes_by_ip() {
+        let device = |ip: &str, room: &str| BasicSpeakerInfo {
+            ip_addr: ip.parse().unwrap(),
+            friendly_name: format!("{} - Sonos One", ip),
+            room_name: room.to_string(),
+        };
+
+        let ssdp = vec![device("192.168.1.100", "Kitchen")];
+        let mut renamed = device("192.168.1.100", "Kitchen");
+        renamed.friendly_name = "Sonos-5CAAFD0A1B2C.local".to_string();
+        let mdns = vec![renamed, device("192.168.1.101", "Office")];
+
+        let merged = merge_devices(ssdp, mdns);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].friendly_name, "192.168.1.100 - Sonos One");
+        assert_eq!(merged[1].room_name, "Office");
     }
 
-    pub async fn discover_devices(&self) -> Result<Vec<String>> {
-        info!("Discovering Sonos devices...");
-        
-        let device_info: Vec<String> = self.devices
-            .iter()
-            .map(|device| format!("{}, {}", device.friendly_name, device.room_name))
-            .collect();
+    #[test]
+    fn test_subnet_hosts() {
+        let hosts = subnet_hosts("192.168.1.77/24").unwrap();
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 1, 254));
+
+        assert_eq!(subnet_hosts("10.0.0.5/32").unwrap(), vec![Ipv4Addr::new(10, 0, 0, 5)]);
+        assert!(subnet_hosts("10.0.0.0/8").is_err());
+        assert!(subnet_hosts("10.0.0.0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe_non_sonos_address() {
+        let result = SonosDiscovery::probe(Ipv4Addr::LOCALHOST).await;
+        assert!(matches!(result, Err(Error::Discovery(_))));
+    }
+
+    #[tokio::test]
+    async fn test_port_open() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(port_open(addr, CONNECT_TIMEOUT).await);
+
+        drop(listener);
+        assert!(!port_open(addr, CONNECT_TIMEOUT).await);
+    }
+
+    #[test]
+    fn test_bridge_warning_only_in_containers() {
+        let local = Some(IpAddr::V4(Ipv4Addr::new(172, 17, 0, 2)));
+        assert_eq!(bridge_warning(false, local), None);
 
-        info!("Found {} Sonos devices", device_info.len());
-        Ok(device_info)
+        let warning = bridge_warning(true, local).unwrap();
+        assert!(warning.contains("172.17.0.2"));
+        assert!(warning.contains("--network host"));
     }
 }
@@ -1,117 +1,865 @@
-use crate::sonos::TrackDatabase;
-use anyhow::Result;
-use log::info;
-use rusty_sonos::{
-    discovery::discover_devices,
-    speaker::Speaker,
-};
+use crate::error::{Error, Result};
+use crate::sonos::alarm::Alarm;
+use crate::sonos::album::AlbumDetector;
+use crate::sonos::art::art_url;
+use crate::sonos::budget::TaskBudget;
+use crate::sonos::client::SonosClient;
+use crate::sonos::firmware::{self, EventSupport};
+use crate::sonos::gena::{self, EventHub, Notification, Sequence, Subscription};
+use crate::sonos::library::Library;
+use crate::sonos::listen_budget::ListenBudget;
+use crate::sonos::sleep::SleepDetector;
+use crate::sonos::pipeline::{EventLog, PolledPosition, RawEvent};
+use crate::sonos::replay::CapturedEvent;
+use crate::sonos::session::{self, Interaction, ListenSession, PlayContext};
+use crate::sonos::soap::{PositionInfo, SoapClient, TransportState};
+use crate::config::{RetryConfig, ScrobbleOn, ThresholdConfig};
+use crate::retry::RetryPolicy;
+use crate::scrobble::{Scrobble, Scrobbler};
+use crate::sonos::{SonosDevice, TrackDatabase};
+use crate::status::Status;
+use chrono::{Local, TimeZone};
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
 use std::net::IpAddr;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, Notify};
+
+/// Default time between polls; events from the speaker trigger a poll right
+/// away
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Time without a poll after which the session is resynced, e.g. after the
+/// host was suspended or the speaker stopped answering for a while
+const SILENCE_THRESHOLD: Duration = Duration::from_secs(30);
+/// Time an on-demand speaker stays subscribed after playback stops
+const ON_DEMAND_IDLE: Duration = Duration::from_secs(10 * 60);
+/// How long the household's alarms are reused before listing them again
+const ALARM_REFRESH: Duration = Duration::from_secs(60 * 60);
+/// Time between checks whether a sleeping portable speaker woke up
+const SLEEP_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Polls between volume reads; changes are only noticed, not timed, so
+/// they needn't be read as often as the position
+const VOLUME_POLLS: u32 = 6;
 
 pub struct EventSubscriber {
-    speaker: Speaker,
+    soap: Arc<dyn SonosClient>,
+    ip_addr: IpAddr,
+    device_id: String,
     friendly_name: String,
+    room: String,
     db: TrackDatabase,
+    status: Arc<Status>,
+    scrobbler: Arc<Scrobbler>,
+    scrobble_on: ScrobbleOn,
+    threshold: ThresholdConfig,
+    /// Overrides of whether plays from a source were chosen by the listener
+    chosen_by_user: BTreeMap<String, bool>,
+    /// The alarms last listed, and when
+    alarms: Mutex<Option<(Instant, Vec<Alarm>)>>,
+    events: Option<EventSettings>,
+    /// For failed subscription renewals
+    subscription_retry: RetryPolicy,
+    /// Set when the speaker's software isn't known to send usable events
+    poll_only: AtomicBool,
+    /// Only subscribe to events while the speaker is playing
+    on_demand: bool,
+    /// A speaker that sleeps, so not answering is no error
+    portable: bool,
+    /// Notified when the speaker announces it is back from sleep
+    wake_up: Option<Arc<Notify>>,
+    /// Set to true when the daemon shuts down
+    shutdown: Option<watch::Receiver<bool>>,
+    budget: TaskBudget,
+    listen_budget: Arc<ListenBudget>,
+    sleep: Arc<SleepDetector>,
+    poll_interval: Duration,
+    albums: Mutex<AlbumDetector>,
+    /// Where the speaker's events and polled positions are logged
+    event_log: Option<Arc<EventLog>>,
+    /// To read the tags of music library files
+    library: Option<Arc<Library>>,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use mockall::predicate::*;
-    use std::str::FromStr;
+/// Where speakers send their events, and for how long to subscribe
+struct EventSettings {
+    hub: Arc<EventHub>,
+    /// Port the HTTP server receiving NOTIFY requests listens on
+    port: u16,
+    timeout: Duration,
+    /// Prefix length of the subnet the speaker shares with this host
+    prefix_len: u32,
+}
 
-    #[tokio::test]
-    async fn test_event_subscriber_new_valid_device() {
-        let device_name = "192.168.1.100 - Sonos Play:1 - RINCON_123456,Living Room";
-        let result = EventSubscriber::new(device_name).await;
-        assert!(result.is_ok());
+/// A live event subscription and the notifications it delivers
+struct Events {
+    subscription: Subscription,
+    notifications: mpsc::Receiver<Notification>,
+}
+
+impl EventSubscriber {
+    /// Polls `device`, sharing `db` with the other subscribers so plays of
+    /// grouped speakers are recognized as one even before they are committed.
+    /// Calls to the speaker and subscription renewals are retried per `retry`.
+    pub fn new(device: &SonosDevice, db: TrackDatabase, retry: &RetryConfig) -> Result<Self> {
+        let soap = SoapClient::new(&device.ip_addr.to_string())?.with_retry_policy(retry.device);
+        let mut subscriber = Self::from_client(Arc::new(soap), &device.id, &device.friendly_name, db);
+        subscriber.subscription_retry = retry.subscription;
+        subscriber.ip_addr = IpAddr::V4(device.ip_addr);
+        subscriber.room = device.room.clone();
+        Ok(subscriber)
     }
 
-    #[tokio::test]
-    async fn test_event_subscriber_new_invalid_device_name() {
-        let device_name = "Invalid Device Name";
-        let result = EventSubscriber::new(device_name).await;
-        assert!(result.is_err());
+    /// Polls the speaker behind `client` without looking it up first. Event
+    /// subscriptions need the speaker's address, which defaults to localhost.
+    pub fn from_client(client: Arc<dyn SonosClient>, device_id: &str, friendly_name: &str, db: TrackDatabase) -> Self {
+        Self {
+            soap: client,
+            ip_addr: IpAddr::from([127, 0, 0, 1]),
+            device_id: device_id.to_string(),
+            friendly_name: friendly_name.to_string(),
+            room: friendly_name.to_string(),
+            db,
+            status: Arc::new(Status::default()),
+            scrobbler: Arc::new(Scrobbler::default()),
+            scrobble_on: ScrobbleOn::default(),
+            threshold: ThresholdConfig::default(),
+            chosen_by_user: BTreeMap::new(),
+            alarms: Mutex::new(None),
+            events: None,
+            subscription_retry: RetryPolicy::SUBSCRIPTION,
+            poll_only: AtomicBool::new(false),
+            on_demand: false,
+            portable: false,
+            wake_up: None,
+            shutdown: None,
+            budget: TaskBudget::default(),
+            listen_budget: Arc::default(),
+            sleep: Arc::default(),
+            poll_interval: POLL_INTERVAL,
+            albums: Mutex::new(AlbumDetector::default()),
+            event_log: None,
+            library: None,
+        }
     }
 
-    #[tokio::test]
-    async fn test_parse_rincon_id() {
-        let device_name = "192.168.1.100 - Sonos Play:1 - RINCON_123456,Living Room";
-        let rincon_id = device_name
-            .split(" - ")
-            .nth(2)
-            .and_then(|s| s.split(',').next())
-            .unwrap();
-        assert_eq!(rincon_id, "RINCON_123456");
+    /// Reports listens and device health to a shared [`Status`]
+    pub fn with_status(mut self, status: Arc<Status>) -> Self {
+        self.status = status;
+        self
     }
-}
 
-impl EventSubscriber {
-    pub async fn new(device_name: &str) -> Result<Self> {
-        let devices = discover_devices(2, 5)
-            .await
-            .map_err(|e| anyhow::anyhow!("{}", e))?;
-        
-        // Extract the RINCON ID from the input string
-        // Format: "IP - Model Name - RINCON_ID, Room Name"
-        let rincon_id = device_name
-            .split(" - ")
-            .nth(2)
-            .and_then(|s| s.split(',').next())
-            .ok_or_else(|| anyhow::anyhow!("Invalid device name format: {}", device_name))?;
-
-        info!("Looking for device with RINCON ID: {}", rincon_id);
-        
-        let device = devices
-            .into_iter()
-            .inspect(|d| info!("Checking device: {}", d.friendly_name))
-            .find(|d| d.friendly_name.contains(rincon_id))
-            .ok_or_else(|| anyhow::anyhow!("Device not found: {}", device_name))?;
-
-        let ip_addr = IpAddr::V4(device.ip_addr);
-        let speaker = Speaker::new(&ip_addr.to_string()).await
-            .map_err(|e| anyhow::anyhow!("Failed to create speaker: {}", e))?;
-        
-        let db = TrackDatabase::new().await?;
-        
-        Ok(Self {
-            speaker,
-            friendly_name: device.friendly_name.clone(),
-            db,
-        })
+    /// Submits now-playing updates and scrobbles through `scrobbler`
+    pub fn with_scrobbler(mut self, scrobbler: Arc<Scrobbler>) -> Self {
+        scrobbler.add_device(&self.friendly_name, &self.room);
+        self.scrobbler = scrobbler;
+        self
+    }
+
+    pub fn with_scrobble_on(mut self, scrobble_on: ScrobbleOn) -> Self {
+        self.scrobble_on = scrobble_on;
+        self
+    }
+
+    /// How much of a listen has to be played before it is scrobbled, unless
+    /// the active profile says otherwise
+    pub fn with_threshold(mut self, threshold: ThresholdConfig) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Overrides per source, or "alarm", of whether its plays are reported
+    /// as chosen by the listener
+    pub fn with_chosen_by_user(mut self, chosen_by_user: BTreeMap<String, bool>) -> Self {
+        self.chosen_by_user = chosen_by_user;
+        self
+    }
+
+    /// Polls only when `budget` has room, which it shares with the other
+    /// subscribers
+    pub fn with_budget(mut self, budget: TaskBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Counts the room's daily play time against `listen_budget`, which it
+    /// shares with the other subscribers
+    pub fn with_listen_budget(mut self, listen_budget: Arc<ListenBudget>) -> Self {
+        self.listen_budget = listen_budget;
+        self
+    }
+
+    /// Stops scrobbling the room while `sleep`, which it shares with the
+    /// other subscribers, takes the listener to be asleep
+    pub fn with_sleep_detector(mut self, sleep: Arc<SleepDetector>) -> Self {
+        self.sleep = sleep;
+        self
+    }
+
+    /// Subscribes to the speaker's transport events for `timeout` at a time,
+    /// delivered through `hub` by the HTTP server listening on `port`, so
+    /// changes are picked up without waiting for the next poll. The speaker
+    /// is expected on this host's subnet with prefix length `prefix_len`.
+    pub fn with_events(mut self, hub: Arc<EventHub>, port: u16, timeout: Duration, prefix_len: u32) -> Self {
+        self.events = Some(EventSettings { hub, port, timeout, prefix_len });
+        self
+    }
+
+    /// Subscribes to events only once polling sees the speaker play, and
+    /// ends the subscription after it has been idle for a while. Saves
+    /// subscriptions on large systems with rarely used rooms.
+    pub fn with_on_demand(mut self, on_demand: bool) -> Self {
+        self.on_demand = on_demand;
+        self
+    }
+
+    /// Treats the speaker not answering as it being asleep, like battery
+    /// speakers do, and waits for it to wake up instead of giving up
+    pub fn with_portable(mut self, portable: bool) -> Self {
+        self.portable = portable;
+        self
+    }
+
+    /// Checks on the sleeping speaker as soon as `wake_up` is notified, so it
+    /// is resubscribed and resynced without waiting for the next check
+    pub fn with_wake_up(mut self, wake_up: Arc<Notify>) -> Self {
+        self.wake_up = Some(wake_up);
+        self
+    }
+
+    /// Polls this often when no events arrive
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Logs the speaker's events and polled positions to `event_log`
+    pub fn with_event_log(mut self, event_log: Arc<EventLog>) -> Self {
+        self.event_log = Some(event_log);
+        self
+    }
+
+    /// Takes the metadata of music library files from their tags where
+    /// `library` can reach them
+    pub fn with_library(mut self, library: Arc<Library>) -> Self {
+        self.library = Some(library);
+        self
+    }
+
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    /// Stops polling once `shutdown` turns true, first scrobbling the listen
+    /// in progress if it already reached the threshold
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
     }
 
     pub async fn poll_current_track(&self) -> Result<()> {
         info!("Starting track polling for device {}...", self.friendly_name);
-        
-        let mut last_track: Option<String> = None;
+        self.check_software_version().await;
+
+        let mut events = match self.on_demand {
+            true => None,
+            false => self.subscribe().await,
+        };
+        let mut session: Option<ListenSession> = None;
+        let mut last_poll = Instant::now();
+        let mut last_activity = Instant::now();
+        let mut missed_events = false;
+        let mut asleep = false;
+        // Last read while playing, to notice it being turned up or down, and
+        // polls since
+        let mut volume = None;
+        let mut volume_polls = 0;
+        // What was submitted last before a restart, to recognize the listen
+        // in progress then if it is still playing
+        let mut resumed = self.db.last_submitted(&self.friendly_name).await?;
         
         loop {
-            let current = self.speaker.get_current_track().await
-                .map_err(|e| anyhow::anyhow!("Failed to get current track: {}", e))?;
-            let track_info = match (current.artist, current.title) {
-                (Some(artist), Some(title)) => format!("{} - {}", artist, title),
-                (Some(artist), None) => artist,
-                (None, Some(title)) => title,
-                (None, None) => "Unknown Track".to_string(),
+            let permit = self.budget.acquire().await;
+            let position = match self.soap.get_position_info().await {
+                Ok(position) => position,
+                Err(e) if self.portable => {
+                    drop(permit);
+                    if !asleep {
+                        info!("{} stopped answering, it is probably asleep: {}", self.friendly_name, e);
+                        self.status.set_device_sleeping(&self.friendly_name);
+                        asleep = true;
+                        // Lapses while the speaker sleeps
+                        events = None;
+                    }
+                    tokio::select! {
+                        _ = tokio::time::sleep(SLEEP_POLL_INTERVAL) => continue,
+                        _ = woken(self.wake_up.clone()) => {
+                            info!("{} announced itself, checking on it", self.friendly_name);
+                            continue;
+                        }
+                        _ = shutdown_requested(self.shutdown.clone()) => {
+                            self.submit_held(true).await?;
+                            return Ok(());
+                        }
+                    }
+                }
+                Err(e) => {
+                    self.status.set_device_health(&self.friendly_name, false);
+                    return Err(e);
+                }
             };
+            self.status.set_device_health(&self.friendly_name, true);
+            if let Some(log) = &self.event_log {
+                log.record(RawEvent::Poll(PolledPosition::now(&self.friendly_name, &position))).await;
+            }
+            if asleep {
+                info!("{} woke up", self.friendly_name);
+                asleep = false;
+                if !self.on_demand {
+                    events = self.subscribe().await;
+                }
+            }
+            self.db.flush_due().await;
+            let elapsed = last_poll.elapsed();
+            let threshold = self.threshold();
+            last_poll = Instant::now();
             
-            if let Some(last) = &last_track {
-                if last != &track_info {
-                    if self.db.log_track(&self.friendly_name, &track_info).await? {
-                        info!("Track changed and logged on {}: {}", self.friendly_name, track_info);
-                        last_track = Some(track_info);
+            let mut playing = false;
+            match session.as_mut() {
+                Some(current) if current.continues_with(&position) => {
+                    let played = current.played;
+                    if missed_events || elapsed >= SILENCE_THRESHOLD {
+                        self.resync(current, &position, elapsed).await;
+                    } else {
+                        current.advance(&position, elapsed);
+                    }
+                    playing = current.played > played;
+                    if playing {
+                        self.listen_budget.add(&self.room, current.played - played);
+                    }
+                    // Not on track changes, which aren't interactions
+                    self.sleep.playing(&self.room, playing);
+                }
+                _ => {
+                    if let Some(mut finished) = session.take() {
+                        // After a gap the track may as well have ended unseen
+                        if finished.skipped() && !missed_events && elapsed < SILENCE_THRESHOLD {
+                            self.interaction(Interaction::Skip).await;
+                        }
+                        if self.scrobble_on == ScrobbleOn::TrackEnd && finished.meets_threshold(&threshold) {
+                            self.scrobble(&mut finished).await?;
+                        }
+                    }
+
+                    let mut next = ListenSession::from_position(&position);
+                    if let Some(library) = &self.library {
+                        library.tag(&mut next).await;
+                    }
+                    next.context = self.play_context(&position).await;
+                    self.scrobbler.party().device_grouped(&self.friendly_name, next.context.kind() == "group");
+                    if let Some((fingerprint, ends_at)) = resumed.take() {
+                        let scrobble = next.to_scrobble(&self.friendly_name);
+                        let same = scrobble.is_some_and(|s| self.scrobbler.prepare(&s).fingerprint() == fingerprint);
+                        if same && next.started_at < ends_at {
+                            info!("{} was scrobbled before the restart: {}", self.friendly_name, next.track_info);
+                            next.scrobbled = true;
+                        }
+                    }
+                    self.status.track_seen();
+                    if self.db.log_track(&self.friendly_name, &next.track_info, &next.context).await? {
+                        info!("New listen logged on {}: {}", self.friendly_name, next.track_info);
+                        self.status.track_logged();
+                    }
+                    if let Some(uri) = &position.album_art_uri {
+                        let url = art_url(self.ip_addr, uri);
+                        if let Err(e) = self.db.record_album_art(&self.friendly_name, next.started_at, &url).await {
+                            warn!("Failed to note the cover of {}: {}", next.track_info, e);
+                        }
+                    }
+                    if let Some(scrobble) = next.to_scrobble(&self.friendly_name) {
+                        self.scrobbler.now_playing(&scrobble).await;
                     }
+                    session = Some(next);
                 }
+            }
+            if !playing {
+                volume = None;
+                volume_polls = 0;
             } else {
-                if self.db.log_track(&self.friendly_name, &track_info).await? {
-                    info!("Initial track logged on {}: {}", self.friendly_name, track_info);
-                    last_track = Some(track_info);
+                if volume_polls % VOLUME_POLLS == 0 {
+                    let current = self.volume().await;
+                    if volume.is_some() && current.is_some() && volume != current {
+                        self.interaction(Interaction::Volume).await;
+                    }
+                    volume = current;
+                }
+                volume_polls += 1;
+            }
+
+            if self.scrobble_on == ScrobbleOn::Threshold {
+                if let Some(current) = session.as_mut().filter(|s| s.meets_threshold(&threshold)) {
+                    self.scrobble(current).await?;
+                }
+            }
+            self.submit_held(false).await?;
+            self.status.set_listening(&self.friendly_name, playing);
+
+            if self.on_demand {
+                if playing {
+                    last_activity = Instant::now();
                 }
+                self.subscribe_on_demand(&mut events, playing, last_activity.elapsed()).await;
             }
+            drop(permit);
             
-            tokio::time::sleep(Duration::from_secs(5)).await;
+            tokio::select! {
+                missed = self.wait(&mut events) => missed_events = missed,
+                _ = shutdown_requested(self.shutdown.clone()) => {
+                    // Otherwise a listen that played long enough is lost, as
+                    // with track_end scrobbling the track hasn't ended yet
+                    if let Some(mut current) = session.take().filter(|s| s.meets_threshold(&threshold)) {
+                        info!("Scrobbling the listen in progress on {} before shutting down", self.friendly_name);
+                        self.scrobble(&mut current).await?;
+                    }
+                    // The grace period is cut short rather than losing them
+                    self.submit_held(true).await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Reconciles `session` with the speaker's transport state after missed
+    /// events or a long silence, instead of trusting the position alone
+    async fn resync(&self, session: &mut ListenSession, position: &PositionInfo, elapsed: Duration) {
+        match self.soap.get_transport_info().await {
+            Ok(state) => {
+                info!("Resyncing {} after {}s ({:?})", self.friendly_name, elapsed.as_secs(), state);
+                session.resync(position, state, elapsed);
+            }
+            Err(e) => {
+                warn!("Failed to get transport info from {}: {}", self.friendly_name, e);
+                session.resync(position, TransportState::Stopped, elapsed);
+            }
+        }
+    }
+
+    /// Logs the speaker's software version and warns about versions whose
+    /// events differ from what is parsed here. Speakers that can't be asked
+    /// are assumed to be compatible.
+    async fn check_software_version(&self) {
+        let version = match self.soap.software_version().await {
+            Ok(version) => version,
+            Err(e) => {
+                debug!("Failed to read the software version of {}: {}", self.friendly_name, e);
+                return;
+            }
+        };
+        let (events, warning) = firmware::compatibility(version);
+        info!("{} runs software {}", self.friendly_name, version);
+        if let Some(warning) = warning {
+            warn!("{}: software {} {}", self.friendly_name, version, warning);
+        }
+        self.poll_only.store(events == EventSupport::PollOnly, Ordering::Relaxed);
+    }
+
+    /// Subscribes to transport events when an event hub is configured.
+    /// Polling continues either way, so failures only log a warning.
+    async fn subscribe(&self) -> Option<Events> {
+        let settings = self.events.as_ref()?;
+        if self.poll_only.load(Ordering::Relaxed) {
+            return None;
         }
+        let callback =
+            gena::callback_url(self.ip_addr, settings.port, settings.hub.token(), &self.device_id, settings.prefix_len);
+        let subscription = match callback {
+            Ok(callback) => {
+                let notifications = settings.hub.register(&self.device_id);
+                let base_url = format!("http://{}:1400", self.ip_addr);
+                Subscription::subscribe(&base_url, &callback, settings.timeout)
+                    .await
+                    .map(|subscription| Events {
+                        subscription: subscription.with_retry_policy(self.subscription_retry),
+                        notifications,
+                    })
+            }
+            Err(e) => Err(e),
+        };
+        match subscription {
+            Ok(events) => {
+                info!("Subscribed to events from {} ({})", self.friendly_name, events.subscription.sid());
+                Some(events)
+            }
+            Err(e) => {
+                warn!("Failed to subscribe to events from {}, polling only: {}", self.friendly_name, e);
+                None
+            }
+        }
+    }
+
+    /// Subscribes an on-demand speaker while it is `playing`, and
+    /// unsubscribes it once it has been `idle` for [`ON_DEMAND_IDLE`]
+    async fn subscribe_on_demand(&self, events: &mut Option<Events>, playing: bool, idle: Duration) {
+        match events.take() {
+            None if playing && self.events.is_some() => {
+                info!("{} started playing, subscribing to its events", self.friendly_name);
+                *events = self.subscribe().await;
+            }
+            Some(active) if idle >= ON_DEMAND_IDLE => {
+                info!("{} is idle, unsubscribing from its events", self.friendly_name);
+                if let Err(e) = active.subscription.unsubscribe().await {
+                    warn!("Failed to unsubscribe from {}: {}", self.friendly_name, e);
+                }
+            }
+            active => *events = active,
+        }
+    }
+
+    /// Sleeps until the next poll is due or an event arrives. Returns whether
+    /// a gap in the event sequence or a forgotten subscription showed that
+    /// transitions were missed, in which case the session is resynced from
+    /// the speaker's current state.
+    async fn wait(&self, events: &mut Option<Events>) -> bool {
+        let Some(active) = events.as_mut() else {
+            tokio::time::sleep(self.poll_interval).await;
+            return false;
+        };
+
+        if active.subscription.renewal_due() {
+            match active.subscription.renew().await {
+                Ok(()) => {}
+                Err(Error::SubscriptionExpired(_)) => {
+                    info!(
+                        "{} forgot its event subscription, probably after a restart; resubscribing",
+                        self.friendly_name
+                    );
+                    self.status.device_resubscribed(&self.friendly_name);
+                    *events = self.subscribe().await;
+                    // Whatever happened while it restarted went unreported
+                    return true;
+                }
+                Err(e) => {
+                    warn!("Failed to renew event subscription on {}, polling only: {}", self.friendly_name, e);
+                    *events = None;
+                    tokio::time::sleep(self.poll_interval).await;
+                    return false;
+                }
+            }
+        }
+
+        let next_poll = self.poll_interval.min(active.subscription.until_renewal());
+        tokio::select! {
+            _ = tokio::time::sleep(next_poll) => false,
+            Some(notification) = active.notifications.recv() => {
+                if let Some(log) = &self.event_log {
+                    let body = String::from_utf8_lossy(&notification.body);
+                    let event = CapturedEvent::now(&self.friendly_name, &notification.sid, notification.seq, &body);
+                    log.record(RawEvent::Notify(event)).await;
+                }
+                match active.subscription.observe(&notification) {
+                    Sequence::Gap(missed) => {
+                        warn!(
+                            "Missed {} events from {}, resyncing from its current state",
+                            missed, self.friendly_name
+                        );
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    /// The queue, playlist or station being played from. Missing context is
+    /// not worth losing the listen over, so failures only log a warning.
+    async fn play_context(&self, position: &PositionInfo) -> PlayContext {
+        match self.soap.get_media_info().await {
+            Ok(media) => PlayContext::new(&media, position),
+            Err(e) => {
+                warn!("Failed to get media info from {}: {}", self.friendly_name, e);
+                PlayContext::default()
+            }
+        }
+    }
+
+    /// The active profile's threshold, or the configured one
+    fn threshold(&self) -> ThresholdConfig {
+        self.scrobbler.profiles().threshold().unwrap_or_else(|| self.threshold.clone())
+    }
+
+    /// The speaker's volume, or `None` when it can't be read, which only
+    /// means changes go unnoticed
+    async fn volume(&self) -> Option<u16> {
+        match self.soap.get_volume().await {
+            Ok(volume) => Some(volume),
+            Err(e) => {
+                debug!("Failed to get the volume of {}: {}", self.friendly_name, e);
+                None
+            }
+        }
+    }
+
+    /// Notes that someone handled the room: it tells the sleep detector they
+    /// are awake and counts towards a party, and is stored for the stats
+    async fn interaction(&self, interaction: Interaction) {
+        debug!("{} was handled: {}", self.room, interaction.as_str());
+        self.sleep.interaction(&self.room);
+        self.scrobbler.party().interaction(&self.room);
+        if let Err(e) = self.db.record_interaction(&self.friendly_name, &self.room, interaction).await {
+            warn!("Failed to record an interaction with {}: {}", self.friendly_name, e);
+        }
+    }
+
+    /// Records and submits `session` unless it was already scrobbled
+    async fn scrobble(&self, session: &mut ListenSession) -> Result<()> {
+        if session.scrobbled {
+            return Ok(());
+        }
+        session.scrobbled = true;
+        if self.listen_budget.stops_scrobbling(&self.room) {
+            info!("Not scrobbling {} on {}, its listening budget is spent", session.track_info, self.friendly_name);
+            return Ok(());
+        }
+        if self.sleep.asleep(&self.room) {
+            info!("Not scrobbling {} on {}, the listener is probably asleep", session.track_info, self.friendly_name);
+            return Ok(());
+        }
+
+        let scrobble = session.to_scrobble(&self.friendly_name);
+        let Some(mut scrobble) = scrobble.filter(|scrobble| self.scrobbler.confident(scrobble)) else {
+            let listen = session.to_quarantined(&self.friendly_name);
+            info!("Quarantined listen on {} ({}): {}", self.friendly_name, listen.reason(), listen.track_info);
+            self.db.quarantine_listen(&listen).await?;
+            return Ok(());
+        };
+        let source = match self.started_by_alarm(session.started_at).await {
+            true => "alarm",
+            false => session.context.kind(),
+        };
+        scrobble.chosen_by_user =
+            self.chosen_by_user.get(source).copied().unwrap_or_else(|| session::chosen_by_user(source));
+        if self.scrobbler.hold(scrobble.clone(), session.context.queue_position) {
+            info!("Holding scrobble on {} for the grace period: {}", self.friendly_name, session.track_info);
+            return Ok(());
+        }
+        self.submit(&scrobble, session.context.queue_position).await
+    }
+
+    /// Whether a listen that started at `started_at` was started by one of
+    /// the speaker's alarms. Failing to list the alarms only logs a warning.
+    async fn started_by_alarm(&self, started_at: i64) -> bool {
+        let fresh = |alarms: &Option<(Instant, Vec<Alarm>)>| {
+            alarms.as_ref().filter(|(listed, _)| listed.elapsed() < ALARM_REFRESH).map(|(_, alarms)| alarms.clone())
+        };
+        let cached = fresh(&self.alarms.lock().unwrap());
+        let alarms = match cached {
+            Some(alarms) => alarms,
+            None => match self.soap.list_alarms().await {
+                Ok(alarms) => {
+                    *self.alarms.lock().unwrap() = Some((Instant::now(), alarms.clone()));
+                    alarms
+                }
+                Err(e) => {
+                    warn!("Failed to list alarms from {}: {}", self.friendly_name, e);
+                    return false;
+                }
+            },
+        };
+        let Some(started) = Local.timestamp_opt(started_at, 0).single() else {
+            return false;
+        };
+        alarms.iter().any(|alarm| alarm.started(&self.device_id, started.naive_local()))
+    }
+
+    /// Submits the held scrobbles whose grace period is over, or all of them
+    /// with `all`
+    async fn submit_held(&self, all: bool) -> Result<()> {
+        for (scrobble, queue_position) in self.scrobbler.take_held(&self.friendly_name, all) {
+            self.submit(&scrobble, queue_position).await?;
+        }
+        Ok(())
+    }
+
+    async fn submit(&self, scrobble: &Scrobble, queue_position: Option<u32>) -> Result<()> {
+        // Plays also scrobbled by a grouped speaker count for that one only
+        if !self.scrobbler.submit(&self.db, scrobble).await? {
+            return Ok(());
+        }
+        self.status.track_scrobbled();
+
+        let album = self
+            .albums
+            .lock()
+            .unwrap()
+            .track_played(scrobble, queue_position)
+            .cloned();
+        if let Some(album) = album {
+            if album.tracks == crate::sonos::MIN_ALBUM_TRACKS {
+                info!("Album listen on {}: {} - {}", self.friendly_name, album.artist, album.album);
+            }
+            self.db.record_album_listen(&album).await?;
+        }
+        Ok(())
+    }
+
+}
+
+/// Resolves once `wake_up` is notified, or never without one
+async fn woken(wake_up: Option<Arc<Notify>>) {
+    match wake_up {
+        Some(wake_up) => wake_up.notified().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once shutdown is requested, or never without a signal
+async fn shutdown_requested(shutdown: Option<watch::Receiver<bool>>) {
+    match shutdown {
+        Some(mut shutdown) => {
+            let _ = shutdown.wait_for(|&stop| stop).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "requires a Sonos speaker on the local network"]
+    async fn test_event_subscriber_new_valid_device() {
+        let device = SonosDevice {
+            ip_addr: "192.168.1.100".parse().unwrap(),
+            room: "Living Room".to_string(),
+            model: "Sonos Play:1".to_string(),
+            id: "RINCON_123456".to_string(),
+            friendly_name: "192.168.1.100 - Sonos Play:1 - RINCON_123456".to_string(),
+        };
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let subscriber = EventSubscriber::new(&device, db, &RetryConfig::default()).unwrap();
+        assert_eq!(subscriber.room(), "Living Room");
+    }
+
+    #[tokio::test]
+    async fn test_poll_scrobbles_with_mock_speaker() {
+        use crate::sonos::client::MockSonosClient;
+        use crate::sonos::firmware::SoftwareVersion;
+        use std::sync::atomic::AtomicU64;
+
+        let mut speaker = MockSonosClient::new();
+        let seconds = Arc::new(AtomicU64::new(0));
+        let polls = seconds.clone();
+        speaker.expect_get_position_info().returning(move || {
+            let position = seconds.fetch_add(1, Ordering::SeqCst);
+            Ok(PositionInfo {
+                track_uri: "x-sonos-spotify:track1".to_string(),
+                duration: "0:01:00".to_string(),
+                position: format!("0:{:02}:{:02}", position / 60, position % 60),
+                title: Some("Get Lucky".to_string()),
+                artist: Some("Daft Punk".to_string()),
+                ..Default::default()
+            })
+        });
+        // Turned up once, a few reads in
+        let volume_reads = Arc::new(AtomicU64::new(0));
+        let reads = volume_reads.clone();
+        speaker.expect_get_volume().returning(move || match reads.fetch_add(1, Ordering::SeqCst) {
+            0..=2 => Ok(20),
+            _ => Ok(25),
+        });
+        speaker.expect_get_media_info().returning(|| Ok(Default::default()));
+        speaker.expect_list_alarms().returning(|| Ok(Vec::new()));
+        speaker.expect_software_version().returning(|| Ok(SoftwareVersion::parse("79.1-56030").unwrap()));
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let (shutdown, shutdown_requested) = watch::channel(false);
+        let subscriber = EventSubscriber::from_client(Arc::new(speaker), "RINCON_1", "Kitchen", db.clone())
+            .with_poll_interval(Duration::from_millis(5))
+            .with_shutdown(shutdown_requested);
+        let polling = tokio::spawn(async move { subscriber.poll_current_track().await });
+
+        // Every poll plays a second, and half the track is 30 seconds
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        shutdown.send_replace(true);
+        polling.await.unwrap().unwrap();
+        db.flush_pending().await.unwrap();
+        assert!(db.last_submitted("Kitchen").await.unwrap().is_some());
+        assert_eq!(db.interactions(0, i64::MAX).await.unwrap(), vec![("Kitchen: volume".to_string(), 1)]);
+        // The volume is read every few polls only
+        let (polls, reads) = (polls.load(Ordering::SeqCst), volume_reads.load(Ordering::SeqCst));
+        assert!(reads > 3 && reads <= polls / u64::from(VOLUME_POLLS) + 1, "{} reads in {} polls", reads, polls);
     }
 
+    #[tokio::test]
+    async fn test_shutdown_mid_listen_with_mock_speaker() {
+        use crate::sonos::client::MockSonosClient;
+        use crate::sonos::firmware::SoftwareVersion;
+        use std::sync::atomic::AtomicU64;
+
+        // Plays `played` seconds of a two minute track, whose threshold is a
+        // minute, and shuts down while it is still on. Returns whether the
+        // listen was scrobbled.
+        async fn shut_down_after(played: u64) -> bool {
+            let mut speaker = MockSonosClient::new();
+            let seconds = AtomicU64::new(0);
+            speaker.expect_get_position_info().returning(move || {
+                let position = seconds.fetch_add(1, Ordering::SeqCst).min(played);
+                Ok(PositionInfo {
+                    track_uri: "x-sonos-spotify:track1".to_string(),
+                    duration: "0:02:00".to_string(),
+                    position: format!("0:{:02}:{:02}", position / 60, position % 60),
+                    title: Some("Get Lucky".to_string()),
+                    artist: Some("Daft Punk".to_string()),
+                    ..Default::default()
+                })
+            });
+            speaker.expect_get_volume().returning(|| Ok(20));
+            speaker.expect_get_media_info().returning(|| Ok(Default::default()));
+            speaker.expect_list_alarms().returning(|| Ok(Vec::new()));
+            speaker.expect_software_version().returning(|| Ok(SoftwareVersion::parse("79.1-56030").unwrap()));
+
+            let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+            let (shutdown, shutdown_requested) = watch::channel(false);
+            // So that only the shutdown can scrobble the listen
+            let subscriber = EventSubscriber::from_client(Arc::new(speaker), "RINCON_1", "Kitchen", db.clone())
+                .with_poll_interval(Duration::from_millis(5))
+                .with_scrobble_on(ScrobbleOn::TrackEnd)
+                .with_shutdown(shutdown_requested);
+            let polling = tokio::spawn(async move { subscriber.poll_current_track().await });
+
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            shutdown.send_replace(true);
+            polling.await.unwrap().unwrap();
+            db.flush_pending().await.unwrap();
+            db.last_submitted("Kitchen").await.unwrap().is_some()
+        }
+
+        assert!(shut_down_after(90).await);
+        assert!(!shut_down_after(30).await);
+    }
+
+    #[tokio::test]
+    async fn test_portable_speaker_sleeps() {
+        use crate::sonos::client::MockSonosClient;
+
+        let mut speaker = MockSonosClient::new();
+        speaker.expect_get_position_info().returning(|| Err(Error::Soap("connection refused".to_string())));
+        speaker.expect_software_version().returning(|| Err(Error::Soap("connection refused".to_string())));
+
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let status = Arc::new(Status::default());
+        let (shutdown, shutdown_requested) = watch::channel(false);
+        let subscriber = EventSubscriber::from_client(Arc::new(speaker), "RINCON_1", "Roam", db)
+            .with_status(status.clone())
+            .with_portable(true)
+            .with_shutdown(shutdown_requested);
+        let polling = tokio::spawn(async move { subscriber.poll_current_track().await });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(status.summary().ends_with("1 sleeping (Roam)"));
+        shutdown.send_replace(true);
+        polling.await.unwrap().unwrap();
+    }
 }
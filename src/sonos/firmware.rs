@@ -0,0 +1,94 @@
+use std::fmt;
+
+/// Newest major version the event handling is known to work with
+const LATEST_KNOWN_MAJOR: u32 = 87;
+
+/// A speaker's software version as its device description reports it, e.g.
+/// "79.1-56030". The marketing version shown in the app ("16.2") differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SoftwareVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub build: u32,
+}
+
+impl SoftwareVersion {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (version, build) = value.trim().split_once('-').unwrap_or((value.trim(), "0"));
+        let (major, minor) = version.split_once('.').unwrap_or((version, "0"));
+        Some(Self { major: major.parse().ok()?, minor: minor.parse().ok()?, build: build.parse().ok()? })
+    }
+}
+
+impl fmt::Display for SoftwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}-{}", self.major, self.minor, self.build)
+    }
+}
+
+/// Whether a speaker's events can be relied on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventSupport {
+    /// Subscribe and react to events as they arrive
+    Full,
+    /// Don't subscribe; only poll the speaker
+    PollOnly,
+}
+
+/// What is known about a range of major versions
+struct Compatibility {
+    /// First major version of the range
+    from: u32,
+    /// First major version after the range
+    until: u32,
+    events: EventSupport,
+    warning: Option<&'static str>,
+}
+
+/// Known software versions and how their events are handled, in order
+const COMPATIBILITY: &[Compatibility] = &[
+    Compatibility {
+        from: 0,
+        until: 29,
+        events: EventSupport::PollOnly,
+        warning: Some("predates the event format this version reads; polling it without event subscriptions"),
+    },
+    Compatibility { from: 29, until: LATEST_KNOWN_MAJOR + 1, events: EventSupport::Full, warning: None },
+    Compatibility {
+        from: LATEST_KNOWN_MAJOR + 1,
+        until: u32::MAX,
+        events: EventSupport::Full,
+        warning: Some(
+            "is newer than any version known here, and its events may have changed; \
+             please report listens that go missing or look wrong",
+        ),
+    },
+];
+
+/// How events from a speaker running `version` are handled, and what to
+/// warn about
+pub fn compatibility(version: SoftwareVersion) -> (EventSupport, Option<&'static str>) {
+    COMPATIBILITY
+        .iter()
+        .find(|known| (known.from..known.until).contains(&version.major))
+        .map_or((EventSupport::Full, None), |known| (known.events, known.warning))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compatibility() {
+        let version = SoftwareVersion::parse("79.1-56030").unwrap();
+        assert_eq!(version, SoftwareVersion { major: 79, minor: 1, build: 56030 });
+        assert_eq!(version.to_string(), "79.1-56030");
+        assert_eq!(compatibility(version), (EventSupport::Full, None));
+
+        let old = SoftwareVersion::parse("26.1").unwrap();
+        assert_eq!(compatibility(old).0, EventSupport::PollOnly);
+        let (events, warning) = compatibility(SoftwareVersion { major: LATEST_KNOWN_MAJOR + 1, minor: 0, build: 0 });
+        assert!(events == EventSupport::Full && warning.is_some());
+        assert_eq!(SoftwareVersion::parse("unknown"), None);
+    }
+}
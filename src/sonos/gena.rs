@@ -0,0 +1,439 @@
+use crate::error::{Error, Result};
+use crate::retry::RetryPolicy;
+use crate::sonos::discovery;
+use hyper::body::Bytes;
+use log::{info, warn};
+use reqwest::{Method, StatusCode};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const EVENT_ENDPOINT: &str = "/MediaRenderer/AVTransport/Event";
+/// Subscription lifetime requested when none is configured
+pub const DEFAULT_SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(300);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Notifications queued per speaker; beyond this they are dropped, which the
+/// subscriber notices as a sequence gap
+const NOTIFICATION_QUEUE: usize = 64;
+/// Prefix length of the subnet speakers are assumed to share with a callback
+/// they can reach when none is configured, as on nearly every home network
+pub const DEFAULT_SPEAKER_PREFIX_LEN: u32 = 24;
+
+/// A GENA NOTIFY request sent by a speaker
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub sid: String,
+    pub seq: u32,
+    /// The request body as received, shared rather than copied
+    pub body: Bytes,
+}
+
+/// How a notification's SEQ relates to the ones received before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sequence {
+    InOrder,
+    /// This many notifications were lost before this one
+    Gap(u32),
+    /// Repeated, reordered, or from another subscription
+    Stale,
+}
+
+/// Hands NOTIFY requests received by the HTTP server to the subscriber of the
+/// speaker they are addressed to.
+///
+/// Callback URLs are `/notify/<token>/<device id>`. Only speakers that were
+/// sent a subscription request know the token, so other hosts on the network
+/// can't inject events.
+pub struct EventHub {
+    token: String,
+    subscribers: Mutex<HashMap<String, mpsc::Sender<Notification>>>,
+}
+
+/// A hub with a random token, valid until the process exits
+impl Default for EventHub {
+    fn default() -> Self {
+        Self::new(new_token())
+    }
+}
+
+impl EventHub {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self { token: token.into(), subscribers: Mutex::default() }
+    }
+
+    /// The secret path segment of the callback URLs
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Notifications for `device_id` from now on, replacing any earlier
+    /// registration
+    pub fn register(&self, device_id: &str) -> mpsc::Receiver<Notification> {
+        let (sender, receiver) = mpsc::channel(NOTIFICATION_QUEUE);
+        self.subscribers.lock().unwrap().insert(device_id.to_string(), sender);
+        receiver
+    }
+
+    /// The notifications waiting to be handled, by device id
+    pub fn queued(&self) -> BTreeMap<String, usize> {
+        let subscribers = self.subscribers.lock().unwrap();
+        subscribers
+            .iter()
+            .filter(|(_, sender)| !sender.is_closed())
+            .map(|(device_id, sender)| (device_id.clone(), sender.max_capacity() - sender.capacity()))
+            .collect()
+    }
+
+    /// Returns false when nobody is subscribed to `device_id`
+    pub fn deliver(&self, device_id: &str, notification: Notification) -> bool {
+        let subscribers = self.subscribers.lock().unwrap();
+        let Some(sender) = subscribers.get(device_id) else {
+            return false;
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(notification) {
+            warn!("Event queue for {} is full, dropping a notification", device_id);
+        }
+        !sender.is_closed()
+    }
+}
+
+/// An AVTransport event subscription on one speaker
+pub struct Subscription {
+    client: reqwest::Client,
+    event_url: String,
+    sid: String,
+    /// Lifetime requested on every renewal
+    requested: Duration,
+    /// Lifetime the speaker granted, which may be shorter
+    timeout: Duration,
+    renewed_at: Instant,
+    next_seq: u32,
+    /// For renewals that fail
+    retry: RetryPolicy,
+}
+
+impl Subscription {
+    /// Subscribes the speaker at `base_url` to send events to `callback` for
+    /// `timeout`, or as long as the speaker grants
+    pub async fn subscribe(base_url: &str, callback: &str, timeout: Duration) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let event_url = format!("{}{}", base_url, EVENT_ENDPOINT);
+        let response = client
+            .request(gena_method("SUBSCRIBE"), &event_url)
+            .header("CALLBACK", format!("<{}>", callback))
+            .header("NT", "upnp:event")
+            .header("TIMEOUT", format_timeout(timeout))
+            .send()
+            .await
+            .map_err(|e| Error::Subscription(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(Error::Subscription(format!("SUBSCRIBE failed with {}", response.status())));
+        }
+
+        let sid = header(&response, "SID")
+            .ok_or_else(|| Error::Subscription("SUBSCRIBE response has no SID".to_string()))?;
+        let mut subscription = Self {
+            client,
+            event_url,
+            sid,
+            requested: timeout,
+            timeout,
+            renewed_at: Instant::now(),
+            next_seq: 0,
+            retry: RetryPolicy::SUBSCRIPTION,
+        };
+        subscription.negotiate(&response);
+        Ok(subscription)
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn sid(&self) -> &str {
+        &self.sid
+    }
+
+    /// Renewals happen halfway through the lifetime the speaker granted
+    pub fn until_renewal(&self) -> Duration {
+        (self.timeout / 2).saturating_sub(self.renewed_at.elapsed())
+    }
+
+    pub fn renewal_due(&self) -> bool {
+        self.until_renewal().is_zero()
+    }
+
+    /// Fails with [`Error::SubscriptionExpired`] when the speaker has
+    /// forgotten the subscription, which no retry will change
+    pub async fn renew(&mut self) -> Result<()> {
+        let response = self
+            .retry
+            .run(
+                || self.send_renewal(),
+                |e, attempt| {
+                    if let Error::SubscriptionExpired(_) = e {
+                        return false;
+                    }
+                    warn!("Renewing {} failed ({}), retrying ({}/{})", self.sid, e, attempt, self.retry.max_retries);
+                    true
+                },
+            )
+            .await?;
+
+        self.negotiate(&response);
+        self.renewed_at = Instant::now();
+        Ok(())
+    }
+
+    async fn send_renewal(&self) -> Result<reqwest::Response> {
+        let response = self
+            .client
+            .request(gena_method("SUBSCRIBE"), &self.event_url)
+            .header("SID", &self.sid)
+            .header("TIMEOUT", format_timeout(self.requested))
+            .send()
+            .await
+            .map_err(|e| Error::Subscription(e.to_string()))?;
+        if response.status() == StatusCode::PRECONDITION_FAILED {
+            return Err(Error::SubscriptionExpired(format!("speaker no longer knows {}", self.sid)));
+        }
+        if !response.status().is_success() {
+            return Err(Error::Subscription(format!("renewal failed with {}", response.status())));
+        }
+        Ok(response)
+    }
+
+    /// Ends the subscription, so the speaker stops sending events
+    pub async fn unsubscribe(self) -> Result<()> {
+        let response = self
+            .client
+            .request(gena_method("UNSUBSCRIBE"), &self.event_url)
+            .header("SID", &self.sid)
+            .send()
+            .await
+            .map_err(|e| Error::Subscription(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(Error::Subscription(format!("UNSUBSCRIBE failed with {}", response.status())));
+        }
+        Ok(())
+    }
+
+    /// Adopts the lifetime the speaker granted; some firmwares grant less
+    /// than was asked for
+    fn negotiate(&mut self, response: &reqwest::Response) {
+        let Some(granted) = header(response, "TIMEOUT").and_then(|t| parse_timeout(&t)) else {
+            return;
+        };
+        if granted < self.requested && granted != self.timeout {
+            info!(
+                "Speaker granted a {}s event subscription instead of {}s",
+                granted.as_secs(),
+                self.requested.as_secs()
+            );
+        }
+        self.timeout = granted;
+    }
+
+    /// Checks `notification` against the SEQ expected next. Speakers number
+    /// the events of a subscription from 0, wrapping around to 1.
+    pub fn observe(&mut self, notification: &Notification) -> Sequence {
+        if notification.sid != self.sid {
+            return Sequence::Stale;
+        }
+
+        let missed = notification.seq.wrapping_sub(self.next_seq);
+        if missed > u32::MAX / 2 {
+            return Sequence::Stale;
+        }
+        self.next_seq = match notification.seq {
+            u32::MAX => 1,
+            seq => seq + 1,
+        };
+        match missed {
+            0 => Sequence::InOrder,
+            missed => Sequence::Gap(missed),
+        }
+    }
+}
+
+/// 128 random bits in hex, for [`EventHub`] tokens
+pub fn new_token() -> String {
+    rand::random::<[u8; 16]>().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The URL a speaker can reach the HTTP server on: the local address of the
+/// interface that routes to `speaker`. Outside the speaker's subnet, whose
+/// prefix length is `prefix_len`, it only warns, as the speaker may well be
+/// routed to it; from a container network it fails, so that the speaker is
+/// polled instead.
+pub fn callback_url(speaker: IpAddr, port: u16, token: &str, device_id: &str, prefix_len: u32) -> Result<String> {
+    let local = UdpSocket::bind(("0.0.0.0", 0))
+        .and_then(|socket| {
+            socket.connect((speaker, 1400))?;
+            socket.local_addr()
+        })
+        .map_err(|e| Error::Subscription(format!("no route to {}: {}", speaker, e)))?;
+    let in_container = discovery::in_container();
+    if let Some(reason) = unreachable_callback(in_container, local.ip(), speaker, prefix_len) {
+        if in_container {
+            return Err(Error::Subscription(reason));
+        }
+        warn!("{}; its events may not arrive, set http.speaker_prefix_len if the subnet is larger", reason);
+    }
+    Ok(format!("http://{}/notify/{}/{}", SocketAddr::new(local.ip(), port), token, device_id))
+}
+
+/// Why `speaker` can't be expected to reach a callback on `local`, if it
+/// can't. Outside the speaker's subnet its events would have to cross a NAT
+/// or router, as they do from a container on a bridge network.
+fn unreachable_callback(in_container: bool, local: IpAddr, speaker: IpAddr, prefix_len: u32) -> Option<String> {
+    let same_subnet = match (local, speaker) {
+        (IpAddr::V4(local), IpAddr::V4(speaker)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len.min(32)).unwrap_or(0);
+            u32::from(local) & mask == u32::from(speaker) & mask
+        }
+        // Speakers only use IPv4, so this is a test setup
+        _ => true,
+    };
+    match (same_subnet, in_container) {
+        (true, _) => None,
+        (false, true) => Some(format!(
+            "local address {} is on a container network {} can't reach; run the container with host \
+             networking (docker run --network host) to receive events",
+            local, speaker
+        )),
+        (false, false) => Some(format!("local address {} is not on the /{} subnet of {}", local, prefix_len, speaker)),
+    }
+}
+
+fn gena_method(name: &str) -> Method {
+    Method::from_bytes(name.as_bytes()).expect("valid method name")
+}
+
+fn header(response: &reqwest::Response, name: &str) -> Option<String> {
+    response.headers().get(name)?.to_str().ok().map(str::to_string)
+}
+
+fn format_timeout(timeout: Duration) -> String {
+    format!("Second-{}", timeout.as_secs())
+}
+
+/// `Second-300` as returned in the TIMEOUT header
+fn parse_timeout(value: &str) -> Option<Duration> {
+    let secs = value.trim().strip_prefix("Second-")?.parse().ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(sid: &str, seq: u32) -> Notification {
+        Notification { sid: sid.to_string(), seq, body: Bytes::new() }
+    }
+
+    #[test]
+    fn test_unreachable_callback_falls_back_to_polling() {
+        let speaker: IpAddr = "192.168.1.20".parse().unwrap();
+        let host: IpAddr = "192.168.1.5".parse().unwrap();
+        let bridge: IpAddr = "172.17.0.2".parse().unwrap();
+        assert_eq!(unreachable_callback(false, host, speaker, 24), None);
+        // With host networking a container has the host's address
+        assert_eq!(unreachable_callback(true, host, speaker, 24), None);
+        assert!(unreachable_callback(true, bridge, speaker, 24).unwrap().contains("--network host"));
+        let vpn: IpAddr = "10.8.0.3".parse().unwrap();
+        assert_eq!(
+            unreachable_callback(false, vpn, speaker, 24).as_deref(),
+            Some("local address 10.8.0.3 is not on the /24 subnet of 192.168.1.20")
+        );
+        // On a larger network
+        let other: IpAddr = "192.168.7.5".parse().unwrap();
+        assert!(unreachable_callback(false, other, speaker, 24).is_some());
+        assert_eq!(unreachable_callback(false, other, speaker, 16), None);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_and_detect_gaps() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("SUBSCRIBE", EVENT_ENDPOINT)
+            .match_header("CALLBACK", "<http://10.0.0.2:8080/notify/RINCON_1>")
+            .match_header("TIMEOUT", "Second-600")
+            .with_header("SID", "uuid:sub-1")
+            .with_header("TIMEOUT", "Second-240")
+            .create_async()
+            .await;
+
+        let callback = "http://10.0.0.2:8080/notify/RINCON_1";
+        let mut subscription = Subscription::subscribe(&server.url(), callback, Duration::from_secs(600))
+            .await
+            .unwrap();
+        mock.assert_async().await;
+        assert_eq!(subscription.sid(), "uuid:sub-1");
+        // The speaker granted less than requested, so renewal comes earlier
+        assert_eq!(subscription.timeout, Duration::from_secs(240));
+        assert!(subscription.until_renewal() <= Duration::from_secs(120));
+        assert!(!subscription.renewal_due());
+
+        assert_eq!(subscription.observe(&notification("uuid:sub-1", 0)), Sequence::InOrder);
+        assert_eq!(subscription.observe(&notification("uuid:sub-1", 1)), Sequence::InOrder);
+        assert_eq!(subscription.observe(&notification("uuid:sub-1", 4)), Sequence::Gap(2));
+        assert_eq!(subscription.observe(&notification("uuid:sub-1", 3)), Sequence::Stale);
+        assert_eq!(subscription.observe(&notification("uuid:old", 5)), Sequence::Stale);
+
+        subscription.next_seq = u32::MAX;
+        assert_eq!(subscription.observe(&notification("uuid:sub-1", u32::MAX)), Sequence::InOrder);
+        assert_eq!(subscription.observe(&notification("uuid:sub-1", 1)), Sequence::InOrder);
+
+        let unsubscribe = server
+            .mock("UNSUBSCRIBE", EVENT_ENDPOINT)
+            .match_header("SID", "uuid:sub-1")
+            .create_async()
+            .await;
+        subscription.unsubscribe().await.unwrap();
+        unsubscribe.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_renewal_of_forgotten_subscription_expires() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("SUBSCRIBE", EVENT_ENDPOINT)
+            .match_header("CALLBACK", mockito::Matcher::Any)
+            .with_header("SID", "uuid:sub-1")
+            .create_async()
+            .await;
+        let renewal = server
+            .mock("SUBSCRIBE", EVENT_ENDPOINT)
+            .match_header("SID", "uuid:sub-1")
+            .with_status(412)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let callback = "http://10.0.0.2:8080/notify/RINCON_1";
+        let mut subscription = Subscription::subscribe(&server.url(), callback, Duration::from_secs(600))
+            .await
+            .unwrap()
+            .with_retry_policy(RetryPolicy { delay_ms: 1, ..RetryPolicy::SUBSCRIPTION });
+        assert!(matches!(subscription.renew().await, Err(Error::SubscriptionExpired(_))));
+        // Not retried
+        renewal.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_event_hub_delivers_to_registered_device() {
+        let hub = EventHub::default();
+        assert!(!hub.deliver("RINCON_1", notification("uuid:sub-1", 0)));
+
+        let mut notifications = hub.register("RINCON_1");
+        assert!(hub.deliver("RINCON_1", notification("uuid:sub-1", 0)));
+        assert!(hub.deliver("RINCON_1", notification("uuid:sub-1", 1)));
+        assert_eq!(hub.queued(), BTreeMap::from([("RINCON_1".to_string(), 2)]));
+        assert_eq!(notifications.recv().await, Some(notification("uuid:sub-1", 0)));
+        assert_eq!(hub.queued()["RINCON_1"], 1);
+    }
+}
@@ -0,0 +1,377 @@
+use crate::config::LibraryConfig;
+use crate::scrobble::{Confidence, MetadataSource};
+use crate::sonos::session::ListenSession;
+use log::debug;
+use percent_encoding::percent_decode_str;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+/// URI scheme Sonos plays music library files on SMB shares with
+const CIFS_SCHEME: &str = "x-file-cifs:";
+/// Largest ID3v2 tag read; tags with big cover art are cut off, but the
+/// text frames usually come first
+const MAX_ID3_SIZE: usize = 1 << 20;
+/// How much of an Ogg file is searched for its comment header
+const MAX_OGG_HEADER: u64 = 1 << 16;
+/// How long reading a file's tags may take before the speaker's metadata is
+/// used instead, e.g. when the share hangs
+const TAG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The UNC-style path of a music library file played from a NAS share, e.g.
+/// `//nas/Music/Air/Moon Safari/01 La femme d'argent.mp3` for
+/// `x-file-cifs://nas/Music/Air/Moon%20Safari/01%20La%20femme%20d'argent.mp3`.
+/// `None` for anything but library files.
+pub fn share_path(uri: &str) -> Option<String> {
+    let path = uri.strip_prefix(CIFS_SCHEME)?.strip_prefix("//")?;
+    let path = path.split(['?', '#']).next()?;
+    let (host, rest) = path.split_once('/')?;
+    if host.is_empty() || rest.is_empty() {
+        return None;
+    }
+    Some(format!("//{}/{}", host, percent_decode_str(rest).decode_utf8_lossy()))
+}
+
+/// Metadata embedded in a music file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileTags {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    /// MusicBrainz recording id
+    pub musicbrainz_id: Option<String>,
+}
+
+impl FileTags {
+    fn set(&mut self, key: &str, value: &str) {
+        let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        if value.is_empty() {
+            return;
+        }
+        let field = match key.to_ascii_uppercase().as_str() {
+            "ARTIST" | "TPE1" => &mut self.artist,
+            "TITLE" | "TIT2" => &mut self.title,
+            "ALBUM" | "TALB" => &mut self.album,
+            "ALBUMARTIST" | "ALBUM ARTIST" | "TPE2" => &mut self.album_artist,
+            "MUSICBRAINZ_TRACKID" => &mut self.musicbrainz_id,
+            _ => return,
+        };
+        // Of several values, the first
+        field.get_or_insert_with(|| value.split('\0').next().unwrap_or_default().to_string());
+    }
+}
+
+/// The music library as mounted on this host, to read the tags of the files
+/// speakers play from it
+#[derive(Debug, Clone, Default)]
+pub struct Library {
+    /// Share paths like `//nas/Music` and where they are mounted, longest
+    /// first
+    mounts: Vec<(String, PathBuf)>,
+}
+
+impl Library {
+    pub fn new(config: &LibraryConfig) -> Self {
+        let mut mounts: Vec<_> = config
+            .mounts
+            .iter()
+            .map(|(share, mount)| (share.trim_end_matches('/').to_lowercase(), mount.clone()))
+            .collect();
+        mounts.sort_by_key(|(share, _)| std::cmp::Reverse(share.len()));
+        Self { mounts }
+    }
+
+    /// Where the file at `uri` is on this host; `None` when it isn't a
+    /// library file, its share isn't mounted or its path would leave the
+    /// mount point
+    pub fn local_path(&self, uri: &str) -> Option<PathBuf> {
+        let path = share_path(uri)?;
+        // Share and host names are case-insensitive
+        self.mounts.iter().find_map(|(share, mount)| {
+            let head = path.get(..share.len()).filter(|head| head.eq_ignore_ascii_case(share))?;
+            let rest = Path::new(path[head.len()..].strip_prefix('/')?);
+            rest.components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+                .then(|| mount.join(rest))
+        })
+    }
+
+    /// Replaces the artist, title and album of `session` with the tags of
+    /// the file it plays, when it is a library file on a mounted share.
+    /// Returns whether it did.
+    pub async fn tag(&self, session: &mut ListenSession) -> bool {
+        let Some(path) = self.local_path(&session.uri) else {
+            return false;
+        };
+        let reading = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || read_tags(&path)
+        });
+        let tags = match tokio::time::timeout(TAG_TIMEOUT, reading).await {
+            Ok(Ok(Ok(Some(tags)))) => tags,
+            Ok(Ok(Ok(None))) => return false,
+            Ok(Ok(Err(e))) => {
+                debug!("Failed to read the tags of {}: {}", path.display(), e);
+                return false;
+            }
+            Ok(Err(e)) => {
+                debug!("Tag reading for {} failed: {}", path.display(), e);
+                return false;
+            }
+            Err(_) => {
+                debug!("Reading the tags of {} timed out", path.display());
+                return false;
+            }
+        };
+        let (Some(artist), Some(title)) = (tags.artist, tags.title) else {
+            return false;
+        };
+        debug!("Tags of {}: {} - {}, {:?}", path.display(), artist, title, tags.album);
+        session.artist = Some(artist);
+        session.title = Some(title);
+        session.album = tags.album.or(session.album.take());
+        session.confidence = Confidence::from_source(MetadataSource::FileTags);
+        true
+    }
+}
+
+/// The ID3v2, FLAC, Ogg Vorbis or Opus tags of the file at `path`; `None`
+/// for other formats
+pub fn read_tags(path: &Path) -> io::Result<Option<FileTags>> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    match &magic {
+        [b'I', b'D', b'3', _] => read_id3(&mut file),
+        b"fLaC" => read_flac(&mut file),
+        b"OggS" => read_ogg(&mut file),
+        _ => Ok(None),
+    }
+}
+
+fn read_id3(file: &mut impl Read) -> io::Result<Option<FileTags>> {
+    let mut header = [0u8; 10];
+    file.read_exact(&mut header)?;
+    let version = header[3];
+    if !(3..=4).contains(&version) {
+        return Ok(None);
+    }
+    let size = (syncsafe(&header[6..10]) as usize).min(MAX_ID3_SIZE);
+    let mut tag = Vec::with_capacity(size);
+    file.take(size as u64).read_to_end(&mut tag)?;
+
+    let mut at = 0;
+    if header[5] & 0x40 != 0 {
+        // The extended header's size counts itself in v2.4 only
+        let size = tag.get(..4).map_or(0, |size| match version {
+            4 => syncsafe(size) as usize,
+            _ => 4 + u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize,
+        });
+        at = size;
+    }
+    let mut tags = FileTags::default();
+    while let Some(frame) = tag.get(at..at + 10) {
+        if frame[0] == 0 {
+            break;
+        }
+        let id = String::from_utf8_lossy(&frame[..4]).into_owned();
+        let size = match version {
+            4 => syncsafe(&frame[4..8]),
+            _ => u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]),
+        } as usize;
+        let Some(body) = tag.get(at + 10..at + 10 + size) else {
+            break;
+        };
+        match id.as_str() {
+            "UFID" => {
+                if let Some((owner, id)) = split_at_nul(body) {
+                    if owner == b"http://musicbrainz.org" {
+                        tags.musicbrainz_id = Some(String::from_utf8_lossy(id).into_owned());
+                    }
+                }
+            }
+            _ if id.starts_with('T') => tags.set(&id, &id3_text(body)),
+            _ => {}
+        }
+        at += 10 + size;
+    }
+    Ok(Some(tags))
+}
+
+/// 28 bits spread over four bytes, seven each
+fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |value, byte| (value << 7) | u32::from(byte & 0x7f))
+}
+
+fn split_at_nul(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let nul = bytes.iter().position(|byte| *byte == 0)?;
+    Some((&bytes[..nul], &bytes[nul + 1..]))
+}
+
+/// A text frame's value, in the encoding its first byte names
+fn id3_text(body: &[u8]) -> String {
+    let Some((&encoding, text)) = body.split_first() else {
+        return String::new();
+    };
+    match encoding {
+        0 => text.iter().map(|&byte| char::from(byte)).collect(),
+        1 | 2 => {
+            let big_endian = encoding == 2 || text.starts_with(&[0xfe, 0xff]);
+            let text = match text.starts_with(&[0xff, 0xfe]) || text.starts_with(&[0xfe, 0xff]) {
+                true => &text[2..],
+                false => text,
+            };
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|unit| match big_endian {
+                    true => u16::from_be_bytes([unit[0], unit[1]]),
+                    false => u16::from_le_bytes([unit[0], unit[1]]),
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    }
+}
+
+fn read_flac(file: &mut (impl Read + Seek)) -> io::Result<Option<FileTags>> {
+    file.seek(SeekFrom::Start(4))?;
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+        if header[0] & 0x7f == 4 {
+            let mut block = Vec::with_capacity(size as usize);
+            file.take(u64::from(size)).read_to_end(&mut block)?;
+            return Ok(vorbis_comments(&block));
+        }
+        if header[0] & 0x80 != 0 {
+            return Ok(Some(FileTags::default()));
+        }
+        file.seek(SeekFrom::Current(i64::from(size)))?;
+    }
+}
+
+/// Finds the comment header in the first pages of an Ogg Vorbis or Opus file
+fn read_ogg(file: &mut impl Read) -> io::Result<Option<FileTags>> {
+    let mut data = Vec::new();
+    file.take(MAX_OGG_HEADER).read_to_end(&mut data)?;
+    // The packets without the page headers between them
+    let mut packets = Vec::new();
+    let mut at = 0;
+    while data.get(at..at + 4) == Some(b"OggS") {
+        let Some(&segments) = data.get(at + 26) else {
+            break;
+        };
+        let table = at + 27;
+        let Some(lengths) = data.get(table..table + usize::from(segments)) else {
+            break;
+        };
+        let start = table + usize::from(segments);
+        let end = start + lengths.iter().map(|&length| usize::from(length)).sum::<usize>();
+        packets.extend_from_slice(&data[start..end.min(data.len())]);
+        at = end;
+    }
+    for magic in [&b"\x03vorbis"[..], &b"OpusTags"[..]] {
+        if let Some(start) = packets.windows(magic.len()).position(|window| window == magic) {
+            return Ok(vorbis_comments(&packets[start + magic.len()..]));
+        }
+    }
+    Ok(None)
+}
+
+/// A Vorbis comment block: the vendor, then `KEY=value` pairs, each after
+/// its little-endian length
+fn vorbis_comments(block: &[u8]) -> Option<FileTags> {
+    let (vendor, at) = u32_at(block, 0)?;
+    let (count, mut at) = u32_at(block, at + vendor)?;
+    let mut tags = FileTags::default();
+    for _ in 0..count {
+        let Some((length, start)) = u32_at(block, at) else {
+            break;
+        };
+        let Some(comment) = block.get(start..start + length) else {
+            break;
+        };
+        at = start + length;
+        if let Some((key, value)) = String::from_utf8_lossy(comment).split_once('=') {
+            tags.set(key, value);
+        }
+    }
+    Some(tags)
+}
+
+/// The little-endian number at `at`, and where what follows it starts
+fn u32_at(bytes: &[u8], at: usize) -> Option<(usize, usize)> {
+    let value = u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?);
+    Some((value as usize, at + 4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_share_path() {
+        let uri = "x-file-cifs://nas/Music/Air/Moon%20Safari/01%20La%20femme%20d'argent.mp3";
+        assert_eq!(share_path(uri).unwrap(), "//nas/Music/Air/Moon Safari/01 La femme d'argent.mp3");
+        assert_eq!(share_path("x-file-cifs://192.168.1.5/m/%C3%89t%C3%A9.flac").unwrap(), "//192.168.1.5/m/Été.flac");
+
+        assert_eq!(share_path("x-sonos-spotify:spotify%3atrack%3a123"), None);
+        assert_eq!(share_path("x-file-cifs://nas"), None);
+
+        let mounts = BTreeMap::from([("//NAS/Music/".to_string(), PathBuf::from("/mnt/music"))]);
+        let library = Library::new(&LibraryConfig { mounts });
+        assert_eq!(library.local_path(uri), Some(PathBuf::from("/mnt/music/Air/Moon Safari/01 La femme d'argent.mp3")));
+        assert_eq!(library.local_path("x-file-cifs://nas/Musicals/Cats.mp3"), None);
+        assert_eq!(library.local_path("x-file-cifs://nas/Music/../../etc/passwd"), None);
+        assert_eq!(library.local_path("x-file-cifs://nas/Music/Air/%2E%2E/%2E%2E/etc/passwd"), None);
+        assert_eq!(library.local_path("x-file-cifs://nas/Music//etc/passwd"), None);
+    }
+
+    fn write(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("sonos-scrobbler-{}-{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_read_id3_and_flac_tags() {
+        let frame = |id: &[u8], body: &[u8]| [id, &(body.len() as u32).to_be_bytes(), &[0, 0], body].concat();
+        let frames = [
+            frame(b"TPE1", b"\x03Daft Punk"),
+            frame(b"TIT2", b"\x01\xff\xfeG\0e\0t\0 \0L\0u\0c\0k\0y\0"),
+            frame(b"TALB", b"\x00Random Access Memories"),
+            frame(b"UFID", b"http://musicbrainz.org\x0012345"),
+        ]
+        .concat();
+        let size = frames.len() as u32;
+        let syncsafe = [21, 14, 7, 0].map(|shift| (size >> shift) as u8 & 0x7f);
+        let mp3 = write("tags.mp3", &[&b"ID3\x03\x00\x00"[..], &syncsafe, &frames, b"\xff\xfb audio"].concat());
+        let tags = read_tags(&mp3).unwrap().unwrap();
+        std::fs::remove_file(mp3).unwrap();
+        assert_eq!(tags.artist.as_deref(), Some("Daft Punk"));
+        assert_eq!(tags.title.as_deref(), Some("Get Lucky"));
+        assert_eq!(tags.album.as_deref(), Some("Random Access Memories"));
+        assert_eq!(tags.musicbrainz_id.as_deref(), Some("12345"));
+
+        let comment = |text: &str| [&(text.len() as u32).to_le_bytes()[..], text.as_bytes()].concat();
+        let comments = [
+            comment("reference libFLAC"),
+            2u32.to_le_bytes().to_vec(),
+            comment("ARTIST=Air"),
+            comment("album=Moon Safari"),
+        ]
+        .concat();
+        let block = |kind: u8, body: &[u8]| [&[kind], &(body.len() as u32).to_be_bytes()[1..], body].concat();
+        let flac = write("tags.flac", &[&b"fLaC"[..], &block(0, &[0; 34]), &block(0x84, &comments)].concat());
+        let tags = read_tags(&flac).unwrap().unwrap();
+        std::fs::remove_file(flac).unwrap();
+        let album = Some("Moon Safari".to_string());
+        assert_eq!(tags, FileTags { artist: Some("Air".to_string()), album, ..FileTags::default() });
+    }
+}
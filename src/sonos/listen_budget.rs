@@ -0,0 +1,105 @@
+use crate::config::{ListenBudgetConfig, OverBudget};
+use crate::notify::Telegram;
+use chrono::{Local, NaiveDate};
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Daily play time per room, for rooms with a budget. Counted in memory, so
+/// a restart starts the day over.
+#[derive(Default)]
+pub struct ListenBudget {
+    /// Budgets by lowercase room name
+    budgets: BTreeMap<String, ListenBudgetConfig>,
+    played: Mutex<HashMap<String, (NaiveDate, Duration)>>,
+    telegram: Option<Arc<Telegram>>,
+}
+
+impl ListenBudget {
+    pub fn new(budgets: &BTreeMap<String, ListenBudgetConfig>) -> Self {
+        Self {
+            budgets: budgets.iter().map(|(room, budget)| (room.to_lowercase(), budget.clone())).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Announces rooms going over their budget in the Telegram chat
+    pub fn with_telegram(mut self, telegram: Arc<Telegram>) -> Self {
+        self.telegram = Some(telegram);
+        self
+    }
+
+    /// Adds `played` to today's play time in `room`, notifying once it goes
+    /// over the room's budget
+    pub fn add(&self, room: &str, played: Duration) {
+        if self.add_on(room, played, Local::now().date_naive()) {
+            let text = format!("{} went over its listening budget for today", room);
+            info!("{}", text);
+            if let Some(telegram) = self.telegram.clone() {
+                tokio::spawn(async move {
+                    if let Err(e) = telegram.send_message(&text).await {
+                        warn!("Telegram: {}", e);
+                    }
+                });
+            }
+        }
+    }
+
+    /// Whether listens in `room` are no longer scrobbled today
+    pub fn stops_scrobbling(&self, room: &str) -> bool {
+        let Some(budget) = self.budgets.get(&room.to_lowercase()) else {
+            return false;
+        };
+        budget.over == OverBudget::StopScrobbling && self.spent(room, budget, Local::now().date_naive())
+    }
+
+    /// Adds `played` to the play time in `room` on `today`. Returns whether
+    /// that took the room over its budget.
+    fn add_on(&self, room: &str, played: Duration, today: NaiveDate) -> bool {
+        let Some(budget) = self.budgets.get(&room.to_lowercase()) else {
+            return false;
+        };
+        let was_spent = self.spent(room, budget, today);
+        let mut rooms = self.played.lock().unwrap();
+        let (day, total) = rooms.entry(room.to_lowercase()).or_insert((today, Duration::ZERO));
+        if *day != today {
+            *day = today;
+            *total = Duration::ZERO;
+        }
+        *total += played;
+        !was_spent && *total >= Duration::from_secs(budget.minutes * 60)
+    }
+
+    fn spent(&self, room: &str, budget: &ListenBudgetConfig, today: NaiveDate) -> bool {
+        self.played
+            .lock()
+            .unwrap()
+            .get(&room.to_lowercase())
+            .is_some_and(|(day, total)| *day == today && *total >= Duration::from_secs(budget.minutes * 60))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_spent_once_per_day() {
+        let budgets = BTreeMap::from([(
+            "Kids Room".to_string(),
+            ListenBudgetConfig { minutes: 60, over: OverBudget::StopScrobbling },
+        )]);
+        let budget = ListenBudget::new(&budgets);
+        let monday = NaiveDate::from_ymd_opt(2024, 3, 4).unwrap();
+        let half_hour = Duration::from_secs(30 * 60);
+
+        assert!(!budget.add_on("kids room", half_hour, monday));
+        assert!(budget.add_on("Kids Room", half_hour, monday));
+        assert!(!budget.add_on("Kids Room", half_hour, monday));
+        assert!(!budget.add_on("Kitchen", half_hour * 10, monday));
+
+        let tuesday = monday.succ_opt().unwrap();
+        assert!(!budget.add_on("Kids Room", half_hour, tuesday));
+    }
+}
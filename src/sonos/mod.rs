@@ -1,7 +1,44 @@
+mod alarm;
+mod album;
+mod art;
+mod budget;
+mod buffer;
+mod client;
 mod discovery;
 mod events;
+mod firmware;
+mod gena;
+mod library;
+mod listen_budget;
+mod pipeline;
 mod database;
+mod replay;
+mod session;
+mod sleep;
+mod soap;
+mod ssdp;
 
-pub use discovery::SonosDiscovery;
+pub use alarm::Alarm;
+pub use album::{AlbumDetector, AlbumListen, MIN_ALBUM_TRACKS};
+pub use art::{art_url, AlbumArt, ArtProxy};
+pub use budget::TaskBudget;
+pub use client::SonosClient;
+pub use discovery::{container_network_warning, SonosDevice, SonosDiscovery};
 pub use events::EventSubscriber;
+pub use firmware::{compatibility, EventSupport, SoftwareVersion};
+pub use gena::{
+    new_token, EventHub, Notification, Sequence, Subscription, DEFAULT_SPEAKER_PREFIX_LEN, DEFAULT_SUBSCRIPTION_TIMEOUT,
+};
+pub use library::{read_tags, share_path, FileTags, Library};
+pub use listen_budget::ListenBudget;
 pub use database::TrackDatabase;
+pub use pipeline::{
+    derive, normalize, prune_log_periodically, rebuild, Action, Decider, Derived, EventLog, LogEntry, Pipeline,
+    PlaybackEvent, PolledPosition, RawEvent, ScrobbleCommand, SessionChange, SessionEvent, SessionRecord, Sessionizer,
+    Stage, LOG_PRUNE_INTERVAL,
+};
+pub use replay::{replay_file, CapturedEvent, EventCapture, Replay, SyntheticEvent};
+pub use session::{describe_track, Interaction, ListenSession, NowPlaying, PlayContext};
+pub use sleep::SleepDetector;
+pub use soap::{CallMetrics, MediaInfo, PositionInfo, SoapClient, TransportEvent, TransportState};
+pub use ssdp::{SsdpListener, WakeUps};
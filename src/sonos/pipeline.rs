@@ -0,0 +1,672 @@
+use crate::config::{ScrobbleOn, ThresholdConfig};
+use crate::error::{Error, Result};
+use crate::scrobble::{Confidence, MetadataSource, Scrobble};
+use crate::sonos::replay::{format_hms, CapturedEvent};
+use crate::sonos::session::{describe_track, parse_hms, ListenSession, NowPlaying};
+use crate::sonos::soap::{parse_last_change, PositionInfo, TransportState};
+use crate::sonos::TrackDatabase;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the position of a speaker is logged while its track doesn't
+/// change; polls in between add nothing the next one doesn't tell
+const POLL_LOG_INTERVAL_MS: i64 = 60_000;
+/// How often events past `event_log_days` are pruned while running
+pub const LOG_PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A stage of the listen pipeline. Every stage is derived from the one
+/// before, so after a fix to a stage's logic it can be derived again from
+/// what was logged of the stage before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// What the speakers reported: event notifications and polled positions
+    Raw,
+    /// Changes of transport state and track, whoever reported them
+    Playback,
+    /// Listens starting, progressing and ending
+    Session,
+    /// Listens to scrobble, quarantine or let go
+    Command,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Raw, Stage::Playback, Stage::Session, Stage::Command];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::Raw => "raw",
+            Stage::Playback => "playback",
+            Stage::Session => "session",
+            Stage::Command => "command",
+        }
+    }
+
+    /// The stage this one is derived from; none for raw events
+    pub fn source(self) -> Option<Stage> {
+        match self {
+            Stage::Raw => None,
+            Stage::Playback => Some(Stage::Raw),
+            Stage::Session => Some(Stage::Playback),
+            Stage::Command => Some(Stage::Session),
+        }
+    }
+}
+
+/// One event of one stage as it is stored in the event log
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub stage: Stage,
+    pub device: String,
+    /// Unix timestamp in milliseconds
+    pub at_ms: i64,
+    /// The event as JSON
+    pub payload: String,
+}
+
+impl LogEntry {
+    fn new<T: Serialize>(stage: Stage, device: &str, at_ms: i64, event: &T) -> Result<Self> {
+        let payload = serde_json::to_string(event).map_err(|e| Error::Config(e.to_string()))?;
+        Ok(Self { stage, device: device.to_string(), at_ms, payload })
+    }
+
+    fn event<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_str(&self.payload)
+            .map_err(|e| Error::Config(format!("{} event from {}: {}", self.stage.as_str(), self.device, e)))
+    }
+}
+
+/// What a speaker reported
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum RawEvent {
+    Notify(CapturedEvent),
+    Poll(PolledPosition),
+}
+
+impl RawEvent {
+    pub fn device(&self) -> &str {
+        match self {
+            RawEvent::Notify(event) => &event.device,
+            RawEvent::Poll(poll) => &poll.device,
+        }
+    }
+
+    /// Unix timestamp in milliseconds
+    pub fn received_at_ms(&self) -> i64 {
+        match self {
+            RawEvent::Notify(event) => event.received_at_ms,
+            RawEvent::Poll(poll) => poll.received_at_ms,
+        }
+    }
+}
+
+/// The answer to a `GetPositionInfo` poll
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PolledPosition {
+    /// Unix timestamp in milliseconds
+    pub received_at_ms: i64,
+    pub device: String,
+    pub position: PositionInfo,
+}
+
+impl PolledPosition {
+    /// `position` of `device`, polled now
+    pub fn now(device: &str, position: &PositionInfo) -> Self {
+        Self { received_at_ms: unix_now_ms(), device: device.to_string(), position: position.clone() }
+    }
+}
+
+/// A change of a speaker's playback
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlaybackEvent {
+    /// Unix timestamp in milliseconds
+    pub at_ms: i64,
+    pub device: String,
+    /// The transport state, unless unchanged or unknown; polls don't tell
+    pub state: Option<TransportState>,
+    /// The current track, unless unchanged. Only polls report its position.
+    pub track: Option<PositionInfo>,
+}
+
+/// The playback change a speaker reported
+pub fn normalize(raw: &RawEvent) -> Result<PlaybackEvent> {
+    match raw {
+        RawEvent::Notify(event) => {
+            let change = parse_last_change(event.body.as_bytes())?;
+            Ok(PlaybackEvent {
+                at_ms: event.received_at_ms,
+                device: event.device.clone(),
+                state: change.state,
+                track: change.track,
+            })
+        }
+        RawEvent::Poll(poll) => Ok(PlaybackEvent {
+            at_ms: poll.received_at_ms,
+            device: poll.device.clone(),
+            state: None,
+            track: Some(poll.position.clone()),
+        }),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionChange {
+    Started,
+    /// More of the listen was played
+    Progressed,
+    Ended,
+}
+
+/// A listen starting, progressing or ending
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionEvent {
+    /// Unix timestamp in milliseconds
+    pub at_ms: i64,
+    pub device: String,
+    pub change: SessionChange,
+    pub listen: SessionRecord,
+}
+
+/// A [`ListenSession`] as it is logged
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub uri: String,
+    pub track_info: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub duration_secs: Option<u64>,
+    /// Unix timestamp of when the listen started
+    pub started_at: i64,
+    /// Time spent playing so far, in milliseconds
+    pub played_ms: u64,
+    pub artist_confidence: u8,
+    pub title_confidence: u8,
+}
+
+impl From<&ListenSession> for SessionRecord {
+    fn from(session: &ListenSession) -> Self {
+        Self {
+            uri: session.uri.clone(),
+            track_info: session.track_info.clone(),
+            artist: session.artist.clone(),
+            title: session.title.clone(),
+            album: session.album.clone(),
+            duration_secs: session.duration.map(|duration| duration.as_secs()),
+            started_at: session.started_at,
+            played_ms: session.played.as_millis() as u64,
+            artist_confidence: session.confidence.artist,
+            title_confidence: session.confidence.title,
+        }
+    }
+}
+
+impl SessionRecord {
+    /// The listen as a session again. Where it was played from isn't logged,
+    /// so thresholds of particular sources don't apply to it.
+    pub fn to_session(&self) -> ListenSession {
+        let mut session = ListenSession::new(NowPlaying {
+            uri: self.uri.clone(),
+            artist: self.artist.clone(),
+            title: self.title.clone(),
+            album: self.album.clone(),
+            duration: self.duration_secs.map(Duration::from_secs),
+            position: None,
+            source: MetadataSource::Didl,
+            stream: false,
+        });
+        session.track_info = self.track_info.clone();
+        session.started_at = self.started_at;
+        session.played = Duration::from_millis(self.played_ms);
+        session.confidence = Confidence { artist: self.artist_confidence, title: self.title_confidence };
+        session
+    }
+}
+
+/// What a speaker was doing as far as its playback events tell
+struct DeviceState {
+    state: TransportState,
+    track: Option<PositionInfo>,
+    /// Track position as last polled, or estimated from the time spent
+    /// playing since
+    position: Duration,
+    last_event_ms: i64,
+    session: Option<ListenSession>,
+}
+
+/// Turns the playback events of every speaker into listens
+#[derive(Default)]
+pub struct Sessionizer {
+    devices: BTreeMap<String, DeviceState>,
+}
+
+impl Sessionizer {
+    pub fn feed(&mut self, event: &PlaybackEvent) -> Vec<SessionEvent> {
+        let device = self.devices.entry(event.device.clone()).or_insert(DeviceState {
+            state: TransportState::Stopped,
+            track: None,
+            position: Duration::ZERO,
+            last_event_ms: event.at_ms,
+            session: None,
+        });
+
+        let elapsed = Duration::from_millis((event.at_ms - device.last_event_ms).max(0) as u64);
+        if device.state == TransportState::Playing {
+            device.position += elapsed;
+        }
+        device.last_event_ms = event.at_ms;
+        if let Some(state) = event.state {
+            device.state = state;
+        }
+        if let Some(track) = &event.track {
+            let same_track = device.track.as_ref().is_some_and(|current| {
+                current.track_uri == track.track_uri && describe_track(current) == describe_track(track)
+            });
+            if !same_track {
+                device.position = Duration::ZERO;
+            }
+            if let Some(position) = parse_hms(&track.position) {
+                device.position = position;
+            }
+            device.track = Some(track.clone());
+        }
+        let Some(track) = &device.track else {
+            return Vec::new();
+        };
+
+        let position = PositionInfo { position: format_hms(device.position), ..track.clone() };
+        let session_event = |change, session: &ListenSession| SessionEvent {
+            at_ms: event.at_ms,
+            device: event.device.clone(),
+            change,
+            listen: session.into(),
+        };
+        match device.session.as_mut() {
+            Some(current) if current.continues_with(&position) => {
+                let played = current.played;
+                current.advance(&position, elapsed);
+                match current.played > played {
+                    true => vec![session_event(SessionChange::Progressed, current)],
+                    false => Vec::new(),
+                }
+            }
+            _ => {
+                let ended = device.session.take().map(|finished| session_event(SessionChange::Ended, &finished));
+                let mut changes: Vec<_> = ended.into_iter().collect();
+                let mut session = ListenSession::from_position(&position);
+                session.started_at = event.at_ms.div_euclid(1000);
+                changes.push(session_event(SessionChange::Started, &session));
+                device.session = Some(session);
+                changes
+            }
+        }
+    }
+
+    /// Ends the listens still in progress, as of the last event of their
+    /// speaker
+    pub fn finish(&mut self) -> Vec<SessionEvent> {
+        std::mem::take(&mut self.devices)
+            .into_iter()
+            .filter_map(|(name, device)| {
+                Some(SessionEvent {
+                    at_ms: device.last_event_ms,
+                    device: name,
+                    change: SessionChange::Ended,
+                    listen: SessionRecord::from(&device.session?),
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Scrobble,
+    /// Played enough, but without a known artist or title
+    Quarantine,
+    /// Ended without being played enough
+    Skip,
+}
+
+/// What to do with a listen, decided once per listen
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScrobbleCommand {
+    /// Unix timestamp in milliseconds
+    pub at_ms: i64,
+    pub device: String,
+    pub action: Action,
+    pub listen: SessionRecord,
+    /// Play time the listen needed to be scrobbled, in seconds
+    pub needed_secs: u64,
+}
+
+impl ScrobbleCommand {
+    /// The listen to submit, for a scrobble
+    pub fn to_scrobble(&self) -> Option<Scrobble> {
+        match self.action {
+            Action::Scrobble => self.listen.to_session().to_scrobble(&self.device),
+            Action::Quarantine | Action::Skip => None,
+        }
+    }
+
+    /// The decision in words, e.g. "Kitchen: scrobbled Daft Punk - Get Lucky
+    /// after 0:02:00"
+    pub fn describe(&self) -> String {
+        let played = format_hms(Duration::from_millis(self.listen.played_ms));
+        let action = match self.action {
+            Action::Scrobble => "scrobbled",
+            Action::Quarantine => "quarantined",
+            Action::Skip => {
+                return format!(
+                    "{}: ended {} without scrobbling, played {} of the {} needed",
+                    self.device,
+                    self.listen.track_info,
+                    played,
+                    format_hms(Duration::from_secs(self.needed_secs))
+                )
+            }
+        };
+        format!("{}: {} {} after {}", self.device, action, self.listen.track_info, played)
+    }
+}
+
+/// Decides per listen whether it is scrobbled, as soon as it played enough
+/// or once it ends depending on `scrobble_on`
+pub struct Decider {
+    scrobble_on: ScrobbleOn,
+    threshold: ThresholdConfig,
+    /// Whether the current listen of each speaker was decided on
+    decided: BTreeMap<String, bool>,
+}
+
+impl Decider {
+    pub fn new(scrobble_on: ScrobbleOn, threshold: ThresholdConfig) -> Self {
+        Self { scrobble_on, threshold, decided: BTreeMap::new() }
+    }
+
+    pub fn feed(&mut self, event: &SessionEvent) -> Option<ScrobbleCommand> {
+        let decided = self.decided.entry(event.device.clone()).or_default();
+        if event.change == SessionChange::Started {
+            *decided = false;
+        }
+        if *decided {
+            return None;
+        }
+
+        let session = event.listen.to_session();
+        let met = session.meets_threshold(&self.threshold);
+        let played_enough = match session.to_scrobble(&event.device) {
+            Some(_) => Action::Scrobble,
+            None => Action::Quarantine,
+        };
+        let action = match (event.change, self.scrobble_on) {
+            (SessionChange::Ended, ScrobbleOn::TrackEnd) if met => played_enough,
+            (SessionChange::Ended, _) => Action::Skip,
+            (_, ScrobbleOn::Threshold) if met => played_enough,
+            _ => return None,
+        };
+        *decided = true;
+        Some(ScrobbleCommand {
+            at_ms: event.at_ms,
+            device: event.device.clone(),
+            action,
+            listen: event.listen.clone(),
+            needed_secs: session.scrobble_threshold(&self.threshold).as_secs(),
+        })
+    }
+}
+
+/// The events of the later stages derived from one event
+#[derive(Debug, Default, PartialEq)]
+pub struct Derived {
+    pub playback: Vec<PlaybackEvent>,
+    pub sessions: Vec<SessionEvent>,
+    pub commands: Vec<ScrobbleCommand>,
+}
+
+impl Derived {
+    /// The events as they are logged, stage by stage
+    pub fn entries(&self) -> Result<Vec<LogEntry>> {
+        let playback = self.playback.iter().map(|e| LogEntry::new(Stage::Playback, &e.device, e.at_ms, e));
+        let sessions = self.sessions.iter().map(|e| LogEntry::new(Stage::Session, &e.device, e.at_ms, e));
+        let commands = self.commands.iter().map(|e| LogEntry::new(Stage::Command, &e.device, e.at_ms, e));
+        playback.chain(sessions).chain(commands).collect()
+    }
+}
+
+/// The stages after raw events, each fed what the one before derived
+pub struct Pipeline {
+    sessionizer: Sessionizer,
+    decider: Decider,
+}
+
+impl Pipeline {
+    pub fn new(scrobble_on: ScrobbleOn, threshold: ThresholdConfig) -> Self {
+        Self { sessionizer: Sessionizer::default(), decider: Decider::new(scrobble_on, threshold) }
+    }
+
+    pub fn feed_raw(&mut self, raw: &RawEvent) -> Result<Derived> {
+        let playback = normalize(raw)?;
+        let derived = self.feed_playback(&playback);
+        Ok(Derived { playback: vec![playback], ..derived })
+    }
+
+    pub fn feed_playback(&mut self, event: &PlaybackEvent) -> Derived {
+        let sessions = self.sessionizer.feed(event);
+        let commands = sessions.iter().filter_map(|session| self.decider.feed(session)).collect();
+        Derived { playback: Vec::new(), sessions, commands }
+    }
+
+    pub fn feed_session(&mut self, event: &SessionEvent) -> Derived {
+        Derived { commands: self.decider.feed(event).into_iter().collect(), ..Derived::default() }
+    }
+
+    /// Ends the listens still in progress
+    pub fn finish(&mut self) -> Derived {
+        let sessions = self.sessionizer.finish();
+        let commands = sessions.iter().filter_map(|session| self.decider.feed(session)).collect();
+        Derived { playback: Vec::new(), sessions, commands }
+    }
+
+    /// Feeds a logged event of the stage before the one being derived
+    fn feed_entry(&mut self, entry: &LogEntry) -> Result<Derived> {
+        match entry.stage {
+            Stage::Raw => self.feed_raw(&entry.event()?),
+            Stage::Playback => Ok(self.feed_playback(&entry.event()?)),
+            Stage::Session => Ok(self.feed_session(&entry.event()?)),
+            Stage::Command => Ok(Derived::default()),
+        }
+    }
+}
+
+/// Logs the events of every speaker as they arrive, each with the events
+/// the pipeline derives from it. This shadows the poller, which still makes
+/// the live submissions; the log is only derived next to it.
+pub struct EventLog {
+    db: TrackDatabase,
+    state: Mutex<LiveState>,
+}
+
+struct LiveState {
+    pipeline: Pipeline,
+    /// The track of every speaker when its position was last logged, and when
+    polls: HashMap<String, (String, i64)>,
+}
+
+impl EventLog {
+    pub fn new(db: TrackDatabase, scrobble_on: ScrobbleOn, threshold: ThresholdConfig) -> Self {
+        let state = LiveState { pipeline: Pipeline::new(scrobble_on, threshold), polls: HashMap::new() };
+        Self { db, state: Mutex::new(state) }
+    }
+
+    /// Logs `raw` and what it leads to. Polls are only logged when the track
+    /// changed or a while after the last logged one. Failures only log a
+    /// warning, the log isn't worth missing a listen over.
+    pub async fn record(&self, raw: RawEvent) {
+        let entries = {
+            let mut state = self.state.lock().unwrap();
+            if let RawEvent::Poll(poll) = &raw {
+                let track = format!("{} {}", poll.position.track_uri, describe_track(&poll.position));
+                let due = state.polls.get(&poll.device).is_none_or(|(logged, at_ms)| {
+                    *logged != track || poll.received_at_ms - at_ms >= POLL_LOG_INTERVAL_MS
+                });
+                if !due {
+                    return;
+                }
+                state.polls.insert(poll.device.clone(), (track, poll.received_at_ms));
+            }
+            LogEntry::new(Stage::Raw, raw.device(), raw.received_at_ms(), &raw).and_then(|entry| {
+                let mut entries = vec![entry];
+                match state.pipeline.feed_raw(&raw) {
+                    Ok(derived) => entries.extend(derived.entries()?),
+                    Err(e) => debug!("Event from {} leads nowhere: {}", raw.device(), e),
+                }
+                Ok(entries)
+            })
+        };
+        if let Err(e) = async { self.db.append_events(&entries?).await }.await {
+            warn!("Failed to log event from {}: {}", raw.device(), e);
+        }
+    }
+}
+
+/// Removes the events logged more than `days` ago, every `interval` until
+/// the task is dropped
+pub async fn prune_log_periodically(db: TrackDatabase, days: u32, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let before = SystemTime::now() - Duration::from_secs(u64::from(days) * 24 * 3600);
+        let before_ms = before.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+        match db.prune_events(before_ms).await {
+            Ok(0) => {}
+            Ok(pruned) => debug!("Pruned {} events older than {} days from the event log", pruned, days),
+            Err(e) => warn!("Failed to prune the event log: {}", e),
+        }
+    }
+}
+
+/// Derives `stage` and the stages after it again from the logged events of
+/// the stage before since `since_ms`, without logging them. Listens already
+/// in progress then are only seen from then on, and those still in progress
+/// at the end aren't ended.
+pub async fn derive(
+    db: &TrackDatabase,
+    stage: Stage,
+    since_ms: i64,
+    scrobble_on: ScrobbleOn,
+    threshold: ThresholdConfig,
+) -> Result<Derived> {
+    let Some(source) = stage.source() else {
+        return Err(Error::Config("raw events are recorded, they can't be derived again".to_string()));
+    };
+    let mut pipeline = Pipeline::new(scrobble_on, threshold);
+    let mut derived = Derived::default();
+    for entry in db.logged_events(source, since_ms).await? {
+        match pipeline.feed_entry(&entry) {
+            Ok(more) => {
+                derived.playback.extend(more.playback);
+                derived.sessions.extend(more.sessions);
+                derived.commands.extend(more.commands);
+            }
+            Err(e) => warn!("Skipping {} event from {}: {}", source.as_str(), entry.device, e),
+        }
+    }
+    Ok(derived)
+}
+
+/// Replaces the logged events of `stage` and the stages after it since
+/// `since_ms` with what they [derive](derive()) to now, e.g. after a fix to
+/// the session logic or a change of the threshold. Returns the scrobble
+/// commands derived.
+pub async fn rebuild(
+    db: &TrackDatabase,
+    stage: Stage,
+    since_ms: i64,
+    scrobble_on: ScrobbleOn,
+    threshold: ThresholdConfig,
+) -> Result<Vec<ScrobbleCommand>> {
+    let derived = derive(db, stage, since_ms, scrobble_on, threshold).await?;
+    let stages: Vec<_> = Stage::ALL.into_iter().filter(|s| *s >= stage).collect();
+    db.replace_events(&stages, since_ms, &derived.entries()?).await?;
+    Ok(derived.commands)
+}
+
+fn unix_now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poll(at_secs: i64, uri: &str, title: &str, position_secs: u64) -> RawEvent {
+        RawEvent::Poll(PolledPosition {
+            received_at_ms: at_secs * 1000,
+            device: "Kitchen".to_string(),
+            position: PositionInfo {
+                track_uri: uri.to_string(),
+                duration: "0:03:00".to_string(),
+                position: format_hms(Duration::from_secs(position_secs)),
+                title: Some(title.to_string()),
+                artist: Some("Daft Punk".to_string()),
+                ..PositionInfo::default()
+            },
+        })
+    }
+
+    #[test]
+    fn test_stages_derive_a_scrobble() {
+        let mut pipeline = Pipeline::new(ScrobbleOn::Threshold, ThresholdConfig::default());
+        let started = pipeline.feed_raw(&poll(1_700_000_000, "x-sonos-spotify:one", "Get Lucky", 0)).unwrap();
+        assert_eq!(started.playback.len(), 1);
+        assert_eq!(started.sessions[0].change, SessionChange::Started);
+        assert_eq!(started.sessions[0].listen.started_at, 1_700_000_000);
+
+        pipeline.feed_raw(&poll(1_700_000_060, "x-sonos-spotify:one", "Get Lucky", 60)).unwrap();
+        let scrobbled = pipeline.feed_raw(&poll(1_700_000_095, "x-sonos-spotify:one", "Get Lucky", 95)).unwrap();
+        assert_eq!(scrobbled.commands[0].describe(), "Kitchen: scrobbled Daft Punk - Get Lucky after 0:01:35");
+        assert_eq!(scrobbled.commands[0].to_scrobble().unwrap().started_at, 1_700_000_000);
+
+        // Decided once: neither more progress nor the end scrobble it again
+        let more = pipeline.feed_raw(&poll(1_700_000_120, "x-sonos-spotify:one", "Get Lucky", 120)).unwrap();
+        assert!(more.commands.is_empty());
+        let next = pipeline.feed_raw(&poll(1_700_000_180, "x-sonos-spotify:two", "Lose Yourself to Dance", 0)).unwrap();
+        let changes: Vec<_> = next.sessions.iter().map(|s| s.change).collect();
+        assert_eq!(changes, [SessionChange::Ended, SessionChange::Started]);
+        assert!(next.commands.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_from_logged_events() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let log = EventLog::new(db.clone(), ScrobbleOn::Threshold, ThresholdConfig::default());
+        log.record(poll(1_700_000_000, "x-sonos-spotify:one", "Get Lucky", 0)).await;
+        // Too soon after the last to be logged
+        log.record(poll(1_700_000_010, "x-sonos-spotify:one", "Get Lucky", 10)).await;
+        log.record(poll(1_700_000_060, "x-sonos-spotify:one", "Get Lucky", 60)).await;
+        log.record(poll(1_700_000_070, "x-sonos-spotify:two", "Lose Yourself to Dance", 0)).await;
+        assert_eq!(db.logged_events(Stage::Raw, 0).await.unwrap().len(), 3);
+        let commands = db.logged_events(Stage::Command, 0).await.unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].event::<ScrobbleCommand>().unwrap().action, Action::Skip);
+
+        // A minute is enough once the threshold is lowered
+        let threshold = ThresholdConfig { max_secs: 60, ..ThresholdConfig::default() };
+        let commands = rebuild(&db, Stage::Session, 0, ScrobbleOn::TrackEnd, threshold.clone()).await.unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].describe(), "Kitchen: scrobbled Daft Punk - Get Lucky after 0:01:00");
+        assert_eq!(db.logged_events(Stage::Raw, 0).await.unwrap().len(), 3);
+        assert_eq!(db.logged_events(Stage::Command, 0).await.unwrap().len(), 1);
+        assert!(rebuild(&db, Stage::Raw, 0, ScrobbleOn::TrackEnd, threshold).await.is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::config::{ScrobbleOn, ThresholdConfig};
+use crate::error::{Error, Result};
+use crate::sonos::pipeline::{normalize, Decider, RawEvent, SessionChange, SessionEvent, Sessionizer};
+use crate::sonos::soap::TransportState;
+use quick_xml::escape::escape;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A NOTIFY request as received from a speaker, one JSON object per line in
+/// an event log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CapturedEvent {
+    /// Unix timestamp in milliseconds
+    pub received_at_ms: i64,
+    pub device: String,
+    pub sid: String,
+    pub seq: u32,
+    pub body: String,
+}
+
+impl CapturedEvent {
+    /// `body` of a NOTIFY from `device`, received now
+    pub fn now(device: &str, sid: &str, seq: u32, body: &str) -> Self {
+        Self {
+            received_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as i64,
+            device: device.to_string(),
+            sid: sid.to_string(),
+            seq,
+            body: body.to_string(),
+        }
+    }
+}
+
+/// A made-up transport event, taken by `POST /dev/inject-event` in dev mode
+/// so listen rules can be tried out without a speaker playing music
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyntheticEvent {
+    /// Device name the listen is logged under
+    pub device: String,
+    /// Transport state as speakers send it, e.g. "PLAYING" or "STOPPED"
+    pub state: String,
+    /// Track URI; made up from the artist and title when unset
+    pub uri: Option<String>,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub duration_secs: Option<u64>,
+    /// Unix timestamp in milliseconds, for skipping ahead in time; now when
+    /// unset
+    pub received_at_ms: Option<i64>,
+}
+
+impl SyntheticEvent {
+    /// The NOTIFY a speaker would have sent for this event
+    pub fn to_captured(&self) -> Result<CapturedEvent> {
+        if TransportState::parse(&self.state).is_none() {
+            return Err(Error::Config(format!("unknown transport state {:?}", self.state)));
+        }
+        let field = |name: &str, value: &Option<String>| match value {
+            Some(value) => format!("<{name}>{}</{name}>", escape(value)),
+            None => String::new(),
+        };
+        let didl = format!(
+            "<DIDL-Lite xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+             xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\"><item>{}{}{}</item></DIDL-Lite>",
+            field("dc:title", &self.title),
+            field("dc:creator", &self.artist),
+            field("upnp:album", &self.album),
+        );
+        let uri = self.uri.clone().unwrap_or_else(|| {
+            let artist = self.artist.as_deref().unwrap_or_default();
+            let name = format!("{}-{}", artist, self.title.as_deref().unwrap_or_default());
+            format!("x-sonos-dev:{}", percent_encoding::utf8_percent_encode(&name, percent_encoding::NON_ALPHANUMERIC))
+        });
+        let duration = format_hms(Duration::from_secs(self.duration_secs.unwrap_or_default()));
+        let last_change = format!(
+            "<Event><InstanceID val=\"0\"><TransportState val=\"{}\"/><CurrentTrackURI val=\"{}\"/>\
+             <CurrentTrackDuration val=\"{}\"/><CurrentTrackMetaData val=\"{}\"/></InstanceID></Event>",
+            escape(&self.state),
+            escape(&uri),
+            duration,
+            escape(&didl)
+        );
+        let body = format!(
+            "<e:propertyset xmlns:e=\"urn:schemas-upnp-org:event-1-0\"><e:property>\
+             <LastChange>{}</LastChange></e:property></e:propertyset>",
+            escape(&last_change)
+        );
+        let mut event = CapturedEvent::now(&self.device, "uuid:dev", 0, &body);
+        if let Some(received_at_ms) = self.received_at_ms {
+            event.received_at_ms = received_at_ms;
+        }
+        Ok(event)
+    }
+}
+
+/// Appends every received event to a daily `events-YYYY-MM-DD.jsonl` file,
+/// the format [`replay_file`] reads
+pub struct EventCapture {
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl EventCapture {
+    pub fn new(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).map_err(|e| Error::Config(format!("{}: {}", dir.display(), e)))?;
+        Ok(Self { dir: dir.to_path_buf(), lock: Mutex::new(()) })
+    }
+
+    pub fn write(&self, event: &CapturedEvent) -> Result<()> {
+        let day = chrono::DateTime::from_timestamp_millis(event.received_at_ms)
+            .unwrap_or_default()
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d");
+        let path = self.dir.join(format!("events-{}.jsonl", day));
+        let line = serde_json::to_string(event).map_err(|e| Error::Config(e.to_string()))?;
+
+        let _guard = self.lock.lock().unwrap();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", line))
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+    }
+}
+
+/// Runs recorded events through the listen pipeline without touching the
+/// network or the database, describing every decision it makes
+pub struct Replay {
+    sessionizer: Sessionizer,
+    decider: Decider,
+}
+
+impl Replay {
+    pub fn new(scrobble_on: ScrobbleOn, threshold: ThresholdConfig) -> Self {
+        Self { sessionizer: Sessionizer::default(), decider: Decider::new(scrobble_on, threshold) }
+    }
+
+    /// Feeds the next event and returns the decisions it led to
+    pub fn feed(&mut self, event: &CapturedEvent) -> Result<Vec<String>> {
+        let playback = normalize(&RawEvent::Notify(event.clone()))?;
+        let sessions = self.sessionizer.feed(&playback);
+        Ok(self.decide(&sessions))
+    }
+
+    /// Ends the listens still in progress when the log ends
+    pub fn finish(&mut self) -> Vec<String> {
+        let sessions = self.sessionizer.finish();
+        self.decide(&sessions)
+    }
+
+    fn decide(&mut self, sessions: &[SessionEvent]) -> Vec<String> {
+        let mut decisions = Vec::new();
+        for session in sessions {
+            if session.change == SessionChange::Started {
+                decisions.push(format!("{}: started {}", session.device, session.listen.track_info));
+            }
+            decisions.extend(self.decider.feed(session).map(|command| command.describe()));
+        }
+        decisions
+    }
+}
+
+/// Replays the event log at `path`, one decision per line
+pub fn replay_file(path: &Path, scrobble_on: ScrobbleOn, threshold: ThresholdConfig) -> Result<Vec<String>> {
+    let file = std::fs::File::open(path).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+    let mut replay = Replay::new(scrobble_on, threshold);
+    let mut decisions = Vec::new();
+
+    for (number, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: CapturedEvent = serde_json::from_str(&line)
+            .map_err(|e| Error::Config(format!("{} line {}: {}", path.display(), number + 1, e)))?;
+        decisions.extend(replay.feed(&event)?);
+    }
+    decisions.extend(replay.finish());
+    Ok(decisions)
+}
+
+pub(super) fn format_hms(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(at_secs: i64, state: &str, uri: &str, title: &str) -> CapturedEvent {
+        let didl = format!(
+            "&lt;DIDL-Lite xmlns:dc=&quot;http://purl.org/dc/elements/1.1/&quot;&gt;&lt;item&gt;\
+             &lt;dc:title&gt;{title}&lt;/dc:title&gt;&lt;dc:creator&gt;Daft Punk&lt;/dc:creator&gt;\
+             &lt;/item&gt;&lt;/DIDL-Lite&gt;"
+        );
+        let last_change = format!(
+            "<Event><InstanceID val=\"0\"><TransportState val=\"{state}\"/>\
+             <CurrentTrackURI val=\"{uri}\"/><CurrentTrackDuration val=\"0:03:00\"/>\
+             <CurrentTrackMetaData val=\"{didl}\"/></InstanceID></Event>"
+        );
+        CapturedEvent {
+            received_at_ms: at_secs * 1000,
+            device: "Kitchen".to_string(),
+            sid: "uuid:sub-1".to_string(),
+            seq: at_secs as u32,
+            body: format!(
+                "<e:propertyset xmlns:e=\"urn:schemas-upnp-org:event-1-0\"><e:property>\
+                 <LastChange>{}</LastChange></e:property></e:propertyset>",
+                last_change.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+            ),
+        }
+    }
+
+    #[test]
+    fn test_captured_events_replay() {
+        let dir = std::env::temp_dir().join(format!("sonos-capture-{}", std::process::id()));
+        let capture = EventCapture::new(&dir).unwrap();
+        capture.write(&event(0, "PLAYING", "x-sonos-spotify:one", "Get Lucky")).unwrap();
+        capture.write(&event(120, "STOPPED", "x-sonos-spotify:one", "Get Lucky")).unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        let decisions = replay_file(&files[0], ScrobbleOn::Threshold, ThresholdConfig::default()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(decisions[1], "Kitchen: scrobbled Daft Punk - Get Lucky after 0:02:00");
+    }
+
+    #[test]
+    fn test_synthetic_events_replay() {
+        let mut replay = Replay::new(ScrobbleOn::Threshold, ThresholdConfig::default());
+        let mut event = SyntheticEvent {
+            device: "Kitchen".to_string(),
+            state: "PLAYING".to_string(),
+            uri: None,
+            artist: Some("Simon & Garfunkel".to_string()),
+            title: Some("The Boxer".to_string()),
+            album: None,
+            duration_secs: Some(308),
+            received_at_ms: Some(0),
+        };
+        let started = replay.feed(&event.to_captured().unwrap()).unwrap();
+        assert_eq!(started, vec!["Kitchen: started Simon & Garfunkel - The Boxer".to_string()]);
+
+        event.received_at_ms = Some(160_000);
+        let scrobbled = replay.feed(&event.to_captured().unwrap()).unwrap();
+        assert_eq!(scrobbled, vec!["Kitchen: scrobbled Simon & Garfunkel - The Boxer after 0:02:40".to_string()]);
+        event.state = "SPINNING".to_string();
+        assert!(matches!(event.to_captured(), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_replay_decisions() {
+        let mut replay = Replay::new(ScrobbleOn::Threshold, ThresholdConfig::default());
+        let started = replay.feed(&event(0, "PLAYING", "x-sonos-spotify:one", "Get Lucky")).unwrap();
+        assert_eq!(started, vec!["Kitchen: started Daft Punk - Get Lucky".to_string()]);
+
+        // Paused after a minute, resumed later: the pause doesn't count
+        assert!(replay.feed(&event(60, "PAUSED_PLAYBACK", "x-sonos-spotify:one", "Get Lucky")).unwrap().is_empty());
+        assert!(replay.feed(&event(600, "PLAYING", "x-sonos-spotify:one", "Get Lucky")).unwrap().is_empty());
+        let scrobbled = replay.feed(&event(640, "PLAYING", "x-sonos-spotify:one", "Get Lucky")).unwrap();
+        assert_eq!(scrobbled, vec!["Kitchen: scrobbled Daft Punk - Get Lucky after 0:01:40".to_string()]);
+
+        replay.feed(&event(700, "PLAYING", "x-sonos-spotify:two", "Lose Yourself to Dance")).unwrap();
+        let ended = replay.finish();
+        assert_eq!(
+            ended,
+            vec!["Kitchen: ended Daft Punk - Lose Yourself to Dance without scrobbling, played 0:00:00 of the 0:01:30 needed"
+                .to_string()]
+        );
+    }
+}
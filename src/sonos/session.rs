@@ -0,0 +1,574 @@
+use crate::config::ThresholdConfig;
+use crate::scrobble::{Confidence, MetadataSource, QuarantinedListen, Scrobble};
+use crate::sonos::soap::{MediaInfo, PositionInfo, TransportState};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// URI schemes Sonos uses for radio stations and other continuous streams
+const STREAM_URI_PREFIXES: &[&str] = &[
+    "x-rincon-mp3radio:",
+    "x-sonosapi-stream:",
+    "x-sonosapi-radio:",
+    "x-sonosapi-hls:",
+    "x-sonosapi-hls-static:",
+    "aac:",
+    "hls-radio:",
+];
+
+/// Placeholders some sources report instead of leaving a field empty
+const PLACEHOLDER_NAMES: &[&str] = &["unknown", "unknown artist", "unknown title", "<unknown>"];
+
+/// Tracks shorter than this are never scrobbled
+const MIN_SCROBBLE_DURATION: Duration = Duration::from_secs(30);
+/// How close to the track boundaries a position jump has to be to count as
+/// the track looping rather than a seek; a little over two poll intervals
+const REPEAT_TOLERANCE: Duration = Duration::from_secs(12);
+
+/// Someone handling a speaker by hand, which tells they are around
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interaction {
+    /// The volume was turned up or down
+    Volume,
+    /// The track was skipped before its end
+    Skip,
+}
+
+impl Interaction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Interaction::Volume => "volume",
+            Interaction::Skip => "skip",
+        }
+    }
+}
+
+/// What a speaker is playing, read from `GetPositionInfo` with the stream
+/// title already split into artist and title
+#[derive(Debug, Clone, PartialEq)]
+pub struct NowPlaying {
+    pub uri: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    /// Track length; unknown for streams
+    pub duration: Option<Duration>,
+    pub position: Option<Duration>,
+    /// Where the artist and title came from
+    pub source: MetadataSource,
+    /// Whether this is a radio station or other continuous stream
+    pub stream: bool,
+}
+
+impl NowPlaying {
+    pub fn from_position(info: &PositionInfo) -> Self {
+        let (artist, title) = track_fields(info);
+        Self {
+            uri: info.track_uri.clone(),
+            artist,
+            title,
+            album: info.album.clone().filter(|_| info.stream_content.is_none()),
+            duration: parse_hms(&info.duration).filter(|d| !d.is_zero()),
+            position: parse_hms(&info.position),
+            source: metadata_source(info),
+            stream: is_stream(info),
+        }
+    }
+}
+
+impl std::fmt::Display for NowPlaying {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => write!(f, "{} - {}", artist, title),
+            (Some(artist), None) => write!(f, "{}", artist),
+            (None, Some(title)) => write!(f, "{}", title),
+            (None, None) => write!(f, "Unknown Track"),
+        }
+    }
+}
+
+/// A single listen on a device: one track, or one song inside a stream or mix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenSession {
+    pub uri: String,
+    pub track_info: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    /// Track length; unknown for streams
+    pub duration: Option<Duration>,
+    /// Unix timestamp of when the listen started
+    pub started_at: i64,
+    /// Time actually spent playing, excluding pauses and seeks
+    pub played: Duration,
+    /// Set once the listen has been scrobbled, so it is only submitted once
+    pub scrobbled: bool,
+    /// Where the listen was played from
+    pub context: PlayContext,
+    /// How far the artist and title can be trusted, given their source
+    pub confidence: Confidence,
+    last_position: Option<Duration>,
+}
+
+impl ListenSession {
+    pub fn from_position(info: &PositionInfo) -> Self {
+        Self::new(NowPlaying::from_position(info))
+    }
+
+    pub fn new(playing: NowPlaying) -> Self {
+        Self {
+            track_info: playing.to_string(),
+            uri: playing.uri,
+            artist: playing.artist,
+            title: playing.title,
+            album: playing.album,
+            duration: playing.duration,
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64,
+            played: Duration::ZERO,
+            scrobbled: false,
+            context: PlayContext::default(),
+            confidence: Confidence::from_source(playing.source),
+            last_position: playing.position,
+        }
+    }
+
+    /// Whether `info` still belongs to this listen.
+    ///
+    /// Within a stream every change of the stream title starts a new listen, so
+    /// songs inside a DJ mix or radio show are logged individually. A blank
+    /// stream title (ads, station idents) does not end the current listen.
+    /// With repeat-one the URI never changes, so a track starting over counts
+    /// as a new listen too.
+    pub fn continues_with(&self, info: &PositionInfo) -> bool {
+        if self.uri != info.track_uri || self.restarted(info) {
+            return false;
+        }
+
+        if is_stream(info) && stream_title(info).is_none() {
+            return true;
+        }
+
+        describe_track(info) == self.track_info
+    }
+
+    /// Adds the playback progress since the last poll, `elapsed` ago.
+    ///
+    /// Progress is measured from the track position and capped by the wall
+    /// clock, so pausing adds nothing and seeking forward is not counted.
+    pub fn advance(&mut self, info: &PositionInfo, elapsed: Duration) {
+        let position = parse_hms(&info.position);
+        if let (Some(previous), Some(current)) = (self.last_position, position) {
+            if current > previous {
+                // Positions only have one-second resolution
+                self.played += (current - previous).min(elapsed + Duration::from_secs(1));
+            }
+        }
+        self.last_position = position;
+    }
+
+    /// Catches up after events were missed or the speaker was silent for a
+    /// while, `elapsed` since the last poll.
+    ///
+    /// Progress over such a gap can't be checked against the polls in
+    /// between, so it is only counted when the speaker is still playing, and
+    /// then at most the wall-clock time. A speaker that is paused or stopped
+    /// now may have stopped at any point during the gap; that time is not
+    /// counted rather than risking a scrobble for a listen that didn't happen.
+    pub fn resync(&mut self, info: &PositionInfo, state: TransportState, elapsed: Duration) {
+        let position = parse_hms(&info.position);
+        if let (TransportState::Playing, Some(previous), Some(current)) = (state, self.last_position, position) {
+            if current > previous {
+                self.played += (current - previous).min(elapsed);
+            }
+        }
+        self.last_position = position;
+    }
+
+    /// Whether the listen ended well before the end of the track, as when
+    /// someone skips to the next one. Streams have no end to go by.
+    pub fn skipped(&self) -> bool {
+        let (Some(duration), Some(position)) = (self.duration, self.last_position) else {
+            return false;
+        };
+        position + REPEAT_TOLERANCE < duration
+    }
+
+    /// Whether the position jumped from the end of the track back to its
+    /// start, as happens when a track repeats
+    fn restarted(&self, info: &PositionInfo) -> bool {
+        let (Some(duration), Some(previous), Some(current)) =
+            (self.duration, self.last_position, parse_hms(&info.position))
+        else {
+            return false;
+        };
+        previous + REPEAT_TOLERANCE >= duration && current < previous && current < REPEAT_TOLERANCE
+    }
+
+    /// Play time after which the listen counts as a scrobble, by default
+    /// half the track but no more than four minutes. Streams have no length,
+    /// so their songs need the full four minutes unless their source has its
+    /// own threshold.
+    pub fn scrobble_threshold(&self, threshold: &ThresholdConfig) -> Duration {
+        threshold.play_time(self.context.kind(), self.duration)
+    }
+
+    /// Whether the track is too short to ever be scrobbled
+    pub fn too_short(&self) -> bool {
+        self.duration.is_some_and(|d| d < MIN_SCROBBLE_DURATION)
+    }
+
+    pub fn meets_threshold(&self, threshold: &ThresholdConfig) -> bool {
+        if self.too_short() {
+            return false;
+        }
+        self.played >= self.scrobble_threshold(threshold)
+    }
+
+    /// The listen as a scrobble for `device`; `None` when the artist or title
+    /// is unknown, since scrobbling services require both
+    pub fn to_scrobble(&self, device: &str) -> Option<Scrobble> {
+        Some(Scrobble {
+            album: self.album.clone(),
+            duration: self.duration,
+            confidence: self.confidence,
+            track_uri: Some(self.uri.clone()),
+            ..Scrobble::new(device, known(&self.artist)?, known(&self.title)?, self.started_at)
+        })
+    }
+
+    /// The listen for the quarantine, for when [`Self::to_scrobble`] fails
+    pub fn to_quarantined(&self, device: &str) -> QuarantinedListen {
+        QuarantinedListen {
+            id: 0,
+            device: device.to_string(),
+            uri: self.uri.clone(),
+            track_info: self.track_info.clone(),
+            artist: known(&self.artist),
+            title: known(&self.title),
+            album: self.album.clone(),
+            started_at: self.started_at,
+            duration: self.duration,
+            confidence: self.confidence,
+        }
+    }
+}
+
+/// `value` unless it is blank or a placeholder like "Unknown Artist"
+fn known(value: &Option<String>) -> Option<String> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty() && !PLACEHOLDER_NAMES.contains(&value.to_lowercase().as_str()))
+        .map(str::to_string)
+}
+
+/// The source a listen was played from: the queue, a saved playlist, a radio
+/// station or an input.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlayContext {
+    pub uri: String,
+    /// Playlist or station name, when the speaker reports one
+    pub name: Option<String>,
+    /// Position of the track in the queue, counting from 1
+    pub queue_position: Option<u32>,
+}
+
+impl PlayContext {
+    pub fn new(media: &MediaInfo, info: &PositionInfo) -> Self {
+        let mut context = Self {
+            uri: media.current_uri.clone(),
+            name: media.title.clone().filter(|name| !name.trim().is_empty()),
+            queue_position: None,
+        };
+        if matches!(context.kind(), "queue" | "playlist") {
+            context.queue_position = info.track_number;
+        }
+        context
+    }
+
+    /// Kind of source, derived from the URI scheme
+    pub fn kind(&self) -> &'static str {
+        let uri = self.uri.as_str();
+        if uri.is_empty() {
+            "unknown"
+        } else if uri.starts_with("x-rincon-queue:") {
+            "queue"
+        } else if uri.starts_with("x-rincon-playlist:") || uri.contains("savedqueues.rsq") {
+            "playlist"
+        } else if uri.starts_with("x-rincon-stream:") {
+            "line-in"
+        } else if uri.starts_with("x-sonos-htastream:") {
+            "tv"
+        } else if uri.starts_with("x-rincon:") {
+            // Grouped with another speaker, which is the one that knows the source
+            "group"
+        } else if STREAM_URI_PREFIXES.iter().any(|prefix| uri.starts_with(prefix)) {
+            "radio"
+        } else {
+            "track"
+        }
+    }
+}
+
+/// Whether plays from `source`, a [`PlayContext::kind`] or "alarm", were
+/// picked by the listener rather than by a station, an input or an alarm
+pub fn chosen_by_user(source: &str) -> bool {
+    !matches!(source, "radio" | "line-in" | "tv" | "alarm")
+}
+
+pub fn is_stream(info: &PositionInfo) -> bool {
+    STREAM_URI_PREFIXES
+        .iter()
+        .any(|prefix| info.track_uri.starts_with(prefix))
+        || matches!(info.duration.as_str(), "" | "0:00:00" | "NOT_IMPLEMENTED")
+}
+
+pub fn describe_track(info: &PositionInfo) -> String {
+    NowPlaying::from_position(info).to_string()
+}
+
+/// Artist and title of what is playing, preferring the stream title when the
+/// source announces one
+fn track_fields(info: &PositionInfo) -> (Option<String>, Option<String>) {
+    let Some(content) = stream_title(info) else {
+        return (info.artist.clone(), info.title.clone());
+    };
+
+    if let Some(fields) = content.strip_prefix("TYPE=SNG|") {
+        let field = |name: &str| {
+            fields
+                .split('|')
+                .find_map(|part| part.strip_prefix(name))
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        return (field("ARTIST "), field("TITLE "));
+    }
+
+    match content.split_once(" - ") {
+        Some((artist, title)) => (Some(artist.trim().to_string()), Some(title.trim().to_string())),
+        None => (None, Some(content)),
+    }
+}
+
+/// Where [`track_fields`] takes the artist and title from
+fn metadata_source(info: &PositionInfo) -> MetadataSource {
+    match stream_title(info) {
+        Some(content) if content.starts_with("TYPE=SNG|") => MetadataSource::StreamFields,
+        Some(_) => MetadataSource::StreamTitle,
+        None => MetadataSource::Didl,
+    }
+}
+
+/// The stream title, which is either plain `Artist - Title` or the
+/// pipe-separated `TYPE=SNG|TITLE ...|ARTIST ...|ALBUM ...` form some services use.
+/// Pipe-separated titles without a song title count as blank.
+fn stream_title(info: &PositionInfo) -> Option<String> {
+    let content = info.stream_content.as_deref()?.trim();
+    if content.is_empty() {
+        return None;
+    }
+
+    if content.starts_with("TYPE=SNG|") && !content.split('|').any(|part| {
+        part.strip_prefix("TITLE ").is_some_and(|title| !title.trim().is_empty())
+    }) {
+        return None;
+    }
+
+    Some(content.to_string())
+}
+
+/// Parses Sonos `H:MM:SS` times; `NOT_IMPLEMENTED` and other values give `None`
+pub fn parse_hms(value: &str) -> Option<Duration> {
+    let mut seconds = 0u64;
+    let mut parts = 0;
+    for part in value.split(':') {
+        seconds = seconds * 60 + part.parse::<u64>().ok()?;
+        parts += 1;
+    }
+    (parts == 3).then(|| Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(content: &str) -> PositionInfo {
+        PositionInfo {
+            track_uri: "x-sonosapi-stream:s1234?sid=254".to_string(),
+            duration: "0:00:00".to_string(),
+            stream_content: Some(content.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_stream_title_change_starts_new_listen() {
+        let session = ListenSession::from_position(&stream("Moderat - Bad Kingdom"));
+        assert_eq!(session.track_info, "Moderat - Bad Kingdom");
+
+        assert!(session.continues_with(&stream("Moderat - Bad Kingdom")));
+        assert!(session.continues_with(&stream("")));
+        assert!(!session.continues_with(&stream("Jon Hopkins - Emerald Rush")));
+    }
+
+    #[test]
+    fn test_now_playing_from_stream() {
+        let playing = NowPlaying::from_position(&stream("TYPE=SNG|TITLE Bad Kingdom|ARTIST Moderat|ALBUM II"));
+        assert_eq!(playing.to_string(), "Moderat - Bad Kingdom");
+        assert_eq!((playing.source, playing.stream, playing.duration), (MetadataSource::StreamFields, true, None));
+    }
+
+    #[test]
+    fn test_track_change_starts_new_listen() {
+        let track = PositionInfo {
+            track_uri: "x-sonos-spotify:track1".to_string(),
+            duration: "0:03:30".to_string(),
+            title: Some("Get Lucky".to_string()),
+            artist: Some("Daft Punk".to_string()),
+            ..Default::default()
+        };
+        let session = ListenSession::from_position(&track);
+        assert!(session.continues_with(&track));
+
+        let next = PositionInfo {
+            track_uri: "x-sonos-spotify:track2".to_string(),
+            ..track
+        };
+        assert!(!session.continues_with(&next));
+    }
+
+    #[test]
+    fn test_repeat_one_starts_new_listen() {
+        let at = |position: &str| PositionInfo {
+            track_uri: "x-sonos-spotify:track1".to_string(),
+            duration: "0:03:00".to_string(),
+            position: position.to_string(),
+            title: Some("Get Lucky".to_string()),
+            ..Default::default()
+        };
+
+        let mut session = ListenSession::from_position(&at("0:02:30"));
+        // Seeking back from the middle of the track is the same listen
+        assert!(session.continues_with(&at("0:00:05")));
+
+        assert!(session.skipped());
+
+        session.advance(&at("0:02:55"), Duration::from_secs(25));
+        assert!(session.continues_with(&at("0:02:59")));
+        assert!(!session.continues_with(&at("0:00:03")));
+        // Played to the end
+        assert!(!session.skipped());
+    }
+
+    #[test]
+    fn test_describe_track_pipe_separated_stream_content() {
+        let info = stream("TYPE=SNG|TITLE Windowlicker|ARTIST Aphex Twin|ALBUM ");
+        assert_eq!(describe_track(&info), "Aphex Twin - Windowlicker");
+
+        let session = ListenSession::from_position(&info);
+        assert_eq!(session.artist.as_deref(), Some("Aphex Twin"));
+        assert_eq!(session.title.as_deref(), Some("Windowlicker"));
+        assert_eq!(session.confidence, Confidence::from_source(MetadataSource::StreamFields));
+
+        let plain = ListenSession::from_position(&stream("Aphex Twin - Windowlicker"));
+        assert_eq!(plain.confidence.lowest(), MetadataSource::StreamTitle.confidence());
+    }
+
+    #[test]
+    fn test_scrobble_threshold() {
+        let at = |position: &str| PositionInfo {
+            track_uri: "x-sonos-spotify:track1".to_string(),
+            duration: "0:03:00".to_string(),
+            position: position.to_string(),
+            ..Default::default()
+        };
+
+        let threshold = ThresholdConfig::default();
+        let mut session = ListenSession::from_position(&at("0:00:00"));
+        assert_eq!(session.scrobble_threshold(&threshold), Duration::from_secs(90));
+
+        // Seeking ahead only counts the polling interval
+        session.advance(&at("0:02:00"), Duration::from_secs(5));
+        assert_eq!(session.played, Duration::from_secs(6));
+        assert!(!session.meets_threshold(&threshold));
+
+        for position in ["0:02:30", "0:03:00"] {
+            session.advance(&at(position), Duration::from_secs(30));
+        }
+        assert_eq!(session.played, Duration::from_secs(66));
+
+        // Pausing keeps the position, so nothing is added
+        session.advance(&at("0:03:00"), Duration::from_secs(30));
+        assert_eq!(session.played, Duration::from_secs(66));
+        assert!(!session.meets_threshold(&threshold));
+
+        session.played = Duration::from_secs(90);
+        assert!(session.meets_threshold(&threshold));
+    }
+
+    #[test]
+    fn test_resync_after_gap() {
+        let at = |position: &str| PositionInfo {
+            track_uri: "x-sonos-spotify:track1".to_string(),
+            duration: "0:06:00".to_string(),
+            position: position.to_string(),
+            ..Default::default()
+        };
+
+        let mut session = ListenSession::from_position(&at("0:00:00"));
+        session.resync(&at("0:01:30"), TransportState::Playing, Duration::from_secs(60));
+        assert_eq!(session.played, Duration::from_secs(60));
+
+        // Paused now: the gap may have been spent paused, so none of it counts
+        session.resync(&at("0:02:30"), TransportState::Paused, Duration::from_secs(120));
+        assert_eq!(session.played, Duration::from_secs(60));
+
+        session.advance(&at("0:02:35"), Duration::from_secs(5));
+        assert_eq!(session.played, Duration::from_secs(65));
+    }
+
+    #[test]
+    fn test_play_context() {
+        let info = PositionInfo { track_number: Some(7), ..Default::default() };
+        let queue = MediaInfo {
+            current_uri: "x-rincon-queue:RINCON_000E58123456#0".to_string(),
+            ..Default::default()
+        };
+        let context = PlayContext::new(&queue, &info);
+        assert_eq!(context.kind(), "queue");
+        assert_eq!(context.queue_position, Some(7));
+
+        let radio = MediaInfo {
+            current_uri: "x-sonosapi-stream:s1234?sid=254".to_string(),
+            title: Some("Radio Paradise".to_string()),
+            track_count: None,
+        };
+        let context = PlayContext::new(&radio, &info);
+        assert_eq!(context.kind(), "radio");
+        assert_eq!(context.name.as_deref(), Some("Radio Paradise"));
+        assert_eq!(context.queue_position, None);
+    }
+
+    #[test]
+    fn test_placeholder_artist_is_not_scrobbled() {
+        let info = PositionInfo {
+            track_uri: "x-file-cifs://nas/track.mp3".to_string(),
+            title: Some("Says".to_string()),
+            artist: Some("Unknown Artist".to_string()),
+            ..Default::default()
+        };
+        let session = ListenSession::from_position(&info);
+        assert!(session.to_scrobble("Kitchen").is_none());
+        assert_eq!(session.to_quarantined("Kitchen").reason(), "missing artist");
+    }
+
+    #[test]
+    fn test_parse_hms() {
+        assert_eq!(parse_hms("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_hms("NOT_IMPLEMENTED"), None);
+        assert_eq!(parse_hms(""), None);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::config::SleepConfig;
+use crate::notify::Telegram;
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveTime, TimeZone, Timelike};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// What is known about the listener in a room
+#[derive(Debug, Default)]
+struct Room {
+    /// Name as configured, for messages
+    name: String,
+    playing: bool,
+    /// When playback started or someone last touched the room
+    last_interaction: Option<DateTime<Local>>,
+    asleep: bool,
+}
+
+/// Tells when the listener probably fell asleep with music playing: a room
+/// playing at night for a while with nobody starting, pausing or otherwise
+/// touching it. Its listens aren't scrobbled until someone does, or confirms
+/// they are still listening.
+#[derive(Default)]
+pub struct SleepDetector {
+    config: Option<SleepConfig>,
+    /// By lowercase room name
+    rooms: Mutex<BTreeMap<String, Room>>,
+    telegram: Option<Arc<Telegram>>,
+}
+
+impl SleepDetector {
+    pub fn new(config: &SleepConfig) -> Self {
+        Self { config: Some(*config), ..Self::default() }
+    }
+
+    /// Asks in the Telegram chat whether rooms taken to be asleep are still
+    /// listened to
+    pub fn with_telegram(mut self, telegram: Arc<Telegram>) -> Self {
+        self.telegram = Some(telegram);
+        self
+    }
+
+    /// Notes whether `room` is playing, taking the listener to be asleep once
+    /// the room played long enough at night without interaction
+    pub fn playing(&self, room: &str, playing: bool) {
+        if !self.playing_at(room, playing, Local::now()) {
+            return;
+        }
+        let minutes = self.config.map(|config| config.minutes).unwrap_or_default();
+        let text = format!(
+            "{} has played for {} minutes tonight without anyone touching it, so it isn't scrobbled any more. \
+             Still listening? Send /awake {}",
+            room, minutes, room
+        );
+        info!("{}", text);
+        if let Some(telegram) = self.telegram.clone() {
+            tokio::spawn(async move {
+                if let Err(e) = telegram.send_message(&text).await {
+                    warn!("Telegram: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Notes that someone touched `room`, so the listener is awake
+    pub fn interaction(&self, room: &str) {
+        self.interaction_at(room, Local::now());
+    }
+
+    /// Whether listens in `room` are not scrobbled as the listener is
+    /// probably asleep
+    pub fn asleep(&self, room: &str) -> bool {
+        self.rooms.lock().unwrap().get(&room.to_lowercase()).is_some_and(|room| room.asleep)
+    }
+
+    /// Confirms the listener in `room`, or in every room, is awake. Returns
+    /// the rooms that were taken to be asleep.
+    pub fn awake(&self, room: Option<&str>) -> Vec<String> {
+        let now = Local::now();
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut woken = Vec::new();
+        for (key, state) in rooms.iter_mut() {
+            if room.is_none_or(|room| room.to_lowercase() == *key) && state.asleep {
+                state.asleep = false;
+                state.last_interaction = Some(now);
+                woken.push(state.name.clone());
+            }
+        }
+        woken
+    }
+
+    /// Returns whether the listener in `room` just fell asleep
+    fn playing_at(&self, room: &str, playing: bool, now: DateTime<Local>) -> bool {
+        let Some(config) = self.config else {
+            return false;
+        };
+        let mut rooms = self.rooms.lock().unwrap();
+        let state = rooms.entry(room.to_lowercase()).or_default();
+        state.name = room.to_string();
+        if playing && !state.playing {
+            // Starting playback is an interaction too
+            state.last_interaction = Some(now);
+            state.asleep = false;
+        }
+        state.playing = playing;
+        if !playing || state.asleep {
+            return false;
+        }
+        let Some(night_start) = night_start(&config, now) else {
+            return false;
+        };
+        let quiet_since = state.last_interaction.map_or(night_start, |last| last.max(night_start));
+        state.asleep = now - quiet_since >= ChronoDuration::minutes(config.minutes as i64);
+        state.asleep
+    }
+
+    fn interaction_at(&self, room: &str, now: DateTime<Local>) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let state = rooms.entry(room.to_lowercase()).or_default();
+        state.name = room.to_string();
+        state.last_interaction = Some(now);
+        if state.asleep {
+            info!("{} was touched, scrobbling it again", room);
+            state.asleep = false;
+        }
+    }
+}
+
+/// When the night `now` is in started, or `None` during the day
+fn night_start(config: &SleepConfig, now: DateTime<Local>) -> Option<DateTime<Local>> {
+    let (from, until, hour) = (config.from_hour, config.until_hour, now.hour());
+    let today = now.date_naive();
+    let day = match from > until {
+        // The night spans midnight
+        true if hour >= from => today,
+        true if hour < until => today.pred_opt()?,
+        false if from <= hour && hour < until => today,
+        _ => return None,
+    };
+    let start = day.and_time(NaiveTime::from_hms_opt(from, 0, 0)?);
+    Local.from_local_datetime(&start).earliest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asleep_after_quiet_night_playback() {
+        let detector = SleepDetector::new(&SleepConfig { from_hour: 23, until_hour: 6, minutes: 60 });
+        let at = |hour, minute| Local.with_ymd_and_hms(2026, 3, 6, hour, minute, 0).unwrap();
+
+        // Playing since the evening; the night only counts from 23:00
+        assert!(!detector.playing_at("Bedroom", true, at(21, 0)));
+        assert!(!detector.playing_at("Bedroom", true, at(23, 30)));
+        detector.interaction_at("bedroom", at(23, 40));
+        assert!(!detector.playing_at("Bedroom", true, at(0, 30) + ChronoDuration::days(1)));
+        assert!(detector.playing_at("Bedroom", true, at(0, 40) + ChronoDuration::days(1)));
+        assert!(detector.asleep("bedroom"));
+        // Told once
+        assert!(!detector.playing_at("Bedroom", true, at(0, 50) + ChronoDuration::days(1)));
+
+        assert_eq!(detector.awake(None), vec!["Bedroom".to_string()]);
+        assert!(!detector.asleep("Bedroom"));
+        assert!(detector.awake(Some("Bedroom")).is_empty());
+
+        // Never during the day
+        assert!(!detector.playing_at("Kitchen", true, at(8, 0)));
+        assert!(!detector.playing_at("Kitchen", true, at(18, 0)));
+        assert!(!SleepDetector::default().playing_at("Kitchen", true, at(23, 59)));
+    }
+}
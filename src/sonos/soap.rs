@@ -0,0 +1,720 @@
+use crate::error::{Error, Result};
+use crate::retry::RetryPolicy;
+use crate::sonos::alarm::Alarm;
+use crate::sonos::firmware::SoftwareVersion;
+use chrono::NaiveTime;
+use quick_xml::events::Event;
+use log::warn;
+use quick_xml::Reader;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const AVTRANSPORT_ENDPOINT: &str = "/MediaRenderer/AVTransport/Control";
+const AVTRANSPORT_SERVICE: &str = "urn:schemas-upnp-org:service:AVTransport:1";
+const CONTENT_DIRECTORY_ENDPOINT: &str = "/MediaServer/ContentDirectory/Control";
+const CONTENT_DIRECTORY_SERVICE: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+const ALARM_CLOCK_ENDPOINT: &str = "/AlarmClock/Control";
+const ALARM_CLOCK_SERVICE: &str = "urn:schemas-upnp-org:service:AlarmClock:1";
+const RENDERING_CONTROL_ENDPOINT: &str = "/MediaRenderer/RenderingControl/Control";
+const RENDERING_CONTROL_SERVICE: &str = "urn:schemas-upnp-org:service:RenderingControl:1";
+const DEVICE_DESCRIPTION: &str = "/xml/device_description.xml";
+/// The `LastChange` variables read from events; the rest, among them large
+/// ones like `NextTrackMetaData`, are skipped without being copied
+const LAST_CHANGE_VARIABLES: [&str; 5] =
+    ["TransportState", "CurrentTrack", "CurrentTrackURI", "CurrentTrackDuration", "CurrentTrackMetaData"];
+
+/// Track details as reported by `AVTransport#GetPositionInfo`.
+///
+/// Unlike `rusty_sonos::responses::CurrentTrack` this keeps the
+/// `r:streamContent` field, which radio stations and continuous mixes use to
+/// announce the song that is currently playing.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PositionInfo {
+    pub track_uri: String,
+    pub duration: String,
+    pub position: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub stream_content: Option<String>,
+    /// Position of the track in the queue, counting from 1
+    pub track_number: Option<u32>,
+    /// Cover art, usually a path on the speaker like `/getaa?s=1&u=...`
+    pub album_art_uri: Option<String>,
+}
+
+/// What the speaker is playing from, as reported by `AVTransport#GetMediaInfo`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaInfo {
+    /// The queue, playlist, station or input the speaker is set to
+    pub current_uri: String,
+    /// Playlist or station name from the URI metadata
+    pub title: Option<String>,
+    /// Number of tracks in the queue or playlist
+    pub track_count: Option<u32>,
+}
+
+/// Playback state as reported by `AVTransport#GetTransportInfo`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportState {
+    Playing,
+    Paused,
+    Stopped,
+    Transitioning,
+}
+
+impl TransportState {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value {
+            "PLAYING" => Some(Self::Playing),
+            "PAUSED_PLAYBACK" => Some(Self::Paused),
+            "STOPPED" | "NO_MEDIA_PRESENT" => Some(Self::Stopped),
+            "TRANSITIONING" => Some(Self::Transitioning),
+            _ => None,
+        }
+    }
+}
+
+/// Changes announced by an AVTransport `LastChange` event. Fields the event
+/// doesn't mention are `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransportEvent {
+    pub state: Option<TransportState>,
+    /// The current track, without a position since events don't carry one
+    pub track: Option<PositionInfo>,
+}
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Per-action counters for SOAP calls made by a [`SoapClient`]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CallMetrics {
+    pub calls: u64,
+    pub retries: u64,
+    pub failures: u64,
+}
+
+/// AVTransport SOAP client for a single speaker.
+///
+/// Sonos devices occasionally reset connections or stop answering for a few
+/// seconds, so calls that fail before a response arrives are retried.
+pub struct SoapClient {
+    base_url: String,
+    client: reqwest::Client,
+    /// For calls that failed on the network level
+    retry: RetryPolicy,
+    metrics: Mutex<HashMap<&'static str, CallMetrics>>,
+}
+
+impl SoapClient {
+    pub fn new(ip_addr: &str) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        Ok(Self {
+            base_url: format!("http://{}:1400", ip_addr),
+            client,
+            retry: RetryPolicy::DEVICE,
+            metrics: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Snapshot of the call counters, keyed by SOAP action
+    pub fn metrics(&self) -> HashMap<&'static str, CallMetrics> {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    pub async fn get_position_info(&self) -> Result<PositionInfo> {
+        let body = self.call("GetPositionInfo", "<InstanceID>0</InstanceID>").await?;
+        parse_position_info(&body)
+    }
+
+    pub async fn get_media_info(&self) -> Result<MediaInfo> {
+        let body = self.call("GetMediaInfo", "<InstanceID>0</InstanceID>").await?;
+        parse_media_info(&body)
+    }
+
+    pub async fn get_transport_info(&self) -> Result<TransportState> {
+        let body = self.call("GetTransportInfo", "<InstanceID>0</InstanceID>").await?;
+        parse_transport_info(&body)
+    }
+
+    pub async fn pause(&self) -> Result<()> {
+        self.call("Pause", "<InstanceID>0</InstanceID>").await?;
+        Ok(())
+    }
+
+    /// Skips to the next track
+    pub async fn next(&self) -> Result<()> {
+        self.call("Next", "<InstanceID>0</InstanceID>").await?;
+        Ok(())
+    }
+
+    /// Removes the queue item at `position`, counting from 1
+    pub async fn remove_from_queue(&self, position: u32) -> Result<()> {
+        let arguments = format!("<InstanceID>0</InstanceID><ObjectID>Q:0/{position}</ObjectID><UpdateID>0</UpdateID>");
+        self.call("RemoveTrackFromQueue", &arguments).await?;
+        Ok(())
+    }
+
+    pub async fn clear_queue(&self) -> Result<()> {
+        self.call("RemoveAllTracksFromQueue", "<InstanceID>0</InstanceID>").await?;
+        Ok(())
+    }
+
+    /// The master volume, 0 to 100
+    pub async fn get_volume(&self) -> Result<u16> {
+        let arguments = "<InstanceID>0</InstanceID><Channel>Master</Channel>";
+        let body = self
+            .call_service(RENDERING_CONTROL_ENDPOINT, RENDERING_CONTROL_SERVICE, "GetVolume", arguments)
+            .await?;
+        parse_volume(&body)
+    }
+
+    /// Up to `count` tracks of the queue from `start` on, counting from 0.
+    /// Only the track fields are filled in.
+    pub async fn browse_queue(&self, start: u32, count: u32) -> Result<Vec<PositionInfo>> {
+        let arguments = format!(
+            "<ObjectID>Q:0</ObjectID><BrowseFlag>BrowseDirectChildren</BrowseFlag><Filter>*</Filter>\
+             <StartingIndex>{start}</StartingIndex><RequestedCount>{count}</RequestedCount><SortCriteria></SortCriteria>"
+        );
+        let body = self
+            .call_service(CONTENT_DIRECTORY_ENDPOINT, CONTENT_DIRECTORY_SERVICE, "Browse", &arguments)
+            .await?;
+        parse_queue(&body, start)
+    }
+
+    /// The alarms of the whole household; any speaker lists all of them
+    pub async fn list_alarms(&self) -> Result<Vec<Alarm>> {
+        let body = self.call_service(ALARM_CLOCK_ENDPOINT, ALARM_CLOCK_SERVICE, "ListAlarms", "").await?;
+        parse_alarms(&body)
+    }
+
+    /// The software version from the speaker's device description
+    pub async fn software_version(&self) -> Result<SoftwareVersion> {
+        let url = format!("{}{}", self.base_url, DEVICE_DESCRIPTION);
+        let body = self.client.get(&url).send().await?.error_for_status()?.text().await?;
+        let version = element_texts(&body)?.remove("softwareVersion");
+        version
+            .as_deref()
+            .and_then(SoftwareVersion::parse)
+            .ok_or_else(|| Error::Soap(format!("no software version in {}: {:?}", url, version)))
+    }
+
+    async fn call(&self, action: &'static str, arguments: &str) -> Result<String> {
+        self.call_service(AVTRANSPORT_ENDPOINT, AVTRANSPORT_SERVICE, action, arguments).await
+    }
+
+    async fn call_service(
+        &self,
+        endpoint: &str,
+        service: &str,
+        action: &'static str,
+        arguments: &str,
+    ) -> Result<String> {
+        let envelope = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body>\
+             </s:Envelope>"
+        );
+
+        let result = self
+            .retry
+            .run(
+                || self.send(endpoint, service, action, &envelope),
+                |e, attempt| {
+                    if !is_transient(e) {
+                        return false;
+                    }
+                    self.record(action, |m| m.retries += 1);
+                    warn!(
+                        "{} to {} failed ({}), retrying ({}/{})",
+                        action, self.base_url, e, attempt, self.retry.max_retries
+                    );
+                    true
+                },
+            )
+            .await;
+
+        self.record(action, |m| m.calls += 1);
+        let (status, body) = result.map_err(|e| {
+            self.record(action, |m| m.failures += 1);
+            Error::from(e)
+        })?;
+
+        if !status.is_success() {
+            self.record(action, |m| m.failures += 1);
+            return Err(Error::Soap(format!("{} failed with {}: {}", action, status, body)));
+        }
+
+        Ok(body)
+    }
+
+    async fn send(
+        &self,
+        endpoint: &str,
+        service: &str,
+        action: &str,
+        envelope: &str,
+    ) -> reqwest::Result<(StatusCode, String)> {
+        let response = self
+            .client
+            .post(format!("{}{}", self.base_url, endpoint))
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPACTION", format!("\"{}#{}\"", service, action))
+            .body(envelope.to_string())
+            .send()
+            .await?;
+
+        let status = response.status();
+        Ok((status, response.text().await?))
+    }
+
+    fn record(&self, action: &'static str, update: impl FnOnce(&mut CallMetrics)) {
+        update(self.metrics.lock().unwrap().entry(action).or_default());
+    }
+}
+
+/// Connection resets, refused connections and timeouts are worth retrying;
+/// a response from the speaker, even an error response, is not.
+fn is_transient(e: &reqwest::Error) -> bool {
+    e.is_timeout() || e.is_connect() || e.is_request() || e.is_body()
+}
+
+pub(crate) fn parse_position_info(xml: &str) -> Result<PositionInfo> {
+    let response = element_texts(xml)?;
+
+    // TrackMetaData is an escaped DIDL-Lite document embedded in the response
+    let metadata = match response.get("TrackMetaData") {
+        Some(didl) if didl != "NOT_IMPLEMENTED" => element_texts(didl)?,
+        _ => HashMap::new(),
+    };
+
+    let field = |map: &HashMap<String, String>, name: &str| map.get(name).cloned();
+
+    Ok(PositionInfo {
+        track_uri: field(&response, "TrackURI").unwrap_or_default(),
+        duration: field(&response, "TrackDuration").unwrap_or_default(),
+        position: field(&response, "RelTime").unwrap_or_default(),
+        title: field(&metadata, "title"),
+        artist: field(&metadata, "creator"),
+        album: field(&metadata, "album"),
+        stream_content: field(&metadata, "streamContent"),
+        track_number: response.get("Track").and_then(|n| n.parse().ok()).filter(|&n| n > 0),
+        album_art_uri: field(&metadata, "albumArtURI"),
+    })
+}
+
+pub(crate) fn parse_media_info(xml: &str) -> Result<MediaInfo> {
+    let response = element_texts(xml)?;
+
+    let metadata = match response.get("CurrentURIMetaData") {
+        Some(didl) if didl != "NOT_IMPLEMENTED" => element_texts(didl)?,
+        _ => HashMap::new(),
+    };
+
+    Ok(MediaInfo {
+        current_uri: response.get("CurrentURI").cloned().unwrap_or_default(),
+        title: metadata.get("title").cloned(),
+        track_count: response.get("NrTracks").and_then(|n| n.parse().ok()).filter(|&n| n > 0),
+    })
+}
+
+pub(crate) fn parse_transport_info(xml: &str) -> Result<TransportState> {
+    let response = element_texts(xml)?;
+    let state = response.get("CurrentTransportState").map(String::as_str).unwrap_or_default();
+    TransportState::parse(state).ok_or_else(|| Error::Soap(format!("unknown transport state {:?}", state)))
+}
+
+pub(crate) fn parse_volume(xml: &str) -> Result<u16> {
+    let response = element_texts(xml)?;
+    let volume = response.get("CurrentVolume").map(String::as_str).unwrap_or_default();
+    volume.parse().map_err(|_| Error::Soap(format!("invalid volume {:?}", volume)))
+}
+
+/// Parses a ContentDirectory `Browse` of the queue, whose `Result` holds an
+/// escaped DIDL-Lite document with an `item` per track. `start` is the index
+/// of the first one.
+/// Parses the `<Alarm>` elements of a ListAlarms response. Alarms with
+/// times that don't parse are skipped.
+pub(crate) fn parse_alarms(xml: &str) -> Result<Vec<Alarm>> {
+    let Some(list) = element_texts(xml)?.remove("CurrentAlarmList") else {
+        return Ok(Vec::new());
+    };
+
+    let mut alarms = Vec::new();
+    let mut reader = Reader::from_str(&list);
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"Alarm" => {
+                let attribute = |name: &str| -> Result<String> {
+                    Ok(match e.try_get_attribute(name)? {
+                        Some(value) => value.unescape_value()?.into_owned(),
+                        None => String::new(),
+                    })
+                };
+                let start = NaiveTime::parse_from_str(&attribute("StartTime")?, "%H:%M:%S");
+                let duration = NaiveTime::parse_from_str(&attribute("Duration")?, "%H:%M:%S");
+                let (Ok(start), Ok(duration)) = (start, duration) else {
+                    continue;
+                };
+                alarms.push(Alarm {
+                    room_id: attribute("RoomUUID")?,
+                    start,
+                    duration: (duration - NaiveTime::MIN).to_std().unwrap_or_default(),
+                    recurrence: attribute("Recurrence")?,
+                    enabled: attribute("Enabled")? == "1",
+                });
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(alarms)
+}
+
+pub(crate) fn parse_queue(xml: &str, start: u32) -> Result<Vec<PositionInfo>> {
+    let Some(didl) = element_texts(xml)?.remove("Result") else {
+        return Ok(Vec::new());
+    };
+
+    let mut tracks = Vec::new();
+    let mut current: Option<String> = None;
+    let mut reader = Reader::from_str(&didl);
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+                if name == "item" {
+                    tracks.push(PositionInfo {
+                        track_number: Some(start + tracks.len() as u32 + 1),
+                        ..Default::default()
+                    });
+                } else if name == "res" {
+                    if let (Some(track), Some(duration)) = (tracks.last_mut(), e.try_get_attribute("duration")?) {
+                        track.duration = duration.unescape_value()?.into_owned();
+                    }
+                }
+                current = Some(name);
+            }
+            Event::Text(text) => {
+                let (Some(track), Some(name)) = (tracks.last_mut(), current.take()) else {
+                    continue;
+                };
+                let value = text.unescape()?.into_owned();
+                match name.as_str() {
+                    "res" => track.track_uri = value,
+                    "title" => track.title = Some(value),
+                    "creator" => track.artist = Some(value),
+                    "album" => track.album = Some(value),
+                    _ => {}
+                }
+            }
+            Event::End(_) => current = None,
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(tracks)
+}
+
+/// Parses the body of an AVTransport NOTIFY: a property set whose
+/// `LastChange` holds an escaped event document with one element per
+/// changed variable, its value in the `val` attribute. Reads the body as
+/// received, without decoding it to a `String` first.
+pub(crate) fn parse_last_change(body: &[u8]) -> Result<TransportEvent> {
+    let Some(last_change) = last_change(body)? else {
+        return Ok(TransportEvent::default());
+    };
+
+    let mut values = HashMap::new();
+    let mut reader = Reader::from_str(&last_change);
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) => {
+                let local_name = e.local_name();
+                let Some(name) = LAST_CHANGE_VARIABLES.iter().find(|name| name.as_bytes() == local_name.as_ref())
+                else {
+                    continue;
+                };
+                if let Some(val) = e.try_get_attribute("val")? {
+                    values.entry(*name).or_insert(val.unescape_value()?.into_owned());
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    let track = values.get("CurrentTrackURI").map(|uri| {
+        let metadata = match values.get("CurrentTrackMetaData") {
+            Some(didl) if !didl.is_empty() && didl != "NOT_IMPLEMENTED" => {
+                element_texts(didl).unwrap_or_default()
+            }
+            _ => HashMap::new(),
+        };
+        PositionInfo {
+            track_uri: uri.clone(),
+            duration: values.get("CurrentTrackDuration").cloned().unwrap_or_default(),
+            position: String::new(),
+            title: metadata.get("title").cloned(),
+            artist: metadata.get("creator").cloned(),
+            album: metadata.get("album").cloned(),
+            stream_content: metadata.get("streamContent").cloned(),
+            track_number: values.get("CurrentTrack").and_then(|n| n.parse().ok()).filter(|&n| n > 0),
+            album_art_uri: metadata.get("albumArtURI").cloned(),
+        }
+    });
+
+    Ok(TransportEvent {
+        state: values.get("TransportState").and_then(|state| TransportState::parse(state)),
+        track,
+    })
+}
+
+/// The unescaped `LastChange` document of a property set. Stops reading at
+/// the end of it.
+fn last_change(body: &[u8]) -> Result<Option<String>> {
+    let mut reader = Reader::from_reader(body);
+    let mut in_last_change = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => in_last_change = e.local_name().as_ref() == b"LastChange",
+            Event::Text(text) if in_last_change => return Ok(Some(text.unescape()?.into_owned())),
+            Event::End(_) => in_last_change = false,
+            Event::Eof => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// Collects the text of every element keyed by its local name, keeping the
+/// first occurrence. Sonos responses are shallow enough for this to be
+/// unambiguous.
+fn element_texts(xml: &str) -> Result<HashMap<String, String>> {
+    let mut reader = Reader::from_str(xml);
+    let mut texts = HashMap::new();
+    let mut current: Option<String> = None;
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                current = Some(String::from_utf8_lossy(e.local_name().as_ref()).into_owned());
+            }
+            Event::Text(text) => {
+                if let Some(name) = current.take() {
+                    let value = text.unescape()?.into_owned();
+                    texts.entry(name).or_insert(value);
+                }
+            }
+            Event::End(_) => current = None,
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(texts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position_response(uri: &str, duration: &str, didl: &str) -> String {
+        let escaped = didl
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        format!(
+            "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+             <u:GetPositionInfoResponse xmlns:u=\"urn:schemas-upnp-org:service:AVTransport:1\">\
+             <Track>1</Track><TrackDuration>{duration}</TrackDuration>\
+             <TrackMetaData>{escaped}</TrackMetaData><TrackURI>{uri}</TrackURI>\
+             <RelTime>0:01:02</RelTime></u:GetPositionInfoResponse></s:Body></s:Envelope>"
+        )
+    }
+
+    #[test]
+    fn test_parse_position_info_track() {
+        let didl = "<DIDL-Lite xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+                    xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">\
+                    <item><dc:title>Get Lucky</dc:title><dc:creator>Daft Punk</dc:creator>\
+                    <upnp:album>Random Access Memories</upnp:album>\
+                    <upnp:albumArtURI>/getaa?s=1&amp;u=x-sonos-spotify%3atrack</upnp:albumArtURI></item></DIDL-Lite>";
+        let xml = position_response("x-sonos-spotify:track", "0:06:09", didl);
+
+        let info = parse_position_info(&xml).unwrap();
+        assert_eq!(info.track_uri, "x-sonos-spotify:track");
+        assert_eq!(info.duration, "0:06:09");
+        assert_eq!(info.position, "0:01:02");
+        assert_eq!(info.title.as_deref(), Some("Get Lucky"));
+        assert_eq!(info.artist.as_deref(), Some("Daft Punk"));
+        assert_eq!(info.album.as_deref(), Some("Random Access Memories"));
+        assert_eq!(info.stream_content, None);
+        assert_eq!(info.track_number, Some(1));
+        assert_eq!(info.album_art_uri.as_deref(), Some("/getaa?s=1&u=x-sonos-spotify%3atrack"));
+    }
+
+    #[test]
+    fn test_parse_media_info() {
+        let xml = "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+                   <u:GetMediaInfoResponse xmlns:u=\"urn:schemas-upnp-org:service:AVTransport:1\">\
+                   <NrTracks>0</NrTracks><MediaDuration>NOT_IMPLEMENTED</MediaDuration>\
+                   <CurrentURI>x-sonosapi-stream:s1234?sid=254</CurrentURI>\
+                   <CurrentURIMetaData>&lt;DIDL-Lite xmlns:dc=&quot;http://purl.org/dc/elements/1.1/&quot;&gt;\
+                   &lt;item&gt;&lt;dc:title&gt;Radio Paradise&lt;/dc:title&gt;&lt;/item&gt;&lt;/DIDL-Lite&gt;\
+                   </CurrentURIMetaData></u:GetMediaInfoResponse></s:Body></s:Envelope>";
+
+        let info = parse_media_info(xml).unwrap();
+        assert_eq!(info.current_uri, "x-sonosapi-stream:s1234?sid=254");
+        assert_eq!(info.title.as_deref(), Some("Radio Paradise"));
+        assert_eq!(info.track_count, None);
+    }
+
+    #[test]
+    fn test_parse_queue() {
+        let didl = "<DIDL-Lite xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+                    xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">\
+                    <item id=\"Q:0/1\"><res duration=\"0:06:09\">x-sonos-spotify:one</res>\
+                    <dc:title>Get Lucky</dc:title><dc:creator>Daft Punk</dc:creator>\
+                    <upnp:album>Random Access Memories</upnp:album></item>\
+                    <item id=\"Q:0/2\"><res duration=\"0:00:20\">x-file-cifs://nas/intro.flac</res>\
+                    <dc:title>Intro</dc:title></item></DIDL-Lite>";
+        let xml = format!(
+            "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+             <u:BrowseResponse xmlns:u=\"urn:schemas-upnp-org:service:ContentDirectory:1\">\
+             <Result>{}</Result><NumberReturned>2</NumberReturned></u:BrowseResponse></s:Body></s:Envelope>",
+            didl.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+        );
+
+        let queue = parse_queue(&xml, 4).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue[0].track_uri, "x-sonos-spotify:one");
+        assert_eq!(queue[0].duration, "0:06:09");
+        assert_eq!(queue[0].artist.as_deref(), Some("Daft Punk"));
+        assert_eq!(queue[0].album.as_deref(), Some("Random Access Memories"));
+        assert_eq!((queue[1].title.as_deref(), queue[1].artist.as_deref()), (Some("Intro"), None));
+        assert_eq!(queue[1].track_number, Some(6));
+    }
+
+    #[test]
+    fn test_parse_transport_info() {
+        let xml = "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+                   <u:GetTransportInfoResponse xmlns:u=\"urn:schemas-upnp-org:service:AVTransport:1\">\
+                   <CurrentTransportState>PAUSED_PLAYBACK</CurrentTransportState>\
+                   <CurrentTransportStatus>OK</CurrentTransportStatus><CurrentSpeed>1</CurrentSpeed>\
+                   </u:GetTransportInfoResponse></s:Body></s:Envelope>";
+        assert_eq!(parse_transport_info(xml).unwrap(), TransportState::Paused);
+        assert!(parse_transport_info("<CurrentTransportState>SPINNING</CurrentTransportState>").is_err());
+
+        let xml = "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+                   <u:GetVolumeResponse xmlns:u=\"urn:schemas-upnp-org:service:RenderingControl:1\">\
+                   <CurrentVolume>23</CurrentVolume></u:GetVolumeResponse></s:Body></s:Envelope>";
+        assert_eq!(parse_volume(xml).unwrap(), 23);
+    }
+
+    #[test]
+    fn test_parse_last_change() {
+        let didl = "&lt;DIDL-Lite xmlns:dc=&quot;http://purl.org/dc/elements/1.1/&quot;&gt;&lt;item&gt;\
+                    &lt;dc:title&gt;Get Lucky&lt;/dc:title&gt;&lt;dc:creator&gt;Daft Punk&lt;/dc:creator&gt;\
+                    &lt;/item&gt;&lt;/DIDL-Lite&gt;";
+        let event = format!(
+            "<Event xmlns=\"urn:schemas-upnp-org:metadata-1-0/AVT/\"><InstanceID val=\"0\">\
+             <TransportState val=\"PLAYING\"/><CurrentTrack val=\"3\"/>\
+             <CurrentTrackURI val=\"x-sonos-spotify:track1\"/>\
+             <CurrentTrackDuration val=\"0:06:09\"/>\
+             <CurrentTrackMetaData val=\"{didl}\"/><NextTrackMetaData val=\"{didl}\"/>\
+             </InstanceID></Event>"
+        );
+        let body = format!(
+            "<e:propertyset xmlns:e=\"urn:schemas-upnp-org:event-1-0\"><e:property>\
+             <LastChange>{}</LastChange></e:property></e:propertyset>",
+            event.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+        );
+
+        let change = parse_last_change(body.as_bytes()).unwrap();
+        assert_eq!(parse_last_change(b"<e:propertyset/>").unwrap(), TransportEvent::default());
+        assert_eq!(change.state, Some(TransportState::Playing));
+        let track = change.track.unwrap();
+        assert_eq!(track.track_uri, "x-sonos-spotify:track1");
+        assert_eq!(track.duration, "0:06:09");
+        assert_eq!(track.title.as_deref(), Some("Get Lucky"));
+        assert_eq!(track.artist.as_deref(), Some("Daft Punk"));
+        assert_eq!(track.track_number, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_get_position_info_over_http() {
+        let mut server = mockito::Server::new_async().await;
+        let didl = "<DIDL-Lite xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\
+                    <item><dc:title>Teardrop</dc:title><dc:creator>Massive Attack</dc:creator></item>\
+                    </DIDL-Lite>";
+        let mock = server
+            .mock("POST", AVTRANSPORT_ENDPOINT)
+            .match_header("SOAPACTION", mockito::Matcher::Regex("#GetPositionInfo".to_string()))
+            .with_body(position_response("x-file-cifs://nas/teardrop.flac", "0:05:29", didl))
+            .create_async()
+            .await;
+
+        let client = SoapClient { base_url: server.url(), ..SoapClient::new("127.0.0.1").unwrap() };
+        let info = client.get_position_info().await.unwrap();
+        mock.assert_async().await;
+
+        assert_eq!(info.title.as_deref(), Some("Teardrop"));
+        assert_eq!(client.metrics()["GetPositionInfo"], CallMetrics { calls: 1, retries: 0, failures: 0 });
+    }
+
+    #[tokio::test]
+    async fn test_remove_from_queue_over_http() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", AVTRANSPORT_ENDPOINT)
+            .match_header("SOAPACTION", mockito::Matcher::Regex("#RemoveTrackFromQueue".to_string()))
+            .match_body(mockito::Matcher::Regex("<ObjectID>Q:0/3</ObjectID>".to_string()))
+            .with_body("<s:Envelope><s:Body><u:RemoveTrackFromQueueResponse/></s:Body></s:Envelope>")
+            .create_async()
+            .await;
+
+        let client = SoapClient { base_url: server.url(), ..SoapClient::new("127.0.0.1").unwrap() };
+        client.remove_from_queue(3).await.unwrap();
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_call_retries_on_connection_failure() {
+        let client = SoapClient {
+            // Nothing listens on port 1, so every attempt is refused
+            base_url: "http://127.0.0.1:1".to_string(),
+            ..SoapClient::new("127.0.0.1").unwrap()
+        }
+        .with_retry_policy(RetryPolicy { max_retries: 2, delay_ms: 0, ..RetryPolicy::DEVICE });
+
+        let result = client.get_position_info().await;
+        assert!(matches!(result, Err(Error::Soap(_))));
+        assert_eq!(client.metrics()["GetPositionInfo"], CallMetrics { calls: 1, retries: 2, failures: 1 });
+    }
+
+    #[test]
+    fn test_parse_position_info_stream_content() {
+        let didl = "<DIDL-Lite xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+                    xmlns:r=\"urn:schemas-rinconnetworks-com:metadata-1-0/\">\
+                    <item><r:streamContent>Moderat - Bad Kingdom</r:streamContent>\
+                    <dc:title>x-sonosapi-stream:s1234?sid=254</dc:title></item></DIDL-Lite>";
+        let xml = position_response("x-sonosapi-stream:s1234?sid=254", "0:00:00", didl);
+
+        let info = parse_position_info(&xml).unwrap();
+        assert_eq!(info.stream_content.as_deref(), Some("Moderat - Bad Kingdom"));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::error::{Error, Result};
+use crate::sonos::{SonosDiscovery, TrackDatabase};
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+
+/// Wakes the pollers of sleeping speakers as soon as they announce
+/// themselves, instead of at their next check
+#[derive(Debug, Default)]
+pub struct WakeUps {
+    devices: Mutex<HashMap<String, Arc<Notify>>>,
+}
+
+impl WakeUps {
+    /// What the poller of the speaker with `device_id` waits on
+    pub fn register(&self, device_id: &str) -> Arc<Notify> {
+        self.devices.lock().unwrap().entry(device_id.to_string()).or_default().clone()
+    }
+
+    /// Wakes the poller of `device_id`. Returns whether it has one.
+    pub fn wake(&self, device_id: &str) -> bool {
+        match self.devices.lock().unwrap().get(device_id) {
+            Some(notify) => {
+                notify.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A NOTIFY a Sonos speaker multicasts when it joins or leaves the network
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Announcement {
+    /// `ssdp:alive`, with the address from the description URL
+    Alive { device_id: String, ip_addr: Option<Ipv4Addr> },
+    /// `ssdp:byebye`
+    ByeBye { device_id: String },
+}
+
+/// Listens passively for the `ssdp:alive` and `ssdp:byebye` notifications
+/// speakers multicast, complementing discovery scans: sleeping speakers are
+/// woken, and with a registry, new and moved devices are added to the
+/// discovery caches and departed ones removed right away.
+pub struct SsdpListener {
+    wake_ups: Arc<WakeUps>,
+    registry: Option<TrackDatabase>,
+    /// Where each device was last announced, so repeated announcements don't
+    /// probe it again
+    known: HashMap<String, Ipv4Addr>,
+}
+
+impl SsdpListener {
+    pub fn new(wake_ups: Arc<WakeUps>) -> Self {
+        Self { wake_ups, registry: None, known: HashMap::new() }
+    }
+
+    /// Keeps the discovery caches in `db` up to date with the announcements
+    pub fn with_registry(mut self, db: TrackDatabase) -> Self {
+        self.registry = Some(db);
+        self
+    }
+
+    /// Handles announcements until the socket fails
+    pub async fn listen(mut self) -> Result<()> {
+        let socket = multicast_socket().map_err(|e| Error::Discovery(format!("SSDP listener: {}", e)))?;
+        info!("Listening for SSDP announcements on port {}", SSDP_PORT);
+        let mut buffer = [0u8; 2048];
+        loop {
+            let (len, from) = socket
+                .recv_from(&mut buffer)
+                .await
+                .map_err(|e| Error::Discovery(format!("SSDP listener: {}", e)))?;
+            if let Some(announcement) = parse_announcement(&String::from_utf8_lossy(&buffer[..len])) {
+                self.handle(announcement, from.ip()).await;
+            }
+        }
+    }
+
+    async fn handle(&mut self, announcement: Announcement, from: IpAddr) {
+        match announcement {
+            Announcement::Alive { device_id, ip_addr } => {
+                if self.wake_ups.wake(&device_id) {
+                    debug!("{} announced itself from {}", device_id, from);
+                }
+                let Some(ip_addr) = ip_addr.or(match from {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(_) => None,
+                }) else {
+                    return;
+                };
+                let Some(db) = &self.registry else {
+                    return;
+                };
+                if self.known.get(&device_id) == Some(&ip_addr) {
+                    return;
+                }
+                match SonosDiscovery::announced(db, ip_addr).await {
+                    Ok(device) => {
+                        debug!("Registered announced device {}", device);
+                        self.known.insert(device_id, ip_addr);
+                    }
+                    Err(e) => warn!("Failed to register announced device {}: {}", device_id, e),
+                }
+            }
+            Announcement::ByeBye { device_id } => {
+                self.known.remove(&device_id);
+                let Some(db) = &self.registry else {
+                    return;
+                };
+                info!("{} left the network", device_id);
+                if let Err(e) = SonosDiscovery::departed(db, &device_id).await {
+                    warn!("Failed to forget departed device {}: {}", device_id, e);
+                }
+            }
+        }
+    }
+}
+
+/// A UDP socket in the SSDP multicast group, sharing the port with other
+/// SSDP listeners on the host
+fn multicast_socket() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, SSDP_PORT)).into())?;
+    socket.join_multicast_v4(&SSDP_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Reads a NOTIFY from a Sonos speaker, whose USN is `uuid:RINCON_...::<type>`
+fn parse_announcement(message: &str) -> Option<Announcement> {
+    let mut lines = message.lines();
+    if !lines.next()?.starts_with("NOTIFY ") {
+        return None;
+    }
+    let (mut nts, mut device_id, mut ip_addr) = (None, None, None);
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "nts" => nts = Some(value.to_ascii_lowercase()),
+            "usn" => {
+                let uuid = value.strip_prefix("uuid:")?;
+                device_id = Some(uuid.split("::").next().unwrap_or(uuid).to_string());
+            }
+            // http://192.168.1.30:1400/xml/device_description.xml
+            "location" => {
+                let host = value.strip_prefix("http://").and_then(|rest| rest.split([':', '/']).next());
+                ip_addr = host.and_then(|host| host.parse().ok());
+            }
+            _ => {}
+        }
+    }
+    let device_id = device_id.filter(|id| id.starts_with("RINCON_"))?;
+    match nts?.as_str() {
+        "ssdp:alive" => Some(Announcement::Alive { device_id, ip_addr }),
+        "ssdp:byebye" => Some(Announcement::ByeBye { device_id }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusty_sonos::discovery::BasicSpeakerInfo;
+    use std::time::Duration;
+
+    const ALIVE: &str = "NOTIFY * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n\
+                         LOCATION: http://192.168.1.30:1400/xml/device_description.xml\r\n\
+                         NT: urn:schemas-upnp-org:device:ZonePlayer:1\r\nNTS: ssdp:alive\r\n\
+                         USN: uuid:RINCON_48A6B8123456::urn:schemas-upnp-org:device:ZonePlayer:1\r\n\r\n";
+
+    #[test]
+    fn test_parse_announcement() {
+        let device_id = "RINCON_48A6B8123456".to_string();
+        assert_eq!(
+            parse_announcement(ALIVE),
+            Some(Announcement::Alive { device_id: device_id.clone(), ip_addr: Some([192, 168, 1, 30].into()) })
+        );
+        assert_eq!(
+            parse_announcement(&ALIVE.replace("ssdp:alive", "ssdp:byebye")),
+            Some(Announcement::ByeBye { device_id })
+        );
+        assert_eq!(parse_announcement(&ALIVE.replace("RINCON_", "other-")), None);
+        assert_eq!(parse_announcement(&ALIVE.replace("NOTIFY *", "M-SEARCH *")), None);
+    }
+
+    #[tokio::test]
+    async fn test_byebye_forgets_device() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let devices = vec![BasicSpeakerInfo {
+            ip_addr: "192.168.1.30".parse().unwrap(),
+            friendly_name: "192.168.1.30 - Sonos Roam - RINCON_48A6B8123456".to_string(),
+            room_name: "Bathroom".to_string(),
+        }];
+        db.save_devices(&devices).await.unwrap();
+        let wake_ups = Arc::new(WakeUps::default());
+        let wake_up = wake_ups.register("RINCON_48A6B8123456");
+        let mut listener = SsdpListener::new(wake_ups);
+
+        // Without a registry, so no speaker is probed
+        let alive = parse_announcement(ALIVE).unwrap();
+        listener.handle(alive, IpAddr::from([192, 168, 1, 30])).await;
+        tokio::time::timeout(Duration::from_secs(1), wake_up.notified()).await.unwrap();
+
+        listener.registry = Some(db.clone());
+        let byebye = Announcement::ByeBye { device_id: "RINCON_48A6B8123456".to_string() };
+        listener.handle(byebye, IpAddr::from([192, 168, 1, 30])).await;
+        assert!(db.load_devices(Duration::from_secs(60)).await.unwrap().is_empty());
+    }
+}
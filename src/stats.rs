@@ -0,0 +1,609 @@
+use crate::error::{Error, Result};
+use crate::sonos::TrackDatabase;
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+/// Entries listed per room in the top tracks and artists
+const TOP_LIMIT: u32 = 5;
+/// Entries listed in each table of a report
+const REPORT_LIMIT: u32 = 10;
+
+/// A calendar month in local time, as a half-open range of Unix timestamps
+#[derive(Debug, Clone, PartialEq)]
+pub struct Period {
+    /// `YYYY-MM`
+    pub name: String,
+    pub start: i64,
+    pub end: i64,
+}
+
+impl Period {
+    pub fn month(year: i32, month: u32) -> Option<Self> {
+        let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+        let next = match month {
+            12 => NaiveDate::from_ymd_opt(year + 1, 1, 1)?,
+            _ => NaiveDate::from_ymd_opt(year, month + 1, 1)?,
+        };
+        Some(Self {
+            name: first.format("%Y-%m").to_string(),
+            start: local_midnight(first),
+            end: local_midnight(next),
+        })
+    }
+
+    /// Parses `YYYY-MM`
+    pub fn parse(value: &str) -> Option<Self> {
+        let (year, month) = value.split_once('-')?;
+        Self::month(year.parse().ok()?, month.parse().ok()?)
+    }
+
+    pub fn previous_month(now: DateTime<Local>) -> Self {
+        let (year, month) = match now.month() {
+            1 => (now.year() - 1, 12),
+            month => (now.year(), month - 1),
+        };
+        Self::month(year, month).expect("previous month is a valid date")
+    }
+
+    /// The ISO week `now` is in, named `YYYY-Www`
+    pub fn week(now: DateTime<Local>) -> Self {
+        let monday = now.date_naive() - chrono::Duration::days(now.weekday().num_days_from_monday() as i64);
+        let week = monday.iso_week();
+        Self {
+            name: format!("{}-W{:02}", week.year(), week.week()),
+            start: local_midnight(monday),
+            end: local_midnight(monday + chrono::Duration::days(7)),
+        }
+    }
+
+    pub fn previous_week(now: DateTime<Local>) -> Self {
+        Self::week(now - chrono::Duration::days(7))
+    }
+
+    pub fn previous_year(now: DateTime<Local>) -> Self {
+        let first = |year| NaiveDate::from_ymd_opt(year, 1, 1).expect("January 1st is a valid date");
+        let year = now.year() - 1;
+        Self { name: year.to_string(), start: local_midnight(first(year)), end: local_midnight(first(year + 1)) }
+    }
+}
+
+/// Which period `stats --period` reports on: the last complete one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportPeriod {
+    Week,
+    #[default]
+    Month,
+    Year,
+}
+
+impl ReportPeriod {
+    pub fn previous(self, now: DateTime<Local>) -> Period {
+        match self {
+            ReportPeriod::Week => Period::previous_week(now),
+            ReportPeriod::Month => Period::previous_month(now),
+            ReportPeriod::Year => Period::previous_year(now),
+        }
+    }
+}
+
+/// How `stats --format` writes a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// Tables ready to paste into a note or issue
+    Markdown,
+    /// One row per entry, for spreadsheets
+    Csv,
+}
+
+/// Midnight at the start of `date`; in the rare zones where DST skips
+/// midnight, UTC midnight is close enough for monthly statistics
+pub fn local_midnight(date: NaiveDate) -> i64 {
+    let midnight = date.and_hms_opt(0, 0, 0).expect("midnight is a valid time");
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .map(|time| time.timestamp())
+        .unwrap_or_else(|| midnight.and_utc().timestamp())
+}
+
+/// Days in a row with scrobbles, given the days with scrobbles as
+/// `YYYY-MM-DD`, latest first. A streak that reached yesterday still counts,
+/// as there may be a listen later today.
+pub fn listening_streak(days: &[String], today: NaiveDate) -> i64 {
+    let days = days.iter().filter_map(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").ok());
+    let mut expected = today;
+    let mut streak = 0;
+    for day in days.skip_while(|day| *day > today) {
+        if streak == 0 && day < expected {
+            // Nothing today yet
+            expected = expected.pred_opt().unwrap_or(expected);
+        }
+        if day != expected {
+            break;
+        }
+        streak += 1;
+        expected = match day.pred_opt() {
+            Some(previous) => previous,
+            None => break,
+        };
+    }
+    streak
+}
+
+/// Which scrobbles a history query covers. Unset fields don't filter.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListenFilter {
+    /// Room, ignoring case
+    pub room: Option<String>,
+    /// Artist, ignoring case
+    pub artist: Option<String>,
+    /// Unix timestamp of the first second included
+    pub from: Option<i64>,
+    /// Unix timestamp of the first second no longer included
+    pub to: Option<i64>,
+}
+
+impl ListenFilter {
+    /// Reads `room`, `artist`, `from` and `to` from query parameters. The
+    /// dates are `YYYY-MM-DD` in local time, both days included.
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self> {
+        let text = |name: &str| query.get(name).filter(|value| !value.is_empty()).cloned();
+        let day = |name: &str| -> Result<Option<NaiveDate>> {
+            text(name)
+                .map(|date| {
+                    NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+                        .map_err(|_| Error::Config(format!("invalid {} date {}, expected YYYY-MM-DD", name, date)))
+                })
+                .transpose()
+        };
+        Ok(Self {
+            room: text("room"),
+            artist: text("artist"),
+            from: day("from")?.map(local_midnight),
+            to: day("to")?.and_then(|day| day.succ_opt()).map(local_midnight),
+        })
+    }
+}
+
+/// What history is counted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    Room,
+    Artist,
+    /// `Artist - Title`
+    Track,
+    /// `Artist - Album`, leaving out scrobbles without an album
+    Album,
+    Genre,
+    /// `YYYY-MM-DD` in local time
+    Day,
+    /// `YYYY-MM` in local time
+    Month,
+}
+
+impl GroupBy {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "room" => Ok(GroupBy::Room),
+            "artist" => Ok(GroupBy::Artist),
+            "track" => Ok(GroupBy::Track),
+            "album" => Ok(GroupBy::Album),
+            "genre" => Ok(GroupBy::Genre),
+            "day" => Ok(GroupBy::Day),
+            "month" => Ok(GroupBy::Month),
+            _ => Err(Error::Config(format!(
+                "cannot group by {}, expected room, artist, track, album, genre, day or month",
+                value
+            ))),
+        }
+    }
+}
+
+/// One scrobble in the listening history
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Listen {
+    pub id: i64,
+    pub room: String,
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+    pub started_at: i64,
+    /// Where a music library file was played from, e.g. `//nas/Music/...`
+    pub file: Option<String>,
+    /// The first scrobble of the artist
+    pub new_artist: bool,
+    /// The first scrobble of the track
+    pub new_track: bool,
+}
+
+/// Order of the listening history
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HistorySort {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+impl HistorySort {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "newest" => Ok(HistorySort::Newest),
+            "oldest" => Ok(HistorySort::Oldest),
+            _ => Err(Error::Config(format!("cannot sort by {}, expected newest or oldest", value))),
+        }
+    }
+}
+
+/// Where the next page of history starts: after the listen that ended the
+/// previous page. Written as `<started_at>.<id>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryCursor {
+    pub started_at: i64,
+    pub id: i64,
+}
+
+impl From<&Listen> for HistoryCursor {
+    fn from(listen: &Listen) -> Self {
+        Self { started_at: listen.started_at, id: listen.id }
+    }
+}
+
+impl fmt::Display for HistoryCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.started_at, self.id)
+    }
+}
+
+impl FromStr for HistoryCursor {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        value
+            .split_once('.')
+            .and_then(|(started_at, id)| Some(Self { started_at: started_at.parse().ok()?, id: id.parse().ok()? }))
+            .ok_or_else(|| Error::Config(format!("invalid cursor {}", value)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomSummary {
+    pub room: String,
+    pub scrobbles: i64,
+    /// Scrobbles rejected by at least one backend
+    pub failures: i64,
+    pub top_tracks: Vec<(String, i64)>,
+    pub top_artists: Vec<(String, i64)>,
+    /// Most scrobbled genre tags
+    pub top_genres: Vec<(String, i64)>,
+}
+
+/// Listening summary of one period: top tracks, artists and genres per room, and
+/// how many scrobbles were submitted and failed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Digest {
+    pub period: Period,
+    pub rooms: Vec<RoomSummary>,
+    /// Albums played through, as room, artist, album and track count
+    pub albums: Vec<(String, String, String, i64)>,
+}
+
+impl Digest {
+    pub async fn build(db: &TrackDatabase, period: Period) -> Result<Self> {
+        let mut rooms = Vec::new();
+        for (room, scrobbles, failures) in db.scrobbles_by_room(period.start, period.end).await? {
+            rooms.push(RoomSummary {
+                top_tracks: db.top_tracks(&room, period.start, period.end, TOP_LIMIT).await?,
+                top_artists: db.top_artists(&room, period.start, period.end, TOP_LIMIT).await?,
+                top_genres: db.top_genres(&room, period.start, period.end, TOP_LIMIT).await?,
+                room,
+                scrobbles,
+                failures,
+            });
+        }
+        let albums = db.album_listens(period.start, period.end).await?;
+        Ok(Self { period, rooms, albums })
+    }
+
+    pub fn total_scrobbles(&self) -> i64 {
+        self.rooms.iter().map(|room| room.scrobbles).sum()
+    }
+
+    pub fn total_failures(&self) -> i64 {
+        self.rooms.iter().map(|room| room.failures).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+
+    /// Plain text rendering, used for the digest email and the `digest` command
+    pub fn render(&self) -> String {
+        let mut text = format!(
+            "Listening digest for {}\n\n{} scrobbles, {} failed\n",
+            self.period.name,
+            self.total_scrobbles(),
+            self.total_failures()
+        );
+
+        for room in &self.rooms {
+            let _ = write!(text, "\n{}: {} scrobbles\n", room.room, room.scrobbles);
+            let sections = [
+                ("Top artists", &room.top_artists),
+                ("Top tracks", &room.top_tracks),
+                ("Top genres", &room.top_genres),
+            ];
+            for (heading, entries) in sections.into_iter().filter(|(_, entries)| !entries.is_empty()) {
+                let _ = writeln!(text, "  {}:", heading);
+                for (i, (name, count)) in entries.iter().enumerate() {
+                    let _ = writeln!(text, "    {}. {} ({})", i + 1, name, count);
+                }
+            }
+        }
+
+        if !self.albums.is_empty() {
+            let _ = write!(text, "\n{} albums listened:\n", self.albums.len());
+            for (room, artist, album, tracks) in &self.albums {
+                let _ = writeln!(text, "  {} - {} ({} tracks, {})", artist, album, tracks, room);
+            }
+        }
+        text
+    }
+}
+
+/// A table of a report: its heading, what its entries are, what they count
+/// and the entries
+type Table<'a> = (&'static str, &'static str, &'static str, &'a [(String, i64)]);
+
+/// Top artists, tracks and rooms of a period
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub period: Period,
+    pub top_artists: Vec<(String, i64)>,
+    pub top_tracks: Vec<(String, i64)>,
+    /// Every room, most scrobbles first
+    pub rooms: Vec<(String, i64)>,
+    pub new_music: NewMusic,
+    /// Volume changes and skips by room, as `Room: kind`
+    pub interactions: Vec<(String, i64)>,
+}
+
+impl Report {
+    pub async fn build(db: &TrackDatabase, period: Period) -> Result<Self> {
+        let filter = ListenFilter { from: Some(period.start), to: Some(period.end), ..ListenFilter::default() };
+        Ok(Self {
+            top_artists: db.aggregate(&filter, GroupBy::Artist, REPORT_LIMIT).await?,
+            top_tracks: db.aggregate(&filter, GroupBy::Track, REPORT_LIMIT).await?,
+            rooms: db.aggregate(&filter, GroupBy::Room, u32::MAX).await?,
+            new_music: NewMusic::build(db, &period).await?,
+            interactions: db.interactions(period.start, period.end).await?,
+            period,
+        })
+    }
+
+    pub fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Markdown => self.to_markdown(),
+            ReportFormat::Csv => self.to_csv(),
+        }
+    }
+
+    fn tables(&self) -> [Table<'_>; 6] {
+        [
+            ("Top artists", "artist", "Plays", &self.top_artists),
+            ("Top tracks", "track", "Plays", &self.top_tracks),
+            ("Rooms", "room", "Plays", &self.rooms),
+            ("New artists", "new_artist", "Plays", &self.new_music.artists),
+            ("New tracks", "new_track", "Plays", &self.new_music.tracks),
+            ("Interactions", "interaction", "Times", &self.interactions),
+        ]
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let total: i64 = self.rooms.iter().map(|(_, count)| count).sum();
+        let mut text = format!("# Listening report for {}\n\n{} scrobbles\n", self.period.name, total);
+        for (heading, column, counted, entries) in self.tables().into_iter().filter(|table| !table.3.is_empty()) {
+            let column = capitalize(column.trim_start_matches("new_"));
+            let _ = write!(text, "\n## {}\n\n| # | {} | {} |\n|---:|---|---:|\n", heading, column, counted);
+            for (i, (name, count)) in entries.iter().enumerate() {
+                let _ = writeln!(text, "| {} | {} | {} |", i + 1, name.replace('|', "\\|"), count);
+            }
+        }
+        text
+    }
+
+    /// Rows of `section,rank,name,plays`, sections being `artist`, `track`,
+    /// `room`, `new_artist`, `new_track` and `interaction`
+    pub fn to_csv(&self) -> String {
+        let mut text = String::from("section,rank,name,plays\n");
+        for (_, section, _, entries) in self.tables() {
+            for (i, (name, count)) in entries.iter().enumerate() {
+                let _ = writeln!(text, "{},{},{},{}", section, i + 1, csv_field(name), count);
+            }
+        }
+        text
+    }
+}
+
+/// Artists and tracks first scrobbled during a period, with their plays then
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NewMusic {
+    pub artists: Vec<(String, i64)>,
+    pub tracks: Vec<(String, i64)>,
+}
+
+impl NewMusic {
+    pub async fn build(db: &TrackDatabase, period: &Period) -> Result<Self> {
+        Ok(Self {
+            artists: db.new_artists(period.start, period.end, REPORT_LIMIT).await?,
+            tracks: db.new_tracks(period.start, period.end, REPORT_LIMIT).await?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.artists.is_empty() && self.tracks.is_empty()
+    }
+
+    /// Plain text rendering, used for the weekly email
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for (heading, entries) in [("New artists", &self.artists), ("New tracks", &self.tracks)] {
+            if entries.is_empty() {
+                continue;
+            }
+            let _ = writeln!(text, "{}:", heading);
+            for (name, plays) in entries {
+                let _ = writeln!(text, "  {} ({})", name, plays);
+            }
+        }
+        text
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    chars.next().map_or_else(String::new, |first| first.to_uppercase().chain(chars).collect())
+}
+
+/// `value` quoted as CSV needs it
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+/// What the dashboard charts: scrobbles per day and per room, and how they
+/// spread over the hours of the week
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Charts {
+    /// `YYYY-MM-DD` in local time, oldest first, leaving out days without
+    /// scrobbles
+    pub per_day: Vec<(String, i64)>,
+    /// Most first
+    pub per_room: Vec<(String, i64)>,
+    /// Scrobbles by local weekday, Monday first, and hour
+    pub heatmap: [[i64; 24]; 7],
+}
+
+impl Charts {
+    pub async fn build(db: &TrackDatabase, filter: &ListenFilter) -> Result<Self> {
+        let mut heatmap = [[0; 24]; 7];
+        for (weekday, hour, count) in db.plays_per_hour(filter).await? {
+            if let Some(cell) = heatmap.get_mut(weekday as usize).and_then(|day| day.get_mut(hour as usize)) {
+                *cell = count;
+            }
+        }
+        Ok(Self {
+            per_day: db.plays_per_day(filter).await?,
+            per_room: db.aggregate(filter, GroupBy::Room, u32::MAX).await?,
+            heatmap,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrobble::Scrobble;
+    use crate::sonos::{AlbumDetector, MIN_ALBUM_TRACKS};
+
+    #[test]
+    fn test_period_month() {
+        let december = Period::parse("2025-12").unwrap();
+        assert_eq!(december.name, "2025-12");
+        assert_eq!(december.end, Period::parse("2026-01").unwrap().start);
+        assert!(Period::parse("2025-13").is_none());
+
+        let now = Local.with_ymd_and_hms(2026, 1, 15, 12, 0, 0).unwrap();
+        assert_eq!(Period::previous_month(now), december);
+
+        let week = Period::previous_week(now);
+        assert_eq!(week.name, "2026-W02");
+        assert_eq!(week.start, local_midnight(NaiveDate::from_ymd_opt(2026, 1, 5).unwrap()));
+        assert_eq!(Period::previous_year(now).end, Period::parse("2026-01").unwrap().start);
+    }
+
+    #[test]
+    fn test_listening_streak() {
+        let today = NaiveDate::from_ymd_opt(2026, 3, 2).unwrap();
+        let days = |days: &[&str]| days.iter().map(|day| day.to_string()).collect::<Vec<_>>();
+        assert_eq!(listening_streak(&days(&["2026-03-02", "2026-03-01", "2026-02-28", "2026-02-26"]), today), 3);
+        assert_eq!(listening_streak(&days(&["2026-03-01", "2026-02-28"]), today), 2);
+        assert_eq!(listening_streak(&days(&["2026-02-28"]), today), 0);
+        assert_eq!(listening_streak(&[], today), 0);
+    }
+
+    #[tokio::test]
+    async fn test_digest() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let period = Period::parse("2026-09").unwrap();
+        for (title, offset) in [("Get Lucky", 0), ("Get Lucky", 3600), ("Around the World", 7200)] {
+            let scrobble = Scrobble::new("Kitchen", "Daft Punk", title, period.start + offset);
+            db.record_scrobble(&scrobble).await.unwrap();
+            db.tag_scrobble(&scrobble, &["french house".to_string()]).await.unwrap();
+        }
+        let mut detector = AlbumDetector::default();
+        for position in 1..=MIN_ALBUM_TRACKS {
+            let track = Scrobble {
+                album: Some("Discovery".to_string()),
+                ..Scrobble::new("Kitchen", "Daft Punk", format!("Track {}", position), period.start + 10_000)
+            };
+            if let Some(album) = detector.track_played(&track, Some(position)) {
+                db.record_album_listen(album).await.unwrap();
+            }
+        }
+
+        let digest = Digest::build(&db, period).await.unwrap();
+        assert_eq!(digest.total_scrobbles(), 3);
+        assert_eq!(digest.rooms[0].top_tracks[0], ("Daft Punk - Get Lucky".to_string(), 2));
+        assert!(digest.render().contains("Kitchen: 3 scrobbles\n  Top artists:\n    1. Daft Punk (3)\n"));
+        assert_eq!(digest.rooms[0].top_genres, vec![("french house".to_string(), 3)]);
+        assert!(digest.render().contains("1 albums listened:\n  Daft Punk - Discovery (4 tracks, Kitchen)\n"));
+    }
+
+    #[tokio::test]
+    async fn test_report_formats() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        let period = Period::parse("2026-09").unwrap();
+        let listens =
+            [("Daft Punk", "Get Lucky", 0), ("Daft Punk", "Get Lucky", 600), ("Justice", "D.A.N.C.E., Pt. 2", 1200)];
+        for (artist, title, offset) in listens {
+            let scrobble = Scrobble::new("Kitchen", artist, title, period.start + offset);
+            db.record_scrobble(&scrobble).await.unwrap();
+        }
+
+        let mut report = Report::build(&db, period).await.unwrap();
+        report.interactions = vec![("Kitchen: volume".to_string(), 4)];
+        let markdown = report.render(ReportFormat::Markdown);
+        assert!(markdown.starts_with("# Listening report for 2026-09\n\n3 scrobbles\n"));
+        let artists = "## Top artists\n\n| # | Artist | Plays |\n|---:|---|---:|\n| 1 | Daft Punk | 2 |\n";
+        assert!(markdown.contains(artists));
+        let interactions = "## Interactions\n\n| # | Interaction | Times |\n|---:|---|---:|\n| 1 | Kitchen: volume | 4 |\n";
+        assert!(markdown.ends_with(interactions));
+        let csv = report.render(ReportFormat::Csv);
+        assert!(csv.starts_with("section,rank,name,plays\nartist,1,Daft Punk,2\nartist,2,Justice,1\n"));
+        assert!(csv.contains("track,2,\"Justice - D.A.N.C.E., Pt. 2\",1\nroom,1,Kitchen,3\n"));
+        assert!(csv.contains("room,1,Kitchen,3\nnew_artist,1,Daft Punk,2\n"));
+        assert!(report.new_music.render().starts_with("New artists:\n  Daft Punk (2)\n  Justice (1)\nNew tracks:\n"));
+    }
+
+    #[tokio::test]
+    async fn test_charts() {
+        let db = TrackDatabase::connect("sqlite::memory:").await.unwrap();
+        // A Monday evening and the Tuesday morning after it
+        let monday = Local.with_ymd_and_hms(2026, 9, 7, 20, 15, 0).unwrap().timestamp();
+        for (device, started_at) in [("Kitchen", monday), ("Kitchen", monday + 600), ("Office", monday + 12 * 3600)] {
+            let scrobble = Scrobble::new(device, "Daft Punk", format!("Track {}", started_at), started_at);
+            db.record_scrobble(&scrobble).await.unwrap();
+        }
+
+        let charts = Charts::build(&db, &ListenFilter::default()).await.unwrap();
+        assert_eq!(charts.per_day, vec![("2026-09-07".to_string(), 2), ("2026-09-08".to_string(), 1)]);
+        assert_eq!(charts.per_room, vec![("Kitchen".to_string(), 2), ("Office".to_string(), 1)]);
+        assert_eq!((charts.heatmap[0][20], charts.heatmap[1][8]), (2, 1));
+        assert_eq!(charts.heatmap.iter().flatten().sum::<i64>(), 3);
+    }
+}
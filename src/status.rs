@@ -0,0 +1,280 @@
+use crate::scrobble::Priority;
+use log::info;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Whether a device answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceHealth {
+    Healthy,
+    /// A portable speaker that went to sleep, as expected from time to time
+    Sleeping,
+    Unhealthy,
+}
+
+/// Counters shared by every device poller, summarized periodically in the log
+/// so it is easy to tell from journald that the service is alive and working.
+#[derive(Debug, Default)]
+pub struct Status {
+    tracks_seen: AtomicU64,
+    tracks_logged: AtomicU64,
+    tracks_scrobbled: AtomicU64,
+    /// Listens recorded while the internet was down, not yet submitted
+    listens_deferred: AtomicU64,
+    devices: Mutex<BTreeMap<String, DeviceHealth>>,
+    /// Devices playing without scrobbling, see `ScrobbleGuard`
+    silent_devices: Mutex<Vec<String>>,
+    /// How often each device had forgotten its event subscription
+    resubscribed: Mutex<BTreeMap<String, u64>>,
+    /// Scrobble backends switched off through the control API
+    disabled_backends: Mutex<Vec<String>>,
+    /// Name of the active profile
+    profile: Mutex<Option<String>>,
+    /// Devices whose listen in progress advanced at the last poll
+    listening: Mutex<BTreeSet<String>>,
+}
+
+impl Status {
+    pub fn track_seen(&self) {
+        self.tracks_seen.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn track_logged(&self) {
+        self.tracks_logged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn track_scrobbled(&self) {
+        self.tracks_scrobbled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_listens_deferred(&self, listens: usize) {
+        self.listens_deferred.store(listens as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_disabled_backends(&self, names: Vec<String>) {
+        *self.disabled_backends.lock().unwrap() = names;
+    }
+
+    pub fn set_profile(&self, name: Option<String>) {
+        *self.profile.lock().unwrap() = name;
+    }
+
+    pub fn set_device_health(&self, device: &str, healthy: bool) {
+        let health = if healthy { DeviceHealth::Healthy } else { DeviceHealth::Unhealthy };
+        self.devices.lock().unwrap().insert(device.to_string(), health);
+    }
+
+    /// Reports a portable speaker as asleep rather than unhealthy
+    pub fn set_device_sleeping(&self, device: &str) {
+        self.devices.lock().unwrap().insert(device.to_string(), DeviceHealth::Sleeping);
+    }
+
+    /// Notes that `device` forgot its event subscription, usually because
+    /// it restarted, and was subscribed to again
+    pub fn device_resubscribed(&self, device: &str) {
+        *self.resubscribed.lock().unwrap().entry(device.to_string()).or_default() += 1;
+    }
+
+    pub fn set_listening(&self, device: &str, listening: bool) {
+        let mut devices = self.listening.lock().unwrap();
+        match listening {
+            true => devices.insert(device.to_string()),
+            false => devices.remove(device),
+        };
+    }
+
+    /// The gauges kept here; the queues are filled in by their owners
+    pub fn gauges(&self) -> Gauges {
+        Gauges {
+            listen_sessions: self.listening.lock().unwrap().len(),
+            listens_deferred: self.listens_deferred.load(Ordering::Relaxed),
+            silent_rooms: self.silent_devices.lock().unwrap().len(),
+            ..Gauges::default()
+        }
+    }
+
+    pub fn set_silent_devices(&self, devices: &[String]) {
+        *self.silent_devices.lock().unwrap() = devices.to_vec();
+    }
+
+    pub fn summary(&self) -> String {
+        let devices = self.devices.lock().unwrap();
+        let with_health = |health: DeviceHealth| -> Vec<&str> {
+            devices
+                .iter()
+                .filter(|(_, device)| **device == health)
+                .map(|(name, _)| name.as_str())
+                .collect()
+        };
+        let unhealthy = with_health(DeviceHealth::Unhealthy);
+        let sleeping = with_health(DeviceHealth::Sleeping);
+
+        let mut summary = format!(
+            "Status: {} tracks seen, {} logged, {} scrobbled; devices: {} healthy, {} unhealthy",
+            self.tracks_seen.load(Ordering::Relaxed),
+            self.tracks_logged.load(Ordering::Relaxed),
+            self.tracks_scrobbled.load(Ordering::Relaxed),
+            devices.len() - unhealthy.len() - sleeping.len(),
+            unhealthy.len(),
+        );
+        if !unhealthy.is_empty() {
+            summary.push_str(&format!(" ({})", unhealthy.join(", ")));
+        }
+        if !sleeping.is_empty() {
+            summary.push_str(&format!(", {} sleeping ({})", sleeping.len(), sleeping.join(", ")));
+        }
+        let silent = self.silent_devices.lock().unwrap();
+        if !silent.is_empty() {
+            summary.push_str(&format!("; playing without scrobbles: {}", silent.join(", ")));
+        }
+        let deferred = self.listens_deferred.load(Ordering::Relaxed);
+        if deferred > 0 {
+            summary.push_str(&format!("; {} listens waiting for the internet", deferred));
+        }
+        let resubscribed = self.resubscribed.lock().unwrap();
+        if !resubscribed.is_empty() {
+            let counts: Vec<_> = resubscribed.iter().map(|(name, count)| format!("{} ({})", name, count)).collect();
+            summary.push_str(&format!("; resubscribed after restarts: {}", counts.join(", ")));
+        }
+        let disabled = self.disabled_backends.lock().unwrap();
+        if !disabled.is_empty() {
+            summary.push_str(&format!("; scrobbling to {} switched off", disabled.join(", ")));
+        }
+        if let Some(profile) = self.profile.lock().unwrap().as_ref() {
+            summary.push_str(&format!("; profile {}", profile));
+        }
+        summary
+    }
+}
+
+/// How far behind the service is at one moment, for `/metrics`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Gauges {
+    /// Devices with a listen in progress
+    pub listen_sessions: usize,
+    pub listens_deferred: u64,
+    /// Rooms that usually scrobble every day, playing for hours without
+    /// scrobbling
+    pub silent_rooms: usize,
+    /// Database writes buffered in memory
+    pub pending_writes: usize,
+    /// Speaker notifications waiting to be handled, by device id
+    pub event_queues: BTreeMap<String, usize>,
+    /// Submissions waiting for each scrobble backend with each priority
+    pub submission_queues: Vec<(String, Priority, usize)>,
+    /// Submissions queued or in progress, over all backends
+    pub submissions_pending: usize,
+}
+
+impl Gauges {
+    /// The gauges in the Prometheus text format
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let mut gauge = |name: &str, help: &str, values: &[(String, u64)]| {
+            let _ = writeln!(text, "# HELP sonos_scrobbler_{} {}", name, help);
+            let _ = writeln!(text, "# TYPE sonos_scrobbler_{} gauge", name);
+            for (labels, value) in values {
+                let _ = writeln!(text, "sonos_scrobbler_{}{} {}", name, labels, value);
+            }
+        };
+        gauge("listen_sessions", "Devices with a listen in progress", &[(String::new(), self.listen_sessions as u64)]);
+        gauge("listens_deferred", "Listens waiting for the internet", &[(String::new(), self.listens_deferred)]);
+        let silent = [(String::new(), self.silent_rooms as u64)];
+        gauge("silent_rooms", "Rooms playing for hours without scrobbling", &silent);
+        gauge("pending_writes", "Database writes buffered in memory", &[(String::new(), self.pending_writes as u64)]);
+        let events: Vec<_> = self
+            .event_queues
+            .iter()
+            .map(|(device, depth)| (format!("{{device=\"{}\"}}", escape(device)), *depth as u64))
+            .collect();
+        gauge("event_queue_depth", "Speaker notifications waiting to be handled", &events);
+        let submissions: Vec<_> = self
+            .submission_queues
+            .iter()
+            .map(|(backend, priority, depth)| {
+                let labels = format!("{{backend=\"{}\",priority=\"{}\"}}", escape(backend), priority.as_str());
+                (labels, *depth as u64)
+            })
+            .collect();
+        gauge("submission_queue_depth", "Submissions waiting for a scrobble backend", &submissions);
+        let pending = [(String::new(), self.submissions_pending as u64)];
+        gauge("submissions_pending", "Submissions queued or in progress", &pending);
+        text
+    }
+}
+
+/// `value` as a Prometheus label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Logs the status summary every `interval` until the task is dropped
+pub async fn log_periodically(status: Arc<Status>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately; skip it so the first summary
+    // covers a full interval
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        info!("{}", status.summary());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let status = Status::default();
+        status.track_seen();
+        status.track_seen();
+        status.track_logged();
+        status.track_scrobbled();
+        status.set_device_health("Kitchen", true);
+        status.set_device_health("Office", false);
+
+        assert_eq!(
+            status.summary(),
+            "Status: 2 tracks seen, 1 logged, 1 scrobbled; devices: 1 healthy, 1 unhealthy (Office)"
+        );
+
+        status.set_device_sleeping("Roam");
+        assert!(status.summary().ends_with("1 healthy, 1 unhealthy (Office), 1 sleeping (Roam)"));
+
+        status.device_resubscribed("Kitchen");
+        status.device_resubscribed("Kitchen");
+        assert!(status.summary().ends_with("; resubscribed after restarts: Kitchen (2)"));
+
+        status.set_listens_deferred(3);
+        assert!(status.summary().contains("; 3 listens waiting for the internet"));
+
+        status.set_disabled_backends(vec!["listenbrainz".to_string()]);
+        assert!(status.summary().ends_with("; scrobbling to listenbrainz switched off"));
+
+        status.set_profile(Some("party".to_string()));
+        assert!(status.summary().ends_with("switched off; profile party"));
+    }
+
+    #[test]
+    fn test_gauges_in_prometheus_format() {
+        let status = Status::default();
+        status.set_listening("Kitchen", true);
+        status.set_listening("Office", true);
+        status.set_listening("Office", false);
+        status.set_silent_devices(&["Kitchen".to_string()]);
+        let mut gauges = status.gauges();
+        gauges.event_queues.insert("RINCON_1".to_string(), 3);
+        gauges.submission_queues.push(("lastfm".to_string(), Priority::Backlog, 12));
+
+        let text = gauges.to_prometheus();
+        assert!(text.contains("# TYPE sonos_scrobbler_listen_sessions gauge\nsonos_scrobbler_listen_sessions 1\n"));
+        assert!(text.contains("# TYPE sonos_scrobbler_silent_rooms gauge\nsonos_scrobbler_silent_rooms 1\n"));
+        assert!(text.contains("sonos_scrobbler_event_queue_depth{device=\"RINCON_1\"} 3\n"));
+        assert!(text.contains("sonos_scrobbler_submission_queue_depth{backend=\"lastfm\",priority=\"backlog\"} 12\n"));
+        assert_eq!(escape("Kid's \"room\""), "Kid's \\\"room\\\"");
+    }
+}
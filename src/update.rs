@@ -0,0 +1,250 @@
+use crate::error::{Error, Result};
+use log::{info, warn};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const RELEASES_URL: &str = "https://api.github.com/repos/harperreed/sonos-scrobbler/releases/latest";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time between release checks while the daemon runs
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A published release
+#[derive(Debug, Clone, PartialEq)]
+pub struct Release {
+    /// Version without the leading "v" of the tag
+    pub version: String,
+    /// Release notes page
+    pub url: String,
+    /// Download URLs of the attached binaries, by file name
+    pub assets: Vec<(String, String)>,
+}
+
+impl Release {
+    /// The binary for this platform, when the release has one
+    fn asset(&self) -> Option<&str> {
+        self.named(&asset_name()?)
+    }
+
+    /// The published SHA-256 of [`Release::asset`], `<binary>.sha256`
+    fn checksum(&self) -> Option<&str> {
+        self.named(&format!("{}.sha256", asset_name()?))
+    }
+
+    fn named(&self, name: &str) -> Option<&str> {
+        self.assets.iter().find(|(asset, _)| asset == name).map(|(_, url)| url.as_str())
+    }
+}
+
+/// Checks GitHub for newer releases and installs them
+pub struct Updater {
+    releases_url: String,
+    client: reqwest::Client,
+}
+
+impl Updater {
+    pub fn new() -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(concat!("sonos-scrobbler/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| Error::Update(e.to_string()))?;
+        Ok(Self { releases_url: RELEASES_URL.to_string(), client })
+    }
+
+    pub async fn latest(&self) -> Result<Release> {
+        let response = self
+            .client
+            .get(&self.releases_url)
+            .header("Accept", "application/vnd.github+json")
+            .send()
+            .await
+            .map_err(|e| Error::Update(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(Error::Update(format!("release lookup failed with {}", response.status())));
+        }
+        let body = response.text().await.map_err(|e| Error::Update(e.to_string()))?;
+        let body: Value =
+            serde_json::from_str(&body).map_err(|e| Error::Update(format!("invalid release: {}", e)))?;
+
+        let tag = body["tag_name"]
+            .as_str()
+            .ok_or_else(|| Error::Update("release has no tag".to_string()))?;
+        let assets = body["assets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|asset| {
+                let name = asset["name"].as_str()?;
+                let url = asset["browser_download_url"].as_str()?;
+                Some((name.to_string(), url.to_string()))
+            })
+            .collect();
+        Ok(Release {
+            version: tag.trim_start_matches('v').to_string(),
+            url: body["html_url"].as_str().unwrap_or_default().to_string(),
+            assets,
+        })
+    }
+
+    /// The latest release if it is newer than this build
+    pub async fn newer_release(&self) -> Result<Option<Release>> {
+        let release = self.latest().await?;
+        Ok(is_newer(&release.version, env!("CARGO_PKG_VERSION")).then_some(release))
+    }
+
+    /// Replaces the running binary with the one from `release`. Returns the
+    /// path of the replaced binary.
+    pub async fn install(&self, release: &Release) -> Result<PathBuf> {
+        let binary = self.download(release).await?;
+
+        let exe = std::env::current_exe().map_err(|e| Error::Update(e.to_string()))?;
+        // Written next to the binary, so the rename stays on one filesystem
+        let staged = exe.with_extension("new");
+        let io = |e: std::io::Error| Error::Update(format!("{}: {}", exe.display(), e));
+        std::fs::write(&staged, &binary).map_err(io)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755)).map_err(io)?;
+        }
+        std::fs::rename(&staged, &exe).map_err(io)?;
+        Ok(exe)
+    }
+
+    /// This platform's binary from `release`, checked against its published
+    /// SHA-256. Fails when the release has no checksum or it doesn't match.
+    async fn download(&self, release: &Release) -> Result<Vec<u8>> {
+        let missing = |what: &str| {
+            Error::Update(format!(
+                "release {} has no {} for {}-{}",
+                release.version,
+                what,
+                std::env::consts::ARCH,
+                std::env::consts::OS
+            ))
+        };
+        let url = release.asset().ok_or_else(|| missing("binary"))?;
+        let checksum_url = release.checksum().ok_or_else(|| missing("checksum"))?;
+
+        let checksum = String::from_utf8_lossy(&self.fetch(checksum_url).await?).into_owned();
+        // `sha256sum` output: the digest, then the file name
+        let expected = checksum.split_whitespace().next().unwrap_or_default().to_ascii_lowercase();
+        let binary = self.fetch(url).await?;
+        let actual: String = Sha256::digest(&binary).iter().map(|byte| format!("{:02x}", byte)).collect();
+        if actual != expected {
+            return Err(Error::Update(format!(
+                "checksum mismatch for release {}: expected {}, downloaded {}",
+                release.version, expected, actual
+            )));
+        }
+        Ok(binary)
+    }
+
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+        let response = self.client.get(url).send().await.map_err(|e| Error::Update(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(Error::Update(format!("download failed with {}", response.status())));
+        }
+        let body = response.bytes().await.map_err(|e| Error::Update(e.to_string()))?;
+        Ok(body.to_vec())
+    }
+}
+
+/// Logs when a newer release is out, every `interval` until the task is
+/// dropped
+pub async fn check_periodically(updater: Updater, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match updater.newer_release().await {
+            Ok(Some(release)) => info!(
+                "Sonos Scrobbler {} is available (running {}): {}",
+                release.version,
+                env!("CARGO_PKG_VERSION"),
+                release.url
+            ),
+            Ok(None) => {}
+            Err(e) => warn!("Update check failed: {}", e),
+        }
+    }
+}
+
+/// Release binaries are named `sonos-scrobbler-<arch>-<os>`; self-update only
+/// supports platforms where a running binary can be replaced
+fn asset_name() -> Option<String> {
+    let os = match std::env::consts::OS {
+        "linux" => "linux",
+        "macos" => "macos",
+        _ => return None,
+    };
+    Some(format!("sonos-scrobbler-{}-{}", std::env::consts::ARCH, os))
+}
+
+/// Compares dotted versions numerically; pre-release suffixes are ignored
+fn is_newer(candidate: &str, current: &str) -> bool {
+    let parse = |version: &str| -> Vec<u64> {
+        version
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+    parse(candidate) > parse(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("0.2.0", "0.1.0"));
+        assert!(is_newer("0.10.0", "0.9.3"));
+        assert!(!is_newer("0.1.0", "0.1.0"));
+        assert!(!is_newer("0.1.0-rc.1", "0.1.0"));
+    }
+
+    #[tokio::test]
+    async fn test_latest_release() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/releases/latest")
+            .with_body(
+                r#"{"tag_name": "v9.0.0", "html_url": "https://github.com/harperreed/sonos-scrobbler/releases/v9.0.0",
+                    "assets": [{"name": "sonos-scrobbler-x86_64-linux", "browser_download_url": "https://example.com/bin"}]}"#,
+            )
+            .create_async()
+            .await;
+
+        let updater = Updater { releases_url: format!("{}/releases/latest", server.url()), ..Updater::new().unwrap() };
+        let release = updater.newer_release().await.unwrap().unwrap();
+        assert_eq!(release.version, "9.0.0");
+        assert_eq!(release.assets[0].0, "sonos-scrobbler-x86_64-linux");
+    }
+
+    #[tokio::test]
+    async fn test_download_checks_sha256() {
+        let Some(name) = asset_name() else { return };
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", "/bin").with_body("new binary").create_async().await;
+        let digest: String = Sha256::digest(b"new binary").iter().map(|byte| format!("{:02x}", byte)).collect();
+        server.mock("GET", "/good.sha256").with_body(format!("{}  {}\n", digest, name)).create_async().await;
+        server.mock("GET", "/bad.sha256").with_body(format!("{}  {}\n", "0".repeat(64), name)).create_async().await;
+
+        let updater = Updater::new().unwrap();
+        let release = |checksum: Option<&str>| Release {
+            version: "9.0.0".to_string(),
+            url: String::new(),
+            assets: std::iter::once((name.clone(), format!("{}/bin", server.url())))
+                .chain(checksum.map(|path| (format!("{}.sha256", name), format!("{}{}", server.url(), path))))
+                .collect(),
+        };
+        assert_eq!(updater.download(&release(Some("/good.sha256"))).await.unwrap(), b"new binary");
+        assert!(updater.download(&release(Some("/bad.sha256"))).await.is_err());
+        assert!(updater.download(&release(None)).await.is_err());
+    }
+}
@@ -0,0 +1,274 @@
+use crate::error::{Error, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::warn;
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+/// Appended to the client's key to prove the server speaks WebSocket
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Largest message accepted; listens are a few hundred bytes
+const MAX_MESSAGE: usize = 1 << 20;
+/// Longest handshake response read before giving up
+const MAX_HANDSHAKE: usize = 8 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Text messages over a WebSocket (RFC 6455) connection, after the
+/// handshake. Only what agents and the hub exchange is supported: text
+/// messages, pings and closing; extensions are never negotiated.
+pub struct WebSocket<S> {
+    stream: S,
+    /// Whether this is the client end, which masks what it sends
+    client: bool,
+}
+
+/// A connection a client WebSocket runs over, with or without TLS
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// The `Sec-WebSocket-Accept` answer to a client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    STANDARD.encode(Sha1::digest(format!("{}{}", key.trim(), HANDSHAKE_GUID)))
+}
+
+/// Opens a WebSocket to a `ws://` or `wss://` URL, sending `token` as a
+/// bearer token. A `wss://` server must present a certificate signed by
+/// `ca_cert` when set, or else by a public authority.
+pub async fn connect(url: &str, token: &str, ca_cert: Option<&Path>) -> Result<WebSocket<Box<dyn Stream>>> {
+    let url = reqwest::Url::parse(url).map_err(|e| Error::Config(format!("{}: {}", url, e)))?;
+    let tls = match url.scheme() {
+        "ws" => false,
+        "wss" => true,
+        _ => return Err(Error::Config(format!("{}: only ws:// and wss:// URLs are supported", url))),
+    };
+    let host = url.host_str().ok_or_else(|| Error::Config(format!("{}: no host", url)))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let loopback = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback(),
+        Err(_) => host.eq_ignore_ascii_case("localhost"),
+    };
+    if !tls && !loopback {
+        warn!("Sending the token for {} unencrypted; use wss:// unless the network is trusted", url);
+    }
+    let tcp = TcpStream::connect((host, port)).await.map_err(io_error)?;
+    let mut stream: Box<dyn Stream> = if tls {
+        let name = ServerName::try_from(host).map_err(|e| Error::Config(format!("{}: {}", url, e)))?;
+        Box::new(connector(ca_cert)?.connect(name, tcp).await.map_err(io_error)?)
+    } else {
+        Box::new(tcp)
+    };
+
+    let key = STANDARD.encode(rand::random::<[u8; 16]>());
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\nAuthorization: Bearer {}\r\n\r\n",
+        url.path(),
+        host,
+        port,
+        key,
+        token
+    );
+    stream.write_all(request.as_bytes()).await.map_err(io_error)?;
+
+    let response = read_head(&mut stream).await?;
+    let status = response.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(Error::Scrobble(format!("{} refused the connection: {}", url, status)));
+    }
+    let accepted = response.lines().filter_map(|line| line.split_once(':')).any(|(name, value)| {
+        name.trim().eq_ignore_ascii_case("sec-websocket-accept") && value.trim() == accept_key(&key)
+    });
+    if !accepted {
+        return Err(Error::Scrobble(format!("{} answered the handshake wrongly", url)));
+    }
+    Ok(WebSocket::client(stream))
+}
+
+/// Verifies servers against `ca_cert` when set, and otherwise against the
+/// public authorities
+fn connector(ca_cert: Option<&Path>) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match ca_cert {
+        Some(path) => {
+            let invalid = |e: std::io::Error| Error::Config(format!("{}: {}", path.display(), e));
+            let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path).map_err(invalid)?))
+                .map_err(invalid)?;
+            if roots.add_parsable_certificates(&certs).0 == 0 {
+                return Err(Error::Config(format!("{}: no certificates found", path.display())));
+            }
+        }
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+        })),
+    }
+    let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Reads an HTTP response's status line and headers, up to the blank line
+async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HANDSHAKE {
+            return Err(Error::Scrobble("handshake response too long".to_string()));
+        }
+        head.push(stream.read_u8().await.map_err(io_error)?);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocket<S> {
+    /// The client end of an upgraded connection
+    pub fn client(stream: S) -> Self {
+        Self { stream, client: true }
+    }
+
+    /// The server end of an upgraded connection
+    pub fn server(stream: S) -> Self {
+        Self { stream, client: false }
+    }
+
+    pub async fn send(&mut self, text: &str) -> Result<()> {
+        self.write_frame(TEXT, text.as_bytes()).await
+    }
+
+    /// The next text message, answering pings on the way; `None` once the
+    /// other end closed the connection
+    pub async fn recv(&mut self) -> Result<Option<String>> {
+        let mut message = Vec::new();
+        loop {
+            let (fin, opcode, payload) = self.read_frame().await?;
+            match opcode {
+                TEXT | CONTINUATION => {
+                    message.extend(payload);
+                    if message.len() > MAX_MESSAGE {
+                        return Err(Error::Scrobble("WebSocket message too large".to_string()));
+                    }
+                    if fin {
+                        let text = String::from_utf8(message)
+                            .map_err(|_| Error::Scrobble("WebSocket message is not UTF-8".to_string()))?;
+                        return Ok(Some(text));
+                    }
+                }
+                PING => self.write_frame(PONG, &payload).await?,
+                PONG => {}
+                CLOSE => {
+                    // Echoing the close is best effort, the connection ends either way
+                    let _ = self.write_frame(CLOSE, &payload).await;
+                    return Ok(None);
+                }
+                _ => return Err(Error::Scrobble(format!("unsupported WebSocket opcode {}", opcode))),
+            }
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<(bool, u8, Vec<u8>)> {
+        let [first, second] = {
+            let mut header = [0; 2];
+            self.stream.read_exact(&mut header).await.map_err(io_error)?;
+            header
+        };
+        let len = match second & 0x7F {
+            126 => self.stream.read_u16().await.map_err(io_error)? as u64,
+            127 => self.stream.read_u64().await.map_err(io_error)?,
+            len => len as u64,
+        };
+        if len > MAX_MESSAGE as u64 {
+            return Err(Error::Scrobble("WebSocket frame too large".to_string()));
+        }
+        let mask = match second & 0x80 != 0 {
+            true => {
+                let mut mask = [0; 4];
+                self.stream.read_exact(&mut mask).await.map_err(io_error)?;
+                Some(mask)
+            }
+            false => None,
+        };
+        let mut payload = vec![0; len as usize];
+        self.stream.read_exact(&mut payload).await.map_err(io_error)?;
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+        Ok((first & 0x80 != 0, first & 0x0F, payload))
+    }
+
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        let masked = if self.client { 0x80 } else { 0 };
+        match payload.len() {
+            len @ 0..=125 => frame.push(masked | len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(masked | 126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(masked | 127);
+                frame.extend((len as u64).to_be_bytes());
+            }
+        }
+        let mut payload = payload.to_vec();
+        if self.client {
+            let mask = rand::random::<[u8; 4]>();
+            frame.extend(mask);
+            apply_mask(&mut payload, mask);
+        }
+        frame.extend(payload);
+        self.stream.write_all(&frame).await.map_err(io_error)?;
+        self.stream.flush().await.map_err(io_error)?;
+        Ok(())
+    }
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::Scrobble(format!("WebSocket: {}", e))
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // The example from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn test_messages_both_ways() {
+        let (client, server) = tokio::io::duplex(1 << 20);
+        let (mut client, mut server) = (WebSocket::client(client), WebSocket::server(server));
+        let long = "x".repeat(70_000);
+
+        client.send("hello").await.unwrap();
+        client.send(&long).await.unwrap();
+        assert_eq!(server.recv().await.unwrap().as_deref(), Some("hello"));
+        assert_eq!(server.recv().await.unwrap(), Some(long));
+
+        server.send("hi").await.unwrap();
+        client.write_frame(PING, b"").await.unwrap();
+        client.write_frame(CLOSE, b"").await.unwrap();
+        assert_eq!(client.recv().await.unwrap().as_deref(), Some("hi"));
+        assert_eq!(server.recv().await.unwrap(), None);
+        assert_eq!(client.recv().await.unwrap(), None);
+    }
+}